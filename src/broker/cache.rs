@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::sync::watch;
+
+use crate::broker::state::partition::{Partition, PartitionIdx};
+use crate::broker::state::topic::Topic;
+use crate::broker::state::Store;
+
+/// The topics and partitions known to the cluster at some metadata version.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub topics: HashMap<String, Topic>,
+    pub partitions: HashMap<(String, PartitionIdx), Partition>,
+}
+
+impl Metadata {
+    fn load(store: &Store) -> Result<Self> {
+        let topics = store.get_topics()?;
+        let mut partitions = HashMap::new();
+        for topic in topics.values() {
+            for idx in topic.partitions.keys() {
+                if let Some(p) = store.get_partition(&topic.name, *idx)? {
+                    partitions.insert((topic.name.clone(), *idx), p);
+                }
+            }
+        }
+        Ok(Self { topics, partitions })
+    }
+
+    pub fn partition(&self, topic: &str, idx: PartitionIdx) -> Option<&Partition> {
+        self.partitions.get(&(topic.to_string(), idx))
+    }
+}
+
+/// Caches the cluster metadata read from the store, reloading it once the store reports that
+/// topics or partitions have changed.
+pub struct MetadataCache {
+    store: Store,
+    changes: watch::Receiver<u64>,
+    cached: Mutex<Option<(u64, Arc<Metadata>)>>,
+}
+
+impl MetadataCache {
+    pub fn new(store: Store) -> Self {
+        let changes = store.subscribe_metadata();
+        Self {
+            store,
+            changes,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Result<Arc<Metadata>> {
+        // read the version before loading, so a change that races the load is picked up next time
+        let version = *self.changes.borrow();
+        let mut cached = self.cached.lock().unwrap();
+        match &*cached {
+            Some((v, metadata)) if *v == version => Ok(metadata.clone()),
+            _ => {
+                let metadata = Arc::new(Metadata::load(&self.store)?);
+                *cached = Some((version, metadata.clone()));
+                Ok(metadata)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetadataCache;
+    use crate::broker::state::topic::Topic;
+    use crate::broker::state::Store;
+    use anyhow::Result;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    #[test]
+    fn invalidates_on_change() -> Result<()> {
+        let store = Store::new(sled::open(tempdir()?)?)?;
+        let cache = MetadataCache::new(store.clone());
+        let first = cache.get()?;
+        assert!(first.topics.is_empty());
+        assert!(Arc::ptr_eq(&first, &cache.get()?));
+
+        store.create_topic(Topic {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            partitions: HashMap::new(),
+            internal: false,
+            compacted: false,
+            max_message_bytes: None,
+            delete_retention_ms: None,
+        })?;
+        store.metadata_changed();
+        assert!(cache.get()?.topics.contains_key("test"));
+        Ok(())
+    }
+}
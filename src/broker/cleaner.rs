@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::Broker;
+use crate::Shutdown;
+
+impl Broker {
+    /// Compacts the local replicas of every compacted topic, returning the number of records
+    /// removed.
+    pub(crate) async fn clean_logs(&self) -> Result<usize> {
+        let mut removed = 0;
+        for topic in self.store.get_topics()?.values().filter(|t| t.compacted) {
+            let retention = topic
+                .delete_retention_ms
+                .unwrap_or(self.config.delete_retention_ms);
+            let retention = Duration::from_millis(retention);
+            for idx in topic.partitions.keys() {
+                let replica = self
+                    .store
+                    .get_partition(&topic.name, PartitionIdx(idx.0))?
+                    .and_then(|p| self.replicas.get(p.id));
+                if let Some(replica) = replica {
+                    removed += replica.lock().await.log.compact(retention)?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Periodically cleans the logs of compacted topics until shutdown.
+pub(crate) async fn run(broker: Arc<Broker>, mut shutdown: Shutdown) -> Result<()> {
+    let period = Duration::from_millis(broker.config.log_cleaner_interval_ms);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            _ = interval.tick() => match broker.clean_logs().await {
+                Ok(0) => {}
+                Ok(removed) => tracing::debug!(removed, "cleaned logs"),
+                Err(e) => tracing::error!(%e, "could not clean logs"),
+            },
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! Compression of the records leaders send to followers, which saves bandwidth on replication
+//! without changing how records are stored. A follower asks for it in a tagged field of its
+//! fetch, and the leader compresses each partition's records as a whole, marking the partition
+//! with the same tag so that the follower decompresses them before appending.
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use kafka_protocol::compression::{Compressor, Decompressor, Gzip, Snappy};
+use kafka_protocol::messages::fetch_response::PartitionData;
+use kafka_protocol::messages::FetchRequest;
+
+/// The tagged field of a fetch that names the compression the follower accepts, and of a
+/// partition in its response that names the compression its records were sent with.
+const COMPRESSION_TAG: i32 = 10_000;
+
+/// How records are compressed on their way from a leader to its followers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationCompression {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+}
+
+impl ReplicationCompression {
+    fn id(self) -> u8 {
+        match self {
+            ReplicationCompression::None => 0,
+            ReplicationCompression::Gzip => 1,
+            ReplicationCompression::Snappy => 2,
+        }
+    }
+
+    fn from_tag(tag: Option<&Vec<u8>>) -> Self {
+        match tag.and_then(|tag| tag.first()) {
+            Some(1) => ReplicationCompression::Gzip,
+            Some(2) => ReplicationCompression::Snappy,
+            _ => ReplicationCompression::None,
+        }
+    }
+
+    /// The compression a follower's fetch asks for.
+    pub(crate) fn requested(req: &FetchRequest) -> Self {
+        Self::from_tag(req.unknown_tagged_fields.get(&COMPRESSION_TAG))
+    }
+
+    /// Asks for fetched records to be sent with this compression.
+    pub(crate) fn request(self, req: &mut FetchRequest) {
+        if self != ReplicationCompression::None {
+            req.unknown_tagged_fields
+                .insert(COMPRESSION_TAG, vec![self.id()]);
+        }
+    }
+
+    /// Compresses a partition's records in place, marking the partition as compressed.
+    pub(crate) fn compress(self, partition: &mut PartitionData) -> Result<()> {
+        let records = match &partition.records {
+            Some(records) if !records.is_empty() => records,
+            _ => return Ok(()),
+        };
+        let mut compressed = BytesMut::new();
+        let write = |buf: &mut BytesMut| {
+            buf.put_slice(records);
+            Ok(())
+        };
+        match self {
+            ReplicationCompression::None => return Ok(()),
+            ReplicationCompression::Gzip => Gzip::compress(&mut compressed, write),
+            ReplicationCompression::Snappy => Snappy::compress(&mut compressed, write),
+        }
+        .map_err(|_| anyhow::anyhow!("could not compress records"))?;
+        partition.records = Some(compressed.freeze());
+        partition
+            .unknown_tagged_fields
+            .insert(COMPRESSION_TAG, vec![self.id()]);
+        Ok(())
+    }
+
+    /// The records of a fetched partition, decompressed if the leader compressed them.
+    pub(crate) fn decompress(partition: &PartitionData) -> Result<Bytes> {
+        let mut records = partition.records.clone().unwrap_or_default();
+        let read = |buf: &mut Bytes| Ok(std::mem::take(buf));
+        let compression = Self::from_tag(partition.unknown_tagged_fields.get(&COMPRESSION_TAG));
+        match compression {
+            ReplicationCompression::None => Ok(records),
+            ReplicationCompression::Gzip => Gzip::decompress(&mut records, read),
+            ReplicationCompression::Snappy => Snappy::decompress(&mut records, read),
+        }
+        .map_err(|_| anyhow::anyhow!("could not decompress records"))
+    }
+}
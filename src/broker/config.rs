@@ -1,5 +1,11 @@
 use std::net::{IpAddr, ToSocketAddrs};
 use std::path::PathBuf;
+use crate::broker::compression::ReplicationCompression;
+use crate::broker::log::{
+    Storage, DEFAULT_INDEX_BYTES, DEFAULT_INDEX_INTERVAL_BYTES, DEFAULT_SEGMENT_BYTES,
+};
+use crate::broker::selector::ReplicaSelectorKind;
+use crate::broker::state::codec::StoreCodec;
 use crate::broker::BrokerId;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -7,6 +13,29 @@ pub struct Peer {
     pub id: BrokerId,
     pub ip: IpAddr,
     pub port: u16,
+    /// The rack the broker is in, if it was configured with one.
+    #[serde(default)]
+    pub rack: Option<String>,
+    /// The host clients are told to connect to, if it differs from `ip`, as it does behind NAT.
+    #[serde(default)]
+    pub advertised_host: Option<String>,
+    /// The port clients are told to connect to, if it differs from `port`.
+    #[serde(default)]
+    pub advertised_port: Option<u16>,
+}
+
+impl Peer {
+    /// The host clients should connect to.
+    pub fn advertised_host(&self) -> String {
+        self.advertised_host
+            .clone()
+            .unwrap_or_else(|| self.ip.to_string())
+    }
+
+    /// The port clients should connect to.
+    pub fn advertised_port(&self) -> u16 {
+        self.advertised_port.unwrap_or(self.port)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -15,9 +44,110 @@ pub struct BrokerConfig {
     pub id: BrokerId,
     pub ip: IpAddr,
     pub port: u16,
-    pub data_dir: PathBuf,
+    /// The host advertised to clients, when they can't reach the broker at `ip`.
+    pub advertised_host: Option<String>,
+    /// The port advertised to clients, when they can't reach the broker at `port`.
+    pub advertised_port: Option<u16>,
+    /// Directories partition logs are stored in. Partitions are spread across them round-robin.
+    pub log_dirs: Vec<PathBuf>,
+    /// Where partition logs and the state store are kept, `disk` or `memory`. In memory
+    /// nothing outlives the broker, which suits tests and ephemeral deployments.
+    pub storage: Storage,
     pub state_file: PathBuf,
+    /// The format values are written to the state file in.
+    pub store_codec: StoreCodec,
     pub peers: Vec<Peer>,
+    /// Whether topics are created on first use when a client asks for their metadata.
+    pub auto_create_topics: bool,
+    /// The number of partitions of topics created automatically or without a partition count,
+    /// `num.partitions`.
+    pub default_partitions: i32,
+    /// The replication factor of topics created automatically or without one, limited to the
+    /// live brokers when the default is used, `default.replication.factor`.
+    pub default_replication_factor: i16,
+    /// The fewest in sync replicas a partition needs to accept writes with `acks=all`.
+    pub min_insync_replicas: usize,
+    /// The rack the broker is in. Consumers in the same rack may fetch from it while it is a
+    /// follower.
+    pub rack: Option<String>,
+    /// How the leader of a partition picks the replica a consumer should fetch it from.
+    pub replica_selector: ReplicaSelectorKind,
+    /// The most requests from one connection handled at once. Responses are still written in
+    /// request order, and produce requests are handled one at a time, in the order they came.
+    pub max_in_flight_requests_per_connection: usize,
+    /// How many tasks accept connections on the broker's listener (`num.network.threads`).
+    pub num_network_threads: usize,
+    /// The most connections queued up waiting to be accepted (`socket.listen.backlog.size`).
+    pub socket_listen_backlog: u32,
+    /// The shortest session timeout a group member may ask for.
+    pub group_min_session_timeout_ms: u64,
+    /// The longest session timeout a group member may ask for.
+    pub group_max_session_timeout_ms: u64,
+    /// The number of partitions of the internal topic that consumer groups are spread across.
+    pub offsets_topic_num_partitions: i32,
+    /// The replication factor of the internal offsets topic, limited to the brokers registered
+    /// when it is created.
+    pub offsets_topic_replication_factor: i16,
+    /// The most partitions the cluster may hold across all topics. Unset, topics can be
+    /// created with any number of partitions.
+    pub max_partitions: Option<usize>,
+    /// The most partitions a single topic may have.
+    pub max_partitions_per_topic: Option<usize>,
+    /// The largest record batch a topic accepts, unless it sets its own `max.message.bytes`.
+    pub message_max_bytes: u32,
+    /// How many offsets an in sync follower can fall behind the leader of a partition before
+    /// produces to it are throttled. Unset, produces are never throttled for replication lag.
+    pub produce_throttle_lag_offsets: Option<u64>,
+    /// The throttle time given to producers of a partition whose followers are lagging.
+    pub produce_throttle_ms: u64,
+    /// The size a log segment grows to before a new one is started.
+    pub log_segment_bytes: u64,
+    /// The size a segment's index is bounded to (`segment.index.bytes`). A segment is rolled
+    /// once its index is full, even if it is smaller than `log_segment_bytes`.
+    pub segment_index_bytes: u64,
+    /// How many bytes are appended to a segment between two index entries
+    /// (`index.interval.bytes`).
+    pub index_interval_bytes: u64,
+    /// The most bytes of recently read and appended record batches cached for each partition.
+    pub log_cache_bytes: u64,
+    /// The most partition logs recovered at once in each log dir when the broker starts.
+    pub num_recovery_threads_per_data_dir: usize,
+    /// How long an append to a partition log can take before it is logged as slow.
+    pub slow_append_ms: u64,
+    /// How often the logs of compacted topics are cleaned.
+    pub log_cleaner_interval_ms: u64,
+    /// How long tombstones in compacted topics are kept before they are cleaned.
+    pub delete_retention_ms: u64,
+    /// The most incremental fetch sessions kept at once. A session started while all are taken
+    /// evicts the least recently used one, whose client falls back to a full fetch.
+    pub max_fetch_sessions: usize,
+    /// The most requests handled at once, across all connections.
+    pub request_handlers: usize,
+    /// How long committed offsets of a group without members are kept, unless the commit set its
+    /// own expiry.
+    pub offsets_retention_minutes: u64,
+    /// How often the controller looks for expired offsets.
+    pub offsets_retention_check_interval_ms: u64,
+    /// The most bytes a follower fetches from a leader at once, across all partitions.
+    pub replica_fetch_max_bytes: u64,
+    /// The least time between two rounds of fetches from leaders.
+    pub replica_fetch_backoff_ms: u64,
+    /// How followers ask leaders to compress the records they replicate, which saves bandwidth
+    /// between brokers. Records are stored as they were produced either way.
+    pub inter_broker_compression: ReplicationCompression,
+    /// How long a follower can go without catching up with the leader's log before it is
+    /// removed from the ISR (`replica.lag.time.max.ms`). Followers rejoin once they catch up.
+    pub replica_lag_time_max_ms: u64,
+    /// How many fetches start waiting for records between purges of the completed ones
+    /// (`fetch.purgatory.purge.interval.requests`).
+    pub fetch_purgatory_purge_interval_requests: usize,
+    /// How many `acks=all` produces start waiting for replication between purges of the
+    /// completed ones (`producer.purgatory.purge.interval.requests`).
+    pub producer_purgatory_purge_interval_requests: usize,
+    /// How long the broker spends flushing partition logs to disk when shutting down.
+    pub shutdown_flush_timeout_ms: u64,
+    /// How often the controller moves partitions off brokers that are no longer registered.
+    pub controller_interval_ms: u64,
 }
 
 impl Default for BrokerConfig {
@@ -26,9 +156,51 @@ impl Default for BrokerConfig {
             id: BrokerId(1),
             ip: resolve("localhost").unwrap(),
             port: 8844,
-            data_dir: tempfile::tempdir().unwrap().into_path(),
+            advertised_host: None,
+            advertised_port: None,
+            log_dirs: vec![tempfile::tempdir().unwrap().into_path()],
+            storage: Storage::Disk,
             state_file: tempfile::tempdir().unwrap().into_path(),
+            store_codec: StoreCodec::Bincode,
             peers: vec![],
+            auto_create_topics: false,
+            default_partitions: 1,
+            default_replication_factor: 1,
+            min_insync_replicas: 1,
+            rack: None,
+            replica_selector: ReplicaSelectorKind::Leader,
+            max_in_flight_requests_per_connection: 1,
+            num_network_threads: 3,
+            socket_listen_backlog: 50,
+            group_min_session_timeout_ms: 6000,
+            group_max_session_timeout_ms: 1800000,
+            offsets_topic_num_partitions: 50,
+            offsets_topic_replication_factor: 3,
+            message_max_bytes: 1024 * 1024 + 12,
+            max_partitions: None,
+            max_partitions_per_topic: None,
+            produce_throttle_lag_offsets: None,
+            produce_throttle_ms: 100,
+            log_segment_bytes: DEFAULT_SEGMENT_BYTES,
+            segment_index_bytes: DEFAULT_INDEX_BYTES,
+            index_interval_bytes: DEFAULT_INDEX_INTERVAL_BYTES,
+            log_cache_bytes: 1024 * 1024,
+            num_recovery_threads_per_data_dir: 1,
+            slow_append_ms: 500,
+            log_cleaner_interval_ms: 15_000,
+            delete_retention_ms: 24 * 60 * 60 * 1000,
+            max_fetch_sessions: 1000,
+            request_handlers: 8,
+            offsets_retention_minutes: 7 * 24 * 60,
+            offsets_retention_check_interval_ms: 600_000,
+            replica_fetch_max_bytes: 10 * 1024 * 1024,
+            replica_fetch_backoff_ms: 100,
+            inter_broker_compression: ReplicationCompression::None,
+            replica_lag_time_max_ms: 30_000,
+            fetch_purgatory_purge_interval_requests: 1000,
+            producer_purgatory_purge_interval_requests: 1000,
+            shutdown_flush_timeout_ms: 10_000,
+            controller_interval_ms: 1000,
         }
     }
 }
@@ -0,0 +1,171 @@
+//! What a connection knows about a request beyond its body, passed along with it to the handler.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use kafka_protocol::messages::create_topics_response::CreatableTopicResult;
+use kafka_protocol::messages::{ApiKey, CreateTopicsResponse, RequestKind, ResponseKind};
+use kafka_protocol::ResponseError::RequestTimedOut;
+use tokio::time::Instant;
+
+use crate::broker::Broker;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestContext {
+    /// When the client stops waiting for the response, if the request says.
+    pub deadline: Option<Instant>,
+    /// The API the request is for, which a response carrying just an error is built for.
+    pub api_key: Option<ApiKey>,
+}
+
+impl RequestContext {
+    /// The context of a request that has just been read.
+    pub fn new(req: &RequestKind) -> Self {
+        Self {
+            deadline: request_timeout(req).map(|timeout| Instant::now() + timeout),
+            api_key: None,
+        }
+    }
+
+    /// Notes the API the request is for.
+    pub fn with_api_key(mut self, api_key: ApiKey) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+}
+
+/// How long the client waits for a request, for requests that carry a timeout. Fetches are left
+/// out, as they already return once their `max_wait_ms` is up, and so are produces, which only
+/// bound waiting for their appends to be replicated by `timeout_ms`: an append that has started
+/// is never abandoned.
+fn request_timeout(req: &RequestKind) -> Option<Duration> {
+    let timeout_ms = match req {
+        RequestKind::CreateTopicsRequest(req) => req.timeout_ms,
+        _ => return None,
+    };
+    (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms as u64))
+}
+
+/// The response to a request that was still being handled at its deadline.
+fn timed_out(req: &RequestKind) -> Option<ResponseKind> {
+    match req {
+        RequestKind::CreateTopicsRequest(req) => {
+            let mut res = CreateTopicsResponse::default();
+            for name in req.topics.keys() {
+                let mut topic_res = CreatableTopicResult::default();
+                topic_res.error_code = RequestTimedOut.code();
+                res.topics.insert(name.clone(), topic_res);
+            }
+            Some(ResponseKind::CreateTopicsResponse(res))
+        }
+        _ => None,
+    }
+}
+
+impl Broker {
+    /// Handles a request, giving up on it once its deadline has passed.
+    pub(crate) async fn handle_request_within(
+        &self,
+        req: RequestKind,
+        ctx: RequestContext,
+    ) -> Result<ResponseKind> {
+        let deadline = match ctx.deadline {
+            Some(deadline) => deadline,
+            None => return self.handle_request(req).await,
+        };
+        let timed_out = timed_out(&req);
+        match tokio::time::timeout_at(deadline, self.handle_request(req)).await {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::debug!("request timed out");
+                timed_out.ok_or_else(|| anyhow::anyhow!("request timed out"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use kafka_protocol::messages::create_topics_request::CreatableTopic;
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        CreateTopicsRequest, ProduceRequest, RequestKind, ResponseKind, TopicName,
+    };
+    use kafka_protocol::ResponseError::RequestTimedOut;
+
+    use super::RequestContext;
+    use crate::broker::handler::test::{new_broker, new_topic};
+    use crate::kafka::util::ToStrBytes;
+
+    #[tokio::test]
+    async fn deadline() -> Result<()> {
+        // the proposal creating the topic is never committed
+        let (_rx, broker) = new_broker();
+        let mut topic = CreatableTopic::default();
+        topic.num_partitions = 1;
+        topic.replication_factor = 1;
+        let mut req = CreateTopicsRequest::default();
+        req.timeout_ms = 100;
+        req.topics
+            .insert(TopicName("test".to_string().to_str_bytes()), topic);
+        let req = RequestKind::CreateTopicsRequest(req);
+        let ctx = RequestContext::new(&req);
+        assert!(ctx.deadline.is_some());
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            broker.handle_request_within(req, ctx),
+        )
+        .await??;
+        let res = match res {
+            ResponseKind::CreateTopicsResponse(res) => res,
+            res => panic!("unexpected response {:?}", res),
+        };
+        assert_eq!(res.topics[0].error_code, RequestTimedOut.code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_outlives_deadline() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partitions = new_topic(&broker, "test", 1)?;
+        let broker = Arc::new(broker);
+
+        let mut pd = PartitionProduceData::default();
+        pd.records = Some(Bytes::from_static(b"one"));
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.acks = 1;
+        req.timeout_ms = 100;
+        req.topic_data
+            .insert(TopicName("test".to_string().to_str_bytes()), td);
+        let req = RequestKind::ProduceRequest(req);
+        let ctx = RequestContext::new(&req);
+        assert!(ctx.deadline.is_none());
+
+        // the append waits on the partition for longer than the client does, and still finishes
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let guard = replica.lock().await;
+        let handle = {
+            let broker = broker.clone();
+            tokio::spawn(async move { broker.handle_request_within(req, ctx).await })
+        };
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(guard);
+        let res = match tokio::time::timeout(Duration::from_secs(1), handle).await??? {
+            ResponseKind::ProduceResponse(res) => res,
+            res => panic!("unexpected response {:?}", res),
+        };
+        let partition = &res.responses[0].partition_responses[0];
+        assert_eq!(partition.error_code, 0);
+        assert_eq!(partition.base_offset, 0);
+        assert_eq!(replica.lock().await.log.newest_offset(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use kafka_protocol::messages::{ApiKey, RequestHeader, RequestKind, ResponseKind};
+
+use crate::broker::config::Peer;
+use crate::broker::error::JosefineError;
+use crate::broker::fsm::Transition;
+use crate::broker::state::partition::Partition;
+use crate::broker::{Broker, BrokerId};
+use crate::kafka::{ConnectedKafkaClient, KafkaClient};
+use crate::Shutdown;
+
+impl Broker {
+    /// Whether this broker is the controller, which is whichever broker leads the raft cluster.
+    pub(crate) fn is_controller(&self) -> bool {
+        self.client.status().is_leader()
+    }
+
+    /// The controller's id, if a raft leader is known. Raft node ids are taken to be the ids of
+    /// the brokers they run alongside.
+    pub(crate) fn controller_id(&self) -> Option<BrokerId> {
+        self.client.status().leader.map(|id| BrokerId(id as i32))
+    }
+
+    /// Forwards a request that only the controller may handle to the controller.
+    pub(crate) async fn forward_to_controller(
+        &self,
+        api_key: ApiKey,
+        api_version: i16,
+        req: RequestKind,
+    ) -> Result<ResponseKind> {
+        let controller = self
+            .controller_id()
+            .and_then(|id| self.get_brokers().ok()?.into_iter().find(|b| b.id == id))
+            .ok_or(JosefineError::NotLeader)?;
+        let mut header = RequestHeader::default();
+        header.request_api_key = api_key as i16;
+        header.request_api_version = api_version;
+
+        let client = self.controller_connection(&controller).await?;
+        let res = client.send(header, req).await;
+        if res.is_err() {
+            // the next request reconnects rather than trying a connection that may be broken
+            let mut cached = self.controller.lock().await;
+            if cached.as_ref().is_some_and(|(_, c)| Arc::ptr_eq(c, &client)) {
+                *cached = None;
+            }
+        }
+        res
+    }
+
+    /// The connection to the controller, which is kept open and reused until the controller
+    /// changes or the connection closes.
+    async fn controller_connection(&self, controller: &Peer) -> Result<Arc<ConnectedKafkaClient>> {
+        let mut cached = self.controller.lock().await;
+        match &*cached {
+            Some((id, client)) if *id == controller.id && !client.is_closed() => {
+                return Ok(client.clone());
+            }
+            _ => {}
+        }
+        let client = KafkaClient::new(SocketAddr::new(controller.ip, controller.port)).await?;
+        let client = Arc::new(client.connect(Shutdown::new()).await?);
+        *cached = Some((controller.id, client.clone()));
+        Ok(client)
+    }
+
+    /// Moves leadership of partitions whose leader is no longer registered to a live in sync
+    /// replica, and drops brokers that are no longer registered from in sync replica sets.
+    /// Returns the number of partitions changed.
+    pub(crate) async fn elect_leaders(&self) -> Result<usize> {
+        let live: HashSet<i32> = self
+            .store
+            .get_registered_brokers()?
+            .into_keys()
+            .map(|id| id.0)
+            .collect();
+        // until brokers register, every configured broker is assumed to be live
+        if live.is_empty() {
+            return Ok(0);
+        }
+
+        let metadata = self.metadata.get()?;
+        let changed: Vec<Transition> = metadata
+            .partitions
+            .values()
+            .filter_map(|p| reelect(p, &live))
+            .map(Transition::EnsurePartition)
+            .collect();
+        let count = changed.len();
+        if count > 0 {
+            self.client
+                .propose(Transition::Batch(changed).serialize()?)
+                .await?;
+        }
+        Ok(count)
+    }
+
+    /// Completes the reassignments whose new replicas have all joined the ISR, returning the
+    /// number completed.
+    pub(crate) async fn complete_reassignments(&self) -> Result<usize> {
+        let metadata = self.metadata.get()?;
+        let completed: Vec<Transition> = metadata
+            .partitions
+            .values()
+            .filter_map(complete_reassignment)
+            .map(Transition::EnsurePartition)
+            .collect();
+        let count = completed.len();
+        if count > 0 {
+            self.client
+                .propose(Transition::Batch(completed).serialize()?)
+                .await?;
+        }
+        Ok(count)
+    }
+}
+
+/// The partition once its reassignment completes, if all the replicas it adds are in sync. The
+/// replicas it removes are dropped, and leadership moves if the leader was one of them.
+fn complete_reassignment(partition: &Partition) -> Option<Partition> {
+    let caught_up = partition
+        .adding_replicas
+        .iter()
+        .all(|id| partition.isr.contains(id));
+    if !partition.is_reassigning() || !caught_up {
+        return None;
+    }
+    let keep = |ids: &[i32]| -> Vec<i32> {
+        ids.iter()
+            .copied()
+            .filter(|id| !partition.removing_replicas.contains(id))
+            .collect()
+    };
+    let isr = keep(&partition.isr);
+    let leader = match isr.first() {
+        Some(_) if isr.contains(&partition.leader.0) => partition.leader,
+        Some(id) => BrokerId(*id),
+        None => return None,
+    };
+    let leader_epoch = match leader == partition.leader {
+        true => partition.leader_epoch,
+        false => partition.leader_epoch + 1,
+    };
+    Some(Partition {
+        assigned_replicas: keep(&partition.assigned_replicas),
+        isr,
+        leader,
+        leader_epoch,
+        adding_replicas: vec![],
+        removing_replicas: vec![],
+        ..partition.clone()
+    })
+}
+
+/// The partition with its in sync replicas limited to live brokers, and a live leader, if that
+/// changes it. Partitions with no live in sync replica are left alone, as electing an out of sync
+/// replica would lose records.
+fn reelect(partition: &Partition, live: &HashSet<i32>) -> Option<Partition> {
+    let isr: Vec<i32> = partition
+        .isr
+        .iter()
+        .copied()
+        .filter(|id| live.contains(id))
+        .collect();
+    let leader = match isr.first() {
+        Some(_) if isr.contains(&partition.leader.0) => partition.leader,
+        Some(id) => BrokerId(*id),
+        None => return None,
+    };
+    if isr == partition.isr && leader == partition.leader {
+        return None;
+    }
+    let leader_epoch = match leader == partition.leader {
+        true => partition.leader_epoch,
+        false => partition.leader_epoch + 1,
+    };
+    Some(Partition {
+        isr,
+        leader,
+        leader_epoch,
+        ..partition.clone()
+    })
+}
+
+/// Runs the controller's duties for as long as this broker leads the raft cluster. Since all
+/// controller state is replicated, a new leader picks up where the last one left off.
+pub(crate) async fn run(broker: Arc<Broker>, mut shutdown: Shutdown) -> Result<()> {
+    let period = Duration::from_millis(broker.config.controller_interval_ms);
+    let mut interval = tokio::time::interval(period);
+    let mut status = broker.client.watch_status();
+    let mut active = false;
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            // the sender only goes away with raft, at which point the status stops changing
+            Ok(()) = status.changed() => {}
+            _ = interval.tick() => {}
+        }
+
+        let controller = broker.is_controller();
+        match (active, controller) {
+            (false, true) => tracing::info!(id = %broker.config.id, "became controller"),
+            (true, false) => tracing::info!(id = %broker.config.id, "resigned as controller"),
+            _ => {}
+        }
+        active = controller;
+        if !active {
+            continue;
+        }
+        match broker.elect_leaders().await {
+            Ok(0) => {}
+            Ok(changed) => tracing::info!(changed, "updated partition leaders"),
+            Err(e) => tracing::error!(%e, "could not update partition leaders"),
+        }
+        match broker.complete_reassignments().await {
+            Ok(0) => {}
+            Ok(completed) => tracing::info!(completed, "completed partition reassignments"),
+            Err(e) => tracing::error!(%e, "could not complete partition reassignments"),
+        }
+        match broker.ensure_offsets_topic().await {
+            Ok(false) => {}
+            Ok(true) => tracing::info!("created the offsets topic"),
+            Err(e) => tracing::error!(%e, "could not create the offsets topic"),
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use uuid::Uuid;
+
+    use crate::broker::config::Peer;
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::state::partition::{Partition, PartitionIdx};
+    use crate::broker::state::topic::Topic;
+    use crate::broker::BrokerId;
+    use crate::raft::{RaftRole, Status};
+    use crate::Shutdown;
+
+    #[tokio::test]
+    async fn reuses_controller_connection() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let controller = Peer {
+            id: BrokerId(2),
+            ip: addr.ip(),
+            port: addr.port(),
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        };
+
+        let first = broker.controller_connection(&controller).await?;
+        let (stream, _) = listener.accept().await?;
+        let second = broker.controller_connection(&controller).await?;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // once the controller closes the connection, the next request opens a new one
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !first.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let third = broker.controller_connection(&controller).await?;
+        assert!(!Arc::ptr_eq(&first, &third));
+        listener.accept().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn takes_over_on_leadership_change() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        apply_proposals(rx, &broker);
+        let (status_tx, status) = tokio::sync::watch::channel(Status {
+            id: 1,
+            term: 1,
+            role: RaftRole::Follower,
+            leader: Some(2),
+        });
+        broker.client = broker.client.clone().with_status(status);
+        broker.config.controller_interval_ms = 10;
+
+        broker.store.create_topic(Topic {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            partitions: [(PartitionIdx(0), vec![BrokerId(1), BrokerId(2)])].into(),
+            internal: false,
+            compacted: false,
+            max_message_bytes: None,
+            delete_retention_ms: None,
+        })?;
+        broker.store.create_partition(Partition {
+            id: Uuid::new_v4(),
+            idx: PartitionIdx(0),
+            topic: "test".to_string(),
+            isr: vec![2, 1],
+            assigned_replicas: vec![1, 2],
+            leader: BrokerId(2),
+            leader_epoch: 0,
+            adding_replicas: vec![],
+            removing_replicas: vec![],
+        })?;
+        // broker 2 has gone away
+        broker.store.register_broker(&broker.peer())?;
+
+        let broker = Arc::new(broker);
+        let shutdown = Shutdown::new();
+        tokio::spawn(super::run(broker.clone(), shutdown.clone()));
+        let leader = || -> Result<BrokerId> {
+            let partition = broker.store.get_partition("test", PartitionIdx(0))?.unwrap();
+            Ok(partition.leader)
+        };
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(leader()?, BrokerId(2));
+
+        status_tx.send(Status {
+            id: 1,
+            term: 2,
+            role: RaftRole::Leader,
+            leader: Some(1),
+        })?;
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while leader()? != BrokerId(1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+        let partition = broker.store.get_partition("test", PartitionIdx(0))?.unwrap();
+        assert_eq!(partition.isr, vec![1]);
+        assert_eq!(partition.leader_epoch, 1);
+        shutdown.shutdown();
+        Ok(())
+    }
+}
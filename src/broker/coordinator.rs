@@ -0,0 +1,116 @@
+//! Group coordination. Each group belongs to a partition of the internal `__consumer_offsets`
+//! topic, picked by hashing the group id, and is coordinated by that partition's leader, so
+//! coordination moves with partition leadership when a broker goes away.
+
+use anyhow::Result;
+use kafka_protocol::messages::create_topics_request::{CreatableTopic, CreateableTopicConfig};
+
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::{Broker, BrokerId};
+use crate::kafka::util::ToStrBytes;
+
+/// The internal topic whose partitions own consumer groups.
+pub const OFFSETS_TOPIC: &str = "__consumer_offsets";
+
+/// The partition of a topic with `partitions` partitions that owns `group`. This matches Kafka's
+/// placement, which takes the group id's Java `String.hashCode`.
+pub(crate) fn group_partition(group: &str, partitions: i32) -> PartitionIdx {
+    let hash = group
+        .encode_utf16()
+        .fold(0i32, |h, c| h.wrapping_mul(31).wrapping_add(c as i32));
+    PartitionIdx(hash.checked_abs().unwrap_or(0) % partitions.max(1))
+}
+
+impl Broker {
+    /// The broker coordinating `group`, or `None` until the offsets topic has been created.
+    pub(crate) fn group_coordinator(&self, group: &str) -> Result<Option<BrokerId>> {
+        let metadata = self.metadata.get()?;
+        let topic = match metadata.topics.get(OFFSETS_TOPIC) {
+            Some(topic) => topic,
+            None => return Ok(None),
+        };
+        let idx = group_partition(group, topic.partitions.len() as i32);
+        Ok(metadata.partition(OFFSETS_TOPIC, idx).map(|p| p.leader))
+    }
+
+    /// Whether this broker coordinates `group`. Until the offsets topic exists every broker
+    /// coordinates the groups it is asked about.
+    pub(crate) fn is_coordinator(&self, group: &str) -> Result<bool> {
+        let coordinator = self.group_coordinator(group)?;
+        Ok(coordinator.is_none_or(|id| id == self.config.id))
+    }
+
+    /// Creates the offsets topic if it doesn't exist yet, returning whether it was created.
+    pub(crate) async fn ensure_offsets_topic(&self) -> Result<bool> {
+        if self.metadata.get()?.topics.contains_key(OFFSETS_TOPIC) {
+            return Ok(false);
+        }
+        // brokers register as they start, so a young cluster may not have enough of them yet
+        let brokers = self.get_broker_ids()?.len() as i16;
+        if brokers == 0 {
+            // a topic without replicas could never be written to, so wait for the next attempt
+            tracing::debug!("no brokers to place the offsets topic on yet");
+            return Ok(false);
+        }
+        let mut config = CreateableTopicConfig::default();
+        config.value = Some("compact".to_string().to_str_bytes());
+        let mut topic = CreatableTopic::default();
+        topic.num_partitions = self.config.offsets_topic_num_partitions;
+        topic.replication_factor = self.config.offsets_topic_replication_factor.min(brokers);
+        topic
+            .configs
+            .insert("cleanup.policy".to_string().to_str_bytes(), config);
+        let res = self.create_topic(OFFSETS_TOPIC, topic).await?;
+        if res.error_code != 0 {
+            let message = res.error_message.as_deref().unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "could not create the offsets topic ({}): {}",
+                res.error_code,
+                message
+            ));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{group_partition, OFFSETS_TOPIC};
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::state::partition::PartitionIdx;
+
+    #[test]
+    fn group_partition_matches_kafka() {
+        // "my-group".hashCode() is -1906497762
+        assert_eq!(group_partition("my-group", 50), PartitionIdx(12));
+        assert_eq!(group_partition("consumers", 50), PartitionIdx(33));
+        assert_eq!(group_partition("ünïcode", 50), PartitionIdx(16));
+        assert_eq!(group_partition("", 50), PartitionIdx(0));
+    }
+
+    #[tokio::test]
+    async fn creates_offsets_topic() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.offsets_topic_num_partitions = 3;
+        apply_proposals(rx, &broker);
+        assert_eq!(broker.group_coordinator("my-group")?, None);
+        assert!(broker.is_coordinator("my-group")?);
+
+        assert!(broker.ensure_offsets_topic().await?);
+        assert!(!broker.ensure_offsets_topic().await?);
+        let topic = broker.store.get_topic(OFFSETS_TOPIC)?.unwrap();
+        assert!(topic.internal);
+        assert!(topic.compacted);
+        assert_eq!(topic.partitions.len(), 3);
+
+        let idx = group_partition("my-group", 3);
+        let partition = broker.store.get_partition(OFFSETS_TOPIC, idx)?.unwrap();
+        assert_eq!(
+            broker.group_coordinator("my-group")?,
+            Some(partition.leader)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! The failures a request can end in as a whole, rather than for one of its partitions, and the
+//! Kafka error codes clients are told about them with. Handlers fail with whatever error they
+//! ran into, which [`JosefineError::classify`] sorts into one of these.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use kafka_protocol::ResponseError;
+
+use crate::kafka::error::ErrorKind;
+use crate::raft::client::Overloaded;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JosefineError {
+    /// Reading or writing a log, or the broker's state, failed.
+    Storage(String),
+    /// The request has to go to the leader of a partition, which this broker isn't.
+    NotLeader,
+    UnknownTopicOrPartition,
+    /// Raft didn't take or commit a proposal in time.
+    Timeout,
+    /// The request couldn't be decoded, or asks for something that makes no sense.
+    InvalidRequest(String),
+    /// The request is of a version of its API that the broker doesn't support.
+    UnsupportedVersion(String),
+    /// The client couldn't be authenticated.
+    AuthenticationFailed(String),
+    /// The client isn't allowed to do what it asked.
+    AuthorizationFailed,
+    Unknown(String),
+}
+
+impl JosefineError {
+    /// Sorts an error a handler failed with by the first of its causes that is recognized,
+    /// taking it to be unknown if none are.
+    pub fn classify(err: &anyhow::Error) -> JosefineError {
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<JosefineError>() {
+                return e.clone();
+            }
+            if cause.is::<std::io::Error>()
+                || cause.is::<sled::Error>()
+                || cause.is::<bincode::ErrorKind>()
+            {
+                return JosefineError::Storage(cause.to_string());
+            }
+            if cause.is::<Overloaded>() || cause.is::<tokio::time::error::Elapsed>() {
+                return JosefineError::Timeout;
+            }
+            match cause.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::UnsupportedVersion { .. }) => {
+                    return JosefineError::UnsupportedVersion(cause.to_string());
+                }
+                Some(ErrorKind::DecodeError | ErrorKind::UnsupportedOperation) => {
+                    return JosefineError::InvalidRequest(cause.to_string());
+                }
+                _ => {}
+            }
+        }
+        JosefineError::Unknown(err.to_string())
+    }
+}
+
+impl Display for JosefineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JosefineError::Storage(e) => write!(f, "storage error: {}", e),
+            JosefineError::NotLeader => write!(f, "not the leader"),
+            JosefineError::UnknownTopicOrPartition => write!(f, "unknown topic or partition"),
+            JosefineError::Timeout => write!(f, "timed out"),
+            JosefineError::InvalidRequest(e) => write!(f, "invalid request: {}", e),
+            JosefineError::UnsupportedVersion(e) => write!(f, "unsupported version: {}", e),
+            JosefineError::AuthenticationFailed(e) => write!(f, "authentication failed: {}", e),
+            JosefineError::AuthorizationFailed => write!(f, "not authorized"),
+            JosefineError::Unknown(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for JosefineError {}
+
+/// The error code a request that failed with `err` is answered with.
+pub fn kafka_error_code(err: &JosefineError) -> i16 {
+    let error = match err {
+        JosefineError::Storage(_) => ResponseError::KafkaStorageError,
+        JosefineError::NotLeader => ResponseError::NotLeaderOrFollower,
+        JosefineError::UnknownTopicOrPartition => ResponseError::UnknownTopicOrPartition,
+        JosefineError::Timeout => ResponseError::RequestTimedOut,
+        JosefineError::InvalidRequest(_) => ResponseError::InvalidRequest,
+        JosefineError::UnsupportedVersion(_) => ResponseError::UnsupportedVersion,
+        JosefineError::AuthenticationFailed(_) => ResponseError::SaslAuthenticationFailed,
+        JosefineError::AuthorizationFailed => ResponseError::ClusterAuthorizationFailed,
+        JosefineError::Unknown(_) => ResponseError::UnknownServerError,
+    };
+    error.code()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use kafka_protocol::ResponseError;
+
+    use super::{kafka_error_code, JosefineError};
+    use crate::kafka::error::ErrorKind;
+    use crate::raft::client::Overloaded;
+
+    #[test]
+    fn error_codes() {
+        let cases = [
+            (JosefineError::Storage("disk".to_string()), ResponseError::KafkaStorageError),
+            (JosefineError::NotLeader, ResponseError::NotLeaderOrFollower),
+            (JosefineError::UnknownTopicOrPartition, ResponseError::UnknownTopicOrPartition),
+            (JosefineError::Timeout, ResponseError::RequestTimedOut),
+            (JosefineError::InvalidRequest("bad".to_string()), ResponseError::InvalidRequest),
+            (
+                JosefineError::UnsupportedVersion("v9".to_string()),
+                ResponseError::UnsupportedVersion,
+            ),
+            (
+                JosefineError::AuthenticationFailed("who".to_string()),
+                ResponseError::SaslAuthenticationFailed,
+            ),
+            (JosefineError::AuthorizationFailed, ResponseError::ClusterAuthorizationFailed),
+            (JosefineError::Unknown("?".to_string()), ResponseError::UnknownServerError),
+        ];
+        for (err, expected) in cases {
+            assert_eq!(kafka_error_code(&err), expected.code(), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "disk full");
+        let unsupported = || ErrorKind::UnsupportedVersion {
+            api_key: 1,
+            version: 99,
+        };
+        let cases = [
+            (anyhow::Error::from(JosefineError::NotLeader), JosefineError::NotLeader),
+            (io.into(), JosefineError::Storage("disk full".to_string())),
+            (Overloaded.into(), JosefineError::Timeout),
+            (
+                ErrorKind::DecodeError.into(),
+                JosefineError::InvalidRequest(ErrorKind::DecodeError.to_string()),
+            ),
+            (
+                unsupported().into(),
+                JosefineError::UnsupportedVersion(unsupported().to_string()),
+            ),
+            (anyhow::anyhow!("oops"), JosefineError::Unknown("oops".to_string())),
+        ];
+        for (err, expected) in cases {
+            assert_eq!(JosefineError::classify(&err), expected);
+        }
+
+        // causes are recognized under the context added to them
+        let err = Err::<(), _>(Overloaded).context("creating topic").unwrap_err();
+        assert_eq!(JosefineError::classify(&err), JosefineError::Timeout);
+    }
+}
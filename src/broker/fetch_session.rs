@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::{FetchRequest, FetchResponse, TopicName};
+use kafka_protocol::ResponseError;
+use kafka_protocol::ResponseError::{FetchSessionIdNotFound, InvalidFetchSessionEpoch};
+
+/// The epoch a client sends to start a new session with a full fetch.
+const INITIAL_EPOCH: i32 = 0;
+
+/// The partitions a client fetches in a session, along with what it was last sent for each.
+#[derive(Debug, Default)]
+struct FetchSession {
+    /// The epoch the next incremental fetch in the session is expected to carry.
+    epoch: i32,
+    partitions: BTreeMap<TopicName, BTreeMap<i32, FetchPartition>>,
+    /// The high watermark last returned for each partition.
+    high_watermarks: HashMap<(TopicName, i32), i64>,
+    /// When the session was last fetched in, on the clock of `Sessions`.
+    last_used: u64,
+}
+
+impl FetchSession {
+    fn update(&mut self, req: &FetchRequest) {
+        for t in &req.topics {
+            let partitions = self.partitions.entry(t.topic.clone()).or_default();
+            for p in &t.partitions {
+                partitions.insert(p.partition, p.clone());
+            }
+        }
+        for t in &req.forgotten_topics_data {
+            if let Some(partitions) = self.partitions.get_mut(&t.topic) {
+                for idx in &t.partitions {
+                    partitions.remove(idx);
+                    self.high_watermarks.remove(&(t.topic.clone(), *idx));
+                }
+                if partitions.is_empty() {
+                    self.partitions.remove(&t.topic);
+                }
+            }
+        }
+        self.epoch = next_epoch(self.epoch);
+    }
+
+    fn topics(&self) -> Vec<FetchTopic> {
+        self.partitions
+            .iter()
+            .map(|(name, partitions)| {
+                let mut topic = FetchTopic::default();
+                topic.topic = name.clone();
+                topic.partitions = partitions.values().cloned().collect();
+                topic
+            })
+            .collect()
+    }
+}
+
+fn next_epoch(epoch: i32) -> i32 {
+    match epoch {
+        i32::MAX => 1,
+        epoch => epoch + 1,
+    }
+}
+
+/// How a fetch request relates to a fetch session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionFetch {
+    /// A full fetch outside of any session.
+    Sessionless,
+    /// A full fetch that starts the session with the given id.
+    Full(i32),
+    /// A fetch of only the partitions that changed in the session with the given id.
+    Incremental(i32),
+}
+
+/// The incremental fetch sessions of the broker's consumers and followers.
+pub struct FetchSessions {
+    max_sessions: usize,
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    next_id: i32,
+    /// Ticks once for every fetch in a session, ordering sessions by when they were last used.
+    clock: u64,
+    sessions: HashMap<i32, FetchSession>,
+}
+
+impl Sessions {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Makes room for a new session by evicting the one that has gone unused the longest.
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .sessions
+            .iter()
+            .min_by_key(|(_, session)| session.last_used)
+            .map(|(id, _)| *id);
+        if let Some(id) = oldest {
+            tracing::debug!(id, "evicting fetch session");
+            self.sessions.remove(&id);
+        }
+    }
+}
+
+impl FetchSessions {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            max_sessions,
+            sessions: Default::default(),
+        }
+    }
+
+    /// Resolves the session a request belongs to. The partitions of an incremental fetch are
+    /// merged into the session, and the request is rewritten to fetch every partition in it. A
+    /// new session takes the place of the least recently used one once all are taken.
+    pub fn begin(&self, req: &mut FetchRequest) -> Result<SessionFetch, ResponseError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if req.session_id != 0 && req.session_epoch <= INITIAL_EPOCH {
+            sessions.sessions.remove(&req.session_id);
+        }
+
+        if req.session_epoch < INITIAL_EPOCH {
+            return Ok(SessionFetch::Sessionless);
+        }
+        if req.session_epoch == INITIAL_EPOCH {
+            if self.max_sessions == 0 {
+                return Ok(SessionFetch::Sessionless);
+            }
+            while sessions.sessions.len() >= self.max_sessions {
+                sessions.evict_least_recently_used();
+            }
+            let id = loop {
+                sessions.next_id = next_epoch(sessions.next_id);
+                if !sessions.sessions.contains_key(&sessions.next_id) {
+                    break sessions.next_id;
+                }
+            };
+            let mut session = FetchSession {
+                last_used: sessions.tick(),
+                ..Default::default()
+            };
+            session.update(req);
+            sessions.sessions.insert(id, session);
+            return Ok(SessionFetch::Full(id));
+        }
+
+        let now = sessions.tick();
+        let session = sessions
+            .sessions
+            .get_mut(&req.session_id)
+            .ok_or(FetchSessionIdNotFound)?;
+        if session.epoch != req.session_epoch {
+            return Err(InvalidFetchSessionEpoch);
+        }
+        session.last_used = now;
+        session.update(req);
+        req.topics = session.topics();
+        Ok(SessionFetch::Incremental(req.session_id))
+    }
+
+    /// Tags the response with its session. Incremental responses only keep the partitions that
+    /// have records, an error or a new high watermark since the last response in the session.
+    pub fn finish(&self, fetch: SessionFetch, res: &mut FetchResponse) {
+        let id = match fetch {
+            SessionFetch::Sessionless => return,
+            SessionFetch::Full(id) | SessionFetch::Incremental(id) => id,
+        };
+        res.session_id = id;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = match sessions.sessions.get_mut(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        for topic in &mut res.responses {
+            topic.partitions.retain(|p| {
+                let key = (topic.topic.clone(), p.partition_index);
+                let previous = session.high_watermarks.insert(key, p.high_watermark);
+                let changed = previous != Some(p.high_watermark)
+                    || p.error_code != 0
+                    || p.records.as_ref().is_some_and(|r| !r.is_empty());
+                changed || fetch == SessionFetch::Full(id)
+            });
+        }
+        if let SessionFetch::Incremental(_) = fetch {
+            res.responses.retain(|t| !t.partitions.is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kafka_protocol::messages::FetchRequest;
+    use kafka_protocol::ResponseError::FetchSessionIdNotFound;
+
+    use super::{FetchSessions, SessionFetch};
+
+    fn start(sessions: &FetchSessions) -> i32 {
+        let mut req = FetchRequest::default();
+        req.session_epoch = 0;
+        match sessions.begin(&mut req) {
+            Ok(SessionFetch::Full(id)) => id,
+            res => panic!("unexpected session {:?}", res),
+        }
+    }
+
+    fn fetch(sessions: &FetchSessions, id: i32, epoch: i32) -> Result<SessionFetch, i16> {
+        let mut req = FetchRequest::default();
+        req.session_id = id;
+        req.session_epoch = epoch;
+        sessions.begin(&mut req).map_err(|e| e.code())
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let sessions = FetchSessions::new(2);
+        let a = start(&sessions);
+        let b = start(&sessions);
+
+        // a was fetched in since b started, so b makes way for c
+        assert_eq!(fetch(&sessions, a, 1), Ok(SessionFetch::Incremental(a)));
+        let c = start(&sessions);
+        assert_eq!(fetch(&sessions, b, 1), Err(FetchSessionIdNotFound.code()));
+        assert_eq!(fetch(&sessions, a, 2), Ok(SessionFetch::Incremental(a)));
+        assert_eq!(fetch(&sessions, c, 1), Ok(SessionFetch::Incremental(c)));
+    }
+}
@@ -0,0 +1,563 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::offset_for_leader_epoch_request::{
+    OffsetForLeaderPartition, OffsetForLeaderTopic,
+};
+use kafka_protocol::messages::{
+    ApiKey, FetchRequest, FetchResponse, OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse,
+    RequestHeader, RequestKind, ResponseKind, TopicName,
+};
+use tokio::task::JoinHandle;
+
+use crate::broker::compression::ReplicationCompression;
+use crate::broker::state::partition::Partition;
+use crate::broker::{Broker, BrokerId};
+use crate::kafka::util::ToStrBytes;
+use crate::kafka::{ConnectedKafkaClient, KafkaClient};
+use crate::Shutdown;
+
+/// The fetch version followers use, the last before topics were identified by id.
+const FETCH_VERSION: i16 = 12;
+/// How long a leader may hold a follower's fetch waiting for new records.
+const FETCH_WAIT: Duration = Duration::from_millis(500);
+/// The OffsetForLeaderEpoch version followers use, the last before flexible versions.
+const OFFSET_FOR_LEADER_EPOCH_VERSION: i16 = 3;
+
+/// Builds the fetches a follower sends to a single leader. Each fetch asks for at most
+/// `max_bytes` in total, which the leader spends on partitions in the order they are requested,
+/// so the first partition is rotated every round to keep a busy partition from starving the rest.
+#[derive(Debug)]
+pub struct ReplicaFetcher {
+    replica_id: BrokerId,
+    max_bytes: u64,
+    round: usize,
+    compression: ReplicationCompression,
+}
+
+impl ReplicaFetcher {
+    pub fn new(replica_id: BrokerId, max_bytes: u64) -> Self {
+        Self {
+            replica_id,
+            max_bytes,
+            round: 0,
+            compression: ReplicationCompression::None,
+        }
+    }
+
+    /// Asks leaders to compress the records they send back.
+    pub fn with_compression(mut self, compression: ReplicationCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The next fetch for `partitions`, given as each partition with the offset to fetch from.
+    pub fn next_request(&mut self, partitions: &[(Partition, u64)]) -> FetchRequest {
+        let max_bytes = self.max_bytes.min(i32::MAX as u64) as i32;
+        let mut req = FetchRequest::default();
+        req.replica_id = self.replica_id.0.into();
+        req.max_wait_ms = FETCH_WAIT.as_millis() as i32;
+        req.min_bytes = 1;
+        req.max_bytes = max_bytes;
+        self.compression.request(&mut req);
+
+        let start = match partitions.len() {
+            0 => 0,
+            len => self.round % len,
+        };
+        self.round = self.round.wrapping_add(1);
+        for (partition, offset) in partitions[start..].iter().chain(&partitions[..start]) {
+            let mut p = FetchPartition::default();
+            p.partition = partition.idx.0;
+            p.current_leader_epoch = partition.leader_epoch;
+            p.fetch_offset = *offset as i64;
+            p.partition_max_bytes = max_bytes;
+            // partitions of one topic may be split up by the rotation, so only merge neighbours
+            match req.topics.last_mut() {
+                Some(t) if **t.topic == partition.topic => t.partitions.push(p),
+                _ => {
+                    let mut t = FetchTopic::default();
+                    t.topic = TopicName(partition.topic.clone().to_str_bytes());
+                    t.partitions.push(p);
+                    req.topics.push(t);
+                }
+            }
+        }
+        req
+    }
+}
+
+/// Splits fetched records into whole batches, dropping a batch at the end that was cut short by
+/// the fetch size.
+pub(crate) fn whole_batches(records: &[u8]) -> Vec<&[u8]> {
+    let mut batches = Vec::new();
+    let mut position = 0;
+    while let Some(header) = records.get(position..position + 12) {
+        let len = 12 + i32::from_be_bytes(header[8..12].try_into().unwrap()).max(0) as usize;
+        match records.get(position..position + len) {
+            Some(batch) => batches.push(batch),
+            None => break,
+        }
+        position += len;
+    }
+    batches
+}
+
+impl Broker {
+    /// The partitions we hold a replica of but don't lead, by leader, with the offset each is
+    /// replicated up to.
+    async fn followed_partitions(&self) -> Result<HashMap<BrokerId, Vec<(Partition, u64)>>> {
+        let mut followed: HashMap<BrokerId, Vec<(Partition, u64)>> = HashMap::new();
+        for partition in self.metadata.get()?.partitions.values() {
+            if partition.leader == self.config.id {
+                continue;
+            }
+            if let Some(replica) = self.replicas.get(partition.id) {
+                let offset = replica.lock().await.log.newest_offset();
+                followed
+                    .entry(partition.leader)
+                    .or_default()
+                    .push((partition.clone(), offset));
+            }
+        }
+        Ok(followed)
+    }
+
+    /// Splits `partitions` into those whose log was checked against the leader's in its current
+    /// epoch, which are ready to fetch, and a request asking the leader where the latest epoch
+    /// of each of the others ended. Logs with no epochs to ask about are truncated to their high
+    /// watermark, the most we know the leader has too.
+    async fn check_divergence(
+        &self,
+        partitions: &[(Partition, u64)],
+    ) -> Result<(Vec<(Partition, u64)>, OffsetForLeaderEpochRequest)> {
+        let mut ready = vec![];
+        let mut req = OffsetForLeaderEpochRequest::default();
+        req.replica_id = self.config.id.0.into();
+        for (partition, offset) in partitions {
+            let replica = match self.replicas.get(partition.id) {
+                Some(replica) => replica,
+                None => continue,
+            };
+            let mut replica = replica.lock().await;
+            if replica.checked_epoch == Some(partition.leader_epoch) {
+                ready.push((partition.clone(), *offset));
+                continue;
+            }
+            let epoch = match replica.epochs.last_key_value() {
+                Some((epoch, _)) => *epoch,
+                None => {
+                    let high_watermark = replica.high_watermark;
+                    replica.truncate(high_watermark)?;
+                    replica.checked_epoch = Some(partition.leader_epoch);
+                    ready.push((partition.clone(), replica.log.newest_offset()));
+                    continue;
+                }
+            };
+            let mut p = OffsetForLeaderPartition::default();
+            p.partition = partition.idx.0;
+            p.current_leader_epoch = partition.leader_epoch;
+            p.leader_epoch = epoch;
+            req.topics
+                .entry(TopicName(partition.topic.clone().to_str_bytes()))
+                .or_insert_with(OffsetForLeaderTopic::default)
+                .partitions
+                .push(p);
+        }
+        Ok((ready, req))
+    }
+
+    /// Truncates the logs the leader answered for to where it says their latest epoch ended,
+    /// dropping the batches that diverged from its log, and returns them as ready to fetch. A
+    /// leader that doesn't know the epoch either only vouches for our high watermark.
+    async fn truncate_diverged(
+        &self,
+        partitions: &[(Partition, u64)],
+        res: &OffsetForLeaderEpochResponse,
+    ) -> Result<Vec<(Partition, u64)>> {
+        let mut ready = vec![];
+        for (name, topic) in &res.topics {
+            for data in &topic.partitions {
+                let checked = partitions
+                    .iter()
+                    .find(|(p, _)| p.topic == ***name && p.idx.0 == data.partition);
+                let partition = match checked {
+                    Some((partition, _)) if data.error_code == 0 => partition,
+                    _ => continue,
+                };
+                let replica = match self.replicas.get(partition.id) {
+                    Some(replica) => replica,
+                    None => continue,
+                };
+                let mut replica = replica.lock().await;
+                let end = match data.end_offset {
+                    end if end >= 0 => end as u64,
+                    _ => replica.high_watermark,
+                };
+                if end < replica.log.newest_offset() {
+                    let (topic, idx) = (&partition.topic, partition.idx);
+                    tracing::info!(%topic, %idx, end, "truncating diverged log");
+                }
+                replica.truncate(end)?;
+                replica.checked_epoch = Some(partition.leader_epoch);
+                ready.push((partition.clone(), replica.log.newest_offset()));
+            }
+        }
+        Ok(ready)
+    }
+
+    /// Appends the whole batches a leader returned to our replicas, returning the number of
+    /// batches appended. Partitions whose log moved since the fetch was sent are skipped.
+    async fn append_fetched(
+        &self,
+        partitions: &[(Partition, u64)],
+        res: &FetchResponse,
+    ) -> Result<usize> {
+        let mut appended = 0;
+        for topic in &res.responses {
+            for data in &topic.partitions {
+                let fetched = partitions
+                    .iter()
+                    .find(|(p, _)| p.topic == **topic.topic && p.idx.0 == data.partition_index);
+                let (partition, offset) = match fetched {
+                    Some(fetched) if data.error_code == 0 => fetched,
+                    _ => continue,
+                };
+                let replica = match self.replicas.get(partition.id) {
+                    Some(replica) => replica,
+                    None => continue,
+                };
+                let mut replica = replica.lock().await;
+                if replica.log.newest_offset() != *offset {
+                    continue;
+                }
+                let records = ReplicationCompression::decompress(data)?;
+                let batches = whole_batches(&records);
+                if !batches.is_empty() {
+                    replica.start_epoch(partition.leader_epoch);
+                }
+                for batch in &batches {
+                    let offset = replica.log.newest_offset();
+                    replica.log.write_all(batch)?;
+                    replica.track_transactions(offset, batch);
+                }
+                appended += batches.len();
+                let high_watermark = data.high_watermark.max(0) as u64;
+                replica.high_watermark = high_watermark.min(replica.log.newest_offset());
+                replica.observe_leader_high_watermark(high_watermark);
+                self.fetch_purgatory.complete(&partition.id);
+            }
+        }
+        Ok(appended)
+    }
+
+    async fn connect_to(&self, id: BrokerId, shutdown: &Shutdown) -> Result<ConnectedKafkaClient> {
+        let peer = self
+            .get_brokers()?
+            .into_iter()
+            .find(|b| b.id == id)
+            .ok_or_else(|| anyhow::anyhow!("broker {} is not known", id))?;
+        let client = KafkaClient::new(SocketAddr::new(peer.ip, peer.port)).await?;
+        client.connect(shutdown.clone()).await
+    }
+
+    async fn fetch_from(
+        &self,
+        leader: BrokerId,
+        client: &ConnectedKafkaClient,
+        fetcher: &mut ReplicaFetcher,
+        partitions: &[(Partition, u64)],
+    ) -> Result<usize> {
+        let (mut ready, req) = self.check_divergence(partitions).await?;
+        if !req.topics.is_empty() {
+            let mut header = RequestHeader::default();
+            header.request_api_key = ApiKey::OffsetForLeaderEpochKey as i16;
+            header.request_api_version = OFFSET_FOR_LEADER_EPOCH_VERSION;
+            let req = RequestKind::OffsetForLeaderEpochRequest(req);
+            match client.send(header, req).await? {
+                ResponseKind::OffsetForLeaderEpochResponse(res) => {
+                    ready.extend(self.truncate_diverged(partitions, &res).await?)
+                }
+                res => return Err(anyhow::anyhow!("unexpected response {:?}", res)),
+            }
+        }
+        if ready.is_empty() {
+            return Ok(0);
+        }
+
+        let mut header = RequestHeader::default();
+        header.request_api_key = ApiKey::FetchKey as i16;
+        header.request_api_version = FETCH_VERSION;
+        let req = RequestKind::FetchRequest(fetcher.next_request(&ready));
+        match client.send(header, req).await? {
+            ResponseKind::FetchResponse(res) if res.error_code == 0 => {
+                self.append_fetched(&ready, &res).await
+            }
+            ResponseKind::FetchResponse(res) => Err(anyhow::anyhow!(
+                "fetch from {} failed with {}",
+                leader,
+                res.error_code
+            )),
+            res => Err(anyhow::anyhow!("unexpected response {:?}", res)),
+        }
+    }
+}
+
+/// Replicates the partitions we follow from their leaders until shutdown. Each leader is fetched
+/// from by its own task, so that a slow leader doesn't hold up replication from the others.
+pub(crate) async fn run(broker: Arc<Broker>, mut shutdown: Shutdown) -> Result<()> {
+    let mut leaders: HashMap<BrokerId, JoinHandle<()>> = HashMap::new();
+    let period = Duration::from_millis(broker.config.replica_fetch_backoff_ms);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            _ = interval.tick() => {}
+        }
+
+        let followed = match broker.followed_partitions().await {
+            Ok(followed) => followed,
+            Err(e) => {
+                tracing::error!(%e, "could not find followed partitions");
+                continue;
+            }
+        };
+        leaders.retain(|_, task| !task.is_finished());
+        for leader in followed.into_keys() {
+            if let Entry::Vacant(entry) = leaders.entry(leader) {
+                entry.insert(tokio::spawn(follow(broker.clone(), leader, shutdown.clone())));
+            }
+        }
+    }
+    for task in leaders.into_values() {
+        task.await?;
+    }
+    Ok(())
+}
+
+/// Fetches the partitions we follow from `leader`, for as long as we follow any, until shutdown.
+async fn follow(broker: Arc<Broker>, leader: BrokerId, mut shutdown: Shutdown) {
+    let mut client: Option<ConnectedKafkaClient> = None;
+    let mut fetcher = ReplicaFetcher::new(broker.config.id, broker.config.replica_fetch_max_bytes)
+        .with_compression(broker.config.inter_broker_compression);
+    let period = Duration::from_millis(broker.config.replica_fetch_backoff_ms);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            _ = interval.tick() => {}
+        }
+
+        let partitions = match broker.followed_partitions().await {
+            Ok(mut followed) => match followed.remove(&leader) {
+                Some(partitions) => partitions,
+                None => break,
+            },
+            Err(e) => {
+                tracing::error!(%e, "could not find followed partitions");
+                continue;
+            }
+        };
+        let connected = match client.take() {
+            Some(connected) => connected,
+            None => match broker.connect_to(leader, &shutdown).await {
+                Ok(connected) => connected,
+                Err(e) => {
+                    tracing::debug!(%leader, %e, "could not connect to leader");
+                    continue;
+                }
+            },
+        };
+        match broker
+            .fetch_from(leader, &connected, &mut fetcher, &partitions)
+            .await
+        {
+            Ok(_) => client = Some(connected),
+            Err(e) => tracing::debug!(%leader, %e, "could not fetch from leader"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::{whole_batches, ReplicaFetcher, FETCH_VERSION};
+    use crate::broker::compression::ReplicationCompression;
+    use crate::broker::config::Peer;
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic};
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::Partition;
+    use crate::broker::BrokerId;
+    use crate::Shutdown;
+    use bytes::BytesMut;
+    use kafka_protocol::messages::{FetchRequest, FetchResponse, OffsetForLeaderEpochResponse};
+    use kafka_protocol::protocol::{Decodable, Encodable};
+
+    #[tokio::test]
+    async fn every_partition_makes_progress() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partitions = new_topic(&broker, "test", 3)?;
+        let batch = idempotent_batch(-1, -1, 1)?;
+        for (partition, batches) in partitions.iter().zip([50, 5, 5]) {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            for _ in 0..batches {
+                replica.log.write_all(&batch)?;
+            }
+            replica.high_watermark = batches;
+        }
+
+        // room for a few batches per fetch, far less than the busy partition has
+        let mut fetcher = ReplicaFetcher::new(BrokerId(2), 3 * batch.len() as u64);
+        let mut offsets: HashMap<i32, u64> = HashMap::new();
+        for _ in 0..3 {
+            let fetch: Vec<_> = partitions
+                .iter()
+                .map(|p| (p.clone(), offsets.get(&p.idx.0).copied().unwrap_or(0)))
+                .collect();
+            let mut req = fetcher.next_request(&fetch);
+            req.max_wait_ms = 0;
+            let res = broker.handle(req, FetchResponse::default()).await?;
+            for data in res.responses.iter().flat_map(|t| &t.partitions) {
+                let records = data.records.as_deref().unwrap_or_default();
+                *offsets.entry(data.partition_index).or_default() +=
+                    whole_batches(records).len() as u64;
+            }
+        }
+        for partition in &partitions {
+            assert!(offsets[&partition.idx.0] > 0, "{} starved", partition.idx);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_replication() -> Result<()> {
+        let (_rx, mut leader) = new_broker();
+        leader.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: leader.config.ip,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partition = new_topic(&leader, "test", 1)?.remove(0);
+        let replica = leader.replicas.get(partition.id).unwrap();
+        let stored = {
+            let mut replica = replica.lock().await;
+            for count in 1..=3 {
+                replica.log.write_all(&idempotent_batch(-1, -1, count * 10)?)?;
+            }
+            replica.high_watermark = 3;
+            replica.log.read_until(0, 3, u64::MAX)?
+        };
+
+        for compression in [ReplicationCompression::Gzip, ReplicationCompression::Snappy] {
+            // the request and response go over the wire, tags and all
+            let mut fetcher =
+                ReplicaFetcher::new(BrokerId(2), 1 << 20).with_compression(compression);
+            let mut req = fetcher.next_request(&[(partition.clone(), 0)]);
+            req.max_wait_ms = 0;
+            let mut buf = BytesMut::new();
+            req.encode(&mut buf, FETCH_VERSION)?;
+            let req = FetchRequest::decode(&mut buf.freeze(), FETCH_VERSION)?;
+            let res = leader.handle(req, FetchResponse::default()).await?;
+            let mut buf = BytesMut::new();
+            res.encode(&mut buf, FETCH_VERSION)?;
+            let res = FetchResponse::decode(&mut buf.freeze(), FETCH_VERSION)?;
+            let sent = res.responses[0].partitions[0].records.clone().unwrap();
+            assert!(sent.len() < stored.len(), "{:?} didn't compress", compression);
+
+            // the follower stores exactly what the leader does
+            let (_rx, follower) = new_broker();
+            let followed = new_topic(&follower, "test", 1)?.remove(0);
+            assert_eq!(follower.append_fetched(&[(followed.clone(), 0)], &res).await?, 3);
+            let replica = follower.replicas.get(followed.id).unwrap();
+            let replicated = replica.lock().await.log.read_until(0, 3, u64::MAX)?;
+            assert_eq!(replicated, stored);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn truncates_diverged_log() -> Result<()> {
+        let batch = idempotent_batch(-1, -1, 1)?;
+        // the new leader has two batches of epoch 0, then one of its own epoch
+        let (_rx, leader) = new_broker();
+        let partition = new_topic(&leader, "test", 1)?.remove(0);
+        leader.store.create_partition(Partition {
+            leader_epoch: 1,
+            ..partition.clone()
+        })?;
+        {
+            let replica = leader.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            for epoch in [0, 0, 1] {
+                replica.start_epoch(epoch);
+                replica.log.write_all(&batch)?;
+            }
+        }
+
+        // we got a third batch of epoch 0 from the old leader that the new one never did
+        let (_rx, follower) = new_broker();
+        let followed = Partition {
+            leader_epoch: 1,
+            ..new_topic(&follower, "test", 1)?.remove(0)
+        };
+        {
+            let replica = follower.replicas.get(followed.id).unwrap();
+            let mut replica = replica.lock().await;
+            replica.start_epoch(0);
+            for _ in 0..3 {
+                replica.log.write_all(&batch)?;
+            }
+        }
+
+        let partitions = [(followed.clone(), 3)];
+        let (ready, req) = follower.check_divergence(&partitions).await?;
+        assert!(ready.is_empty());
+        let res = leader
+            .handle(req, OffsetForLeaderEpochResponse::default())
+            .await?;
+        let ready = follower.truncate_diverged(&partitions, &res).await?;
+        assert_eq!(ready, vec![(followed.clone(), 2)]);
+        let replica = follower.replicas.get(followed.id).unwrap();
+        assert_eq!(replica.lock().await.log.newest_offset(), 2);
+
+        // the log isn't checked again until the epoch changes
+        let (ready, req) = follower.check_divergence(&[(followed.clone(), 2)]).await?;
+        assert_eq!(ready, vec![(followed, 2)]);
+        assert!(req.topics.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_following_idle_leader() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+        // we lead the only partition, so there is nothing to fetch from broker 2
+        let follow = super::follow(Arc::new(broker), BrokerId(2), Shutdown::new());
+        tokio::time::timeout(Duration::from_secs(1), follow).await?;
+        Ok(())
+    }
+}
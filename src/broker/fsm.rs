@@ -1,10 +1,17 @@
 use anyhow::Result;
+use uuid::Uuid;
 use crate::broker::config::Peer;
+use crate::broker::BrokerId;
 
-use crate::broker::state::partition::Partition;
+use crate::broker::state::group::GroupOp;
+use crate::broker::state::offset::CommittedOffset;
+use crate::broker::state::partition::{Partition, PartitionIdx};
+use crate::broker::state::quota::QuotaEntity;
 use crate::broker::state::Store;
 use crate::broker::state::topic::Topic;
+use crate::broker::state::txn::TxnOp;
 use crate::raft::fsm::Fsm;
+use crate::raft::{Entry, EntryType};
 
 // FSM impl
 
@@ -21,12 +28,14 @@ impl JosefineFsm {
     fn ensure_topic(&mut self, topic: Topic) -> Result<Vec<u8>> {
         tracing::trace!(%topic.name, "create topic");
         let topic = self.store.create_topic(topic)?;
+        self.store.metadata_changed();
         Ok(bincode::serialize(&topic)?)
     }
 
     fn ensure_partition(&mut self, partition: Partition) -> Result<Vec<u8>> {
         tracing::trace!(%partition.idx, "create partition");
         let partition = self.store.create_partition(partition)?;
+        self.store.metadata_changed();
         Ok(bincode::serialize(&partition)?)
     }
 
@@ -35,19 +44,137 @@ impl JosefineFsm {
         let broker = self.store.create_broker(broker)?;
         Ok(bincode::serialize(&broker)?)
     }
+
+    fn register_broker(&mut self, broker: Peer) -> Result<Vec<u8>> {
+        tracing::trace!(%broker.id, "register broker");
+        self.store.register_broker(&broker)?;
+        Ok(Vec::new())
+    }
+
+    fn deregister_broker(&mut self, id: BrokerId) -> Result<Vec<u8>> {
+        tracing::trace!(%id, "deregister broker");
+        self.store.deregister_broker(id)?;
+        Ok(Vec::new())
+    }
+
+    fn set_cluster_id(&mut self, cluster_id: Uuid) -> Result<Vec<u8>> {
+        tracing::trace!(%cluster_id, "set cluster id");
+        let cluster_id = self.store.set_cluster_id(cluster_id)?;
+        Ok(bincode::serialize(&cluster_id)?)
+    }
+
+    fn set_client_quota(&mut self, entity: QuotaEntity, key: String, value: Option<f64>) -> Result<Vec<u8>> {
+        tracing::trace!(?entity, %key, ?value, "set client quota");
+        self.store.set_client_quota(&entity, &key, value)?;
+        Ok(Vec::new())
+    }
+
+    fn update_group(&mut self, id: String, op: GroupOp) -> Result<Vec<u8>> {
+        tracing::trace!(%id, ?op, "update group");
+        let res = self.store.update_group(&id, &op)?;
+        Ok(bincode::serialize(&res)?)
+    }
+
+    fn update_txn_state(&mut self, transactional_id: String, op: TxnOp) -> Result<Vec<u8>> {
+        tracing::trace!(%transactional_id, ?op, "update txn state");
+        let res = self.store.update_txn(&transactional_id, &op)?;
+        Ok(bincode::serialize(&res)?)
+    }
+
+    fn allocate_producer_id(&mut self) -> Result<Vec<u8>> {
+        let producer_id = self.store.allocate_producer_id()?;
+        tracing::trace!(producer_id, "allocate producer id");
+        Ok(bincode::serialize(&producer_id)?)
+    }
+
+    fn commit_offsets(&mut self, group: String, offsets: Vec<(String, i32, CommittedOffset)>) -> Result<Vec<u8>> {
+        tracing::trace!(%group, len = offsets.len(), "commit offsets");
+        self.store.commit_offsets(&group, &offsets)?;
+        Ok(Vec::new())
+    }
+
+    fn delete_offsets(&mut self, group: String, partitions: Vec<(String, i32)>) -> Result<Vec<u8>> {
+        tracing::trace!(%group, len = partitions.len(), "delete offsets");
+        self.store.delete_offsets(&group, &partitions)?;
+        Ok(Vec::new())
+    }
+
+    fn update_isr(
+        &mut self,
+        topic: String,
+        idx: PartitionIdx,
+        leader_epoch: i32,
+        from: Vec<i32>,
+        isr: Vec<i32>,
+    ) -> Result<Vec<u8>> {
+        tracing::trace!(%topic, %idx, ?isr, "update isr");
+        let updated = self.store.update_isr(&topic, idx, leader_epoch, &from, &isr)?;
+        if updated {
+            self.store.metadata_changed();
+        }
+        Ok(bincode::serialize(&updated)?)
+    }
+
+    fn batch(&mut self, transitions: Vec<Transition>) -> Result<Vec<u8>> {
+        tracing::trace!(len = transitions.len(), "apply batch");
+        self.store.apply_batch(&transitions)?;
+        if transitions.iter().any(Transition::changes_metadata) {
+            self.store.metadata_changed();
+        }
+        Ok(Vec::new())
+    }
 }
 
 impl Fsm for JosefineFsm {
     #[tracing::instrument]
-    fn transition(&mut self, input: Vec<u8>) -> Result<Vec<u8>> {
+    fn apply(&mut self, entry: &Entry) -> Result<Vec<u8>> {
+        let input = match &entry.entry_type {
+            EntryType::Data { data } => data,
+            EntryType::Config { .. } | EntryType::Noop => return Ok(Vec::new()),
+        };
+
         tracing::trace!("transitioning to new state");
-        let t = Transition::deserialize(&input)?;
+        let t = Transition::deserialize(input)?;
         match t {
             Transition::EnsureTopic(topic) => self.ensure_topic(topic),
             Transition::EnsurePartition(partition) => self.ensure_partition(partition),
             Transition::EnsureBroker(broker) => self.ensure_broker(broker),
+            Transition::RegisterBroker(broker) => self.register_broker(broker),
+            Transition::DeregisterBroker(id) => self.deregister_broker(id),
+            Transition::SetClusterId(cluster_id) => self.set_cluster_id(cluster_id),
+            Transition::SetClientQuota { entity, key, value } => {
+                self.set_client_quota(entity, key, value)
+            }
+            Transition::UpdateGroup { id, op } => self.update_group(id, op),
+            Transition::UpdateTxnState {
+                transactional_id,
+                op,
+            } => self.update_txn_state(transactional_id, op),
+            Transition::AllocateProducerId => self.allocate_producer_id(),
+            Transition::CommitOffsets { group, offsets } => self.commit_offsets(group, offsets),
+            Transition::DeleteOffsets { group, partitions } => {
+                self.delete_offsets(group, partitions)
+            }
+            Transition::Batch(transitions) => self.batch(transitions),
+            Transition::UpdateIsr {
+                topic,
+                idx,
+                leader_epoch,
+                from,
+                isr,
+            } => self.update_isr(topic, idx, leader_epoch, from, isr),
         }
     }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        self.store.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        self.store.restore(snapshot)?;
+        self.store.metadata_changed();
+        Ok(())
+    }
 }
 
 // State Transitions
@@ -57,6 +184,47 @@ pub enum Transition {
     EnsureTopic(Topic),
     EnsurePartition(Partition),
     EnsureBroker(Peer),
+    /// Adds a broker to the set of live brokers advertised to clients.
+    RegisterBroker(Peer),
+    /// Removes a broker from the set of live brokers.
+    DeregisterBroker(BrokerId),
+    /// Sets the cluster id, unless one has already been set.
+    SetClusterId(Uuid),
+    /// Sets a quota value for an entity, removing it if `value` is `None`.
+    SetClientQuota {
+        entity: QuotaEntity,
+        key: String,
+        value: Option<f64>,
+    },
+    /// Applies an operation to a consumer group. The response is the updated group, or the
+    /// reason the operation was not allowed.
+    UpdateGroup { id: String, op: GroupOp },
+    /// Applies an operation to the transactions of a transactional id. The response is the
+    /// updated transaction, or the reason the operation was not allowed.
+    UpdateTxnState { transactional_id: String, op: TxnOp },
+    /// Hands out a producer id to an idempotent producer. The response is the id.
+    AllocateProducerId,
+    /// Records the offsets a group has committed, by topic and partition.
+    CommitOffsets {
+        group: String,
+        offsets: Vec<(String, i32, CommittedOffset)>,
+    },
+    /// Removes committed offsets of a group, as when they expire.
+    DeleteOffsets {
+        group: String,
+        partitions: Vec<(String, i32)>,
+    },
+    /// A group of transitions that are applied atomically.
+    Batch(Vec<Transition>),
+    /// Replaces the ISR of a partition, as long as it still has the leader epoch and ISR the
+    /// change was decided on. The response is whether it was replaced.
+    UpdateIsr {
+        topic: String,
+        idx: PartitionIdx,
+        leader_epoch: i32,
+        from: Vec<i32>,
+        isr: Vec<i32>,
+    },
 }
 
 impl Transition {
@@ -67,4 +235,102 @@ impl Transition {
     pub fn deserialize(buf: &[u8]) -> Result<Self> {
         Ok(bincode::deserialize(buf)?)
     }
+
+    /// Whether applying the transition changes topic or partition metadata.
+    pub fn changes_metadata(&self) -> bool {
+        match self {
+            Transition::EnsureTopic(_)
+            | Transition::EnsurePartition(_)
+            | Transition::UpdateIsr { .. } => true,
+            Transition::Batch(transitions) => transitions.iter().any(Transition::changes_metadata),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JosefineFsm, Transition};
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::state::partition::{Partition, PartitionIdx};
+    use crate::broker::state::topic::Topic;
+    use crate::broker::state::Store;
+    use crate::broker::BrokerId;
+    use crate::raft::fsm::Fsm;
+    use crate::raft::{Entry, EntryType};
+    use anyhow::Result;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn invalid_partition() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let partition = Partition {
+            id: Uuid::new_v4(),
+            idx: PartitionIdx(0),
+            topic: "missing".to_string(),
+            isr: vec![1],
+            assigned_replicas: vec![1],
+            leader: BrokerId(1),
+            leader_epoch: 0,
+            adding_replicas: vec![],
+            removing_replicas: vec![],
+        };
+        let err = broker
+            .client
+            .propose(Transition::EnsurePartition(partition).serialize()?)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("topic missing does not exist"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifies_metadata_change() -> Result<()> {
+        let (rx, broker) = new_broker();
+        let mut changes = broker.store.subscribe_metadata();
+        apply_proposals(rx, &broker);
+
+        let topic = Topic {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            partitions: HashMap::new(),
+            internal: false,
+            compacted: false,
+            max_message_bytes: None,
+            delete_retention_ms: None,
+        };
+        broker
+            .client
+            .propose(Transition::EnsureTopic(topic).serialize()?)
+            .await?;
+        assert!(changes.has_changed()?);
+        changes.changed().await?;
+        assert_eq!(*changes.borrow(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn applies_raft_entries() -> Result<()> {
+        let store = Store::new(sled::open(tempfile::tempdir()?)?)?;
+        let mut fsm = JosefineFsm::new(store.clone());
+        let cluster_id = Uuid::new_v4();
+        let entry = |entry_type| Entry {
+            entry_type,
+            term: 1,
+            index: 1,
+        };
+        assert!(fsm.apply(&entry(EntryType::Noop))?.is_empty());
+        let data = Transition::SetClusterId(cluster_id).serialize()?;
+        fsm.apply(&entry(EntryType::Data { data }))?;
+        assert_eq!(store.get_cluster_id()?, Some(cluster_id));
+
+        let snapshot = fsm.snapshot()?;
+        let restored = Store::new(sled::open(tempfile::tempdir()?)?)?;
+        JosefineFsm::new(restored.clone()).restore(&snapshot)?;
+        assert_eq!(restored.get_cluster_id()?, Some(cluster_id));
+        Ok(())
+    }
 }
@@ -0,0 +1,63 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::state::txn::TxnOp;
+use crate::broker::Broker;
+use anyhow::Result;
+use kafka_protocol::messages::add_partitions_to_txn_response::{
+    AddPartitionsToTxnPartitionResult, AddPartitionsToTxnTopicResult,
+};
+use kafka_protocol::messages::{AddPartitionsToTxnRequest, AddPartitionsToTxnResponse};
+use kafka_protocol::ResponseError::{OperationNotAttempted, UnknownTopicOrPartition};
+
+impl Handler<AddPartitionsToTxnRequest> for Broker {
+    async fn handle(
+        &self,
+        req: AddPartitionsToTxnRequest,
+        mut res: AddPartitionsToTxnResponse,
+    ) -> Result<AddPartitionsToTxnResponse> {
+        let mut partitions = vec![];
+        let mut unknown = vec![];
+        for (name, topic) in &req.topics {
+            for idx in &topic.partitions {
+                match self.store.get_partition(name, PartitionIdx(*idx))? {
+                    Some(_) => partitions.push((name.to_string(), *idx)),
+                    None => unknown.push((name.to_string(), *idx)),
+                }
+            }
+        }
+
+        // the partitions are added all together or not at all
+        let error = |partition: &(String, i32)| match unknown.contains(partition) {
+            true => UnknownTopicOrPartition.code(),
+            false => OperationNotAttempted.code(),
+        };
+        let code = match unknown.is_empty() {
+            true => {
+                let op = TxnOp::AddPartitions {
+                    producer_id: req.producer_id.0,
+                    producer_epoch: req.producer_epoch,
+                    partitions,
+                };
+                match self.update_txn(&req.transactional_id, op).await? {
+                    Ok(_) => None,
+                    Err(e) => Some(e.code()),
+                }
+            }
+            false => None,
+        };
+
+        for (name, topic) in req.topics {
+            let mut results = AddPartitionsToTxnTopicResult::default();
+            for idx in topic.partitions {
+                let mut result = AddPartitionsToTxnPartitionResult::default();
+                result.error_code = match unknown.is_empty() {
+                    true => code.unwrap_or(0),
+                    false => error(&(name.to_string(), idx)),
+                };
+                results.results.insert(idx, result);
+            }
+            res.results.insert(name, results);
+        }
+        Ok(res)
+    }
+}
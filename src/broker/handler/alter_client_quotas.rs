@@ -0,0 +1,109 @@
+use crate::broker::fsm::Transition;
+use crate::broker::handler::Handler;
+use crate::broker::state::quota::QuotaEntity;
+use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
+use anyhow::Result;
+use kafka_protocol::messages::alter_client_quotas_request::EntryData;
+use kafka_protocol::messages::alter_client_quotas_response;
+use kafka_protocol::messages::{AlterClientQuotasRequest, AlterClientQuotasResponse};
+use kafka_protocol::ResponseError::InvalidRequest;
+
+fn validate(entry: &EntryData) -> std::result::Result<(), String> {
+    if entry.entity.is_empty() {
+        return Err("quota entity cannot be empty".to_string());
+    }
+    for op in entry.ops.iter().filter(|op| !op.remove) {
+        if !op.value.is_finite() || op.value < 0.0 {
+            return Err(format!("invalid value {} for quota {}", op.value, &*op.key));
+        }
+    }
+    Ok(())
+}
+
+impl Handler<AlterClientQuotasRequest> for Broker {
+    async fn handle(
+        &self,
+        req: AlterClientQuotasRequest,
+        mut res: AlterClientQuotasResponse,
+    ) -> Result<AlterClientQuotasResponse> {
+        for entry in req.entries {
+            let mut result = alter_client_quotas_response::EntryData::default();
+            result.entity = entry
+                .entity
+                .iter()
+                .map(|e| {
+                    let mut entity = alter_client_quotas_response::EntityData::default();
+                    entity.entity_type = e.entity_type.clone();
+                    entity.entity_name = e.entity_name.clone();
+                    entity
+                })
+                .collect();
+
+            if let Err(message) = validate(&entry) {
+                result.error_code = InvalidRequest.code();
+                result.error_message = Some(message.to_str_bytes());
+                res.entries.push(result);
+                continue;
+            }
+
+            if !req.validate_only {
+                let entity = QuotaEntity::new(entry.entity.iter().map(|e| {
+                    (
+                        e.entity_type.to_string(),
+                        e.entity_name.as_ref().map(|x| x.to_string()),
+                    )
+                }));
+                let transitions = entry
+                    .ops
+                    .into_iter()
+                    .map(|op| Transition::SetClientQuota {
+                        entity: entity.clone(),
+                        key: op.key.to_string(),
+                        value: if op.remove { None } else { Some(op.value) },
+                    })
+                    .collect();
+                self.client
+                    .propose(Transition::Batch(transitions).serialize()?)
+                    .await?;
+            }
+
+            res.entries.push(result);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::new_broker;
+    use crate::broker::handler::Handler;
+    use anyhow::Result;
+    use kafka_protocol::messages::alter_client_quotas_request::{EntityData, EntryData, OpData};
+    use kafka_protocol::messages::{AlterClientQuotasRequest, AlterClientQuotasResponse};
+    use kafka_protocol::protocol::StrBytes;
+    use kafka_protocol::ResponseError::InvalidRequest;
+
+    #[tokio::test]
+    async fn negative_value() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let mut entity = EntityData::default();
+        entity.entity_type = StrBytes::from_str("client-id");
+        entity.entity_name = Some(StrBytes::from_str("producer"));
+        let mut op = OpData::default();
+        op.key = StrBytes::from_str("producer_byte_rate");
+        op.value = -1.0;
+        let mut entry = EntryData::default();
+        entry.entity = vec![entity];
+        entry.ops = vec![op];
+        let mut req = AlterClientQuotasRequest::default();
+        req.entries = vec![entry];
+
+        let res = broker
+            .handle(req, AlterClientQuotasResponse::default())
+            .await?;
+        assert_eq!(res.entries[0].error_code, InvalidRequest.code());
+        assert!(broker.store.get_client_quotas()?.is_empty());
+        Ok(())
+    }
+}
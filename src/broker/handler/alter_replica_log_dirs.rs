@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use kafka_protocol::messages::alter_replica_log_dirs_response::{
+    AlterReplicaLogDirPartitionResult, AlterReplicaLogDirTopicResult,
+};
+use kafka_protocol::messages::{AlterReplicaLogDirsRequest, AlterReplicaLogDirsResponse};
+use kafka_protocol::ResponseError;
+use kafka_protocol::ResponseError::{KafkaStorageError, LogDirNotFound, ReplicaNotAvailable};
+
+use crate::broker::handler::Handler;
+use crate::broker::log::LogCopy;
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::Broker;
+
+impl Broker {
+    /// Moves the replica of a partition to `dir`. The log is copied a segment at a time while it
+    /// keeps taking writes, then held still to copy the rest and swapped for the copy, after which
+    /// the old directory is removed. Until the swap the original log is left as it was.
+    pub(crate) async fn move_replica(
+        &self,
+        topic: &str,
+        idx: PartitionIdx,
+        dir: &Path,
+    ) -> std::result::Result<(), ResponseError> {
+        if !self.log_dirs.dirs().iter().any(|d| d == dir) {
+            return Err(LogDirNotFound);
+        }
+        let replica = self
+            .replicas
+            .get_partition(topic, idx)
+            .ok_or(ReplicaNotAvailable)?;
+        let from = replica.lock().await.log.path().to_owned();
+        let name = from.file_name().ok_or(KafkaStorageError)?.to_owned();
+        if from.parent() == Some(dir) {
+            return Ok(());
+        }
+
+        let future = dir.join(format!(".{}.future", name.to_string_lossy()));
+        let moved = async {
+            let mut copy = LogCopy::new(&future)?;
+            // let appends through between segments, so only the tail holds up the partition
+            while replica.lock().await.log.copy_segment(&mut copy)? {}
+            let mut replica = replica.lock().await;
+            let log = replica.log.finish_copy(copy, &dir.join(&name))?;
+            Ok::<_, std::io::Error>(std::mem::replace(&mut replica.log, log))
+        };
+        match moved.await {
+            Ok(old) => {
+                drop(old);
+                tracing::info!(?from, ?dir, "moved replica");
+                if let Err(e) = std::fs::remove_dir_all(&from) {
+                    tracing::warn!(?from, %e, "could not remove the moved replica's old dir");
+                }
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!(?from, ?dir, %e, "could not move replica");
+                let _ = std::fs::remove_dir_all(&future);
+                Err(KafkaStorageError)
+            }
+        }
+    }
+}
+
+impl Handler<AlterReplicaLogDirsRequest> for Broker {
+    async fn handle(
+        &self,
+        req: AlterReplicaLogDirsRequest,
+        mut res: AlterReplicaLogDirsResponse,
+    ) -> Result<AlterReplicaLogDirsResponse> {
+        for (dir, d) in req.dirs {
+            let dir = PathBuf::from(dir.to_string());
+            for (name, t) in d.topics {
+                let mut topic = AlterReplicaLogDirTopicResult::default();
+                for idx in t.partitions {
+                    let mut partition = AlterReplicaLogDirPartitionResult::default();
+                    partition.partition_index = idx;
+                    if let Err(e) = self.move_replica(&name, PartitionIdx(idx), &dir).await {
+                        partition.error_code = e.code();
+                    }
+                    topic.partitions.push(partition);
+                }
+                topic.topic_name = name;
+                res.results.push(topic);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use anyhow::Result;
+    use kafka_protocol::messages::alter_replica_log_dirs_request::{
+        AlterReplicaLogDir, AlterReplicaLogDirTopic,
+    };
+    use kafka_protocol::messages::{
+        AlterReplicaLogDirsRequest, AlterReplicaLogDirsResponse, TopicName,
+    };
+    use kafka_protocol::ResponseError::{KafkaStorageError, LogDirNotFound};
+
+    use crate::broker::fetcher::whole_batches;
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic};
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::PartitionIdx;
+    use crate::kafka::util::ToStrBytes;
+
+    #[tokio::test]
+    async fn moves_between_dirs() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker
+            .config
+            .log_dirs
+            .push(tempfile::tempdir()?.into_path());
+        broker.log_dirs = crate::broker::replica::LogDirs::new(&broker.config.log_dirs)?;
+        broker.config.log_segment_bytes = 1;
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let replica = broker.replicas.get(partition.id).unwrap();
+        let batch = idempotent_batch(-1, -1, 1)?;
+        for _ in 0..3 {
+            replica.lock().await.log.write_all(&batch)?;
+        }
+        let from = replica.lock().await.log.path().to_owned();
+        let to = broker
+            .log_dirs
+            .dirs()
+            .iter()
+            .find(|d| Some(d.as_path()) != from.parent())
+            .unwrap()
+            .clone();
+
+        let mut req = AlterReplicaLogDirsRequest::default();
+        let mut dir = AlterReplicaLogDir::default();
+        let mut topic = AlterReplicaLogDirTopic::default();
+        topic.partitions = vec![0];
+        dir.topics
+            .insert(TopicName("test".to_string().to_str_bytes()), topic);
+        req.dirs
+            .insert(to.to_string_lossy().to_string().to_str_bytes(), dir);
+        let res = broker
+            .handle(req, AlterReplicaLogDirsResponse::default())
+            .await?;
+        assert_eq!(res.results[0].partitions[0].error_code, 0);
+        assert!(!from.exists());
+        assert_eq!(replica.lock().await.log.path(), to.join("test-0"));
+
+        // every batch is still there, and the log carries on from where it was
+        let mut replica = replica.lock().await;
+        replica.log.write_all(&batch)?;
+        assert_eq!(replica.log.newest_offset(), 4);
+        for offset in 0..4 {
+            let records = replica.log.read_from(offset, u64::MAX)?;
+            assert_eq!(whole_batches(&records)[0], &batch[..]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_move_keeps_original() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker
+            .config
+            .log_dirs
+            .push(tempfile::tempdir()?.into_path());
+        broker.log_dirs = crate::broker::replica::LogDirs::new(&broker.config.log_dirs)?;
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let replica = broker.replicas.get(partition.id).unwrap();
+        let batch = idempotent_batch(-1, -1, 1)?;
+        replica.lock().await.log.write_all(&batch)?;
+        let from = replica.lock().await.log.path().to_owned();
+        assert_eq!(
+            broker
+                .move_replica("test", PartitionIdx(0), &from.join("missing"))
+                .await,
+            Err(LogDirNotFound)
+        );
+
+        // the disk goes away
+        let to = broker
+            .log_dirs
+            .dirs()
+            .iter()
+            .find(|d| Some(d.as_path()) != from.parent())
+            .unwrap()
+            .clone();
+        std::fs::remove_dir_all(&to)?;
+        std::fs::write(&to, b"")?;
+        let moved = broker.move_replica("test", PartitionIdx(0), &to).await;
+        assert_eq!(moved, Err(KafkaStorageError));
+        let replica = replica.lock().await;
+        assert_eq!(replica.log.path(), from);
+        assert_eq!(replica.log.read_from(0, u64::MAX)?, &batch[..]);
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
+use std::collections::BTreeMap;
+
 use crate::broker::handler::Handler;
 use crate::broker::Broker;
 use kafka_protocol::messages::api_versions_response::ApiVersion;
 use kafka_protocol::messages::*;
 use kafka_protocol::protocol::Message;
+use kafka_protocol::ResponseError::UnsupportedVersion;
 
 fn api_version<T: Message>() -> ApiVersion {
     let mut v = ApiVersion::default();
@@ -11,76 +14,221 @@ fn api_version<T: Message>() -> ApiVersion {
     v
 }
 
+/// The range of versions supported for each API, as advertised to clients.
+pub fn supported_versions() -> BTreeMap<i16, ApiVersion> {
+    let mut res = ApiVersionsResponse::default();
+    res.api_keys.insert(
+        ApiKey::ProduceKey as i16,
+        api_version::<ProduceRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::FetchKey as i16,
+        api_version::<FetchRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::ListOffsetsKey as i16,
+        api_version::<ListOffsetsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::MetadataKey as i16,
+        api_version::<MetadataRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::LeaderAndIsrKey as i16,
+        api_version::<LeaderAndIsrRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::StopReplicaKey as i16,
+        api_version::<StopReplicaRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::FindCoordinatorKey as i16,
+        api_version::<FindCoordinatorRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::JoinGroupKey as i16,
+        api_version::<JoinGroupRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::HeartbeatKey as i16,
+        api_version::<HeartbeatRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::ListGroupsKey as i16,
+        api_version::<ListGroupsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::SyncGroupKey as i16,
+        api_version::<SyncGroupRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::CreateTopicsKey as i16,
+        api_version::<CreateTopicsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::LeaveGroupKey as i16,
+        api_version::<LeaveGroupRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::DeleteGroupsKey as i16,
+        api_version::<DeleteGroupsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::ListGroupsKey as i16,
+        api_version::<ListGroupsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::ApiVersionsKey as i16,
+        api_version::<ApiVersionsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::DeleteTopicsKey as i16,
+        api_version::<DeleteTopicsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::DescribeClientQuotasKey as i16,
+        api_version::<DescribeClientQuotasRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::AlterClientQuotasKey as i16,
+        api_version::<AlterClientQuotasRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::DescribeProducersKey as i16,
+        api_version::<DescribeProducersRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::OffsetForLeaderEpochKey as i16,
+        api_version::<OffsetForLeaderEpochRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::InitProducerIdKey as i16,
+        api_version::<InitProducerIdRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::AddPartitionsToTxnKey as i16,
+        api_version::<AddPartitionsToTxnRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::EndTxnKey as i16,
+        api_version::<EndTxnRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::DescribeLogDirsKey as i16,
+        api_version::<DescribeLogDirsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::AlterReplicaLogDirsKey as i16,
+        api_version::<AlterReplicaLogDirsRequest>(),
+    );
+    res.api_keys.insert(
+        ApiKey::ListPartitionReassignmentsKey as i16,
+        api_version::<ListPartitionReassignmentsRequest>(),
+    );
+    res.api_keys.into_iter().collect()
+}
+
+/// Builds the response to a request at a `requested` version that isn't supported, along with
+/// the version to encode it with, the closest one we support to what the client asked for.
+/// Returns `None` for APIs this broker doesn't serve at all, and for those whose responses only
+/// carry errors per topic or entity, which can't be filled in without decoding the request.
+/// Like Kafka, the connection is closed on those instead.
+pub fn unsupported_version(api_key: ApiKey, requested: i16) -> Option<(i16, ResponseKind)> {
+    let code = UnsupportedVersion.code();
+    let versions = supported_versions();
+    let supported = versions.get(&(api_key as i16))?;
+    let version = requested.clamp(supported.min_version, supported.max_version);
+    if api_key == ApiKey::ApiVersionsKey {
+        // clients fall back to v0 to learn which versions are supported
+        let mut res = ApiVersionsResponse::default();
+        res.error_code = code;
+        res.api_keys = versions.into_iter().collect();
+        return Some((0, ResponseKind::ApiVersionsResponse(res)));
+    }
+    Some((version, error_response(api_key, code)?))
+}
+
+/// Builds a response that carries nothing but the error `code`. Returns `None` for APIs whose
+/// responses have no top level error code to carry it in.
+pub fn error_response(api_key: ApiKey, code: i16) -> Option<ResponseKind> {
+    let res = match api_key {
+        ApiKey::ApiVersionsKey => {
+            let mut res = ApiVersionsResponse::default();
+            res.error_code = code;
+            ResponseKind::ApiVersionsResponse(res)
+        }
+        ApiKey::FetchKey => {
+            let mut res = FetchResponse::default();
+            res.error_code = code;
+            ResponseKind::FetchResponse(res)
+        }
+        ApiKey::FindCoordinatorKey => {
+            let mut res = FindCoordinatorResponse::default();
+            res.error_code = code;
+            ResponseKind::FindCoordinatorResponse(res)
+        }
+        ApiKey::JoinGroupKey => {
+            let mut res = JoinGroupResponse::default();
+            res.error_code = code;
+            ResponseKind::JoinGroupResponse(res)
+        }
+        ApiKey::HeartbeatKey => {
+            let mut res = HeartbeatResponse::default();
+            res.error_code = code;
+            ResponseKind::HeartbeatResponse(res)
+        }
+        ApiKey::ListGroupsKey => {
+            let mut res = ListGroupsResponse::default();
+            res.error_code = code;
+            ResponseKind::ListGroupsResponse(res)
+        }
+        ApiKey::SyncGroupKey => {
+            let mut res = SyncGroupResponse::default();
+            res.error_code = code;
+            ResponseKind::SyncGroupResponse(res)
+        }
+        ApiKey::LeaveGroupKey => {
+            let mut res = LeaveGroupResponse::default();
+            res.error_code = code;
+            ResponseKind::LeaveGroupResponse(res)
+        }
+        ApiKey::DescribeClientQuotasKey => {
+            let mut res = DescribeClientQuotasResponse::default();
+            res.error_code = code;
+            ResponseKind::DescribeClientQuotasResponse(res)
+        }
+        ApiKey::InitProducerIdKey => {
+            let mut res = InitProducerIdResponse::default();
+            res.error_code = code;
+            ResponseKind::InitProducerIdResponse(res)
+        }
+        ApiKey::EndTxnKey => {
+            let mut res = EndTxnResponse::default();
+            res.error_code = code;
+            ResponseKind::EndTxnResponse(res)
+        }
+        ApiKey::DescribeLogDirsKey => {
+            let mut res = DescribeLogDirsResponse::default();
+            res.error_code = code;
+            ResponseKind::DescribeLogDirsResponse(res)
+        }
+        ApiKey::ListPartitionReassignmentsKey => {
+            let mut res = ListPartitionReassignmentsResponse::default();
+            res.error_code = code;
+            ResponseKind::ListPartitionReassignmentsResponse(res)
+        }
+        _ => return None,
+    };
+    Some(res)
+}
+
 impl Handler<ApiVersionsRequest> for Broker {
     async fn handle(
         &self,
         _req: ApiVersionsRequest,
         mut res: ApiVersionsResponse,
     ) -> anyhow::Result<ApiVersionsResponse> {
-        res.api_keys.insert(
-            ApiKey::ProduceKey as i16,
-            api_version::<ProduceRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::FetchKey as i16,
-            api_version::<FetchRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::ListOffsetsKey as i16,
-            api_version::<ListOffsetsRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::MetadataKey as i16,
-            api_version::<MetadataRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::LeaderAndIsrKey as i16,
-            api_version::<LeaderAndIsrRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::StopReplicaKey as i16,
-            api_version::<StopReplicaRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::FindCoordinatorKey as i16,
-            api_version::<FindCoordinatorRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::JoinGroupKey as i16,
-            api_version::<JoinGroupRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::HeartbeatKey as i16,
-            api_version::<HeartbeatRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::ListGroupsKey as i16,
-            api_version::<ListGroupsRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::SyncGroupKey as i16,
-            api_version::<SyncGroupRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::CreateTopicsKey as i16,
-            api_version::<CreateTopicsRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::DeleteGroupsKey as i16,
-            api_version::<DeleteGroupsRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::ListGroupsKey as i16,
-            api_version::<ListGroupsRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::ApiVersionsKey as i16,
-            api_version::<ApiVersionsRequest>(),
-        );
-        res.api_keys.insert(
-            ApiKey::DeleteTopicsKey as i16,
-            api_version::<DeleteTopicsRequest>(),
-        );
+        res.api_keys = supported_versions().into_iter().collect();
         Ok(res)
     }
 }
@@ -1,3 +1,4 @@
+use crate::broker::coordinator::OFFSETS_TOPIC;
 use crate::broker::fsm::Transition;
 use crate::broker::state::topic::Topic;
 use anyhow::Result;
@@ -9,13 +10,17 @@ use kafka_protocol::messages::create_topics_response::CreatableTopicResult;
 
 use kafka_protocol::messages::{
     ApiKey, CreateTopicsRequest, CreateTopicsResponse, LeaderAndIsrRequest, RequestHeader,
-    RequestKind,
+    RequestKind, ResponseKind,
+};
+use kafka_protocol::protocol::Message;
+use kafka_protocol::ResponseError::{
+    InvalidConfig, InvalidPartitions, InvalidReplicationFactor, NotController, RequestTimedOut,
 };
-use kafka_protocol::ResponseError::InvalidReplicationFactor;
 
 use crate::broker::handler::Handler;
 use crate::broker::Broker;
 use crate::broker::BrokerId;
+use crate::kafka::util::ToStrBytes;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 
@@ -23,15 +28,39 @@ use uuid::Uuid;
 
 use crate::broker::state::partition::{Partition, PartitionIdx};
 use crate::kafka::KafkaClient;
+use crate::raft::client::Overloaded;
 use crate::Shutdown;
 
+/// Whether the topic's `cleanup.policy`, a list of policies, includes `compact`.
+fn is_compacted(topic: &CreatableTopic) -> bool {
+    topic.configs.iter().any(|(name, config)| {
+        &**name == "cleanup.policy"
+            && config
+                .value
+                .as_deref()
+                .is_some_and(|v| v.split(',').any(|policy| policy.trim() == "compact"))
+    })
+}
+
+/// The value of one of the topic's configs, if it was set.
+fn topic_config<T>(topic: &CreatableTopic, name: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let config = topic.configs.iter().find(|(n, _)| &***n == name);
+    match config.and_then(|(_, config)| config.value.as_deref()) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(anyhow::anyhow!("invalid {}: {}", name, e)),
+        },
+        None => Ok(None),
+    }
+}
+
 impl Broker {
     async fn make_partitions(&self, name: &str, topic: &CreatableTopic) -> Result<Vec<Partition>> {
-        let mut brokers = self.get_broker_ids();
-
-        if topic.replication_factor > brokers.len() as i16 {
-            todo!("figure out protocol level errors")
-        }
+        let mut brokers = self.get_broker_ids()?;
 
         let mut partitions = Vec::new();
 
@@ -54,6 +83,9 @@ impl Broker {
                 isr: replicas.clone(),
                 assigned_replicas: replicas,
                 leader: BrokerId(leader.0),
+                leader_epoch: 0,
+                adding_replicas: vec![],
+                removing_replicas: vec![],
             };
 
             partitions.push(partition);
@@ -62,7 +94,49 @@ impl Broker {
         Ok(partitions)
     }
 
-    async fn create_topic(&self, name: &str, t: CreatableTopic) -> Result<CreatableTopicResult> {
+    pub(crate) async fn create_topic(&self, name: &str, t: CreatableTopic) -> Result<CreatableTopicResult> {
+        let configs = topic_config(&t, "max.message.bytes")
+            .and_then(|max| Ok((max, topic_config(&t, "delete.retention.ms")?)));
+        let (max_message_bytes, delete_retention_ms) = match configs {
+            Ok(configs) => configs,
+            Err(e) => {
+                let mut res = CreatableTopicResult::default();
+                res.error_code = InvalidConfig.code();
+                res.error_message = Some(e.to_string().to_str_bytes());
+                return Ok(res);
+            }
+        };
+        // -1 has been replaced with the broker's default by now
+        if t.num_partitions <= 0 {
+            let mut res = CreatableTopicResult::default();
+            res.error_code = InvalidPartitions.code();
+            let e = format!("number of partitions {} must be positive", t.num_partitions);
+            res.error_message = Some(e.to_str_bytes());
+            return Ok(res);
+        }
+        if t.replication_factor <= 0 {
+            let mut res = CreatableTopicResult::default();
+            res.error_code = InvalidReplicationFactor.code();
+            let e = format!("replication factor {} must be positive", t.replication_factor);
+            res.error_message = Some(e.to_str_bytes());
+            return Ok(res);
+        }
+        // counted from the store, so topics created moments ago count too
+        let existing = self.store.get_topics()?.values().map(|t| t.partitions.len()).sum();
+        if let Err(e) = self.check_partition_limits(existing, t.num_partitions) {
+            let mut res = CreatableTopicResult::default();
+            res.error_code = InvalidPartitions.code();
+            res.error_message = Some(e.to_str_bytes());
+            return Ok(res);
+        }
+        let brokers = self.get_broker_ids()?.len();
+        if t.replication_factor > brokers as i16 {
+            let mut res = CreatableTopicResult::default();
+            res.error_code = InvalidReplicationFactor.code();
+            let e = format!("replication factor is larger than the {} brokers", brokers);
+            res.error_message = Some(e.to_str_bytes());
+            return Ok(res);
+        }
         let ps = self.make_partitions(name, &t).await?;
 
         let topic = {
@@ -78,7 +152,10 @@ impl Broker {
                 id: Uuid::new_v4(),
                 name: (*name).to_string(),
                 partitions,
-                internal: false,
+                internal: name == OFFSETS_TOPIC,
+                compacted: is_compacted(&t),
+                max_message_bytes,
+                delete_retention_ms,
             }
         };
 
@@ -87,20 +164,22 @@ impl Broker {
         res.num_partitions = t.num_partitions;
         res.replication_factor = t.replication_factor;
 
-        self.client
-            .propose(Transition::EnsureTopic(topic).serialize()?)
-            .await?;
-
-        // TODO we should really do topic + partitions within single tx
-        for p in ps {
-            let _ = &self
-                .client
-                .propose(Transition::EnsurePartition(p).serialize()?)
-                .await?;
+        let mut transitions = vec![Transition::EnsureTopic(topic)];
+        transitions.extend(ps.into_iter().map(Transition::EnsurePartition));
+        if let Err(e) = self
+            .client
+            .propose(Transition::Batch(transitions).serialize()?)
+            .await
+        {
+            if e.is::<Overloaded>() {
+                res.error_code = RequestTimedOut.code();
+                return Ok(res);
+            }
+            return Err(e);
         }
 
         // Start isr
-        for b in self.get_brokers() {
+        for b in self.get_brokers()? {
             let mut header = RequestHeader::default();
             header.request_api_version = 5;
             header.request_api_key = ApiKey::LeaderAndIsrKey as i16;
@@ -127,13 +206,84 @@ impl Broker {
     }
 }
 
+impl Broker {
+    /// Fills in the configured defaults for a topic that leaves its partition count or
+    /// replication factor to the broker by asking for -1. The default replication factor is
+    /// limited to the live brokers.
+    fn apply_topic_defaults(&self, topic: &mut CreatableTopic) -> Result<()> {
+        if topic.num_partitions == -1 {
+            topic.num_partitions = self.config.default_partitions;
+        }
+        if topic.replication_factor == -1 {
+            let brokers = self.get_broker_ids()?.len();
+            topic.replication_factor = self
+                .config
+                .default_replication_factor
+                .min(brokers as i16);
+        }
+        Ok(())
+    }
+
+    /// Checks that a topic of `num_partitions` partitions fits within the configured partition
+    /// limits, given the `existing` partitions of every other topic.
+    fn check_partition_limits(&self, existing: usize, num_partitions: i32) -> Result<(), String> {
+        let num_partitions = num_partitions.max(0) as usize;
+        if let Some(max) = self.config.max_partitions_per_topic {
+            if num_partitions > max {
+                return Err(format!("topics can have at most {} partitions", max));
+            }
+        }
+        if let Some(max) = self.config.max_partitions {
+            if existing + num_partitions > max {
+                return Err(format!(
+                    "the cluster can hold at most {} partitions, and has {}",
+                    max, existing
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands topic creation to the controller, or fails every topic with `NotController` while
+    /// no controller is known.
+    async fn forward_create_topics(
+        &self,
+        req: CreateTopicsRequest,
+        mut res: CreateTopicsResponse,
+    ) -> Result<CreateTopicsResponse> {
+        if self.controller_id().is_none() {
+            for name in req.topics.into_keys() {
+                let mut t = CreatableTopicResult::default();
+                t.error_code = NotController.code();
+                res.topics.insert(name, t);
+            }
+            return Ok(res);
+        }
+
+        let req = RequestKind::CreateTopicsRequest(req);
+        let version = CreateTopicsRequest::VERSIONS.max;
+        match self
+            .forward_to_controller(ApiKey::CreateTopicsKey, version, req)
+            .await?
+        {
+            ResponseKind::CreateTopicsResponse(res) => Ok(res),
+            res => Err(anyhow::anyhow!("unexpected response from controller {:?}", res)),
+        }
+    }
+}
+
 impl Handler<CreateTopicsRequest> for Broker {
     async fn handle(
         &self,
         req: CreateTopicsRequest,
         mut res: CreateTopicsResponse,
     ) -> Result<CreateTopicsResponse> {
-        for (name, topic) in req.topics.into_iter() {
+        if !self.is_controller() {
+            return self.forward_create_topics(req, res).await;
+        }
+
+        for (name, mut topic) in req.topics.into_iter() {
+            self.apply_topic_defaults(&mut topic)?;
             if self.store.topic_exists(&name)? {
                 // TODO
             }
@@ -147,23 +297,28 @@ impl Handler<CreateTopicsRequest> for Broker {
 
 #[cfg(test)]
 mod tests {
-    use crate::broker::handler::test::new_broker;
+    use crate::broker::handler::test::{
+        apply_proposals, new_broker, new_broker_with_queue, new_topic,
+    };
     use std::collections::HashMap;
 
     use crate::broker::handler::Handler;
     use crate::broker::state::topic::Topic;
+    use crate::kafka::util::ToStrBytes;
     use anyhow::Result;
     use kafka_protocol::messages::create_topics_request::CreatableTopic;
     use kafka_protocol::messages::{CreateTopicsRequest, CreateTopicsResponse, TopicName};
     use kafka_protocol::protocol::StrBytes;
+    use kafka_protocol::ResponseError::{
+        InvalidPartitions, InvalidReplicationFactor, RequestTimedOut,
+    };
 
     #[tokio::test]
     async fn execute() -> Result<()> {
         let (mut rx, broker) = new_broker();
         let mut req = CreateTopicsRequest::default();
         let topic_name = TopicName(StrBytes::from_str("Test"));
-        req.topics
-            .insert(topic_name.clone(), CreatableTopic::default());
+        req.topics.insert(topic_name.clone(), topic(1, 1));
         let (res, _) = tokio::join!(
             tokio::spawn(async move { broker.handle(req, CreateTopicsResponse::default()).await }),
             tokio::spawn(async move {
@@ -172,6 +327,9 @@ mod tests {
                     id: uuid::Uuid::new_v4(),
                     name: "Test".to_string(),
                     internal: false,
+                    compacted: false,
+                    max_message_bytes: None,
+                    delete_retention_ms: None,
                     partitions: HashMap::new(),
                 };
                 cb.send(Ok(crate::raft::rpc::Response::new(bincode::serialize(
@@ -187,4 +345,129 @@ mod tests {
         assert_eq!(&topic_name, name);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn overloaded() -> Result<()> {
+        let (_rx, broker) = new_broker_with_queue(1);
+        // nothing drains the queue, so fill it up before creating the topic
+        let client = broker.client.clone();
+        tokio::spawn(async move { client.propose(vec![]).await });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mut req = CreateTopicsRequest::default();
+        let topic_name = TopicName(StrBytes::from_str("Test"));
+        req.topics.insert(topic_name.clone(), topic(1, 1));
+        let res = broker.handle(req, CreateTopicsResponse::default()).await?;
+        assert_eq!(res.topics[&topic_name].error_code, RequestTimedOut.code());
+        Ok(())
+    }
+
+    fn topic(partitions: i32, replication_factor: i16) -> CreatableTopic {
+        let mut topic = CreatableTopic::default();
+        topic.num_partitions = partitions;
+        topic.replication_factor = replication_factor;
+        topic
+    }
+
+    #[tokio::test]
+    async fn partition_limits() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.max_partitions = Some(6);
+        broker.config.max_partitions_per_topic = Some(3);
+        apply_proposals(rx, &broker);
+        new_topic(&broker, "existing", 2)?;
+        broker.store.metadata_changed();
+
+        let create = |topics: Vec<(&str, i32)>| {
+            let mut req = CreateTopicsRequest::default();
+            for (name, partitions) in topics {
+                let name = TopicName(name.to_string().to_str_bytes());
+                req.topics.insert(name, topic(partitions, 1));
+            }
+            broker.handle(req, CreateTopicsResponse::default())
+        };
+        let code = |res: &CreateTopicsResponse, name: &str| {
+            res.topics[&TopicName(name.to_string().to_str_bytes())].error_code
+        };
+
+        // a topic over the per topic cap is refused, however much room the cluster has
+        let res = create(vec![("large", 4)]).await?;
+        assert_eq!(code(&res, "large"), InvalidPartitions.code());
+        assert!(!broker.store.topic_exists("large")?);
+
+        // the first topic fits under the total, taking up the room the second would need
+        let res = create(vec![("a", 3), ("b", 2)]).await?;
+        assert_eq!(code(&res, "a"), 0);
+        assert_eq!(code(&res, "b"), InvalidPartitions.code());
+        assert!(broker.store.topic_exists("a")?);
+        assert!(!broker.store.topic_exists("b")?);
+
+        // while one that exactly fills the cluster is still created
+        let res = create(vec![("c", 1)]).await?;
+        assert_eq!(code(&res, "c"), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broker_defaults() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.default_partitions = 3;
+        broker.config.default_replication_factor = 3;
+        apply_proposals(rx, &broker);
+
+        // the default replication factor is limited to the one live broker
+        let mut req = CreateTopicsRequest::default();
+        let name = TopicName(StrBytes::from_str("defaults"));
+        req.topics.insert(name.clone(), topic(-1, -1));
+        let res = broker.handle(req, CreateTopicsResponse::default()).await?;
+        let t = &res.topics[&name];
+        assert_eq!((t.error_code, t.num_partitions, t.replication_factor), (0, 3, 1));
+        let created = broker.store.get_topic("defaults")?.unwrap();
+        assert_eq!(created.partitions.len(), 3);
+        assert!(created.partitions.values().all(|replicas| replicas.len() == 1));
+
+        // while asking for more replicas than there are brokers fails
+        let mut req = CreateTopicsRequest::default();
+        let name = TopicName(StrBytes::from_str("replicated"));
+        req.topics.insert(name.clone(), topic(1, 3));
+        let res = broker.handle(req, CreateTopicsResponse::default()).await?;
+        assert_eq!(res.topics[&name].error_code, InvalidReplicationFactor.code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_positive_counts() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let create = |name: &str, partitions, replication_factor| {
+            let mut req = CreateTopicsRequest::default();
+            let name = TopicName(name.to_string().to_str_bytes());
+            req.topics.insert(name, topic(partitions, replication_factor));
+            broker.handle(req, CreateTopicsResponse::default())
+        };
+        let code = |res: CreateTopicsResponse| res.topics.values().next().unwrap().error_code;
+        assert_eq!(code(create("a", 0, 1).await?), InvalidPartitions.code());
+        assert_eq!(code(create("b", -2, 1).await?), InvalidPartitions.code());
+        assert_eq!(code(create("c", 1, 0).await?), InvalidReplicationFactor.code());
+        assert_eq!(code(create("d", 1, -2).await?), InvalidReplicationFactor.code());
+        for name in ["a", "b", "c", "d"] {
+            assert!(!broker.store.topic_exists(name)?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zero_default_replication_factor() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.default_replication_factor = 0;
+        apply_proposals(rx, &broker);
+
+        let mut req = CreateTopicsRequest::default();
+        let name = TopicName(StrBytes::from_str("defaults"));
+        req.topics.insert(name.clone(), topic(1, -1));
+        let res = broker.handle(req, CreateTopicsResponse::default()).await?;
+        assert_eq!(res.topics[&name].error_code, InvalidReplicationFactor.code());
+        Ok(())
+    }
 }
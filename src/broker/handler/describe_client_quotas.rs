@@ -0,0 +1,130 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::quota::QuotaEntity;
+use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
+use anyhow::Result;
+use kafka_protocol::messages::describe_client_quotas_request::ComponentData;
+use kafka_protocol::messages::describe_client_quotas_response::{EntityData, EntryData, ValueData};
+use kafka_protocol::messages::{DescribeClientQuotasRequest, DescribeClientQuotasResponse};
+use kafka_protocol::ResponseError::InvalidRequest;
+
+const MATCH_EXACT: i8 = 0;
+const MATCH_DEFAULT: i8 = 1;
+const MATCH_ANY: i8 = 2;
+
+fn matches(entity: &QuotaEntity, component: &ComponentData) -> bool {
+    match (entity.0.get(&*component.entity_type), component.match_type) {
+        (Some(Some(name)), MATCH_EXACT) => component._match.as_deref() == Some(name.as_str()),
+        (Some(None), MATCH_DEFAULT) => true,
+        (Some(Some(_)), MATCH_ANY) => true,
+        _ => false,
+    }
+}
+
+impl Handler<DescribeClientQuotasRequest> for Broker {
+    async fn handle(
+        &self,
+        req: DescribeClientQuotasRequest,
+        mut res: DescribeClientQuotasResponse,
+    ) -> Result<DescribeClientQuotasResponse> {
+        if req.components.iter().any(|c| c.match_type > MATCH_ANY || c.match_type < MATCH_EXACT) {
+            res.error_code = InvalidRequest.code();
+            res.error_message = Some("invalid match type".to_string().to_str_bytes());
+            return Ok(res);
+        }
+
+        // quotas may have been altered through another broker
+        self.client.read().await?;
+        let entries = self
+            .store
+            .get_client_quotas()?
+            .into_iter()
+            .filter(|(entity, _)| req.components.iter().all(|c| matches(entity, c)))
+            .filter(|(entity, _)| {
+                !req.strict
+                    || entity.0.keys().all(|t| {
+                        req.components.iter().any(|c| &*c.entity_type == t)
+                    })
+            })
+            .map(|(entity, values)| {
+                let mut entry = EntryData::default();
+                entry.entity = entity
+                    .0
+                    .into_iter()
+                    .map(|(entity_type, entity_name)| {
+                        let mut e = EntityData::default();
+                        e.entity_type = entity_type.to_str_bytes();
+                        e.entity_name = entity_name.map(|x| x.to_str_bytes());
+                        e
+                    })
+                    .collect();
+                entry.values = values
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let mut v = ValueData::default();
+                        v.key = key.to_str_bytes();
+                        v.value = value;
+                        v
+                    })
+                    .collect();
+                entry
+            })
+            .collect();
+
+        res.entries = Some(entries);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::handler::Handler;
+    use anyhow::Result;
+    use kafka_protocol::messages::alter_client_quotas_request::{EntityData, EntryData, OpData};
+    use kafka_protocol::messages::describe_client_quotas_request::ComponentData;
+    use kafka_protocol::messages::{
+        AlterClientQuotasRequest, AlterClientQuotasResponse, DescribeClientQuotasRequest,
+        DescribeClientQuotasResponse,
+    };
+    use kafka_protocol::protocol::StrBytes;
+
+    #[tokio::test]
+    async fn producer_byte_rate() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let mut entity = EntityData::default();
+        entity.entity_type = StrBytes::from_str("client-id");
+        entity.entity_name = Some(StrBytes::from_str("producer"));
+        let mut op = OpData::default();
+        op.key = StrBytes::from_str("producer_byte_rate");
+        op.value = 1024.0;
+        let mut entry = EntryData::default();
+        entry.entity = vec![entity];
+        entry.ops = vec![op];
+        let mut req = AlterClientQuotasRequest::default();
+        req.entries = vec![entry];
+        let res = broker
+            .handle(req, AlterClientQuotasResponse::default())
+            .await?;
+        assert_eq!(res.entries[0].error_code, 0);
+
+        let mut component = ComponentData::default();
+        component.entity_type = StrBytes::from_str("client-id");
+        component.match_type = 0;
+        component._match = Some(StrBytes::from_str("producer"));
+        let mut req = DescribeClientQuotasRequest::default();
+        req.components = vec![component];
+        let res = broker
+            .handle(req, DescribeClientQuotasResponse::default())
+            .await?;
+
+        let entries = res.entries.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].entity[0].entity_name.as_deref(), Some("producer"));
+        assert_eq!(&*entries[0].values[0].key, "producer_byte_rate");
+        assert_eq!(entries[0].values[0].value, 1024.0);
+        Ok(())
+    }
+}
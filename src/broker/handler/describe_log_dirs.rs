@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::broker::handler::Handler;
+use crate::broker::log::LogStore;
+use crate::broker::state::partition::Partition;
+use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
+use anyhow::Result;
+use kafka_protocol::messages::describe_log_dirs_response::{
+    DescribeLogDirsPartition, DescribeLogDirsResult, DescribeLogDirsTopic,
+};
+use kafka_protocol::messages::{DescribeLogDirsRequest, DescribeLogDirsResponse, TopicName};
+use kafka_protocol::ResponseError::KafkaStorageError;
+
+impl Broker {
+    /// Describes the partitions stored in `dir`, by the name of the directory each is in.
+    async fn describe_log_dir(
+        &self,
+        dir: &Path,
+        partitions: &HashMap<String, &Partition>,
+    ) -> Result<DescribeLogDirsResult> {
+        let mut topics: BTreeMap<String, Vec<DescribeLogDirsPartition>> = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            let partition = match partitions.get(&name) {
+                Some(partition) => partition,
+                None => continue,
+            };
+            let replica = match self.replicas.get(partition.id) {
+                Some(replica) => replica,
+                None => continue,
+            };
+            let replica = replica.lock().await;
+            let mut p = DescribeLogDirsPartition::default();
+            p.partition_index = partition.idx.0;
+            p.partition_size = replica.log.size() as i64;
+            // how far a follower's log is behind the high watermark its leader last reported
+            if partition.leader != self.config.id {
+                p.offset_lag = replica
+                    .leader_high_watermark
+                    .saturating_sub(replica.log.end_offset()) as i64;
+            }
+            topics.entry(partition.topic.clone()).or_default().push(p);
+        }
+
+        let mut result = DescribeLogDirsResult::default();
+        for (name, partitions) in topics {
+            let mut topic = DescribeLogDirsTopic::default();
+            topic.name = TopicName(name.to_str_bytes());
+            topic.partitions = partitions;
+            result.topics.push(topic);
+        }
+        Ok(result)
+    }
+}
+
+impl Handler<DescribeLogDirsRequest> for Broker {
+    async fn handle(
+        &self,
+        req: DescribeLogDirsRequest,
+        mut res: DescribeLogDirsResponse,
+    ) -> Result<DescribeLogDirsResponse> {
+        let metadata = self.metadata.get()?;
+        // every partition when no topics were asked for
+        let partitions = metadata
+            .partitions
+            .values()
+            .filter(|p| match &req.topics {
+                Some(topics) => topics
+                    .iter()
+                    .any(|(name, t)| **name == *p.topic && t.partitions.contains(&p.idx.0)),
+                None => true,
+            })
+            .map(|p| (p.dir_name(), p))
+            .collect();
+
+        for dir in self.log_dirs.dirs() {
+            let mut result = match self.describe_log_dir(dir, &partitions).await {
+                Ok(result) => result,
+                Err(e) => {
+                    tracing::error!(?dir, %e, "could not describe log dir");
+                    let mut result = DescribeLogDirsResult::default();
+                    result.error_code = KafkaStorageError.code();
+                    result
+                }
+            };
+            result.log_dir = dir.to_string_lossy().to_string().to_str_bytes();
+            res.results.push(result);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic};
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::Partition;
+    use crate::broker::BrokerId;
+    use crate::kafka::util::ToStrBytes;
+    use anyhow::Result;
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        DescribeLogDirsRequest, DescribeLogDirsResponse, ProduceRequest, ProduceResponse, TopicName,
+    };
+    use kafka_protocol::ResponseError::KafkaStorageError;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn partition_size() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 2)?;
+
+        let batch = idempotent_batch(-1, -1, 10)?.freeze();
+        let mut pd = PartitionProduceData::default();
+        pd.records = Some(batch.clone());
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.acks = 1;
+        let topic = TopicName("test".to_string().to_str_bytes());
+        req.topic_data.insert(topic.clone(), td);
+        for _ in 0..3 {
+            broker
+                .handle(req.clone(), ProduceResponse::default())
+                .await?;
+        }
+
+        // every topic
+        let mut req = DescribeLogDirsRequest::default();
+        req.topics = None;
+        let res = broker
+            .handle(req, DescribeLogDirsResponse::default())
+            .await?;
+        assert_eq!(res.results.len(), 1);
+        let result = &res.results[0];
+        assert_eq!(result.error_code, 0);
+        let topic = result.topics.iter().find(|t| t.name == topic).unwrap();
+        let mut partitions = topic.partitions.clone();
+        partitions.sort_by_key(|p| p.partition_index);
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].partition_size, 3 * batch.len() as i64);
+        assert_eq!(partitions[0].offset_lag, 0);
+        assert_eq!(partitions[1].partition_size, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follower_offset_lag() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let partition = broker.store.create_partition(Partition {
+            leader: BrokerId(2),
+            isr: vec![2, 1],
+            assigned_replicas: vec![2, 1],
+            ..partition
+        })?;
+        {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            replica.log.write_all(&idempotent_batch(-1, -1, 10)?)?;
+            replica.high_watermark = 1;
+            replica.observe_leader_high_watermark(4);
+        }
+
+        let mut req = DescribeLogDirsRequest::default();
+        req.topics = None;
+        let res = broker
+            .handle(req, DescribeLogDirsResponse::default())
+            .await?;
+        let partition = &res.results[0].topics[0].partitions[0];
+        assert_eq!(partition.offset_lag, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreadable_dir() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        std::fs::remove_dir_all(&broker.log_dirs.dirs()[0])?;
+
+        let res = broker
+            .handle(
+                DescribeLogDirsRequest::default(),
+                DescribeLogDirsResponse::default(),
+            )
+            .await?;
+        assert_eq!(res.error_code, 0);
+        assert_eq!(res.results[0].error_code, KafkaStorageError.code());
+        Ok(())
+    }
+}
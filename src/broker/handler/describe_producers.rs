@@ -0,0 +1,125 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::Broker;
+use kafka_protocol::messages::describe_producers_response::{
+    PartitionResponse, ProducerState, TopicResponse,
+};
+use kafka_protocol::messages::{DescribeProducersRequest, DescribeProducersResponse, TopicName};
+use kafka_protocol::ResponseError;
+use kafka_protocol::ResponseError::{NotLeaderOrFollower, UnknownTopicOrPartition};
+
+impl Broker {
+    /// The active idempotent producers of a partition we lead.
+    async fn describe_producers(
+        &self,
+        topic: &TopicName,
+        idx: i32,
+    ) -> anyhow::Result<Result<Vec<ProducerState>, ResponseError>> {
+        let p = match self.store.get_partition(topic, PartitionIdx(idx))? {
+            Some(p) => p,
+            None => return Ok(Err(UnknownTopicOrPartition)),
+        };
+        let replica = match self.replicas.get(p.id) {
+            Some(replica) if p.leader == self.config.id => replica,
+            _ => return Ok(Err(NotLeaderOrFollower)),
+        };
+
+        let replica = replica.lock().await;
+        let producers = replica
+            .producers
+            .iter()
+            .map(|(id, state)| {
+                let mut producer = ProducerState::default();
+                producer.producer_id = (*id).into();
+                producer.producer_epoch = state.epoch as i32;
+                producer.last_sequence = state.last_sequence;
+                producer.last_timestamp = state.last_timestamp;
+                producer
+            })
+            .collect();
+        Ok(Ok(producers))
+    }
+}
+
+impl Handler<DescribeProducersRequest> for Broker {
+    async fn handle(
+        &self,
+        req: DescribeProducersRequest,
+        mut res: DescribeProducersResponse,
+    ) -> anyhow::Result<DescribeProducersResponse> {
+        for t in req.topics {
+            let mut topic = TopicResponse::default();
+            for idx in t.partition_indexes {
+                let mut partition = PartitionResponse::default();
+                partition.partition_index = idx;
+                match self.describe_producers(&t.name, idx).await? {
+                    Ok(producers) => partition.active_producers = producers,
+                    Err(e) => partition.error_code = e.code(),
+                }
+                topic.partitions.push(partition);
+            }
+            topic.name = t.name;
+            res.topics.push(topic);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic};
+    use crate::broker::handler::Handler;
+    use crate::kafka::util::ToStrBytes;
+    use anyhow::Result;
+    use kafka_protocol::messages::describe_producers_request::TopicRequest;
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        DescribeProducersRequest, DescribeProducersResponse, ProduceRequest, ProduceResponse,
+        TopicName,
+    };
+    use kafka_protocol::ResponseError::UnknownTopicOrPartition;
+
+    fn topic_name(name: &str) -> TopicName {
+        TopicName(name.to_string().to_str_bytes())
+    }
+
+    #[tokio::test]
+    async fn idempotent_producer() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+
+        for sequence in [0, 2] {
+            let mut pd = PartitionProduceData::default();
+            pd.records = Some(idempotent_batch(7, sequence, 2)?.freeze());
+            let mut td = TopicProduceData::default();
+            td.partition_data.push(pd);
+            let mut req = ProduceRequest::default();
+            req.topic_data.insert(topic_name("test"), td);
+            broker.handle(req, ProduceResponse::default()).await?;
+        }
+
+        let mut req = DescribeProducersRequest::default();
+        for name in ["test", "unknown"] {
+            let mut topic = TopicRequest::default();
+            topic.name = topic_name(name);
+            topic.partition_indexes = vec![0];
+            req.topics.push(topic);
+        }
+        let res = broker
+            .handle(req, DescribeProducersResponse::default())
+            .await?;
+
+        let partition = &res.topics[0].partitions[0];
+        assert_eq!(partition.error_code, 0);
+        assert_eq!(partition.active_producers.len(), 1);
+        let producer = &partition.active_producers[0];
+        assert_eq!(producer.producer_id, 7i64);
+        assert_eq!(producer.producer_epoch, 3);
+        assert_eq!(producer.last_sequence, 3);
+        assert_eq!(producer.last_timestamp, 1001);
+
+        let partition = &res.topics[1].partitions[0];
+        assert_eq!(partition.error_code, UnknownTopicOrPartition.code());
+        Ok(())
+    }
+}
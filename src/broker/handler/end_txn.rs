@@ -0,0 +1,224 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::txn::{TxnOp, TxnState};
+use crate::broker::Broker;
+use anyhow::Result;
+use kafka_protocol::messages::{EndTxnRequest, EndTxnResponse};
+use kafka_protocol::ResponseError::CoordinatorNotAvailable;
+
+impl Handler<EndTxnRequest> for Broker {
+    async fn handle(&self, req: EndTxnRequest, mut res: EndTxnResponse) -> Result<EndTxnResponse> {
+        let op = TxnOp::End {
+            producer_id: req.producer_id.0,
+            producer_epoch: req.producer_epoch,
+            commit: req.committed,
+        };
+        let txn = match self.update_txn(&req.transactional_id, op).await? {
+            Ok(txn) => txn,
+            Err(e) => {
+                res.error_code = e.code();
+                return Ok(res);
+            }
+        };
+
+        // a retry of an end that already completed has nothing left to do
+        if matches!(txn.state, TxnState::PrepareCommit | TxnState::PrepareAbort) {
+            // the transaction stays prepared, so the client's retry writes the markers again
+            if let Err(e) = self.write_markers(&txn, req.committed).await {
+                tracing::error!(%e, "could not end transaction");
+                res.error_code = CoordinatorNotAvailable.code();
+                return Ok(res);
+            }
+            if let Err(e) = self
+                .update_txn(&req.transactional_id, TxnOp::Complete)
+                .await?
+            {
+                res.error_code = e.code();
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{
+        apply_proposals, new_broker, new_topic, transactional_batch,
+    };
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::Partition;
+    use crate::broker::state::txn::TxnState;
+    use crate::broker::{Broker, BrokerId};
+    use crate::kafka::util::ToStrBytes;
+    use anyhow::Result;
+    use kafka_protocol::ResponseError::CoordinatorNotAvailable;
+    use kafka_protocol::messages::add_partitions_to_txn_request::AddPartitionsToTxnTopic;
+    use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        AddPartitionsToTxnRequest, AddPartitionsToTxnResponse, EndTxnRequest, EndTxnResponse,
+        FetchRequest, FetchResponse, InitProducerIdRequest, InitProducerIdResponse, ProduceRequest,
+        ProduceResponse, TopicName, TransactionalId,
+    };
+
+    /// Starts a transaction on both partitions of "test", writing a batch to each, and returns
+    /// the producer's id and epoch.
+    async fn begin(broker: &Broker) -> Result<(i64, i16)> {
+        let transactional_id = TransactionalId("txn".to_string().to_str_bytes());
+        let mut req = InitProducerIdRequest::default();
+        req.transactional_id = Some(transactional_id.clone());
+        let res = broker
+            .handle(req, InitProducerIdResponse::default())
+            .await?;
+        assert_eq!(res.error_code, 0);
+        let (producer_id, producer_epoch) = (res.producer_id.0, res.producer_epoch);
+
+        let topic = TopicName("test".to_string().to_str_bytes());
+        let mut partitions = AddPartitionsToTxnTopic::default();
+        partitions.partitions = vec![0, 1];
+        let mut req = AddPartitionsToTxnRequest::default();
+        req.transactional_id = transactional_id;
+        req.producer_id = producer_id.into();
+        req.producer_epoch = producer_epoch;
+        req.topics.insert(topic.clone(), partitions);
+        let res = broker
+            .handle(req, AddPartitionsToTxnResponse::default())
+            .await?;
+        let results = &res.results[&topic].results;
+        assert!(results.values().all(|r| r.error_code == 0));
+
+        let mut td = TopicProduceData::default();
+        for idx in 0..2 {
+            let mut pd = PartitionProduceData::default();
+            pd.index = idx;
+            pd.records = Some(transactional_batch(producer_id, producer_epoch, 0, 2)?.freeze());
+            td.partition_data.push(pd);
+        }
+        let mut req = ProduceRequest::default();
+        req.acks = 1;
+        req.transactional_id = Some(TransactionalId("txn".to_string().to_str_bytes()));
+        req.topic_data.insert(topic, td);
+        broker.handle(req, ProduceResponse::default()).await?;
+        Ok((producer_id, producer_epoch))
+    }
+
+    async fn end(broker: &Broker, producer: (i64, i16), commit: bool) -> Result<EndTxnResponse> {
+        let mut req = EndTxnRequest::default();
+        req.transactional_id = TransactionalId("txn".to_string().to_str_bytes());
+        req.producer_id = producer.0.into();
+        req.producer_epoch = producer.1;
+        req.committed = commit;
+        broker.handle(req, EndTxnResponse::default()).await
+    }
+
+    /// Fetches both partitions of "test" as a consumer reading committed records.
+    async fn fetch_committed(broker: &Broker) -> Result<FetchResponse> {
+        let mut t = FetchTopic::default();
+        t.topic = TopicName("test".to_string().to_str_bytes());
+        for idx in 0..2 {
+            let mut p = FetchPartition::default();
+            p.partition = idx;
+            p.partition_max_bytes = 1024;
+            t.partitions.push(p);
+        }
+        let mut req = FetchRequest::default();
+        req.replica_id = (-1).into();
+        req.isolation_level = 1;
+        req.max_bytes = 4096;
+        req.topics.push(t);
+        broker.handle(req, FetchResponse::default()).await
+    }
+
+    #[tokio::test]
+    async fn commit() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+        new_topic(&broker, "test", 2)?;
+        let producer = begin(&broker).await?;
+
+        // the open transaction holds back committed reads
+        let res = fetch_committed(&broker).await?;
+        for p in &res.responses[0].partitions {
+            assert_eq!(p.last_stable_offset, 0);
+            assert!(p.records.as_ref().unwrap().is_empty());
+        }
+
+        let res = end(&broker, producer, true).await?;
+        assert_eq!(res.error_code, 0);
+        let txn = broker.store.get_txn("txn")?.unwrap();
+        assert_eq!(txn.state, TxnState::CompleteCommit);
+        assert!(txn.partitions.is_empty());
+
+        let res = fetch_committed(&broker).await?;
+        for p in &res.responses[0].partitions {
+            // the records along with the commit marker
+            assert_eq!(p.last_stable_offset, 2);
+            assert!(!p.records.as_ref().unwrap().is_empty());
+            assert_eq!(p.aborted_transactions, Some(vec![]));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn abort() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+        new_topic(&broker, "test", 2)?;
+        let producer = begin(&broker).await?;
+
+        let res = end(&broker, producer, false).await?;
+        assert_eq!(res.error_code, 0);
+        let txn = broker.store.get_txn("txn")?.unwrap();
+        assert_eq!(txn.state, TxnState::CompleteAbort);
+
+        // consumers are told to skip the producer's batches from the start of the transaction
+        let res = fetch_committed(&broker).await?;
+        for p in &res.responses[0].partitions {
+            assert_eq!(p.last_stable_offset, 2);
+            let aborted = p.aborted_transactions.as_ref().unwrap();
+            assert_eq!(aborted.len(), 1);
+            assert_eq!(aborted[0].producer_id.0, producer.0);
+            assert_eq!(aborted[0].first_offset, 0);
+        }
+
+        // the transaction can't be committed once it was aborted
+        let res = end(&broker, producer, true).await?;
+        assert_eq!(
+            res.error_code,
+            kafka_protocol::ResponseError::InvalidTxnState.code()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_markers_leave_transaction_prepared() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+        let partitions = new_topic(&broker, "test", 2)?;
+        let producer = begin(&broker).await?;
+
+        // partition 1 moved to a leader we can't write markers for
+        broker.store.create_partition(Partition {
+            leader: BrokerId(2),
+            assigned_replicas: vec![1, 2],
+            isr: vec![1, 2],
+            ..partitions[1].clone()
+        })?;
+        let res = end(&broker, producer, true).await?;
+        assert_eq!(res.error_code, CoordinatorNotAvailable.code());
+        let txn = broker.store.get_txn("txn")?.unwrap();
+        assert_eq!(txn.state, TxnState::PrepareCommit);
+        assert_eq!(txn.partitions.len(), 2);
+
+        // and the retry writes them once it can
+        broker.store.create_partition(partitions[1].clone())?;
+        let res = end(&broker, producer, true).await?;
+        assert_eq!(res.error_code, 0);
+        let txn = broker.store.get_txn("txn")?.unwrap();
+        assert_eq!(txn.state, TxnState::CompleteCommit);
+        for partition in &partitions {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            assert!(replica.lock().await.ongoing_txns.is_empty());
+        }
+        Ok(())
+    }
+}
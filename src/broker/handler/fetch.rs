@@ -0,0 +1,791 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::broker::compression::ReplicationCompression;
+use crate::broker::error::JosefineError;
+use crate::broker::handler::{check_leader_epoch, Handler, PartitionError};
+use crate::broker::log::LogStore;
+use crate::broker::purgatory::DelayedOperation;
+use crate::broker::replica::Replica;
+use crate::broker::state::partition::{Partition, PartitionIdx};
+use crate::broker::{Broker, BrokerId};
+use anyhow::Result;
+use bytes::Bytes;
+use kafka_protocol::messages::fetch_response::{
+    AbortedTransaction, FetchableTopicResponse, PartitionData,
+};
+use kafka_protocol::messages::{FetchRequest, FetchResponse};
+use kafka_protocol::ResponseError::{NotLeaderOrFollower, UnknownTopicOrPartition};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// The isolation level of consumers that only read records of committed transactions.
+const READ_COMMITTED: i8 = 1;
+
+type FetchReplicas<L> = Vec<Vec<Result<(Partition, Arc<Mutex<Replica<L>>>), PartitionError>>>;
+
+impl<L: LogStore> Broker<L> {
+    fn fetch_replicas(&self, req: &FetchRequest) -> Result<FetchReplicas<L>> {
+        req.topics
+            .iter()
+            .map(|t| {
+                t.partitions
+                    .iter()
+                    .map(|p| {
+                        let partition = self
+                            .store
+                            .get_partition(&t.topic, PartitionIdx(p.partition))?;
+                        Ok(match partition {
+                            Some(partition) if self.can_fetch_from(&partition, &req.rack_id) => {
+                                check_leader_epoch(&partition, p.current_leader_epoch)
+                                    .map_err(PartitionError::from)
+                                    .and_then(|()| {
+                                        let replica = self
+                                            .replicas
+                                            .get_partition(&partition.topic, partition.idx);
+                                        let replica = replica.map(|r| (partition, r));
+                                        replica.ok_or_else(|| NotLeaderOrFollower.into())
+                                    })
+                            }
+                            Some(partition) => Err(self.not_leader(partition.leader)?),
+                            None => Err(UnknownTopicOrPartition.into()),
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Whether a consumer in `rack_id` may fetch the partition from this broker, either because
+    /// we lead it, or because we are an in sync follower in the consumer's rack.
+    fn can_fetch_from(&self, partition: &Partition, rack_id: &str) -> bool {
+        if partition.leader == self.config.id {
+            return true;
+        }
+        let same_rack = !rack_id.is_empty() && self.config.rack.as_deref() == Some(rack_id);
+        same_rack && partition.isr.contains(&self.config.id.0)
+    }
+
+    /// Sends consumers fetching from one of our follower replicas to the leader instead, until
+    /// the replica has caught up with where the leader was when we started following it.
+    async fn hold_back_lagging(&self, replicas: &mut FetchReplicas<L>) -> Result<()> {
+        for replica in replicas.iter_mut().flatten() {
+            let leader = match replica {
+                Ok((partition, r)) if partition.leader != self.config.id => {
+                    match r.lock().await.caught_up {
+                        true => continue,
+                        false => partition.leader,
+                    }
+                }
+                _ => continue,
+            };
+            *replica = Err(self.not_leader(leader)?);
+        }
+        Ok(())
+    }
+
+    /// Records the log end offsets a follower reports by fetching from us, advancing the high
+    /// watermarks of the partitions we lead.
+    async fn record_follower_fetch(&self, req: &FetchRequest, replicas: &FetchReplicas<L>) {
+        let now = Instant::now();
+        for (t, replicas) in req.topics.iter().zip(replicas) {
+            for (p, replica) in t.partitions.iter().zip(replicas) {
+                let (partition, replica) = match replica {
+                    Ok((partition, replica)) if partition.leader == self.config.id => {
+                        (partition, replica)
+                    }
+                    _ => continue,
+                };
+                let mut replica = replica.lock().await;
+                replica.lead(partition.leader_epoch, now);
+                replica.update_follower(req.replica_id.0, p.fetch_offset.max(0) as u64, now);
+                if replica.update_high_watermark(partition.leader, &partition.isr) {
+                    self.fetch_purgatory.complete(&partition.id);
+                    self.produce_purgatory.complete(&partition.id);
+                }
+            }
+        }
+    }
+
+    /// Reads every requested partition, returning the response along with the number of bytes
+    /// read. Consumers only read up to the high watermark, or the last stable offset when reading
+    /// committed records, while followers read everything so they can replicate it. Consumers
+    /// reading committed records don't see the batches of aborted transactions, and consumers
+    /// the replica selector prefers another replica for are pointed to it instead of being
+    /// read for. Partitions are read in the order requested until the request's `max_bytes` is
+    /// spent.
+    async fn read_partitions(
+        &self,
+        req: &FetchRequest,
+        replicas: &FetchReplicas<L>,
+        mut res: FetchResponse,
+    ) -> Result<(FetchResponse, usize)> {
+        let mut total = 0;
+        // only consumers that say where they are are sent elsewhere
+        let brokers = match req.replica_id.0 < 0 && !req.rack_id.is_empty() {
+            true => Some(self.get_brokers()?),
+            false => None,
+        };
+        for (t, replicas) in req.topics.iter().zip(replicas) {
+            let mut topic = FetchableTopicResponse::default();
+            topic.topic = t.topic.clone();
+            topic.topic_id = t.topic_id;
+            for (p, replica) in t.partitions.iter().zip(replicas) {
+                let mut partition = PartitionData::default();
+                partition.partition_index = p.partition;
+                match replica {
+                    Ok((current, replica)) => {
+                        partition.current_leader.leader_id = current.leader.0.into();
+                        partition.current_leader.leader_epoch = current.leader_epoch;
+                        let replica = replica.lock().await;
+                        let preferred = match &brokers {
+                            Some(brokers) if current.leader == self.config.id => {
+                                self.replica_selector.select(current, brokers, &req.rack_id)
+                            }
+                            _ => self.config.id,
+                        };
+                        if preferred != self.config.id {
+                            partition.preferred_read_replica = preferred.0.into();
+                            partition.high_watermark = replica.high_watermark as i64;
+                            partition.records = Some(Bytes::new());
+                            topic.partitions.push(partition);
+                            continue;
+                        }
+                        let committed =
+                            req.replica_id.0 < 0 && req.isolation_level == READ_COMMITTED;
+                        let limit = match (req.replica_id.0 >= 0, committed) {
+                            (true, _) => replica.log.end_offset(),
+                            (false, true) => replica.last_stable_offset(),
+                            (false, false) => replica.high_watermark,
+                        };
+                        let start = p.fetch_offset.max(0) as u64;
+                        let remaining = (req.max_bytes.max(0) as u64).saturating_sub(total as u64);
+                        let max_bytes = (p.partition_max_bytes.max(0) as u64).min(remaining);
+                        let records = match committed {
+                            true => replica.read_committed(start, limit, max_bytes)?,
+                            false => replica.log.read(start, limit, max_bytes)?,
+                        };
+                        total += records.len();
+                        partition.high_watermark = replica.high_watermark as i64;
+                        partition.last_stable_offset = replica.last_stable_offset() as i64;
+                        if committed {
+                            let aborted = replica.aborted_between(start, limit).map(|t| {
+                                let mut aborted = AbortedTransaction::default();
+                                aborted.producer_id = t.producer_id.into();
+                                aborted.first_offset = t.first_offset as i64;
+                                aborted
+                            });
+                            partition.aborted_transactions = Some(aborted.collect());
+                        }
+                        partition.records = Some(Bytes::from(records));
+                    }
+                    Err(e) => {
+                        partition.error_code = e.code();
+                        if let Some(leader) = &e.leader {
+                            partition.current_leader.leader_id = leader.id.0.into();
+                        }
+                    }
+                }
+                topic.partitions.push(partition);
+            }
+            res.responses.push(topic);
+        }
+        Ok((res, total))
+    }
+}
+
+/// A fetch waiting for at least `min_bytes` of records to be readable.
+struct DelayedFetch<'a, L> {
+    broker: &'a Broker<L>,
+    req: &'a FetchRequest,
+    replicas: &'a FetchReplicas<L>,
+    res: FetchResponse,
+}
+
+impl<L: LogStore> DelayedOperation for DelayedFetch<'_, L> {
+    type Output = FetchResponse;
+
+    async fn try_complete(&mut self) -> Result<Option<FetchResponse>> {
+        let (fetched, bytes) = self
+            .broker
+            .read_partitions(self.req, self.replicas, self.res.clone())
+            .await?;
+        let redirected = fetched
+            .responses
+            .iter()
+            .flat_map(|t| &t.partitions)
+            .any(|p| p.preferred_read_replica.0 >= 0);
+        // there is nothing to wait for without any partitions to read
+        let readable = self.replicas.iter().flatten().any(Result::is_ok);
+        let done = bytes >= self.req.min_bytes.max(0) as usize || redirected || !readable;
+        Ok(done.then_some(fetched))
+    }
+
+    async fn on_expiration(self) -> Result<FetchResponse> {
+        let (fetched, _) = self
+            .broker
+            .read_partitions(self.req, self.replicas, self.res)
+            .await?;
+        Ok(fetched)
+    }
+}
+
+impl<L: LogStore> Handler<FetchRequest> for Broker<L> {
+    async fn handle(
+        &self,
+        mut req: FetchRequest,
+        mut res: FetchResponse,
+    ) -> Result<FetchResponse> {
+        // only the brokers of the cluster may fetch as followers
+        if req.replica_id.0 >= 0 && !self.get_broker_ids()?.contains(&BrokerId(req.replica_id.0)) {
+            return Err(JosefineError::AuthorizationFailed.into());
+        }
+        let session = match self.fetch_sessions.begin(&mut req) {
+            Ok(session) => session,
+            Err(e) => {
+                res.error_code = e.code();
+                return Ok(res);
+            }
+        };
+        let deadline = Instant::now() + Duration::from_millis(req.max_wait_ms.max(0) as u64);
+        let mut replicas = self.fetch_replicas(&req)?;
+        self.hold_back_lagging(&mut replicas).await?;
+        if req.replica_id.0 >= 0 {
+            self.record_follower_fetch(&req, &replicas).await;
+        }
+        // an append to any of the partitions, or a move of its high watermark, may make enough
+        // records readable
+        let keys: Vec<Uuid> = replicas.iter().flatten().flatten().map(|(p, _)| p.id).collect();
+        let fetch = DelayedFetch {
+            broker: self,
+            req: &req,
+            replicas: &replicas,
+            res,
+        };
+        let mut fetched = self.fetch_purgatory.watch(&keys, deadline, fetch).await?;
+        self.fetch_sessions.finish(session, &mut fetched);
+        // only the records that are sent are compressed, once and without holding any replica
+        if req.replica_id.0 >= 0 {
+            let compression = ReplicationCompression::requested(&req);
+            for partition in fetched.responses.iter_mut().flat_map(|t| &mut t.partitions) {
+                compression.compress(partition)?;
+            }
+        }
+        Ok(fetched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::broker::compression::ReplicationCompression;
+    use crate::broker::config::Peer;
+    use crate::broker::error::JosefineError;
+    use crate::broker::fetcher::whole_batches;
+    use crate::broker::handler::test::{
+        idempotent_batch, new_broker, new_topic, transactional_batch,
+    };
+    use crate::broker::handler::Handler;
+    use crate::broker::selector::ReplicaSelectorKind;
+    use crate::broker::state::partition::Partition;
+    use crate::broker::txn::marker_batch;
+    use crate::broker::BrokerId;
+    use crate::kafka::util::ToStrBytes;
+    use anyhow::Result;
+    use bytes::Bytes;
+    use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        FetchRequest, FetchResponse, ProduceRequest, ProduceResponse, TopicName,
+    };
+    use kafka_protocol::records::RecordBatchDecoder;
+    use kafka_protocol::ResponseError::{
+        FencedLeaderEpoch, InvalidFetchSessionEpoch, NotLeaderOrFollower,
+    };
+    use std::io::Write;
+    use tokio::time::Instant;
+
+    fn fetch_request(topic: &str, max_wait_ms: i32, min_bytes: i32) -> FetchRequest {
+        let mut partition = FetchPartition::default();
+        partition.partition_max_bytes = 1024;
+        let mut t = FetchTopic::default();
+        t.topic = TopicName(topic.to_string().to_str_bytes());
+        t.partitions.push(partition);
+        let mut req = FetchRequest::default();
+        // consumers don't have a replica id
+        req.replica_id = (-1).into();
+        req.max_wait_ms = max_wait_ms;
+        req.min_bytes = min_bytes;
+        req.topics.push(t);
+        req
+    }
+
+    fn produce_request(topic: &str, records: &'static [u8]) -> ProduceRequest {
+        let mut pd = PartitionProduceData::default();
+        pd.records = Some(Bytes::from_static(records));
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.topic_data
+            .insert(TopicName(topic.to_string().to_str_bytes()), td);
+        req
+    }
+
+    #[tokio::test]
+    async fn waits_for_max_wait() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+
+        let start = Instant::now();
+        let res = broker
+            .handle(fetch_request("test", 100, 1), FetchResponse::default())
+            .await?;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let records = res.responses[0].partitions[0].records.as_ref().unwrap();
+        assert!(records.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn woken_by_produce() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+
+        let start = Instant::now();
+        let (res, _) = tokio::join!(
+            broker.handle(fetch_request("test", 10_000, 1), FetchResponse::default()),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                broker
+                    .handle(
+                        produce_request("test", b"records"),
+                        ProduceResponse::default(),
+                    )
+                    .await
+            }
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let partition = &res?.responses[0].partitions[0];
+        assert_eq!(partition.records.as_deref(), Some(&b"records"[..]));
+        assert_eq!(partition.high_watermark, 1);
+        Ok(())
+    }
+
+    /// An empty record batch of `size` bytes.
+    fn batch(size: usize) -> Vec<u8> {
+        let mut batch = vec![0u8; size];
+        batch[8..12].copy_from_slice(&((size - 12) as i32).to_be_bytes());
+        batch
+    }
+
+    #[tokio::test]
+    async fn same_rack_follower() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.rack = Some("a".to_string());
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        // led by another broker, with this one in sync
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let partition = broker.store.create_partition(Partition {
+            leader: BrokerId(2),
+            isr: vec![2, 1],
+            assigned_replicas: vec![2, 1],
+            ..partition
+        })?;
+
+        let replica = broker.replicas.get(partition.id).unwrap();
+        {
+            let mut replica = replica.lock().await;
+            replica.log.write_all(&batch(20))?;
+            replica.log.write_all(&batch(30))?;
+            replica.high_watermark = 1;
+            replica.observe_leader_high_watermark(1);
+        }
+
+        let mut req = fetch_request("test", 0, 0);
+        req.rack_id = "a".to_string().to_str_bytes();
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.error_code, 0);
+        assert_eq!(partition.records.as_deref(), Some(&batch(20)[..]));
+        assert_eq!(partition.high_watermark, 1);
+
+        // consumers elsewhere have to fetch from the leader
+        let mut req = fetch_request("test", 0, 0);
+        req.rack_id = "b".to_string().to_str_bytes();
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.error_code, NotLeaderOrFollower.code());
+        assert_eq!(partition.current_leader.leader_id, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lagging_follower() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.rack = Some("a".to_string());
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let partition = broker.store.create_partition(Partition {
+            leader: BrokerId(2),
+            isr: vec![2, 1],
+            assigned_replicas: vec![2, 1],
+            ..partition
+        })?;
+        let fetch = || {
+            let mut req = fetch_request("test", 0, 0);
+            req.rack_id = "a".to_string().to_str_bytes();
+            broker.handle(req, FetchResponse::default())
+        };
+
+        // until the replica hears from the leader, and then while it is short of the high
+        // watermark it heard, consumers are sent to the leader
+        let replica = broker.replicas.get(partition.id).unwrap();
+        for observed in [None, Some(2)] {
+            if let Some(high_watermark) = observed {
+                let mut replica = replica.lock().await;
+                replica.log.write_all(&batch(20))?;
+                replica.observe_leader_high_watermark(high_watermark);
+            }
+            let res = fetch().await?;
+            let partition = &res.responses[0].partitions[0];
+            assert_eq!(partition.error_code, NotLeaderOrFollower.code());
+            assert_eq!(partition.current_leader.leader_id, 2);
+        }
+
+        // once it catches up it serves them, even if the leader has moved on since
+        {
+            let mut replica = replica.lock().await;
+            replica.log.write_all(&batch(30))?;
+            replica.high_watermark = 2;
+            replica.observe_leader_high_watermark(5);
+        }
+        let res = fetch().await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.error_code, 0);
+        assert_eq!(partition.high_watermark, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preferred_read_replica() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.replica_selector = ReplicaSelectorKind::RackAware.build();
+        broker.config.rack = Some("a".to_string());
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: Some("b".to_string()),
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            ..partition
+        })?;
+
+        // the consumer is sent to the in sync replica in its rack without waiting
+        let mut req = fetch_request("test", 10_000, 1);
+        req.rack_id = "b".to_string().to_str_bytes();
+        let start = Instant::now();
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.preferred_read_replica, 2);
+
+        // while one in the leader's rack is served by the leader
+        let mut req = fetch_request("test", 0, 0);
+        req.rack_id = "a".to_string().to_str_bytes();
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        assert_eq!(res.responses[0].partitions[0].preferred_read_replica, -1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follower_fetch_advances_high_watermark() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            ..partition
+        })?;
+
+        broker
+            .handle(
+                produce_request("test", b"records"),
+                ProduceResponse::default(),
+            )
+            .await?;
+        // the follower doesn't have the records yet, so consumers can't see them
+        let res = broker
+            .handle(fetch_request("test", 0, 0), FetchResponse::default())
+            .await?;
+        assert_eq!(res.responses[0].partitions[0].high_watermark, 0);
+
+        // followers can read past the high watermark to catch up
+        let mut req = fetch_request("test", 0, 0);
+        req.replica_id = 2.into();
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.records.as_deref(), Some(&b"records"[..]));
+
+        // and once they've fetched past the records, they're committed
+        let mut req = fetch_request("test", 0, 0);
+        req.replica_id = 2.into();
+        req.topics[0].partitions[0].fetch_offset = 1;
+        broker.handle(req, FetchResponse::default()).await?;
+        let res = broker
+            .handle(fetch_request("test", 0, 0), FetchResponse::default())
+            .await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.high_watermark, 1);
+        assert_eq!(partition.records.as_deref(), Some(&b"records"[..]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compresses_only_sent_records() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            for _ in 0..3 {
+                replica.log.write_all(&idempotent_batch(-1, -1, 10)?)?;
+            }
+        }
+        let mut req = fetch_request("test", 0, 0);
+        req.replica_id = 2.into();
+        ReplicationCompression::Gzip.request(&mut req);
+
+        // reads, which the purgatory may retry, leave the records as they are
+        let replicas = broker.fetch_replicas(&req)?;
+        let (read, _) = broker
+            .read_partitions(&req, &replicas, FetchResponse::default())
+            .await?;
+        let records = read.responses[0].partitions[0].records.clone().unwrap();
+        assert_eq!(whole_batches(&records).len(), 3);
+
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let sent = &res.responses[0].partitions[0];
+        assert!(sent.records.as_ref().unwrap().len() < records.len());
+        assert_eq!(ReplicationCompression::decompress(sent)?, records);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_follower() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+
+        let mut req = fetch_request("test", 0, 0);
+        req.replica_id = 2.into();
+        let err = broker.handle(req, FetchResponse::default()).await.unwrap_err();
+        assert_eq!(JosefineError::classify(&err), JosefineError::AuthorizationFailed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incremental_fetch_session() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 2)?;
+
+        // a full fetch of both partitions starts the session
+        let mut req = fetch_request("test", 0, 0);
+        let mut partition = req.topics[0].partitions[0].clone();
+        partition.partition = 1;
+        req.topics[0].partitions.push(partition);
+        req.session_epoch = 0;
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        assert_ne!(res.session_id, 0);
+        assert_eq!(res.responses[0].partitions.len(), 2);
+        let session_id = res.session_id;
+
+        broker
+            .handle(
+                produce_request("test", b"records"),
+                ProduceResponse::default(),
+            )
+            .await?;
+
+        // the incremental fetch only names the partition that changed
+        let mut req = fetch_request("test", 0, 0);
+        req.session_id = session_id;
+        req.session_epoch = 1;
+        let res = broker.handle(req.clone(), FetchResponse::default()).await?;
+        assert_eq!(res.error_code, 0);
+        assert_eq!(res.session_id, session_id);
+        assert_eq!(res.responses.len(), 1);
+        let partitions = &res.responses[0].partitions;
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].partition_index, 0);
+        assert_eq!(partitions[0].records.as_deref(), Some(&b"records"[..]));
+
+        // replaying an epoch forces the client back to a full fetch
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        assert_eq!(res.error_code, InvalidFetchSessionEpoch.code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_leader_epoch() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        broker.store.create_partition(Partition {
+            leader_epoch: 3,
+            ..partition
+        })?;
+
+        let mut req = fetch_request("test", 0, 0);
+        req.topics[0].partitions[0].current_leader_epoch = 2;
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.error_code, FencedLeaderEpoch.code());
+
+        let mut req = fetch_request("test", 0, 0);
+        req.topics[0].partitions[0].current_leader_epoch = 3;
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.error_code, 0);
+        assert_eq!(partition.current_leader.leader_epoch, 3);
+        Ok(())
+    }
+
+    /// Whether each batch in `records` is transactional, and whether it is a control batch.
+    fn batch_flags(records: &[u8]) -> Result<Vec<(bool, bool)>> {
+        whole_batches(records)
+            .into_iter()
+            .map(|batch| {
+                let records = RecordBatchDecoder::decode(&mut Bytes::copy_from_slice(batch))?;
+                Ok((records[0].transactional, records[0].control))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn control_batches() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let batches = [
+            transactional_batch(7, 0, 0, 1)?.freeze(),
+            marker_batch(7, 0, true)?,
+            transactional_batch(8, 0, 0, 1)?.freeze(),
+            marker_batch(8, 0, false)?,
+            idempotent_batch(-1, -1, 1)?.freeze(),
+        ];
+        {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            for (offset, batch) in batches.iter().enumerate() {
+                replica.log.write_all(batch)?;
+                replica.track_transactions(offset as u64, batch);
+            }
+            replica.high_watermark = batches.len() as u64;
+        }
+
+        // consumers reading uncommitted records get the markers along with every record
+        let res = broker
+            .handle(fetch_request("test", 0, 0), FetchResponse::default())
+            .await?;
+        let records = res.responses[0].partitions[0].records.clone().unwrap();
+        assert_eq!(records, batches.concat());
+        assert_eq!(
+            batch_flags(&records)?,
+            vec![
+                (true, false),
+                (true, true),
+                (true, false),
+                (true, true),
+                (false, false)
+            ]
+        );
+
+        // while those reading committed records only miss the aborted records
+        let mut req = fetch_request("test", 0, 0);
+        req.isolation_level = 1;
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let records = res.responses[0].partitions[0].records.clone().unwrap();
+        assert_eq!(
+            batch_flags(&records)?,
+            vec![(true, false), (true, true), (true, true), (false, false)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_committed_skips_aborted() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        // larger than the marker, so that a fetch sized to it can hold the marker too
+        let aborted = transactional_batch(7, 0, 0, 5)?;
+        let marker = marker_batch(7, 0, false)?;
+        let committed = idempotent_batch(-1, -1, 1)?;
+        {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            for (offset, batch) in [&aborted[..], &marker[..], &committed[..]].iter().enumerate() {
+                replica.log.write_all(batch)?;
+                replica.track_transactions(offset as u64, batch);
+            }
+            replica.high_watermark = 3;
+        }
+
+        let res = broker
+            .handle(fetch_request("test", 0, 0), FetchResponse::default())
+            .await?;
+        let records = res.responses[0].partitions[0].records.clone().unwrap();
+        assert_eq!(records, [&aborted[..], &marker[..], &committed[..]].concat());
+
+        let mut req = fetch_request("test", 0, 0);
+        req.isolation_level = 1;
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let partition = &res.responses[0].partitions[0];
+        assert_eq!(partition.last_stable_offset, 3);
+        let records = partition.records.clone().unwrap();
+        assert_eq!(records, [&marker[..], &committed[..]].concat());
+
+        // a fetch of only the aborted batch reads on to the marker
+        let mut req = fetch_request("test", 0, 0);
+        req.isolation_level = 1;
+        req.topics[0].partitions[0].partition_max_bytes = aborted.len() as i32;
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        let records = res.responses[0].partitions[0].records.clone().unwrap();
+        assert!(records.starts_with(&marker));
+        Ok(())
+    }
+}
@@ -4,19 +4,102 @@ use crate::kafka::util::ToStrBytes;
 use kafka_protocol::messages;
 use kafka_protocol::messages::find_coordinator_response::Coordinator;
 use kafka_protocol::messages::{FindCoordinatorRequest, FindCoordinatorResponse};
+use kafka_protocol::ResponseError::CoordinatorNotAvailable;
+
+/// The key type of a group, as opposed to a transactional id.
+const GROUP_KEY_TYPE: i8 = 0;
+
+impl Broker {
+    /// The coordinator of `key`. Groups are coordinated by the leader of their offsets topic
+    /// partition, while transactional ids are coordinated by whichever broker is asked.
+    fn find_coordinator(&self, key: &str, key_type: i8) -> anyhow::Result<Coordinator> {
+        let mut coordinator = Coordinator::default();
+        coordinator.key = key.to_string().to_str_bytes();
+        let id = match key_type {
+            GROUP_KEY_TYPE => self.group_coordinator(key)?.unwrap_or(self.config.id),
+            _ => self.config.id,
+        };
+        match self.get_brokers()?.into_iter().find(|b| b.id == id) {
+            Some(peer) => {
+                coordinator.node_id = messages::BrokerId(peer.id.0);
+                coordinator.host = peer.advertised_host().to_str_bytes();
+                coordinator.port = peer.advertised_port() as i32;
+            }
+            None => {
+                coordinator.node_id = messages::BrokerId(-1);
+                coordinator.error_code = CoordinatorNotAvailable.code();
+            }
+        }
+        Ok(coordinator)
+    }
+}
 
 impl Handler<FindCoordinatorRequest> for Broker {
     async fn handle(
         &self,
-        _: FindCoordinatorRequest,
+        req: FindCoordinatorRequest,
         mut res: FindCoordinatorResponse,
     ) -> anyhow::Result<FindCoordinatorResponse> {
-        let mut coordinator = Coordinator::default();
-        coordinator.node_id = messages::BrokerId(self.config.id.0);
-        coordinator.host = self.config.ip.to_string().to_str_bytes();
-        coordinator.port = self.config.port as i32;
-
-        res.coordinators.push(coordinator);
+        // before v4 a single key is looked up, and its coordinator is the whole response
+        if req.coordinator_keys.is_empty() {
+            let coordinator = self.find_coordinator(&req.key, req.key_type)?;
+            res.error_code = coordinator.error_code;
+            res.node_id = coordinator.node_id;
+            res.host = coordinator.host;
+            res.port = coordinator.port;
+            return Ok(res);
+        }
+        for key in &req.coordinator_keys {
+            let coordinator = self.find_coordinator(key, req.key_type)?;
+            res.coordinators.push(coordinator);
+        }
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use kafka_protocol::messages::{FindCoordinatorRequest, FindCoordinatorResponse};
+
+    use crate::broker::coordinator::{group_partition, OFFSETS_TOPIC};
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::Partition;
+    use crate::broker::BrokerId;
+    use crate::kafka::util::ToStrBytes;
+
+    #[tokio::test]
+    async fn follows_partition_leader() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.offsets_topic_num_partitions = 2;
+        apply_proposals(rx, &broker);
+        broker.ensure_offsets_topic().await?;
+
+        let mut req = FindCoordinatorRequest::default();
+        req.coordinator_keys = vec!["my-group".to_string().to_str_bytes()];
+        let res = broker
+            .handle(req.clone(), FindCoordinatorResponse::default())
+            .await?;
+        assert_eq!(res.coordinators[0].node_id.0, broker.config.id.0);
+        assert!(broker.is_coordinator("my-group")?);
+
+        // the partition moves to a broker that isn't known, so there's no one to send clients to
+        let idx = group_partition("my-group", 2);
+        let partition = broker.store.get_partition(OFFSETS_TOPIC, idx)?.unwrap();
+        let other = broker.config.id.0 + 1;
+        broker.store.create_partition(Partition {
+            leader: BrokerId(other),
+            assigned_replicas: vec![other],
+            isr: vec![other],
+            ..partition
+        })?;
+        broker.store.metadata_changed();
+        let res = broker
+            .handle(req, FindCoordinatorResponse::default())
+            .await?;
+        assert_ne!(res.coordinators[0].error_code, 0);
+        assert!(!broker.is_coordinator("my-group")?);
+        Ok(())
+    }
+}
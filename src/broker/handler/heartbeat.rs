@@ -0,0 +1,25 @@
+use crate::broker::handler::Handler;
+use crate::broker::Broker;
+use anyhow::Result;
+use kafka_protocol::messages::{HeartbeatRequest, HeartbeatResponse};
+use kafka_protocol::ResponseError::UnknownMemberId;
+
+impl Handler<HeartbeatRequest> for Broker {
+    async fn handle(
+        &self,
+        req: HeartbeatRequest,
+        mut res: HeartbeatResponse,
+    ) -> Result<HeartbeatResponse> {
+        res.error_code = match self.store.get_group(&req.group_id.0)? {
+            Some(group) => match group
+                .check_instance(&req.member_id, req.group_instance_id.as_deref())
+                .and_then(|()| group.heartbeat(&req.member_id, req.generation_id))
+            {
+                Ok(()) => 0,
+                Err(e) => e.code(),
+            },
+            None => UnknownMemberId.code(),
+        };
+        Ok(res)
+    }
+}
@@ -0,0 +1,38 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::txn::TxnOp;
+use crate::broker::Broker;
+use anyhow::Result;
+use kafka_protocol::messages::{InitProducerIdRequest, InitProducerIdResponse};
+
+impl Handler<InitProducerIdRequest> for Broker {
+    async fn handle(
+        &self,
+        req: InitProducerIdRequest,
+        mut res: InitProducerIdResponse,
+    ) -> Result<InitProducerIdResponse> {
+        let transactional_id = match &req.transactional_id {
+            Some(id) => id.to_string(),
+            None => {
+                res.producer_id = self.allocate_producer_id().await?.into();
+                res.producer_epoch = 0;
+                return Ok(res);
+            }
+        };
+
+        let op = TxnOp::Init {
+            timeout_ms: req.transaction_timeout_ms,
+        };
+        match self.update_txn(&transactional_id, op).await? {
+            Ok(txn) => {
+                res.producer_id = txn.producer_id.into();
+                res.producer_epoch = txn.producer_epoch;
+            }
+            Err(e) => {
+                res.producer_id = (-1).into();
+                res.producer_epoch = -1;
+                res.error_code = e.code();
+            }
+        }
+        Ok(res)
+    }
+}
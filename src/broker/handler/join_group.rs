@@ -0,0 +1,160 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::group::{GroupOp, Member};
+use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
+use anyhow::Result;
+use bytes::Bytes;
+use kafka_protocol::messages::join_group_response::JoinGroupResponseMember;
+use kafka_protocol::messages::{JoinGroupRequest, JoinGroupResponse};
+use kafka_protocol::ResponseError::InvalidSessionTimeout;
+use uuid::Uuid;
+
+impl Broker {
+    /// Whether the timeouts a member asked for are allowed. The session timeout must be within
+    /// the configured bounds, and a rebalance timeout, which requests before v1 don't have, must
+    /// be positive.
+    fn valid_timeouts(&self, req: &JoinGroupRequest) -> bool {
+        let min = self.config.group_min_session_timeout_ms;
+        let max = self.config.group_max_session_timeout_ms;
+        let session = u64::try_from(req.session_timeout_ms).is_ok_and(|t| (min..=max).contains(&t));
+        session && (req.rebalance_timeout_ms == -1 || req.rebalance_timeout_ms > 0)
+    }
+}
+
+impl Handler<JoinGroupRequest> for Broker {
+    async fn handle(
+        &self,
+        req: JoinGroupRequest,
+        mut res: JoinGroupResponse,
+    ) -> Result<JoinGroupResponse> {
+        if !self.valid_timeouts(&req) {
+            res.error_code = InvalidSessionTimeout.code();
+            res.member_id = req.member_id;
+            return Ok(res);
+        }
+        let member_id = if req.member_id.is_empty() {
+            Uuid::new_v4().to_string()
+        } else {
+            req.member_id.to_string()
+        };
+        let member = Member {
+            id: member_id.clone(),
+            instance_id: req.group_instance_id.as_ref().map(|i| i.to_string()),
+            protocol_type: req.protocol_type.to_string(),
+            protocols: req
+                .protocols
+                .iter()
+                .map(|(name, p)| (name.to_string(), p.metadata.to_vec()))
+                .collect(),
+            // requests before v1 wait on a rebalance for as long as the session lasts
+            rebalance_timeout_ms: match req.rebalance_timeout_ms {
+                -1 => req.session_timeout_ms,
+                timeout => timeout,
+            },
+            ..Default::default()
+        };
+
+        let group = match self
+            .update_group(&req.group_id.0, GroupOp::Join(member))
+            .await?
+        {
+            Ok(group) => group,
+            Err(e) => {
+                res.error_code = e.code();
+                return Ok(res);
+            }
+        };
+
+        res.generation_id = group.generation_id;
+        res.protocol_type = group.protocol_type.map(|p| p.to_str_bytes());
+        res.protocol_name = group.protocol.clone().map(|p| p.to_str_bytes());
+        res.leader = group.leader.clone().unwrap_or_default().to_str_bytes();
+        res.member_id = member_id.clone().to_str_bytes();
+
+        // only the leader gets the member metadata it needs to compute the assignment
+        if group.leader.as_ref() == Some(&member_id) {
+            res.members = group
+                .members
+                .values()
+                .map(|m| {
+                    let mut member = JoinGroupResponseMember::default();
+                    member.member_id = m.id.clone().to_str_bytes();
+                    member.metadata = m
+                        .protocols
+                        .iter()
+                        .find(|(name, _)| Some(name) == group.protocol.as_ref())
+                        .map(|(_, metadata)| Bytes::from(metadata.clone()))
+                        .unwrap_or_default();
+                    member
+                })
+                .collect();
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::handler::Handler;
+    use anyhow::Result;
+    use kafka_protocol::messages::join_group_request::JoinGroupRequestProtocol;
+    use kafka_protocol::messages::{GroupId, JoinGroupRequest, JoinGroupResponse};
+    use kafka_protocol::protocol::StrBytes;
+    use kafka_protocol::ResponseError::InvalidSessionTimeout;
+
+    fn join_request() -> JoinGroupRequest {
+        let mut req = JoinGroupRequest::default();
+        req.group_id = GroupId(StrBytes::from_str("group"));
+        req.protocol_type = StrBytes::from_str("consumer");
+        req.session_timeout_ms = 10000;
+        req.protocols.insert(
+            StrBytes::from_str("range"),
+            JoinGroupRequestProtocol::default(),
+        );
+        req
+    }
+
+    #[tokio::test]
+    async fn leader() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let res = broker
+            .handle(join_request(), JoinGroupResponse::default())
+            .await?;
+
+        assert_eq!(res.error_code, 0);
+        assert_eq!(res.generation_id, 1);
+        assert!(!res.member_id.is_empty());
+        assert_eq!(res.leader, res.member_id);
+        assert_eq!(res.members.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_timeout_bounds() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.group_min_session_timeout_ms = 6000;
+        broker.config.group_max_session_timeout_ms = 30000;
+        apply_proposals(rx, &broker);
+
+        let join = |session_timeout_ms, rebalance_timeout_ms| {
+            let mut req = join_request();
+            req.session_timeout_ms = session_timeout_ms;
+            req.rebalance_timeout_ms = rebalance_timeout_ms;
+            broker.handle(req, JoinGroupResponse::default())
+        };
+        let invalid = InvalidSessionTimeout.code();
+        assert_eq!(join(5999, 60000).await?.error_code, invalid);
+        assert_eq!(join(30001, 60000).await?.error_code, invalid);
+        assert_eq!(join(-1, 60000).await?.error_code, invalid);
+        assert_eq!(join(10000, 0).await?.error_code, invalid);
+        // nothing joined, so the first member to get in still leads
+        let res = join(6000, -1).await?;
+        assert_eq!(res.error_code, 0);
+        assert_eq!(res.leader, res.member_id);
+        assert_eq!(join(30000, 60000).await?.error_code, 0);
+        Ok(())
+    }
+}
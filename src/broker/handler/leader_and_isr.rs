@@ -1,6 +1,5 @@
-use crate::broker::BrokerId;
+use crate::broker::error::JosefineError;
 use crate::broker::handler::Handler;
-use crate::broker::replica::Replica;
 use crate::broker::Broker;
 use kafka_protocol::messages::{LeaderAndIsrRequest, LeaderAndIsrResponse};
 use crate::broker::state::partition::PartitionIdx;
@@ -16,10 +15,9 @@ impl Handler<LeaderAndIsrRequest> for Broker {
                 let partition = self
                     .store
                     .get_partition(&ps.topic_name, PartitionIdx(ps.partition_index))?
-                    .ok_or(anyhow::anyhow!("could not find partition"))?;
-                let pid = partition.id;
-                let replica = Replica::new(&self.config.data_dir, BrokerId(ps.leader.0), partition);
-                self.replicas.add(pid, replica);
+                    .ok_or(JosefineError::UnknownTopicOrPartition)?;
+                let replica = self.new_replica(&partition);
+                self.replicas.add(&partition, replica);
             }
         }
 
@@ -0,0 +1,65 @@
+use crate::broker::handler::Handler;
+use crate::broker::state::group::GroupOp;
+use crate::broker::Broker;
+use anyhow::Result;
+use kafka_protocol::messages::leave_group_response::MemberResponse;
+use kafka_protocol::messages::{LeaveGroupRequest, LeaveGroupResponse};
+
+impl Handler<LeaveGroupRequest> for Broker {
+    async fn handle(
+        &self,
+        req: LeaveGroupRequest,
+        mut res: LeaveGroupResponse,
+    ) -> Result<LeaveGroupResponse> {
+        // v0-2 send a single member id, later versions a batch of members
+        if !req.member_id.is_empty() {
+            let op = GroupOp::Leave {
+                member_id: req.member_id.to_string(),
+            };
+            if let Err(e) = self.update_group(&req.group_id.0, op).await? {
+                res.error_code = e.code();
+            }
+        }
+
+        for member in req.members {
+            let op = GroupOp::Leave {
+                member_id: member.member_id.to_string(),
+            };
+            let mut member_res = MemberResponse::default();
+            if let Err(e) = self.update_group(&req.group_id.0, op).await? {
+                member_res.error_code = e.code();
+            }
+            member_res.member_id = member.member_id;
+            member_res.group_instance_id = member.group_instance_id;
+            res.members.push(member_res);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::handler::Handler;
+    use anyhow::Result;
+    use kafka_protocol::messages::leave_group_request::MemberIdentity;
+    use kafka_protocol::messages::{GroupId, LeaveGroupRequest, LeaveGroupResponse};
+    use kafka_protocol::protocol::StrBytes;
+    use kafka_protocol::ResponseError::UnknownMemberId;
+
+    #[tokio::test]
+    async fn unknown_member() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let mut member = MemberIdentity::default();
+        member.member_id = StrBytes::from_str("consumer-1");
+        let mut req = LeaveGroupRequest::default();
+        req.group_id = GroupId(StrBytes::from_str("group"));
+        req.members = vec![member];
+        let res = broker.handle(req, LeaveGroupResponse::default()).await?;
+
+        assert_eq!(res.members[0].error_code, UnknownMemberId.code());
+        Ok(())
+    }
+}
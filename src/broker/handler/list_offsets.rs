@@ -0,0 +1,209 @@
+use anyhow::Result;
+use kafka_protocol::messages::list_offsets_request::ListOffsetsPartition;
+use kafka_protocol::messages::list_offsets_response::{
+    ListOffsetsPartitionResponse, ListOffsetsTopicResponse,
+};
+use kafka_protocol::messages::{ListOffsetsRequest, ListOffsetsResponse};
+use kafka_protocol::ResponseError::{NotLeaderOrFollower, UnknownTopicOrPartition};
+
+use crate::broker::fetcher::whole_batches;
+use crate::broker::handler::{check_leader_epoch, Handler, PartitionError};
+use crate::broker::log::LogStore;
+use crate::broker::replica::Replica;
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::Broker;
+
+/// The timestamp asking for the offset the next record will be readable at.
+const LATEST_TIMESTAMP: i64 = -1;
+/// The timestamp asking for the first offset still in the log.
+const EARLIEST_TIMESTAMP: i64 = -2;
+/// The isolation level of consumers that only read records of committed transactions.
+const READ_COMMITTED: i8 = 1;
+/// How much of the log is read at a time when looking for a timestamp.
+const SCAN_BYTES: u64 = 1024 * 1024;
+
+/// The offset of the first batch appended before `limit` whose largest timestamp is at least
+/// `timestamp`, along with that timestamp. Batches without a v2 header are skipped.
+fn offset_for_timestamp<L: LogStore>(
+    replica: &Replica<L>,
+    timestamp: i64,
+    limit: u64,
+) -> Result<Option<(u64, i64)>> {
+    let mut offset = replica.log.start_offset();
+    while offset < limit {
+        let records = replica.log.read(offset, limit, SCAN_BYTES)?;
+        let batches = whole_batches(&records);
+        if batches.is_empty() {
+            break;
+        }
+        for batch in batches {
+            if batch.len() >= 43 && batch[16] == 2 {
+                let max_timestamp = i64::from_be_bytes(batch[35..43].try_into().unwrap());
+                if max_timestamp >= timestamp {
+                    return Ok(Some((offset, max_timestamp)));
+                }
+            }
+            offset += 1;
+        }
+    }
+    Ok(None)
+}
+
+impl<L: LogStore> Broker<L> {
+    /// Looks up the offset a partition's timestamp refers to. Consumers can only see up to the
+    /// high watermark, or the last stable offset when reading committed records, while
+    /// followers see the whole log.
+    async fn list_offset(
+        &self,
+        topic: &str,
+        p: &ListOffsetsPartition,
+        req: &ListOffsetsRequest,
+    ) -> Result<Result<ListOffsetsPartitionResponse, PartitionError>> {
+        let partition = match self.store.get_partition(topic, PartitionIdx(p.partition_index))? {
+            Some(partition) => partition,
+            None => return Ok(Err(UnknownTopicOrPartition.into())),
+        };
+        if partition.leader != self.config.id {
+            return Ok(Err(self.not_leader(partition.leader)?));
+        }
+        if let Err(e) = check_leader_epoch(&partition, p.current_leader_epoch) {
+            return Ok(Err(e.into()));
+        }
+        let replica = match self.replicas.get(partition.id) {
+            Some(replica) => replica,
+            None => return Ok(Err(NotLeaderOrFollower.into())),
+        };
+        let replica = replica.lock().await;
+        let limit = match (req.replica_id.0 >= 0, req.isolation_level == READ_COMMITTED) {
+            (true, _) => replica.log.end_offset(),
+            (false, true) => replica.last_stable_offset(),
+            (false, false) => replica.high_watermark,
+        };
+
+        let mut res = ListOffsetsPartitionResponse::default();
+        res.partition_index = p.partition_index;
+        res.leader_epoch = partition.leader_epoch;
+        res.timestamp = -1;
+        res.offset = match p.timestamp {
+            LATEST_TIMESTAMP => limit as i64,
+            EARLIEST_TIMESTAMP => replica.log.start_offset() as i64,
+            timestamp => match offset_for_timestamp(&replica, timestamp, limit)? {
+                Some((offset, timestamp)) => {
+                    res.timestamp = timestamp;
+                    offset as i64
+                }
+                None => -1,
+            },
+        };
+        if res.offset >= 0 {
+            res.old_style_offsets = vec![res.offset];
+        }
+        Ok(Ok(res))
+    }
+}
+
+impl<L: LogStore> Handler<ListOffsetsRequest> for Broker<L> {
+    async fn handle(
+        &self,
+        req: ListOffsetsRequest,
+        mut res: ListOffsetsResponse,
+    ) -> Result<ListOffsetsResponse> {
+        for t in &req.topics {
+            let mut topic = ListOffsetsTopicResponse::default();
+            topic.name = t.name.clone();
+            for p in &t.partitions {
+                let partition = match self.list_offset(&t.name, p, &req).await? {
+                    Ok(partition) => partition,
+                    Err(e) => {
+                        let mut partition = ListOffsetsPartitionResponse::default();
+                        partition.partition_index = p.partition_index;
+                        partition.error_code = e.code();
+                        partition.timestamp = -1;
+                        partition.offset = -1;
+                        partition
+                    }
+                };
+                topic.partitions.push(partition);
+            }
+            res.topics.push(topic);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use kafka_protocol::messages::list_offsets_request::{ListOffsetsPartition, ListOffsetsTopic};
+    use kafka_protocol::messages::{ListOffsetsRequest, ListOffsetsResponse, TopicName};
+    use kafka_protocol::ResponseError::UnknownTopicOrPartition;
+    use std::io::Write;
+
+    use super::{EARLIEST_TIMESTAMP, LATEST_TIMESTAMP, READ_COMMITTED};
+    use crate::broker::handler::test::{
+        idempotent_batch, new_broker, new_topic, transactional_batch,
+    };
+    use crate::broker::handler::Handler;
+    use crate::broker::txn::marker_batch;
+    use crate::broker::Broker;
+    use crate::kafka::util::ToStrBytes;
+
+    /// Lists the offset of partition 0 of `topic` for `timestamp`, returning the error code and
+    /// offset.
+    async fn list(
+        broker: &Broker,
+        topic: &str,
+        timestamp: i64,
+        isolation_level: i8,
+    ) -> Result<(i16, i64)> {
+        let mut partition = ListOffsetsPartition::default();
+        partition.timestamp = timestamp;
+        let mut t = ListOffsetsTopic::default();
+        t.name = TopicName(topic.to_string().to_str_bytes());
+        t.partitions.push(partition);
+        let mut req = ListOffsetsRequest::default();
+        req.replica_id = (-1).into();
+        req.isolation_level = isolation_level;
+        req.topics.push(t);
+        let res = broker.handle(req, ListOffsetsResponse::default()).await?;
+        let partition = &res.topics[0].partitions[0];
+        Ok((partition.error_code, partition.offset))
+    }
+
+    #[tokio::test]
+    async fn open_transaction_pins_lso() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        let replica = broker.replicas.get(partition.id).unwrap();
+        let append = |batch: bytes::Bytes| {
+            let replica = replica.clone();
+            async move {
+                let mut replica = replica.lock().await;
+                let offset = replica.log.newest_offset();
+                replica.log.write_all(&batch)?;
+                replica.track_transactions(offset, &batch);
+                replica.high_watermark = replica.log.newest_offset();
+                Ok::<_, anyhow::Error>(())
+            }
+        };
+        append(idempotent_batch(-1, -1, 1)?.freeze()).await?;
+
+        // the transaction opened at offset 1 holds the LSO there, however much is appended after
+        append(transactional_batch(7, 0, 0, 1)?.freeze()).await?;
+        append(idempotent_batch(-1, -1, 1)?.freeze()).await?;
+        assert_eq!(list(&broker, "test", LATEST_TIMESTAMP, READ_COMMITTED).await?, (0, 1));
+        assert_eq!(list(&broker, "test", LATEST_TIMESTAMP, 0).await?, (0, 3));
+
+        // until it commits
+        append(marker_batch(7, 0, true)?).await?;
+        assert_eq!(list(&broker, "test", LATEST_TIMESTAMP, READ_COMMITTED).await?, (0, 4));
+        assert_eq!(list(&broker, "test", EARLIEST_TIMESTAMP, READ_COMMITTED).await?, (0, 0));
+
+        // the test batches are timestamped from 1000
+        assert_eq!(list(&broker, "test", 1000, 0).await?, (0, 0));
+        assert_eq!(list(&broker, "test", i64::MAX, 0).await?, (0, -1));
+        let (code, _) = list(&broker, "missing", LATEST_TIMESTAMP, 0).await?;
+        assert_eq!(code, UnknownTopicOrPartition.code());
+        Ok(())
+    }
+}
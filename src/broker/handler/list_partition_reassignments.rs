@@ -0,0 +1,143 @@
+use anyhow::Result;
+use kafka_protocol::messages::list_partition_reassignments_response::{
+    OngoingPartitionReassignment, OngoingTopicReassignment,
+};
+use kafka_protocol::messages::{
+    BrokerId, ListPartitionReassignmentsRequest, ListPartitionReassignmentsResponse, TopicName,
+};
+
+use crate::broker::handler::Handler;
+use crate::broker::state::partition::Partition;
+use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
+
+fn broker_ids<'a>(ids: impl Iterator<Item = &'a i32>) -> Vec<BrokerId> {
+    ids.map(|id| BrokerId(*id)).collect()
+}
+
+/// How far along the reassignment of a partition is. Every assigned replica is listed, so the
+/// target is the replicas less those being removed, while only the new replicas that have yet to
+/// join the ISR are listed as being added.
+fn progress(partition: &Partition) -> OngoingPartitionReassignment {
+    let catching_up = partition
+        .adding_replicas
+        .iter()
+        .filter(|id| !partition.isr.contains(id));
+    let mut res = OngoingPartitionReassignment::default();
+    res.partition_index = partition.idx.0;
+    res.replicas = broker_ids(partition.assigned_replicas.iter());
+    res.adding_replicas = broker_ids(catching_up);
+    res.removing_replicas = broker_ids(partition.removing_replicas.iter());
+    res
+}
+
+impl Handler<ListPartitionReassignmentsRequest> for Broker {
+    async fn handle(
+        &self,
+        req: ListPartitionReassignmentsRequest,
+        mut res: ListPartitionReassignmentsResponse,
+    ) -> Result<ListPartitionReassignmentsResponse> {
+        let metadata = self.metadata.get()?;
+        let mut partitions: Vec<&Partition> = metadata
+            .partitions
+            .values()
+            .filter(|p| p.is_reassigning())
+            .filter(|p| match &req.topics {
+                Some(topics) => topics
+                    .iter()
+                    .any(|t| **t.name == *p.topic && t.partition_indexes.contains(&p.idx.0)),
+                None => true,
+            })
+            .collect();
+        partitions.sort_by_key(|p| (&p.topic, p.idx));
+
+        for partition in partitions {
+            let name = TopicName(partition.topic.clone().to_str_bytes());
+            match res.topics.last_mut() {
+                Some(topic) if topic.name == name => topic.partitions.push(progress(partition)),
+                _ => {
+                    let mut topic = OngoingTopicReassignment::default();
+                    topic.name = name;
+                    topic.partitions.push(progress(partition));
+                    res.topics.push(topic);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use kafka_protocol::messages::{
+        BrokerId, ListPartitionReassignmentsRequest, ListPartitionReassignmentsResponse,
+    };
+
+    use crate::broker::handler::test::{apply_proposals, new_broker, new_topic};
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::Partition;
+
+    #[tokio::test]
+    async fn reports_until_caught_up() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+        let partitions = new_topic(&broker, "test", 2)?;
+        let list = || {
+            let req = ListPartitionReassignmentsRequest::default();
+            broker.handle(req, ListPartitionReassignmentsResponse::default())
+        };
+        assert!(list().await?.topics.is_empty());
+
+        // partition 1 moves from broker 1 to brokers 2 and 3
+        let moving = Partition {
+            assigned_replicas: vec![1, 2, 3],
+            adding_replicas: vec![2, 3],
+            removing_replicas: vec![1],
+            ..partitions[1].clone()
+        };
+        broker.store.create_partition(moving.clone())?;
+        broker.store.metadata_changed();
+        let res = list().await?;
+        assert_eq!(res.topics.len(), 1);
+        assert_eq!(&*res.topics[0].name, "test");
+        let partition = &res.topics[0].partitions[0];
+        assert_eq!(partition.partition_index, 1);
+        assert_eq!(
+            partition.replicas,
+            vec![BrokerId(1), BrokerId(2), BrokerId(3)]
+        );
+        assert_eq!(partition.adding_replicas, vec![BrokerId(2), BrokerId(3)]);
+        assert_eq!(partition.removing_replicas, vec![BrokerId(1)]);
+
+        // broker 2 catches up
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2],
+            ..moving.clone()
+        })?;
+        broker.store.metadata_changed();
+        let res = list().await?;
+        assert_eq!(
+            res.topics[0].partitions[0].adding_replicas,
+            vec![BrokerId(3)]
+        );
+
+        // then broker 3 does, which completes the reassignment
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2, 3],
+            ..moving
+        })?;
+        broker.store.metadata_changed();
+        assert_eq!(broker.complete_reassignments().await?, 1);
+        assert!(list().await?.topics.is_empty());
+        let partition = broker
+            .store
+            .get_partition("test", partitions[1].idx)?
+            .unwrap();
+        assert_eq!(partition.assigned_replicas, vec![2, 3]);
+        assert_eq!(partition.isr, vec![2, 3]);
+        assert_eq!(partition.leader.0, 2);
+        assert_eq!(partition.leader_epoch, partitions[1].leader_epoch + 1);
+        Ok(())
+    }
+}
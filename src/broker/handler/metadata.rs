@@ -1,12 +1,14 @@
+use kafka_protocol::messages::create_topics_request::CreatableTopic;
 use kafka_protocol::messages::metadata_request::MetadataRequestTopic;
 use kafka_protocol::messages::metadata_response::{
     MetadataResponseBroker, MetadataResponsePartition, MetadataResponseTopic,
 };
 use kafka_protocol::messages::{BrokerId, MetadataRequest, MetadataResponse, TopicName};
 use kafka_protocol::protocol::Builder;
-use kafka_protocol::protocol::StrBytes;
-use kafka_protocol::ResponseError::{TopicAlreadyExists, UnknownTopicOrPartition};
+use kafka_protocol::ResponseError::UnknownTopicOrPartition;
 
+use crate::broker::cache::Metadata;
+use crate::broker::error::JosefineError;
 use crate::broker::handler::Handler;
 use crate::broker::state::topic::Topic;
 use crate::broker::Broker;
@@ -19,23 +21,27 @@ impl Handler<MetadataRequest> for Broker {
         req: MetadataRequest,
         mut res: MetadataResponse,
     ) -> anyhow::Result<MetadataResponse> {
-        self.get_brokers().iter().for_each(|b| {
+        self.get_brokers()?.iter().for_each(|b| {
             res.brokers.insert(
                 BrokerId(b.id.0),
                 MetadataResponseBroker::builder()
-                    .host(b.ip.to_string().to_str_bytes())
-                    .port(b.port as i32)
+                    .host(b.advertised_host().to_str_bytes())
+                    .port(b.advertised_port() as i32)
+                    .rack(b.rack.clone().map(|r| r.to_str_bytes()))
                     .build()
                     .unwrap(),
             );
         });
 
-        res.controller_id = BrokerId(1);
-        res.cluster_id = Some(StrBytes::from_str("josefine"));
+        res.controller_id = BrokerId(self.controller_id().map_or(-1, |id| id.0));
+        res.cluster_id = self
+            .store
+            .get_cluster_id()?
+            .map(|id| id.to_string().to_str_bytes());
         res.throttle_time_ms = 1000;
 
         if let Some(topics) = req.topics {
-            self.get_topic_metadata(&mut res, topics)?;
+            self.get_topic_metadata(&mut res, topics).await?;
         } else {
             self.get_all_topic_metadata(&mut res)?;
         }
@@ -45,17 +51,27 @@ impl Handler<MetadataRequest> for Broker {
 }
 
 impl Broker {
-    fn get_topic_metadata(
+    async fn get_topic_metadata(
         &self,
         res: &mut MetadataResponse,
         topics: Vec<MetadataRequestTopic>,
     ) -> anyhow::Result<()> {
         for topic_req in topics.into_iter() {
-            let name = topic_req.name.unwrap();
-            let topic = self.store.get_topic(&name)?;
+            let name = topic_req.name.ok_or_else(|| {
+                JosefineError::InvalidRequest("topics can only be looked up by name".to_string())
+            })?;
+            let mut metadata = self.metadata.get()?;
 
-            if let Some(topic) = topic {
-                let t = self.build_topic_metadata(name.to_string(), &topic)?;
+            if !metadata.topics.contains_key(&**name) && self.config.auto_create_topics {
+                let mut t = CreatableTopic::default();
+                t.num_partitions = self.config.default_partitions;
+                t.replication_factor = self.config.default_replication_factor;
+                self.create_topic(&name, t).await?;
+                metadata = self.metadata.get()?;
+            }
+
+            if let Some(topic) = metadata.topics.get(&**name) {
+                let t = self.build_topic_metadata(&metadata, topic)?;
                 res.topics.insert(name, t);
             } else {
                 res.topics.insert(
@@ -71,10 +87,10 @@ impl Broker {
     }
 
     fn get_all_topic_metadata(&self, res: &mut MetadataResponse) -> anyhow::Result<()> {
-        let topics = self.store.get_topics()?;
-        for (name, topic) in topics.into_iter() {
-            let t = self.build_topic_metadata(name, &topic)?;
-            let s = topic.name.to_str_bytes();
+        let metadata = self.metadata.get()?;
+        for topic in metadata.topics.values() {
+            let t = self.build_topic_metadata(&metadata, topic)?;
+            let s = topic.name.clone().to_str_bytes();
             res.topics.insert(TopicName(s), t);
         }
         Ok(())
@@ -82,18 +98,19 @@ impl Broker {
 
     fn build_topic_metadata(
         &self,
-        name: String,
+        metadata: &Metadata,
         topic: &Topic,
     ) -> anyhow::Result<MetadataResponseTopic> {
         let t = MetadataResponseTopic::builder()
             .topic_id(topic.id)
+            .is_internal(topic.internal)
             .partitions(
                 topic
                     .partitions
                     .iter()
                     .map(|(k, _v)| {
                         let mut mp = MetadataResponsePartition::default();
-                        match self.store.get_partition(&topic.name, *k)? {
+                        match metadata.partition(&topic.name, *k).cloned() {
                             Some(p) => {
                                 // mp.leader_id messages:: = p.leader;
                                 mp.leader_id = p.leader.0.into();
@@ -101,7 +118,7 @@ impl Broker {
                                 mp.isr_nodes = p.isr.into_iter().map(BrokerId).collect();
                                 mp.replica_nodes =
                                     p.assigned_replicas.into_iter().map(BrokerId).collect();
-                                mp.leader_epoch = 3;
+                                mp.leader_epoch = p.leader_epoch;
                             }
                             None => {
                                 tracing::error!("could not fine partition");
@@ -120,12 +137,24 @@ impl Broker {
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
-    use kafka_protocol::messages::{MetadataRequest, MetadataResponse};
-    use kafka_protocol::protocol::Builder;
+    use kafka_protocol::messages::metadata_request::MetadataRequestTopic;
+    use kafka_protocol::messages::{BrokerId, MetadataRequest, MetadataResponse, TopicName};
+    use kafka_protocol::protocol::{Builder, StrBytes};
+    use kafka_protocol::ResponseError::UnknownTopicOrPartition;
 
-    use crate::broker::handler::test::new_broker;
+    use crate::broker::config::Peer;
+    use crate::broker::fsm::Transition;
+    use crate::broker::handler::test::{apply_proposals, new_broker};
     use crate::broker::handler::Handler;
 
+    fn topic_request(name: &'static str) -> MetadataRequest {
+        let mut topic = MetadataRequestTopic::default();
+        topic.name = Some(TopicName(StrBytes::from_str(name)));
+        let mut req = MetadataRequest::default();
+        req.topics = Some(vec![topic]);
+        req
+    }
+
     #[tokio::test]
     async fn execute() -> Result<()> {
         let (_rx, broker) = new_broker();
@@ -134,4 +163,109 @@ mod tests {
             .await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn unknown_topic() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let res = broker
+            .handle(topic_request("test"), MetadataResponse::default())
+            .await?;
+        let topic = &res.topics[&TopicName(StrBytes::from_str("test"))];
+        assert_eq!(topic.error_code, UnknownTopicOrPartition.code());
+        assert!(!broker.store.topic_exists("test")?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auto_create_topic() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.auto_create_topics = true;
+        broker.config.default_partitions = 2;
+        apply_proposals(rx, &broker);
+
+        let res = broker
+            .handle(topic_request("test"), MetadataResponse::default())
+            .await?;
+        let topic = &res.topics[&TopicName(StrBytes::from_str("test"))];
+        assert_eq!(topic.error_code, 0);
+        assert_eq!(topic.partitions.len(), 2);
+        assert!(broker.store.topic_exists("test")?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auto_create_within_partition_limits() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.auto_create_topics = true;
+        broker.config.default_partitions = 2;
+        broker.config.max_partitions_per_topic = Some(1);
+        apply_proposals(rx, &broker);
+
+        let res = broker
+            .handle(topic_request("test"), MetadataResponse::default())
+            .await?;
+        let topic = &res.topics[&TopicName(StrBytes::from_str("test"))];
+        assert_eq!(topic.error_code, UnknownTopicOrPartition.code());
+        assert!(!broker.store.topic_exists("test")?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn live_brokers() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let other = Peer {
+            id: crate::broker::BrokerId(2),
+            ip: broker.config.ip,
+            port: 8845,
+            rack: Some("b".to_string()),
+            advertised_host: None,
+            advertised_port: None,
+        };
+        for transition in [
+            Transition::RegisterBroker(broker.peer()),
+            Transition::RegisterBroker(other),
+        ] {
+            broker.client.propose(transition.serialize()?).await?;
+        }
+        let res = broker
+            .handle(MetadataRequest::default(), MetadataResponse::default())
+            .await?;
+        assert_eq!(res.brokers.len(), 2);
+        assert_eq!(res.brokers[&BrokerId(2)].port, 8845);
+        assert_eq!(res.brokers[&BrokerId(2)].rack.as_deref(), Some("b"));
+
+        let deregister = Transition::DeregisterBroker(crate::broker::BrokerId(2));
+        broker.client.propose(deregister.serialize()?).await?;
+        let res = broker
+            .handle(MetadataRequest::default(), MetadataResponse::default())
+            .await?;
+        assert_eq!(res.brokers.keys().collect::<Vec<_>>(), vec![&BrokerId(1)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn advertised_address() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.advertised_host = Some("broker-1.example.com".to_string());
+        broker.config.advertised_port = Some(19092);
+        apply_proposals(rx, &broker);
+
+        let res = broker
+            .handle(MetadataRequest::default(), MetadataResponse::default())
+            .await?;
+        let advertised = &res.brokers[&BrokerId(1)];
+        assert_eq!(&*advertised.host, "broker-1.example.com");
+        assert_eq!(advertised.port, 19092);
+
+        // which is also what other brokers advertise once this one registers
+        let register = Transition::RegisterBroker(broker.peer());
+        broker.client.propose(register.serialize()?).await?;
+        let res = broker
+            .handle(MetadataRequest::default(), MetadataResponse::default())
+            .await?;
+        assert_eq!(res.brokers[&BrokerId(1)].port, 19092);
+        Ok(())
+    }
 }
@@ -1,17 +1,42 @@
 use std::fmt::Debug;
 
 use kafka_protocol::protocol::Request;
+use kafka_protocol::ResponseError;
+use kafka_protocol::ResponseError::{
+    FencedLeaderEpoch, LeaderNotAvailable, NotLeaderOrFollower, UnknownLeaderEpoch,
+};
 
+use crate::broker::config::Peer;
+use crate::broker::log::LogStore;
+use crate::broker::state::partition::Partition;
+use crate::broker::{Broker, BrokerId};
 use anyhow::Result;
 
-mod api_versions;
+mod add_partitions_to_txn;
+mod alter_client_quotas;
+mod alter_replica_log_dirs;
+pub(crate) mod api_versions;
 mod create_topics;
+mod describe_client_quotas;
+mod describe_log_dirs;
+mod describe_producers;
+mod end_txn;
+mod fetch;
 mod find_coordinator;
+mod heartbeat;
+mod init_producer_id;
+mod join_group;
 mod leader_and_isr;
+mod leave_group;
 mod list_groups;
+mod list_offsets;
+mod list_partition_reassignments;
 mod metadata;
+mod offset_for_leader_epoch;
 mod produce;
-mod test;
+mod sync_group;
+#[cfg(test)]
+pub(crate) mod test;
 
 pub(crate) trait Handler<Req, Res = <Req as Request>::Response>: Debug
 where
@@ -32,3 +57,60 @@ where
         Res::default()
     }
 }
+
+/// The error for a single partition. When another broker leads the partition, it carries that
+/// broker's endpoint so that clients can redirect to it.
+#[derive(Debug, Clone)]
+pub(crate) struct PartitionError {
+    pub error: ResponseError,
+    pub leader: Option<Peer>,
+}
+
+impl PartitionError {
+    pub fn code(&self) -> i16 {
+        self.error.code()
+    }
+
+    pub fn message(&self) -> Option<String> {
+        self.leader
+            .as_ref()
+            .map(|l| {
+                let (host, port) = (l.advertised_host(), l.advertised_port());
+                format!("leader is broker {} at {}:{}", l.id, host, port)
+            })
+    }
+}
+
+impl From<ResponseError> for PartitionError {
+    fn from(error: ResponseError) -> Self {
+        Self {
+            error,
+            leader: None,
+        }
+    }
+}
+
+/// Checks the leader epoch a client expects a partition to be in, if it sent one. A client that
+/// is behind is fenced, while one that is ahead knows of a leader change we haven't seen yet.
+pub(crate) fn check_leader_epoch(partition: &Partition, epoch: i32) -> Result<(), ResponseError> {
+    match epoch {
+        e if e < 0 || e == partition.leader_epoch => Ok(()),
+        e if e < partition.leader_epoch => Err(FencedLeaderEpoch),
+        _ => Err(UnknownLeaderEpoch),
+    }
+}
+
+impl<L: LogStore> Broker<L> {
+    /// The error for a partition led by `leader` rather than us, or a generic one if we don't
+    /// know where the leader is.
+    pub(crate) fn not_leader(&self, leader: BrokerId) -> Result<PartitionError> {
+        let peer = self.get_brokers()?.into_iter().find(|b| b.id == leader);
+        Ok(match peer {
+            Some(peer) if leader != self.config.id => PartitionError {
+                error: NotLeaderOrFollower,
+                leader: Some(peer),
+            },
+            _ => LeaderNotAvailable.into(),
+        })
+    }
+}
@@ -0,0 +1,139 @@
+use crate::broker::handler::{check_leader_epoch, Handler, PartitionError};
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::Broker;
+use kafka_protocol::messages::offset_for_leader_epoch_request::OffsetForLeaderPartition;
+use kafka_protocol::messages::offset_for_leader_epoch_response::{
+    EpochEndOffset, OffsetForLeaderTopicResult,
+};
+use kafka_protocol::messages::{OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse};
+use kafka_protocol::ResponseError::{NotLeaderOrFollower, UnknownTopicOrPartition};
+
+impl Broker {
+    /// Where the requested epoch ended in a partition we lead, which is where a replica that
+    /// followed an older leader should truncate to before fetching again.
+    async fn epoch_end_offset(
+        &self,
+        topic: &str,
+        req: &OffsetForLeaderPartition,
+    ) -> anyhow::Result<Result<EpochEndOffset, PartitionError>> {
+        let p = match self.store.get_partition(topic, PartitionIdx(req.partition))? {
+            Some(p) => p,
+            None => return Ok(Err(UnknownTopicOrPartition.into())),
+        };
+        if p.leader != self.config.id {
+            return Ok(Err(self.not_leader(p.leader)?));
+        }
+        if let Err(e) = check_leader_epoch(&p, req.current_leader_epoch) {
+            return Ok(Err(e.into()));
+        }
+        let replica = match self.replicas.get(p.id) {
+            Some(replica) => replica,
+            None => return Ok(Err(NotLeaderOrFollower.into())),
+        };
+
+        let mut res = EpochEndOffset::default();
+        if let Some((epoch, end)) = replica.lock().await.end_offset_for(req.leader_epoch) {
+            res.leader_epoch = epoch;
+            res.end_offset = end as i64;
+        }
+        Ok(Ok(res))
+    }
+}
+
+impl Handler<OffsetForLeaderEpochRequest> for Broker {
+    async fn handle(
+        &self,
+        req: OffsetForLeaderEpochRequest,
+        mut res: OffsetForLeaderEpochResponse,
+    ) -> anyhow::Result<OffsetForLeaderEpochResponse> {
+        for (name, t) in req.topics {
+            let mut topic = OffsetForLeaderTopicResult::default();
+            for p in &t.partitions {
+                let mut partition = match self.epoch_end_offset(&name, p).await? {
+                    Ok(partition) => partition,
+                    Err(e) => {
+                        let mut partition = EpochEndOffset::default();
+                        partition.error_code = e.code();
+                        partition
+                    }
+                };
+                partition.partition = p.partition;
+                topic.partitions.push(partition);
+            }
+            res.topics.insert(name, topic);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic};
+    use crate::broker::handler::Handler;
+    use crate::broker::state::partition::Partition;
+    use crate::broker::Broker;
+    use crate::kafka::util::ToStrBytes;
+    use anyhow::Result;
+    use kafka_protocol::messages::offset_for_leader_epoch_request::{
+        OffsetForLeaderPartition, OffsetForLeaderTopic,
+    };
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse, ProduceRequest,
+        ProduceResponse, TopicName,
+    };
+    use kafka_protocol::ResponseError::FencedLeaderEpoch;
+
+    async fn produce(broker: &Broker) -> Result<()> {
+        let mut pd = PartitionProduceData::default();
+        pd.records = Some(idempotent_batch(-1, -1, 1)?.freeze());
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.topic_data
+            .insert(TopicName("test".to_string().to_str_bytes()), td);
+        broker.handle(req, ProduceResponse::default()).await?;
+        Ok(())
+    }
+
+    async fn end_offset(
+        broker: &Broker,
+        current_leader_epoch: i32,
+        leader_epoch: i32,
+    ) -> Result<(i16, i32, i64)> {
+        let mut partition = OffsetForLeaderPartition::default();
+        partition.current_leader_epoch = current_leader_epoch;
+        partition.leader_epoch = leader_epoch;
+        let mut topic = OffsetForLeaderTopic::default();
+        topic.partitions.push(partition);
+        let mut req = OffsetForLeaderEpochRequest::default();
+        let name = TopicName("test".to_string().to_str_bytes());
+        req.topics.insert(name.clone(), topic);
+
+        let res = broker
+            .handle(req, OffsetForLeaderEpochResponse::default())
+            .await?;
+        let p = &res.topics[&name].partitions[0];
+        Ok((p.error_code, p.leader_epoch, p.end_offset))
+    }
+
+    #[tokio::test]
+    async fn epoch_end_offsets() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partition = new_topic(&broker, "test", 1)?.remove(0);
+        produce(&broker).await?;
+        produce(&broker).await?;
+        broker.store.create_partition(Partition {
+            leader_epoch: 2,
+            ..partition
+        })?;
+        produce(&broker).await?;
+
+        assert_eq!(end_offset(&broker, -1, 0).await?, (0, 0, 2));
+        // nothing was appended in epoch 1, so it ends where epoch 0 did
+        assert_eq!(end_offset(&broker, 2, 1).await?, (0, 0, 2));
+        assert_eq!(end_offset(&broker, 2, 2).await?, (0, 2, 3));
+        assert_eq!(end_offset(&broker, 1, 2).await?.0, FencedLeaderEpoch.code());
+        Ok(())
+    }
+}
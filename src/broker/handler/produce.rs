@@ -1,36 +1,192 @@
-use std::io::Write;
+use std::time::Duration;
 
-use crate::broker::handler::Handler;
+use tokio::time::Instant;
+
+use crate::broker::fetcher::whole_batches;
+use crate::broker::handler::{Handler, PartitionError};
+use crate::broker::log::{is_control_batch, LogStore};
+use crate::broker::purgatory::DelayedOperation;
 use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
 
-use kafka_protocol::messages::ProduceRequest;
-use kafka_protocol::protocol::Request;
 use crate::broker::state::partition::PartitionIdx;
+use kafka_protocol::messages::produce_response::{PartitionProduceResponse, TopicProduceResponse};
+use kafka_protocol::messages::{ProduceRequest, TopicName};
+use kafka_protocol::protocol::Request;
+use kafka_protocol::ResponseError::{
+    InvalidRecord, KafkaStorageError, MessageTooLarge, NotEnoughReplicas, NotLeaderOrFollower,
+    RequestTimedOut, UnknownTopicOrPartition,
+};
+use uuid::Uuid;
+
+impl<L: LogStore> Broker<L> {
+    /// Appends records to a partition, returning the partition's id, the offset they were written
+    /// at and the end of the log after them, or the error to report for the partition.
+    async fn append(
+        &self,
+        topic: &str,
+        idx: i32,
+        acks: i16,
+        records: &[u8],
+    ) -> anyhow::Result<Result<(Uuid, i64, u64), PartitionError>> {
+        let p = match self.store.get_partition(topic, PartitionIdx(idx))? {
+            Some(p) => p,
+            None => return Ok(Err(UnknownTopicOrPartition.into())),
+        };
+        if p.leader != self.config.id {
+            return Ok(Err(self.not_leader(p.leader)?));
+        }
+        if acks == -1 && p.isr.len() < self.config.min_insync_replicas {
+            return Ok(Err(NotEnoughReplicas.into()));
+        }
+        let max_message_bytes = self
+            .metadata
+            .get()?
+            .topics
+            .get(topic)
+            .and_then(|t| t.max_message_bytes)
+            .unwrap_or(self.config.message_max_bytes);
+        let batches = whole_batches(records);
+        // only the transaction coordinator writes markers, straight to the log
+        if batches.iter().any(|b| is_control_batch(b)) {
+            return Ok(Err(InvalidRecord.into()));
+        }
+        if batches.iter().any(|b| b.len() > max_message_bytes as usize) {
+            return Ok(Err(MessageTooLarge.into()));
+        }
+        let replica = match self.replicas.get_partition(topic, p.idx) {
+            Some(replica) => replica,
+            None => return Ok(Err(NotLeaderOrFollower.into())),
+        };
+
+        // appends to a partition queue up on its lock, so batches land in the order they arrive
+        let mut replica = replica.lock().await;
+        if let Err(e) = replica.check_sequences(records) {
+            return Ok(Err(e.into()));
+        }
+        replica.start_epoch(p.leader_epoch);
+        let offset = replica.log.end_offset() as i64;
+        if let Err(e) = replica.log.append(records) {
+            tracing::error!(%e, topic, idx, "couldn't append to log");
+            return Ok(Err(KafkaStorageError.into()));
+        }
+        let end = replica.log.end_offset();
+        replica.track_producers(records);
+        replica.track_transactions(offset as u64, records);
+        if replica.update_high_watermark(p.leader, &p.isr) {
+            self.produce_purgatory.complete(&p.id);
+        }
+        self.fetch_purgatory.complete(&p.id);
+        Ok(Ok((p.id, offset, end)))
+    }
+
+    /// The time to throttle producers of a partition for, which is nonzero while one of its in
+    /// sync followers lags further behind than configured.
+    async fn produce_throttle_ms(&self, topic: &str, idx: i32) -> anyhow::Result<i32> {
+        let max_lag = match self.config.produce_throttle_lag_offsets {
+            Some(max_lag) => max_lag,
+            None => return Ok(0),
+        };
+        let p = match self.store.get_partition(topic, PartitionIdx(idx))? {
+            Some(p) => p,
+            None => return Ok(0),
+        };
+        let replica = match self.replicas.get_partition(topic, p.idx) {
+            Some(replica) => replica,
+            None => return Ok(0),
+        };
+        let lag = replica.lock().await.follower_lag(p.leader, &p.isr);
+        if lag <= max_lag {
+            return Ok(0);
+        }
+        tracing::debug!(topic, idx, lag, "throttling produce to lagging partition");
+        Ok(self.config.produce_throttle_ms as i32)
+    }
+}
+
+/// A produce with `acks=all` waiting for its appends to be replicated, which each is once the
+/// high watermark of its partition reaches the end of the append.
+struct DelayedProduce<'a, L> {
+    broker: &'a Broker<L>,
+    /// The topic and response slot of each append not yet replicated, along with the id of the
+    /// partition it went to and the end of the log after it.
+    pending: Vec<(&'a TopicName, usize, Uuid, u64)>,
+    /// The topic and response slot of each append that can't be replicated, along with the
+    /// error to report for it.
+    failed: Vec<(&'a TopicName, usize, PartitionError)>,
+}
 
-impl Handler<ProduceRequest> for Broker {
+impl<'a, L: LogStore> DelayedOperation for DelayedProduce<'a, L> {
+    type Output = Vec<(&'a TopicName, usize, PartitionError)>;
+
+    async fn try_complete(&mut self) -> anyhow::Result<Option<Self::Output>> {
+        let mut pending = vec![];
+        for (t, slot, id, end) in self.pending.drain(..) {
+            match self.broker.replicas.get(id) {
+                Some(replica) if replica.lock().await.high_watermark >= end => {}
+                Some(_) => pending.push((t, slot, id, end)),
+                None => self.failed.push((t, slot, NotLeaderOrFollower.into())),
+            }
+        }
+        self.pending = pending;
+        Ok(self.pending.is_empty().then(|| std::mem::take(&mut self.failed)))
+    }
+
+    async fn on_expiration(mut self) -> anyhow::Result<Self::Output> {
+        for (t, slot, _, _) in self.pending {
+            self.failed.push((t, slot, RequestTimedOut.into()));
+        }
+        Ok(self.failed)
+    }
+}
+
+impl<L: LogStore> Handler<ProduceRequest> for Broker<L> {
     async fn handle(
         &self,
         req: ProduceRequest,
-        res: <ProduceRequest as Request>::Response,
+        mut res: <ProduceRequest as Request>::Response,
     ) -> anyhow::Result<<ProduceRequest as Request>::Response> {
+        // with acks=0 or acks=1 the response goes out once the leader has appended, while with
+        // acks=all it waits for the appends to every partition to be replicated
+        let deadline = Instant::now() + Duration::from_millis(req.timeout_ms.max(0) as u64);
+        let mut pending = vec![];
         for (t, td) in req.topic_data.iter() {
-            let _topic = self.store.get_topic(t)?.expect("TODO: topic doesn't exist");
+            let mut topic_res = TopicProduceResponse::default();
             for pd in td.partition_data.iter() {
+                let mut partition_res = PartitionProduceResponse::default();
+                partition_res.index = pd.index;
+                partition_res.base_offset = -1;
                 if let Some(bytes) = &pd.records {
-                    let p = self
-                        .store
-                        .get_partition(t, PartitionIdx(pd.index))?
-                        .expect("TODO: partition doesn't exist");
-                    let replica = self
-                        .replicas
-                        .get(p.id)
-                        .expect("TODO: replica doesn't exist");
-                    let mut replica = replica.lock().expect("mutex poisoned");
-                    replica.log.write_all(&bytes[..])?;
+                    match self.append(t, pd.index, req.acks, &bytes[..]).await? {
+                        Ok((id, offset, end)) => {
+                            partition_res.base_offset = offset;
+                            if req.acks == -1 {
+                                let slot = topic_res.partition_responses.len();
+                                pending.push((t, slot, id, end));
+                            }
+                            let throttle_ms = self.produce_throttle_ms(t, pd.index).await?;
+                            res.throttle_time_ms = res.throttle_time_ms.max(throttle_ms);
+                        }
+                        Err(e) => {
+                            partition_res.error_code = e.code();
+                            partition_res.error_message = e.message().map(ToStrBytes::to_str_bytes);
+                        }
+                    }
                 }
+                topic_res.partition_responses.push(partition_res);
             }
+            res.responses.insert(t.clone(), topic_res);
         }
 
+        let keys: Vec<Uuid> = pending.iter().map(|(_, _, id, _)| *id).collect();
+        let produce = DelayedProduce {
+            broker: self,
+            pending,
+            failed: vec![],
+        };
+        for (t, slot, e) in self.produce_purgatory.watch(&keys, deadline, produce).await? {
+            res.responses[t].partition_responses[slot].error_code = e.code();
+        }
         Ok(res)
     }
 }
@@ -38,9 +194,33 @@ impl Handler<ProduceRequest> for Broker {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::broker::handler::test::new_broker;
+    use crate::broker::config::Peer;
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic};
+    use crate::broker::state::partition::Partition;
+    use crate::broker::state::topic::Topic;
+    use crate::broker::BrokerId;
     use anyhow::Result;
+    use bytes::Bytes;
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
     use kafka_protocol::messages::ProduceResponse;
+    use kafka_protocol::ResponseError::{
+        DuplicateSequenceNumber, LeaderNotAvailable, OutOfOrderSequenceNumber,
+        UnknownTopicOrPartition,
+    };
+    use crate::kafka::util::ToStrBytes;
+    use std::time::Duration;
+
+    fn produce_request(topic: &str, idx: i32, records: &'static [u8]) -> ProduceRequest {
+        let mut pd = PartitionProduceData::default();
+        pd.index = idx;
+        pd.records = Some(Bytes::from_static(records));
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.topic_data
+            .insert(TopicName(topic.to_string().to_str_bytes()), td);
+        req
+    }
 
     #[tokio::test]
     async fn execute() -> Result<()> {
@@ -50,4 +230,307 @@ mod tests {
             .await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn concurrent_partitions() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partitions = new_topic(&broker, "test", 2)?;
+
+        // hold the append lock of partition 0, as a slow append would
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let guard = replica.lock().await;
+
+        // partition 1 is not blocked by partition 0
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            broker.handle(produce_request("test", 1, b"one"), ProduceResponse::default()),
+        )
+        .await??;
+
+        // while another append to partition 0 has to wait its turn
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(100),
+            broker.handle(produce_request("test", 0, b"two"), ProduceResponse::default()),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        drop(guard);
+        broker
+            .handle(produce_request("test", 0, b"two"), ProduceResponse::default())
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partition_errors() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+
+        let mut req = produce_request("test", 0, b"one");
+        let mut unknown = PartitionProduceData::default();
+        unknown.index = 1;
+        unknown.records = Some(Bytes::from_static(b"two"));
+        req.topic_data[0].partition_data.push(unknown);
+        let res = broker.handle(req, ProduceResponse::default()).await?;
+
+        let partitions = &res.responses[0].partition_responses;
+        assert_eq!(partitions[0].error_code, 0);
+        assert_eq!(partitions[0].base_offset, 0);
+        assert_eq!(partitions[1].error_code, UnknownTopicOrPartition.code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn not_leader() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.peers.push(Peer {
+            id: BrokerId(2),
+            ip: "10.0.0.2".parse()?,
+            port: 8845,
+            rack: None,
+            advertised_host: None,
+            advertised_port: None,
+        });
+        let partitions = new_topic(&broker, "test", 2)?;
+        for (partition, leader) in partitions.into_iter().zip([2, 3]) {
+            broker.store.create_partition(Partition {
+                leader: BrokerId(leader),
+                leader_epoch: 0,
+                isr: vec![leader],
+                assigned_replicas: vec![1, leader],
+                ..partition
+            })?;
+        }
+
+        let mut req = produce_request("test", 0, b"one");
+        let mut pd = PartitionProduceData::default();
+        pd.index = 1;
+        pd.records = Some(Bytes::from_static(b"two"));
+        req.topic_data[0].partition_data.push(pd);
+        let res = broker.handle(req, ProduceResponse::default()).await?;
+
+        let partitions = &res.responses[0].partition_responses;
+        assert_eq!(partitions[0].error_code, NotLeaderOrFollower.code());
+        assert_eq!(
+            partitions[0].error_message.as_deref(),
+            Some("leader is broker 2 at 10.0.0.2:8845")
+        );
+        // broker 3 isn't known, so there's nowhere to redirect to
+        assert_eq!(partitions[1].error_code, LeaderNotAvailable.code());
+        assert_eq!(partitions[1].error_message, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn out_of_order_sequences() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+
+        let mut codes = vec![];
+        // the batch starting at 4 overtook the one starting at 2, which is then retried twice
+        for sequence in [0, 4, 2, 4, 2] {
+            let mut pd = PartitionProduceData::default();
+            pd.records = Some(idempotent_batch(7, sequence, 2)?.freeze());
+            let mut td = TopicProduceData::default();
+            td.partition_data.push(pd);
+            let mut req = ProduceRequest::default();
+            req.topic_data
+                .insert(TopicName("test".to_string().to_str_bytes()), td);
+            let res = broker.handle(req, ProduceResponse::default()).await?;
+            codes.push(res.responses[0].partition_responses[0].error_code);
+        }
+
+        assert_eq!(
+            codes,
+            vec![
+                0,
+                OutOfOrderSequenceNumber.code(),
+                0,
+                0,
+                DuplicateSequenceNumber.code()
+            ]
+        );
+        Ok(())
+    }
+
+    async fn produce_batch(broker: &Broker, topic: &str, batch: Bytes) -> Result<i16> {
+        let mut pd = PartitionProduceData::default();
+        pd.records = Some(batch);
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.topic_data
+            .insert(TopicName(topic.to_string().to_str_bytes()), td);
+        let res = broker.handle(req, ProduceResponse::default()).await?;
+        Ok(res.responses[0].partition_responses[0].error_code)
+    }
+
+    #[tokio::test]
+    async fn max_message_bytes() -> Result<()> {
+        let batch = idempotent_batch(-1, -1, 1)?.freeze();
+        let (_rx, mut broker) = new_broker();
+        broker.config.message_max_bytes = batch.len() as u32;
+        new_topic(&broker, "default", 1)?;
+        // the topic's own limit takes precedence, and new_topic keeps the existing topic
+        broker.store.create_topic(Topic {
+            name: "topic".to_string(),
+            partitions: [(PartitionIdx(0), vec![broker.config.id])].into(),
+            max_message_bytes: Some(batch.len() as u32 - 1),
+            ..Default::default()
+        })?;
+        new_topic(&broker, "topic", 1)?;
+
+        assert_eq!(produce_batch(&broker, "default", batch.clone()).await?, 0);
+        assert_eq!(
+            produce_batch(&broker, "topic", batch.clone()).await?,
+            MessageTooLarge.code()
+        );
+        let smaller = idempotent_batch(-1, -1, 0)?.freeze();
+        assert_eq!(produce_batch(&broker, "topic", smaller).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_markers() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        new_topic(&broker, "test", 1)?;
+        let marker = crate::broker::txn::marker_batch(7, 0, true)?;
+        assert_eq!(
+            produce_batch(&broker, "test", marker).await?,
+            InvalidRecord.code()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acks() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partitions = new_topic(&broker, "test", 1)?;
+        // the partition has a follower in its ISR that hasn't fetched yet
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            ..partitions[0].clone()
+        })?;
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let produce = |acks, timeout_ms| {
+            let mut req = produce_request("test", 0, b"one");
+            req.acks = acks;
+            req.timeout_ms = timeout_ms;
+            broker.handle(req, ProduceResponse::default())
+        };
+
+        // acks=0 and acks=1 respond as soon as the leader has appended
+        for (acks, offset) in [(0, 0), (1, 1)] {
+            let res = produce(acks, 1000).await?;
+            let partition = &res.responses[0].partition_responses[0];
+            assert_eq!((partition.error_code, partition.base_offset), (0, offset));
+            let replica = replica.lock().await;
+            assert_eq!(replica.log.newest_offset(), offset as u64 + 1);
+            assert_eq!(replica.high_watermark, 0);
+        }
+
+        // acks=all waits for the follower to replicate the batch
+        let all = produce(-1, 5000);
+        tokio::pin!(all);
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut all).await.is_err());
+        assert_eq!(replica.lock().await.log.newest_offset(), 3);
+        {
+            let mut replica = replica.lock().await;
+            replica.follower_offsets.insert(2, 3);
+            replica.update_high_watermark(BrokerId(1), &[1, 2]);
+        }
+        broker.produce_purgatory.complete(&partitions[0].id);
+        let res = tokio::time::timeout(Duration::from_secs(1), all).await??;
+        let partition = &res.responses[0].partition_responses[0];
+        assert_eq!((partition.error_code, partition.base_offset), (0, 2));
+
+        // and gives up once the request times out
+        let res = produce(-1, 50).await?;
+        let partition = &res.responses[0].partition_responses[0];
+        assert_eq!(partition.error_code, RequestTimedOut.code());
+        assert_eq!(partition.base_offset, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acks_all_waits_for_its_own_append() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partitions = new_topic(&broker, "test", 1)?;
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            ..partitions[0].clone()
+        })?;
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let produce = |records| {
+            let mut req = produce_request("test", 0, records);
+            req.acks = -1;
+            req.timeout_ms = 5000;
+            broker.handle(req, ProduceResponse::default())
+        };
+        let replicated = |offset| {
+            let replica = replica.clone();
+            async move {
+                let mut replica = replica.lock().await;
+                replica.follower_offsets.insert(2, offset);
+                replica.update_high_watermark(BrokerId(1), &[1, 2]);
+            }
+        };
+
+        let first = produce(b"one");
+        tokio::pin!(first);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut first).await.is_err());
+        let second = produce(b"two");
+        tokio::pin!(second);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut second).await.is_err());
+        assert_eq!(replica.lock().await.log.end_offset(), 2);
+
+        // the follower has only fetched the first append
+        replicated(1).await;
+        broker.produce_purgatory.complete(&partitions[0].id);
+        let res = tokio::time::timeout(Duration::from_secs(1), first).await??;
+        let partition = &res.responses[0].partition_responses[0];
+        assert_eq!((partition.error_code, partition.base_offset), (0, 0));
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut second).await.is_err());
+
+        replicated(2).await;
+        broker.produce_purgatory.complete(&partitions[0].id);
+        let res = tokio::time::timeout(Duration::from_secs(1), second).await??;
+        let partition = &res.responses[0].partition_responses[0];
+        assert_eq!((partition.error_code, partition.base_offset), (0, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn throttles_lagging_partition() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.produce_throttle_lag_offsets = Some(1);
+        broker.config.produce_throttle_ms = 250;
+        let partitions = new_topic(&broker, "test", 2)?;
+        // partition 0 has a follower in its ISR that never fetches
+        broker.store.create_partition(Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            ..partitions[0].clone()
+        })?;
+
+        let produce = |idx| {
+            let req = produce_request("test", idx, b"one");
+            broker.handle(req, ProduceResponse::default())
+        };
+        assert_eq!(produce(0).await?.throttle_time_ms, 0);
+        assert_eq!(produce(0).await?.throttle_time_ms, 250);
+        for _ in 0..2 {
+            assert_eq!(produce(1).await?.throttle_time_ms, 0);
+        }
+
+        // the follower catches up
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let caught_up = replica.lock().await.log.newest_offset();
+        replica.lock().await.follower_offsets.insert(2, caught_up);
+        assert_eq!(produce(0).await?.throttle_time_ms, 0);
+        Ok(())
+    }
 }
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use crate::broker::handler::Handler;
+use crate::broker::purgatory::DelayedOperation;
+use crate::broker::state::group::{Group, GroupError, GroupOp, GroupState};
+use crate::broker::Broker;
+use crate::kafka::util::ToStrBytes;
+use anyhow::Result;
+use bytes::Bytes;
+use kafka_protocol::messages::{SyncGroupRequest, SyncGroupResponse};
+use tokio::time::Instant;
+
+/// The sync of a member that isn't the leader, waiting for the leader's assignment.
+struct DelayedSync<'a> {
+    broker: &'a Broker,
+    group_id: &'a str,
+    member_id: &'a str,
+    generation_id: i32,
+}
+
+impl DelayedOperation for DelayedSync<'_> {
+    type Output = std::result::Result<Group, GroupError>;
+
+    async fn try_complete(&mut self) -> Result<Option<Self::Output>> {
+        let group = match self.broker.store.get_group(self.group_id)? {
+            Some(group) if group.members.contains_key(self.member_id) => group,
+            _ => return Ok(Some(Err(GroupError::UnknownMemberId))),
+        };
+        if group.generation_id != self.generation_id {
+            return Ok(Some(Err(GroupError::RebalanceInProgress)));
+        }
+        Ok(match group.state {
+            GroupState::CompletingRebalance => None,
+            GroupState::Stable => Some(Ok(group)),
+            _ => Some(Err(GroupError::RebalanceInProgress)),
+        })
+    }
+
+    async fn on_expiration(self) -> Result<Self::Output> {
+        Ok(Err(GroupError::RebalanceInProgress))
+    }
+}
+
+impl Handler<SyncGroupRequest> for Broker {
+    async fn handle(
+        &self,
+        req: SyncGroupRequest,
+        mut res: SyncGroupResponse,
+    ) -> Result<SyncGroupResponse> {
+        let member_id = req.member_id.to_string();
+        let op = GroupOp::Sync {
+            member_id: member_id.clone(),
+            instance_id: req.group_instance_id.as_ref().map(|i| i.to_string()),
+            generation_id: req.generation_id,
+            assignments: req
+                .assignments
+                .iter()
+                .map(|a| (a.member_id.to_string(), a.assignment.to_vec()))
+                .collect(),
+        };
+
+        let group = match self.update_group(&req.group_id.0, op).await? {
+            // the leader hasn't sent the assignment yet, so wait for it
+            Ok(group) if group.state == GroupState::CompletingRebalance => {
+                let timeout = group.members[&member_id].rebalance_timeout_ms;
+                let deadline = Instant::now() + Duration::from_millis(timeout.max(0) as u64);
+                let sync = DelayedSync {
+                    broker: self,
+                    group_id: &req.group_id.0,
+                    member_id: &member_id,
+                    generation_id: req.generation_id,
+                };
+                let keys = [req.group_id.0.to_string()];
+                self.sync_purgatory.watch(&keys, deadline, sync).await?
+            }
+            group => group,
+        };
+        match group {
+            Ok(group) => {
+                res.protocol_type = group.protocol_type.map(|p| p.to_str_bytes());
+                res.protocol_name = group.protocol.map(|p| p.to_str_bytes());
+                if let Some(member) = group.members.get(&member_id) {
+                    res.assignment = Bytes::from(member.assignment.clone());
+                }
+            }
+            Err(e) => res.error_code = e.code(),
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::handler::Handler;
+    use crate::broker::Broker;
+    use crate::kafka::util::ToStrBytes;
+    use anyhow::Result;
+    use bytes::Bytes;
+    use kafka_protocol::messages::join_group_request::JoinGroupRequestProtocol;
+    use kafka_protocol::messages::sync_group_request::SyncGroupRequestAssignment;
+    use kafka_protocol::messages::{
+        GroupId, JoinGroupRequest, JoinGroupResponse, SyncGroupRequest, SyncGroupResponse,
+    };
+    use kafka_protocol::protocol::StrBytes;
+    use kafka_protocol::ResponseError::RebalanceInProgress;
+
+    async fn join(broker: &Broker, member_id: &str) -> Result<JoinGroupResponse> {
+        let mut req = JoinGroupRequest::default();
+        req.group_id = GroupId(StrBytes::from_str("group"));
+        req.member_id = member_id.to_string().to_str_bytes();
+        req.protocol_type = StrBytes::from_str("consumer");
+        req.session_timeout_ms = 10000;
+        req.rebalance_timeout_ms = 100;
+        req.protocols.insert(
+            StrBytes::from_str("range"),
+            JoinGroupRequestProtocol::default(),
+        );
+        broker.handle(req, JoinGroupResponse::default()).await
+    }
+
+    fn sync_request(member_id: &StrBytes, generation_id: i32) -> SyncGroupRequest {
+        let mut req = SyncGroupRequest::default();
+        req.group_id = GroupId(StrBytes::from_str("group"));
+        req.member_id = member_id.clone();
+        req.generation_id = generation_id;
+        req
+    }
+
+    #[tokio::test]
+    async fn waits_for_leader_assignment() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        // the second member to join has the leader rejoin for the next generation
+        let leader = join(&broker, "").await?.member_id;
+        let follower = join(&broker, "").await?.member_id;
+        let leader = join(&broker, &leader).await?;
+        let follower = join(&broker, &follower).await?;
+        assert_eq!(leader.leader, leader.member_id);
+        assert_eq!(follower.generation_id, leader.generation_id);
+
+        let mut assignment = SyncGroupRequestAssignment::default();
+        assignment.member_id = follower.member_id.clone();
+        assignment.assignment = Bytes::from_static(b"assigned");
+        let mut leader_sync = sync_request(&leader.member_id, leader.generation_id);
+        leader_sync.assignments.push(assignment);
+
+        let (follower_res, leader_res) = tokio::join!(
+            broker.handle(
+                sync_request(&follower.member_id, follower.generation_id),
+                SyncGroupResponse::default()
+            ),
+            broker.handle(leader_sync, SyncGroupResponse::default())
+        );
+        assert_eq!(leader_res?.error_code, 0);
+        let follower_res = follower_res?;
+        assert_eq!(follower_res.error_code, 0);
+        assert_eq!(follower_res.assignment, Bytes::from_static(b"assigned"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expires_without_leader_assignment() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+
+        let leader = join(&broker, "").await?.member_id;
+        let follower = join(&broker, "").await?.member_id;
+        join(&broker, &leader).await?;
+        let follower = join(&broker, &follower).await?;
+
+        let res = broker
+            .handle(
+                sync_request(&follower.member_id, follower.generation_id),
+                SyncGroupResponse::default(),
+            )
+            .await?;
+        assert_eq!(res.error_code, RebalanceInProgress.code());
+        Ok(())
+    }
+}
@@ -1,26 +1,155 @@
+use crate::broker::state::partition::{Partition, PartitionIdx};
+use crate::broker::state::topic::Topic;
 use crate::broker::state::Store;
-use crate::broker::{Broker, Replicas};
-use crate::raft::client::RaftClient;
-use crate::raft::rpc::{Proposal, Response, ResponseError};
+use crate::broker::Broker;
+use crate::broker::log::LogStore;
+use crate::broker::replica::Replica;
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::broker::fsm::JosefineFsm;
+use crate::raft::client::{ProposalRequest, RaftClient};
+use crate::raft::fsm::Fsm;
+use crate::raft::rpc::{Response, ResponseError};
+use crate::raft::{Entry, EntryType, RaftRole, Status};
+use std::time::Duration;
 use tempfile::tempdir;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::oneshot::Sender;
-
-pub(crate) fn new_broker() -> (
-    UnboundedReceiver<(
-        Proposal,
-        Sender<std::result::Result<Response, ResponseError>>,
-    )>,
-    Broker,
-) {
-    let (client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
-    (
-        client_rx,
-        Broker {
-            store: Store::new(sled::open(tempdir().unwrap()).unwrap()),
-            client: RaftClient::new(client_tx),
-            config: Default::default(),
-            replicas: Replicas::new(),
-        },
+use tokio::sync::mpsc::Receiver;
+use bytes::BytesMut;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+
+pub(crate) fn new_broker() -> (Receiver<ProposalRequest>, Broker) {
+    new_broker_with_queue(1024)
+}
+
+/// Creates a broker whose raft proposal queue holds at most `size` proposals. The broker leads
+/// raft, and so is the controller.
+pub(crate) fn new_broker_with_queue(size: usize) -> (Receiver<ProposalRequest>, Broker) {
+    let (client_tx, client_rx) = tokio::sync::mpsc::channel(size);
+    let (_, status) = tokio::sync::watch::channel(Status {
+        id: 1,
+        term: 1,
+        role: RaftRole::Leader,
+        leader: Some(1),
+    });
+    let broker = Broker::new(
+        Store::new(sled::open(tempdir().unwrap()).unwrap()).unwrap(),
+        RaftClient::new(client_tx, Duration::from_millis(100)).with_status(status),
+        Default::default(),
     )
+    .unwrap();
+    (client_rx, broker)
+}
+
+/// Creates a topic with the given number of partitions, each led by this broker and with a
+/// local replica.
+pub(crate) fn new_topic(broker: &Broker, name: &str, partitions: i32) -> anyhow::Result<Vec<Partition>> {
+    new_topic_with(broker, name, partitions, |p| broker.new_replica(p))
+}
+
+/// Like [`new_topic`], but creates each partition's replica with `new_replica`.
+pub(crate) fn new_topic_with<L: LogStore>(
+    broker: &Broker<L>,
+    name: &str,
+    partitions: i32,
+    new_replica: impl Fn(&Partition) -> Replica<L>,
+) -> anyhow::Result<Vec<Partition>> {
+    let id = broker.config.id;
+    broker.store.create_topic(Topic {
+        id: Uuid::new_v4(),
+        name: name.to_string(),
+        partitions: (0..partitions).map(|i| (PartitionIdx(i), vec![id])).collect::<HashMap<_, _>>(),
+        internal: false,
+        compacted: false,
+        max_message_bytes: None,
+        delete_retention_ms: None,
+    })?;
+
+    (0..partitions)
+        .map(|i| {
+            let partition = broker.store.create_partition(Partition {
+                id: Uuid::new_v4(),
+                idx: PartitionIdx(i),
+                topic: name.to_string(),
+                isr: vec![id.0],
+                assigned_replicas: vec![id.0],
+                leader: id,
+                leader_epoch: 0,
+                adding_replicas: vec![],
+                removing_replicas: vec![],
+            })?;
+            let replica = new_replica(&partition);
+            broker.replicas.add(&partition, replica);
+            Ok(partition)
+        })
+        .collect()
+}
+
+/// Commits every proposal straight to the broker's store, standing in for a single node raft
+/// cluster.
+pub(crate) fn apply_proposals(mut rx: Receiver<ProposalRequest>, broker: &Broker) {
+    let mut fsm = JosefineFsm::new(broker.store.clone());
+    tokio::spawn(async move {
+        let mut index = 0;
+        while let Some((proposal, cb)) = rx.recv().await {
+            // proposals are applied as they arrive, so a read has nothing to wait for
+            if proposal.is_read() {
+                let _ = cb.send(Ok(Response::new(bincode::serialize(&index).unwrap())));
+                continue;
+            }
+            index += 1;
+            let entry = Entry {
+                entry_type: EntryType::Data {
+                    data: proposal.get(),
+                },
+                term: 1,
+                index,
+            };
+            let res = fsm.apply(&entry).map(Response::new).map_err(|e| ResponseError::new(e.to_string()));
+            let _ = cb.send(res);
+        }
+    });
+}
+
+/// A batch of `count` records from an idempotent producer, starting at `sequence`.
+pub(crate) fn idempotent_batch(producer_id: i64, sequence: i32, count: i32) -> anyhow::Result<BytesMut> {
+    producer_batch(producer_id, 3, sequence, count, false)
+}
+
+/// A batch of `count` records written by a transactional producer, starting at `sequence`.
+pub(crate) fn transactional_batch(producer_id: i64, producer_epoch: i16, sequence: i32, count: i32) -> anyhow::Result<BytesMut> {
+    producer_batch(producer_id, producer_epoch, sequence, count, true)
+}
+
+fn producer_batch(
+    producer_id: i64,
+    producer_epoch: i16,
+    sequence: i32,
+    count: i32,
+    transactional: bool,
+) -> anyhow::Result<BytesMut> {
+    let records: Vec<Record> = (0..count)
+        .map(|i| Record {
+            transactional,
+            control: false,
+            partition_leader_epoch: 0,
+            producer_id,
+            producer_epoch,
+            timestamp_type: TimestampType::Creation,
+            offset: i as i64,
+            sequence: sequence + i,
+            timestamp: 1000 + i as i64,
+            key: None,
+            value: None,
+            headers: Default::default(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(&mut buf, records.iter(), &options)?;
+    Ok(buf)
 }
@@ -0,0 +1,217 @@
+//! Shrinking and expanding the in sync replica sets of the partitions this broker leads. A
+//! follower is in sync as long as it has caught up with the leader's log within the last
+//! `replica_lag_time_max_ms`, which is the one threshold both removing and re-adding it go by.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time::Instant;
+
+use crate::broker::fsm::Transition;
+use crate::broker::log::LogStore;
+use crate::broker::replica::Replica;
+use crate::broker::state::partition::Partition;
+use crate::broker::Broker;
+use crate::Shutdown;
+
+impl Broker {
+    /// Removes the followers that have fallen too far behind from the ISRs of the partitions we
+    /// lead, and adds back those that have caught up, returning the number of partitions changed.
+    pub(crate) async fn update_isrs(&self) -> Result<usize> {
+        let max_lag = Duration::from_millis(self.config.replica_lag_time_max_ms);
+        let now = Instant::now();
+        let metadata = self.metadata.get()?;
+        let mut changed = vec![];
+        for partition in metadata.partitions.values() {
+            if partition.leader != self.config.id {
+                continue;
+            }
+            let replica = match self.replicas.get(partition.id) {
+                Some(replica) => replica,
+                None => continue,
+            };
+            let mut replica = replica.lock().await;
+            replica.lead(partition.leader_epoch, now);
+            if let Some(isr) = updated_isr(partition, &replica, max_lag, now) {
+                tracing::info!(
+                    topic = %partition.topic,
+                    idx = %partition.idx.0,
+                    from = ?partition.isr,
+                    to = ?isr,
+                    "updating isr"
+                );
+                changed.push(Transition::UpdateIsr {
+                    topic: partition.topic.clone(),
+                    idx: partition.idx,
+                    leader_epoch: partition.leader_epoch,
+                    from: partition.isr.clone(),
+                    isr,
+                });
+            }
+        }
+        let count = changed.len();
+        if count > 0 {
+            self.client
+                .propose(Transition::Batch(changed).serialize()?)
+                .await?;
+        }
+        Ok(count)
+    }
+}
+
+/// The ISR of a partition we lead once lagging followers are removed and caught up ones added,
+/// if that changes it. The leader is always in sync with itself.
+fn updated_isr<L: LogStore>(
+    partition: &Partition,
+    replica: &Replica<L>,
+    max_lag: Duration,
+    now: Instant,
+) -> Option<Vec<i32>> {
+    let in_sync = |id: &i32| *id == partition.leader.0 || !replica.is_lagging(*id, max_lag, now);
+    let mut isr: Vec<i32> = partition.isr.iter().copied().filter(in_sync).collect();
+    let joining = partition
+        .assigned_replicas
+        .iter()
+        .filter(|id| !isr.contains(id) && in_sync(id))
+        .copied()
+        .collect::<Vec<_>>();
+    isr.extend(joining);
+    match isr == partition.isr {
+        true => None,
+        false => Some(isr),
+    }
+}
+
+/// Periodically updates the ISRs of the partitions this broker leads until shutdown. They are
+/// checked twice per `replica_lag_time_max_ms`, so that a follower is removed at most half that
+/// again after it falls behind.
+pub(crate) async fn run(broker: Arc<Broker>, mut shutdown: Shutdown) -> Result<()> {
+    loop {
+        let period = Duration::from_millis(broker.config.replica_lag_time_max_ms / 2);
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            _ = tokio::time::sleep(period.max(Duration::from_millis(1))) => {
+                match broker.update_isrs().await {
+                    Ok(0) => {}
+                    Ok(changed) => tracing::debug!(changed, "updated isrs"),
+                    Err(e) => tracing::error!(%e, "could not update isrs"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::time::Instant;
+
+    use crate::broker::handler::test::{apply_proposals, new_broker, new_topic};
+    use crate::broker::state::partition::Partition;
+    use crate::broker::Broker;
+
+    #[tokio::test]
+    async fn lag_threshold() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.replica_lag_time_max_ms = 10_000;
+        apply_proposals(rx, &broker);
+        let partitions = new_topic(&broker, "test", 1)?;
+        let partition = Partition {
+            isr: vec![1, 2, 3],
+            assigned_replicas: vec![1, 2, 3, 4],
+            ..partitions[0].clone()
+        };
+        broker.store.create_partition(partition.clone())?;
+        broker.store.metadata_changed();
+
+        // 2 caught up just over the threshold ago, 3 and 4 just under it
+        let now = Instant::now();
+        let replica = broker.replicas.get(partition.id).unwrap();
+        {
+            let mut replica = replica.lock().await;
+            replica.lead(partition.leader_epoch, now - Duration::from_millis(20_000));
+            replica.update_follower(2, 0, now - Duration::from_millis(10_100));
+            replica.update_follower(3, 0, now - Duration::from_millis(9_900));
+            replica.update_follower(4, 0, now - Duration::from_millis(9_900));
+        }
+        assert_eq!(broker.update_isrs().await?, 1);
+        let isr = |broker: &Broker| -> Result<Vec<i32>> {
+            Ok(broker
+                .store
+                .get_partition("test", partition.idx)?
+                .unwrap()
+                .isr)
+        };
+        assert_eq!(isr(&broker)?, vec![1, 3, 4]);
+
+        // nothing changes until 2 catches up again
+        broker.store.metadata_changed();
+        assert_eq!(broker.update_isrs().await?, 0);
+        replica.lock().await.update_follower(2, 0, Instant::now());
+        assert_eq!(broker.update_isrs().await?, 1);
+        assert_eq!(isr(&broker)?, vec![1, 3, 4, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_isr_change() -> Result<()> {
+        let (rx, broker) = new_broker();
+        apply_proposals(rx, &broker);
+        let partitions = new_topic(&broker, "test", 1)?;
+        let partition = Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            leader_epoch: 1,
+            ..partitions[0].clone()
+        };
+        broker.store.create_partition(partition.clone())?;
+
+        // decided on an earlier epoch, or on an isr that has changed since
+        let update = |leader_epoch, from: &[i32]| {
+            broker
+                .store
+                .update_isr("test", partition.idx, leader_epoch, from, &[1])
+        };
+        assert!(!update(0, &[1, 2])?);
+        assert!(!update(1, &[1, 2, 3])?);
+        assert!(update(1, &[1, 2])?);
+        let isr = broker.store.get_partition("test", partition.idx)?.unwrap().isr;
+        assert_eq!(isr, vec![1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_leadership_resets_followers() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.replica_lag_time_max_ms = 10_000;
+        apply_proposals(rx, &broker);
+        let partitions = new_topic(&broker, "test", 1)?;
+        let partition = Partition {
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2],
+            ..partitions[0].clone()
+        };
+        broker.store.create_partition(partition.clone())?;
+        broker.store.metadata_changed();
+
+        // 2 fell behind while we led in an earlier epoch
+        let now = Instant::now();
+        let replica = broker.replicas.get(partition.id).unwrap();
+        {
+            let mut replica = replica.lock().await;
+            replica.lead(partition.leader_epoch - 1, now - Duration::from_millis(30_000));
+            replica.update_follower(2, 0, now - Duration::from_millis(20_000));
+        }
+
+        // leading again gives it the full lag time from now
+        assert_eq!(broker.update_isrs().await?, 0);
+        let replica = replica.lock().await;
+        assert_eq!(replica.leading_epoch, Some(partition.leader_epoch));
+        assert!(replica.follower_caught_up.is_empty());
+        Ok(())
+    }
+}
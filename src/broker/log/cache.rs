@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+
+/// The most recently read or appended record batches of a log, keyed by offset and bounded by
+/// their total size. The least recently used batches are evicted first.
+#[derive(Debug, Default)]
+pub struct BatchCache {
+    capacity: u64,
+    bytes: u64,
+    batches: HashMap<u64, (Bytes, u64)>,
+    /// Offsets by the tick they were last used at, oldest first.
+    used: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl BatchCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The most bytes of batches the cache holds.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn insert(&mut self, offset: u64, batch: Bytes) {
+        self.remove(offset);
+        let len = batch.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+        while self.bytes + len > self.capacity {
+            match self.used.pop_first() {
+                Some((_, oldest)) => self.remove(oldest),
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.used.insert(self.tick, offset);
+        self.batches.insert(offset, (batch, self.tick));
+        self.bytes += len;
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<Bytes> {
+        let (batch, used) = self.batches.get_mut(&offset)?;
+        self.tick += 1;
+        self.used.remove(used);
+        self.used.insert(self.tick, offset);
+        *used = self.tick;
+        Some(batch.clone())
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, offset: u64) -> bool {
+        self.batches.contains_key(&offset)
+    }
+
+    /// Forgets every batch at or after `offset`, as when the log is truncated.
+    pub fn remove_from(&mut self, offset: u64) {
+        let removed: Vec<u64> = self
+            .batches
+            .keys()
+            .copied()
+            .filter(|o| *o >= offset)
+            .collect();
+        for o in removed {
+            self.remove(o);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.batches.clear();
+        self.used.clear();
+        self.bytes = 0;
+    }
+
+    fn remove(&mut self, offset: u64) {
+        if let Some((batch, used)) = self.batches.remove(&offset) {
+            self.used.remove(&used);
+            self.bytes -= batch.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BatchCache;
+    use bytes::Bytes;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = BatchCache::new(10);
+        cache.insert(0, Bytes::from_static(b"aaaa"));
+        cache.insert(1, Bytes::from_static(b"bbbb"));
+        assert!(cache.get(0).is_some());
+
+        cache.insert(2, Bytes::from_static(b"cccc"));
+        assert!(cache.contains(0));
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2));
+
+        // never cached, as it could not fit
+        cache.insert(3, Bytes::from(vec![0; 11]));
+        assert!(!cache.contains(3));
+
+        cache.remove_from(2);
+        assert!(cache.contains(0));
+        assert!(!cache.contains(2));
+    }
+}
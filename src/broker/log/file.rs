@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+
+/// Where the batches of a segment are kept: a file on disk, or memory for logs that don't need
+/// to outlive the broker. Batches are only ever appended, or cut off at the end.
+pub trait SegmentFile: Read + Send + Sync {
+    /// The number of bytes in the segment.
+    fn len(&self) -> Result<u64, Error>;
+
+    /// Fills `buf` from `position`, failing if that runs past the end.
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> Result<(), Error>;
+
+    /// Writes `buf` at the end of the segment.
+    fn append(&mut self, buf: &[u8]) -> Result<(), Error>;
+
+    /// Cuts the segment down to `len` bytes, so that appends continue from there.
+    fn truncate(&mut self, len: u64) -> Result<(), Error>;
+
+    /// Makes sure everything appended survives a crash.
+    fn sync(&self) -> Result<(), Error>;
+}
+
+impl SegmentFile for File {
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> Result<(), Error> {
+        FileExt::read_exact_at(self, buf, position)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.write_all(buf)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.set_len(len)?;
+        // later writes continue from the new end
+        self.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.sync_data()
+    }
+}
+
+/// A segment kept in memory. Reads pick up from the end of the last append, as they do for a
+/// file.
+#[derive(Debug, Default)]
+pub struct MemoryFile {
+    bytes: Vec<u8>,
+    cursor: usize,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = (&self.bytes[self.cursor.min(self.bytes.len())..]).read(buf)?;
+        self.cursor += n;
+        Ok(n)
+    }
+}
+
+impl SegmentFile for MemoryFile {
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> Result<(), Error> {
+        let start = position as usize;
+        match self.bytes.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "read past the end of segment")),
+        }
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.bytes.extend_from_slice(buf);
+        self.cursor = self.bytes.len();
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.bytes.truncate(len as usize);
+        self.cursor = self.bytes.len();
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
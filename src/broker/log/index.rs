@@ -1,4 +1,4 @@
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 
 use std::io::Write;
 use std::path::PathBuf;
@@ -6,17 +6,24 @@ use std::path::PathBuf;
 use crate::broker::log::entry::Entry;
 use memmap::MmapMut;
 
-const MAX_BYTES_INDEX: u64 = 10 * 1024 * 1024;
+use crate::broker::log::DEFAULT_INDEX_BYTES;
+
+const ENTRY_BYTES: usize = 16;
 
 pub struct Index {
     base_offset: u64,
+    entries: usize,
+    /// The file the index is mapped from, or none for an index kept in memory.
+    file: Option<File>,
+    /// The size the index file is preallocated to, which bounds the number of entries.
+    max_bytes: u64,
     mmap: Box<MmapMut>,
 }
 
 impl Index {
     pub fn new(path: PathBuf, base_offset: u64) -> Index {
         let mut path = path;
-        path.push(format!("{}.index", base_offset));
+        path.push(Index::file_name(base_offset));
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -24,48 +31,120 @@ impl Index {
             .open(path)
             .expect("Couldn't create index file.");
 
-        file.set_len(MAX_BYTES_INDEX).unwrap();
+        file.set_len(DEFAULT_INDEX_BYTES).unwrap();
 
         Index {
             base_offset,
+            entries: 0,
             mmap: Box::new(unsafe { MmapMut::map_mut(&file).unwrap() }),
+            file: Some(file),
+            max_bytes: DEFAULT_INDEX_BYTES,
+        }
+    }
+
+    /// Creates an index that is only kept in memory.
+    pub fn in_memory(base_offset: u64) -> Index {
+        Index {
+            base_offset,
+            entries: 0,
+            mmap: Box::new(MmapMut::map_anon(DEFAULT_INDEX_BYTES as usize).unwrap()),
+            file: None,
+            max_bytes: DEFAULT_INDEX_BYTES,
         }
     }
 
+    /// Preallocates the index file to `max_bytes`, rounded down to whole entries, but never so
+    /// small that the entries already written, or at least one, no longer fit.
+    pub fn resize(&mut self, max_bytes: u64) {
+        let entry_bytes = ENTRY_BYTES as u64;
+        let entries = (max_bytes / entry_bytes).max(self.entries as u64).max(1);
+        let max_bytes = entries * entry_bytes;
+        match &self.file {
+            Some(file) => {
+                self.mmap.flush().unwrap();
+                file.set_len(max_bytes).expect("Couldn't resize index file.");
+                *self.mmap = unsafe { MmapMut::map_mut(file).unwrap() };
+            }
+            None => {
+                let mut mmap = MmapMut::map_anon(max_bytes as usize).unwrap();
+                let used = self.entries * ENTRY_BYTES;
+                mmap[..used].copy_from_slice(&self.mmap[..used]);
+                *self.mmap = mmap;
+            }
+        }
+        self.max_bytes = max_bytes;
+    }
+
+    /// Whether there is no room left for another entry.
+    pub fn is_full(&self) -> bool {
+        (self.entries + 1) * ENTRY_BYTES > self.max_bytes as usize
+    }
+
+    pub fn file_name(base_offset: u64) -> String {
+        format!("{}.index", base_offset)
+    }
+
+    /// The number of entries written.
+    pub fn count(&self) -> usize {
+        self.entries
+    }
+
     pub fn write_at(&mut self, bytes: &[u8], offset: u64) {
         (&mut self.mmap[offset as usize..])
             .write_all(bytes)
             .unwrap();
     }
 
+    /// Appends an entry. Entries must be written in increasing offset order.
     pub fn write_entry(&mut self, entry: Entry) {
         let mut e = entry;
         e.offset -= self.base_offset;
         let bytes: Vec<u8> = e.into();
-        self.write_at(bytes.as_ref(), e.offset);
+        self.write_at(bytes.as_ref(), (self.entries * ENTRY_BYTES) as u64);
+        self.entries += 1;
     }
 
-    #[allow(dead_code)]
-    pub fn read_entry(&self, offset: usize) -> Entry {
-        let bytes = &self.mmap[offset..offset + 16];
-        let mut entry = Entry::from(bytes);
+    /// Takes the first `count` entries already in the file as written, as when reopening it.
+    pub fn restore(&mut self, count: usize) {
+        self.entries = count;
+    }
+
+    /// Keeps only the first `count` entries.
+    pub fn truncate(&mut self, count: usize) {
+        self.entries = self.entries.min(count);
+    }
+
+    /// Reads the entry in the given slot.
+    pub fn read_entry(&self, slot: usize) -> Entry {
+        let start = slot * ENTRY_BYTES;
+        let mut entry = Entry::from(&self.mmap[start..start + ENTRY_BYTES]);
         entry.offset += self.base_offset;
         entry
     }
 
-    #[allow(dead_code)]
+    /// Finds the entry with the largest offset less than or equal to `offset`.
     pub fn find_entry(&self, offset: u64) -> Option<Entry> {
-        let idx = self
-            .mmap
-            .windows(16)
-            .position(|x| Entry::from(x).offset == offset)?;
-        let entry = Entry::from(&self.mmap[idx..idx + 16]);
-        Some(entry)
+        self.find_slot(offset).map(|slot| self.read_entry(slot))
+    }
+
+    /// Finds the slot of the entry with the largest offset less than or equal to `offset`.
+    pub fn find_slot(&self, offset: u64) -> Option<usize> {
+        let (mut lo, mut hi) = (0, self.entries);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.read_entry(mid).offset <= offset {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo.checked_sub(1)
     }
 
-    #[allow(dead_code)]
     pub fn sync(&self) {
-        self.mmap.flush().unwrap();
+        if self.file.is_some() {
+            self.mmap.flush().unwrap();
+        }
     }
 }
 
@@ -74,7 +153,7 @@ mod tests {
 
     use std::env;
     use std::fs;
-    use std::fs::OpenOptions;
+    use std::fs::{File, OpenOptions};
     use std::io::Read;
     use std::io::Seek;
     use std::io::SeekFrom;
@@ -110,6 +189,23 @@ mod tests {
         assert_eq!(entry, index.read_entry(0));
     }
 
+    #[test]
+    fn find_entry() {
+        before();
+
+        let mut path = env::temp_dir();
+        path.push("test");
+        let mut index = super::Index::new(path, 10);
+        index.write_entry(Entry::new(10, 0));
+        index.write_entry(Entry::new(14, 100));
+        index.write_entry(Entry::new(20, 250));
+
+        assert_eq!(index.find_entry(9), None);
+        assert_eq!(index.find_entry(10), Some(Entry::new(10, 0)));
+        assert_eq!(index.find_entry(17), Some(Entry::new(14, 100)));
+        assert_eq!(index.find_entry(25), Some(Entry::new(20, 250)));
+    }
+
     #[test]
     fn relative_offset() {
         before();
@@ -130,7 +226,7 @@ mod tests {
             .open(path)
             .unwrap();
 
-        file.seek(SeekFrom::Start(15)).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
         let mut bytes = [0u8; 16];
         file.read(&mut bytes).unwrap();
 
@@ -1,60 +1,611 @@
+use std::collections::HashMap;
 use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
+use bytes::{Bytes, BytesMut};
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions,
+};
+
+use cache::BatchCache;
 use segment::Segment;
 use std::fs;
 
+use crate::metrics::LOG_APPEND_LATENCY;
+
+mod cache;
 mod entry;
+mod file;
 mod index;
 mod reader;
 mod segment;
 
+/// The default size a segment grows to before a new one is started.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 1024 * 1024 * 1024;
+/// The default size segment index files are preallocated to.
+pub const DEFAULT_INDEX_BYTES: u64 = 10 * 1024 * 1024;
+/// The default number of bytes written between two index entries.
+pub const DEFAULT_INDEX_INTERVAL_BYTES: u64 = 4096;
+/// The default time an append can take before it is logged as slow.
+pub const DEFAULT_SLOW_APPEND: Duration = Duration::from_millis(500);
+
+/// Where a log keeps its segments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// In files in the log's directory.
+    #[default]
+    Disk,
+    /// In memory only, so the log is lost when the broker stops.
+    Memory,
+}
+
+/// Where a replica keeps its records. Batches are appended to the end of the store and each
+/// is assigned the next offset, so a store holds every offset from its start to its end.
+pub trait LogStore: Send + Sync + 'static {
+    /// Appends `records`, assigning them the end offset.
+    fn append(&mut self, records: &[u8]) -> Result<(), Error>;
+
+    /// Reads at most `max_bytes` of the batches from `offset` up to, but not including, `limit`.
+    /// At least one batch is returned if there is one, however large it is.
+    fn read(&self, offset: u64, limit: u64, max_bytes: u64) -> Result<Vec<u8>, Error>;
+
+    /// Removes the batch at `offset` and every batch after it.
+    fn truncate(&mut self, offset: u64) -> Result<(), Error>;
+
+    /// Makes every appended batch durable.
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// The offset of the first batch still in the store.
+    fn start_offset(&self) -> u64;
+
+    /// The offset the next append will be assigned.
+    fn end_offset(&self) -> u64;
+}
+
 pub struct Log {
+    /// The directory the log's segments are in. An in-memory log is only named after it.
     path: PathBuf,
+    storage: Storage,
     segments: Vec<Segment>,
     active_segment: usize,
+    segment_bytes: u64,
+    /// The size each segment's index is bounded to. A segment whose index is full is rolled,
+    /// however small it is.
+    index_bytes: u64,
+    index_interval_bytes: u64,
     rwlock: RwLock<u8>,
+    /// Recently read and appended batches, so that reads at the tail of the log are served from
+    /// memory.
+    cache: Mutex<BatchCache>,
+    slow_append: Duration,
+    /// When compaction first came across each tombstone still in the log, by the offset of its
+    /// batch and its key.
+    tombstones: HashMap<(u64, Bytes), SystemTime>,
+}
+
+/// A copy of a log being made in another directory. Segments are copied one at a time, so that
+/// the log only has to be held still for a segment at a time, until the copy is caught up and
+/// opened in place of the log with [`Log::finish_copy`].
+pub struct LogCopy {
+    path: PathBuf,
+    segments: Vec<Segment>,
+}
+
+impl LogCopy {
+    /// Starts a copy in `path`, clearing out anything left there by an earlier copy.
+    pub fn new(path: &Path) -> Result<LogCopy, Error> {
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+        fs::create_dir_all(path)?;
+        Ok(LogCopy {
+            path: path.to_owned(),
+            segments: Vec::new(),
+        })
+    }
+
+    /// Where the copy is being made.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 }
 
 impl Log {
     #[allow(dead_code)]
     pub fn new(path: &Path) -> Log {
-        fs::create_dir_all(&path).expect("Couldn't create log dir");
-        let segment = Segment::new(path.to_owned(), 0);
+        Log::with_segment_bytes(path, DEFAULT_SEGMENT_BYTES)
+    }
+
+    /// Creates a log that starts a new segment once the active one reaches `segment_bytes`.
+    pub fn with_segment_bytes(path: &Path, segment_bytes: u64) -> Log {
+        fs::create_dir_all(path).expect("Couldn't create log dir");
+        Log::create(Storage::Disk, path, segment_bytes)
+    }
+
+    /// Creates a log like [`Log::with_segment_bytes`] that is kept in memory, and never touches
+    /// `path`.
+    pub fn in_memory(path: &Path, segment_bytes: u64) -> Log {
+        Log::create(Storage::Memory, path, segment_bytes)
+    }
+
+    fn create(storage: Storage, path: &Path, segment_bytes: u64) -> Log {
+        let segment = Segment::create(storage, path.to_owned(), 0);
         let segments = vec![segment];
         Log {
             path: path.to_owned(),
+            storage,
             segments,
             active_segment: 0,
+            segment_bytes,
+            index_bytes: DEFAULT_INDEX_BYTES,
+            index_interval_bytes: DEFAULT_INDEX_INTERVAL_BYTES,
             rwlock: RwLock::new(255),
+            cache: Mutex::new(BatchCache::default()),
+            slow_append: DEFAULT_SLOW_APPEND,
+            tombstones: HashMap::new(),
+        }
+    }
+
+    /// Opens the log in `path`, recovering the segments an earlier run left there in offset order,
+    /// or creates an empty one if there are none.
+    pub fn open(path: &Path, segment_bytes: u64) -> Result<Log, Error> {
+        fs::create_dir_all(path)?;
+        let mut base_offsets = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            let base_offset = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|base_offset| base_offset.parse::<u64>().ok());
+            base_offsets.extend(base_offset);
+        }
+        if base_offsets.is_empty() {
+            return Ok(Log::with_segment_bytes(path, segment_bytes));
+        }
+        base_offsets.sort_unstable();
+
+        let mut segments = Vec::with_capacity(base_offsets.len());
+        for (i, base_offset) in base_offsets.iter().enumerate() {
+            let next_offset = base_offsets.get(i + 1).copied();
+            segments.push(Segment::open(path.to_owned(), *base_offset, next_offset)?);
         }
+        Ok(Log {
+            path: path.to_owned(),
+            storage: Storage::Disk,
+            active_segment: segments.len() - 1,
+            segments,
+            segment_bytes,
+            index_bytes: DEFAULT_INDEX_BYTES,
+            index_interval_bytes: DEFAULT_INDEX_INTERVAL_BYTES,
+            rwlock: RwLock::new(255),
+            cache: Mutex::new(BatchCache::default()),
+            slow_append: DEFAULT_SLOW_APPEND,
+            tombstones: HashMap::new(),
+        })
+    }
+
+    /// Bounds the index of the active segment and of those started from now on to `index_bytes`,
+    /// adding an entry at most every `index_interval_bytes`.
+    pub fn with_index(mut self, index_bytes: u64, index_interval_bytes: u64) -> Log {
+        self.index_bytes = index_bytes;
+        self.index_interval_bytes = index_interval_bytes;
+        let active = self.segments.remove(self.active_segment);
+        let active = active.with_index(index_bytes, index_interval_bytes);
+        self.segments.insert(self.active_segment, active);
+        self
+    }
+
+    /// Logs a warning for every append that takes at least `slow_append`.
+    pub fn with_slow_append(mut self, slow_append: Duration) -> Log {
+        self.slow_append = slow_append;
+        self
+    }
+
+    /// Caches up to `cache_bytes` of recently read and appended batches.
+    pub fn with_cache_bytes(mut self, cache_bytes: u64) -> Log {
+        self.cache = Mutex::new(BatchCache::new(cache_bytes));
+        self
+    }
+
+    /// The directory the log's segments are in.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    fn newest_offset(&self) -> u64 {
+    /// The number of bytes in the log's segments.
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(Segment::size).sum()
+    }
+
+    /// The offset the next write will be assigned.
+    pub fn newest_offset(&self) -> u64 {
         self.segments[self.active_segment].next_offset
     }
+
+    /// Returns the byte position, within its segment, of the batch containing `offset`, or of the
+    /// next batch if the offset is no longer in the log. Returns `None` if the offset has not
+    /// been written yet.
+    pub fn position_of(&self, offset: u64) -> Result<Option<u64>, Error> {
+        let _lock = self.rwlock.read().expect("Couldn't obtain read lock.");
+        if offset >= self.newest_offset() {
+            return Ok(None);
+        }
+
+        let segment = self.segment_for(offset);
+        let offset = offset.max(segment.base_offset);
+        Ok(Some(segment.position_of(offset)?))
+    }
+
+    /// Reads at most `max_bytes` of the batches starting at `offset`, stopping at the end of the
+    /// segment that contains it.
+    pub fn read_from(&self, offset: u64, max_bytes: u64) -> Result<Vec<u8>, Error> {
+        self.read_until(offset, self.newest_offset(), max_bytes)
+    }
+
+    /// Like [`Log::read_from`], but never reads the batch at `limit` or any after it.
+    pub fn read_until(&self, offset: u64, limit: u64, max_bytes: u64) -> Result<Vec<u8>, Error> {
+        if offset >= limit {
+            return Ok(vec![]);
+        }
+        if let Some(records) = self.read_cached(offset, limit, max_bytes) {
+            return Ok(records);
+        }
+        let records = self.read_disk(offset, limit, max_bytes)?;
+        if !self.segment_for(offset).is_dense() {
+            self.cache_batches(offset, &records);
+        }
+        Ok(records)
+    }
+
+    /// Reads the same bytes as [`Log::read_disk`] from the cache, if every batch they span is
+    /// cached. Compacted segments are always read from disk, as their offsets may have gaps.
+    fn read_cached(&self, offset: u64, limit: u64, max_bytes: u64) -> Option<Vec<u8>> {
+        let segment = self.segment_for(offset);
+        if segment.is_dense() || offset < segment.base_offset {
+            return None;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let mut records = Vec::new();
+        for offset in offset..limit.min(segment.next_offset) {
+            if records.len() as u64 >= max_bytes {
+                break;
+            }
+            records.extend_from_slice(&cache.get(offset)?);
+        }
+        records.truncate(max_bytes as usize);
+        Some(records)
+    }
+
+    /// Caches the whole batches in `records`, which were read starting at `offset`.
+    fn cache_batches(&self, offset: u64, records: &[u8]) {
+        let mut cache = self.cache.lock().unwrap();
+        let (mut position, mut offset) = (0, offset);
+        while let Some(header) = records.get(position..position + 12) {
+            let len = 12 + i32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+            match records.get(position..position + len) {
+                Some(batch) => cache.insert(offset, Bytes::copy_from_slice(batch)),
+                None => break,
+            }
+            position += len;
+            offset += 1;
+        }
+    }
+
+    fn read_disk(&self, offset: u64, limit: u64, max_bytes: u64) -> Result<Vec<u8>, Error> {
+        let position = match self.position_of(offset)? {
+            Some(position) => position,
+            None => return Ok(vec![]),
+        };
+        let segment = self.segment_for(offset);
+        // the limit only matters if it falls in the segment being read from
+        let max_bytes = match self.position_of(limit)? {
+            Some(end) if self.segment_for(limit).base_offset == segment.base_offset => {
+                max_bytes.min(end - position)
+            }
+            _ => max_bytes,
+        };
+
+        let _lock = self.rwlock.read().expect("Couldn't obtain read lock.");
+        segment.read_at(position, max_bytes)
+    }
+
+    /// Finds the latest record for each key, as the offset of its batch and its index within
+    /// the batch. Batches that can't be decoded are skipped, as are control batches, whose keys
+    /// only say which marker they hold.
+    pub fn compact_keys(&self) -> Result<HashMap<Bytes, (u64, usize)>, Error> {
+        let mut keys = HashMap::new();
+        for segment in &self.segments {
+            for (offset, batch) in segment.batches()? {
+                if is_control_batch(&batch) {
+                    continue;
+                }
+                let records = match decode_records(batch) {
+                    Ok(records) => records,
+                    Err(_) => continue,
+                };
+                for (i, record) in records.into_iter().enumerate() {
+                    if let Some(key) = record.key {
+                        keys.insert(key, (offset, i));
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Rewrites every segment but the active one to keep only the latest record for each key.
+    /// Tombstones are kept for `tombstone_retention` after the first compaction that could have
+    /// removed them, so that consumers have a chance to see the delete. Surviving records keep
+    /// their offsets. Returns the number of records removed.
+    pub fn compact(&mut self, tombstone_retention: Duration) -> Result<usize, Error> {
+        self.compact_at(tombstone_retention, SystemTime::now())
+    }
+
+    /// Like [`Log::compact`], with `now` as the current time.
+    pub fn compact_at(
+        &mut self,
+        tombstone_retention: Duration,
+        now: SystemTime,
+    ) -> Result<usize, Error> {
+        let keys = self.compact_keys()?;
+        // only the tombstones that are kept need remembering
+        let mut tombstones = HashMap::new();
+
+        let _lock = self.rwlock.write().expect("Couldn't obtain write lock.");
+        // compacted batches are rewritten, so none of the cached copies can be trusted
+        self.cache.lock().unwrap().clear();
+        let mut removed = 0;
+        for i in 0..self.segments.len() {
+            if i == self.active_segment {
+                continue;
+            }
+
+            let segment = &self.segments[i];
+            let mut batches = Vec::new();
+            let mut removed_from_segment = 0;
+            for (offset, batch) in segment.batches()? {
+                // transaction markers are kept as they are, for consumers to end transactions by
+                if is_control_batch(&batch) {
+                    batches.push((offset, batch));
+                    continue;
+                }
+                let records = match decode_records(batch.clone()) {
+                    Ok(records) => records,
+                    Err(_) => {
+                        batches.push((offset, batch));
+                        continue;
+                    }
+                };
+                let total = records.len();
+                let kept: Vec<Record> = records
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, record)| match &record.key {
+                        Some(key) if keys.get(key) != Some(&(offset, *i)) => false,
+                        Some(key) if record.value.is_none() => {
+                            let tombstone = (offset, key.clone());
+                            let seen = self.tombstones.get(&tombstone).copied().unwrap_or(now);
+                            let elapsed = now.duration_since(seen).unwrap_or_default();
+                            if elapsed >= tombstone_retention {
+                                return false;
+                            }
+                            tombstones.insert(tombstone, seen);
+                            true
+                        }
+                        Some(_) => true,
+                        None => true,
+                    })
+                    .map(|(_, record)| record)
+                    .collect();
+
+                removed_from_segment += total - kept.len();
+                if kept.len() == total {
+                    batches.push((offset, batch));
+                } else if !kept.is_empty() {
+                    batches.push((offset, encode_records(&kept)?));
+                }
+            }
+
+            if removed_from_segment > 0 {
+                self.segments[i] = Segment::rewrite(
+                    self.storage,
+                    self.path.clone(),
+                    self.path.join("cleaning"),
+                    segment.base_offset,
+                    segment.next_offset,
+                    batches,
+                )?;
+                removed += removed_from_segment;
+            }
+        }
+        self.tombstones = tombstones;
+        Ok(removed)
+    }
+
+    /// Removes the batch at `offset` and every batch after it.
+    pub fn truncate(&mut self, offset: u64) -> Result<(), Error> {
+        let _lock = self.rwlock.write().expect("Couldn't obtain write lock.");
+        if offset >= self.newest_offset() {
+            return Ok(());
+        }
+        self.cache.lock().unwrap().remove_from(offset);
+
+        while self.segments.len() > 1 && self.segments.last().unwrap().base_offset >= offset {
+            let segment = self.segments.pop().unwrap();
+            if self.storage == Storage::Memory {
+                continue;
+            }
+            fs::remove_file(self.path.join(Segment::log_name(segment.base_offset)))?;
+            fs::remove_file(self.path.join(index::Index::file_name(segment.base_offset)))?;
+        }
+        self.active_segment = self.segments.len() - 1;
+        let segment = &mut self.segments[self.active_segment];
+        segment.truncate(offset.max(segment.base_offset))
+    }
+
+    /// Copies the first segment that `copy` is missing, or has an outdated copy of, returning
+    /// whether there was one. The active segment is left for [`Log::finish_copy`], since it
+    /// keeps changing until the log is held still.
+    pub fn copy_segment(&self, copy: &mut LogCopy) -> Result<bool, Error> {
+        self.check_on_disk()?;
+        let _lock = self.rwlock.read().expect("Couldn't obtain read lock.");
+        self.copy_next(copy, self.active_segment)
+    }
+
+    /// Brings `copy` up to date, then moves it to `path` and opens it as a log with the same
+    /// settings as this one. The log itself is left as it was, so a failed copy loses nothing.
+    pub fn finish_copy(&self, mut copy: LogCopy, path: &Path) -> Result<Log, Error> {
+        self.check_on_disk()?;
+        let _lock = self.rwlock.read().expect("Couldn't obtain read lock.");
+        while self.copy_next(&mut copy, self.segments.len())? {}
+        // the open handles follow the files, so the segments stay usable once they're moved
+        fs::rename(&copy.path, path)?;
+        Ok(Log {
+            path: path.to_owned(),
+            storage: Storage::Disk,
+            segments: copy.segments,
+            active_segment: self.active_segment,
+            segment_bytes: self.segment_bytes,
+            index_bytes: self.index_bytes,
+            index_interval_bytes: self.index_interval_bytes,
+            rwlock: RwLock::new(255),
+            cache: Mutex::new(BatchCache::new(self.cache.lock().unwrap().capacity())),
+            slow_append: self.slow_append,
+            tombstones: self.tombstones.clone(),
+        })
+    }
+
+    /// Fails for a log kept in memory, which has no files to copy.
+    fn check_on_disk(&self) -> Result<(), Error> {
+        match self.storage {
+            Storage::Disk => Ok(()),
+            Storage::Memory => Err(Error::new(
+                ErrorKind::Unsupported,
+                "an in-memory log can't be copied",
+            )),
+        }
+    }
+
+    /// Copies the first of the first `count` segments that isn't copied as it is now.
+    fn copy_next(&self, copy: &mut LogCopy, count: usize) -> Result<bool, Error> {
+        // segments are only ever removed from the end of a log, by truncation
+        copy.segments.truncate(self.segments.len());
+        let stale = self.segments[..count]
+            .iter()
+            .enumerate()
+            .find(|(i, s)| copy.segments.get(*i).is_none_or(|c| !s.same_as(c)));
+        let (i, segment) = match stale {
+            Some(stale) => stale,
+            None => return Ok(false),
+        };
+        let copied = segment.copy_to(copy.path.clone())?;
+        match copy.segments.get_mut(i) {
+            Some(outdated) => *outdated = copied,
+            None => copy.segments.push(copied),
+        }
+        Ok(true)
+    }
+
+    fn segment_for(&self, offset: u64) -> &Segment {
+        self.segments
+            .iter()
+            .rev()
+            .find(|s| s.base_offset <= offset)
+            .unwrap_or(&self.segments[0])
+    }
+}
+
+/// Whether `batch` is a v2 control batch, which holds transaction markers rather than records.
+pub(crate) fn is_control_batch(batch: &[u8]) -> bool {
+    match batch.get(..23) {
+        Some(header) if header[16] == 2 => i16::from_be_bytes([header[21], header[22]]) & 0x20 != 0,
+        _ => false,
+    }
+}
+
+fn decode_records(batch: Vec<u8>) -> Result<Vec<Record>, Error> {
+    RecordBatchDecoder::decode(&mut Bytes::from(batch))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn encode_records(records: &[Record]) -> Result<Vec<u8>, Error> {
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(&mut buf, records.iter(), &options)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(buf.to_vec())
 }
 
 impl Write for Log {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         let _lock = self.rwlock.write().expect("Couldn't obtain write lock.");
 
-        if self.segments[self.active_segment].full() {
-            let segment = Segment::new(self.path.to_owned(), self.newest_offset());
+        if self.segments[self.active_segment].full(self.segment_bytes) {
+            let segment = Segment::create(self.storage, self.path.to_owned(), self.newest_offset())
+                .with_index(self.index_bytes, self.index_interval_bytes);
             self.active_segment = self.segments.len();
             self.segments.push(segment);
         }
 
+        let offset = self.newest_offset();
+        let start = Instant::now();
         self.segments[self.active_segment].write_all(buf)?;
+        let elapsed = start.elapsed();
+        LOG_APPEND_LATENCY.record(elapsed);
+        if elapsed >= self.slow_append {
+            let partition = self.path.file_name().unwrap_or_default().to_string_lossy();
+            tracing::warn!(%partition, offset, ?elapsed, "slow append");
+        }
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(offset, Bytes::copy_from_slice(buf));
         Result::Ok(buf.len())
     }
 
+    /// Syncs every segment to disk, including those rolled since the last flush.
     fn flush(&mut self) -> Result<(), Error> {
-        self.segments[self.active_segment].flush()
+        let _lock = self.rwlock.write().expect("Couldn't obtain write lock.");
+        for segment in &mut self.segments {
+            segment.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl LogStore for Log {
+    fn append(&mut self, records: &[u8]) -> Result<(), Error> {
+        self.write_all(records)
+    }
+
+    fn read(&self, offset: u64, limit: u64, max_bytes: u64) -> Result<Vec<u8>, Error> {
+        self.read_until(offset, limit, max_bytes)
+    }
+
+    fn truncate(&mut self, offset: u64) -> Result<(), Error> {
+        Log::truncate(self, offset)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Write::flush(self)
+    }
+
+    fn start_offset(&self) -> u64 {
+        self.segments[0].base_offset
+    }
+
+    fn end_offset(&self) -> u64 {
+        self.newest_offset()
     }
 }
 
@@ -71,6 +622,12 @@ mod tests {
     use std::fs::File;
     use std::io::Read;
     use std::io::Write;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use bytes::Bytes;
+    use kafka_protocol::records::{Record, TimestampType};
+
+    use crate::broker::txn::marker_batch;
 
     #[test]
     fn test_write() {
@@ -89,4 +646,233 @@ mod tests {
             .expect("Read contents into string.");
         assert_eq!(contents, "onetwothree");
     }
+
+    /// A record batch with the given total size, of which only the length header is filled in.
+    fn batch(size: usize) -> Vec<u8> {
+        let mut batch = vec![0u8; size];
+        batch[8..12].copy_from_slice(&((size - 12) as i32).to_be_bytes());
+        batch
+    }
+
+    #[test]
+    fn position_of() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = super::Log::new(dir.path());
+
+        // the first and last batches are indexed, the ones in between are only reachable by
+        // scanning forward from the first
+        log.write_all(&batch(1000)).unwrap();
+        log.write_all(&batch(2000)).unwrap();
+        log.write_all(&batch(1500)).unwrap();
+        log.write_all(&batch(100)).unwrap();
+
+        assert_eq!(log.position_of(0).unwrap(), Some(0));
+        assert_eq!(log.position_of(1).unwrap(), Some(1000));
+        assert_eq!(log.position_of(2).unwrap(), Some(3000));
+        assert_eq!(log.position_of(3).unwrap(), Some(4500));
+        assert_eq!(log.position_of(4).unwrap(), None);
+    }
+
+    #[test]
+    fn full_index_rolls() {
+        let dir = tempfile::tempdir().unwrap();
+        // room for two entries, with every batch indexed
+        let mut log = super::Log::new(dir.path()).with_index(32, 1);
+        for size in [100, 200, 300, 400, 500] {
+            log.write_all(&batch(size)).unwrap();
+        }
+
+        // far short of the segment size, the segments are rolled as their indexes fill up
+        let base_offsets: Vec<u64> = log.segments.iter().map(|s| s.base_offset).collect();
+        assert_eq!(base_offsets, vec![0, 2, 4]);
+        assert_eq!(std::fs::metadata(dir.path().join("2.index")).unwrap().len(), 32);
+        for (offset, size) in [100, 200, 300, 400, 500].into_iter().enumerate() {
+            let read = log.read_until(offset as u64, offset as u64 + 1, u64::MAX).unwrap();
+            assert_eq!(read, batch(size));
+        }
+    }
+
+    #[test]
+    fn read_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = super::Log::new(dir.path());
+        log.write_all(&batch(20)).unwrap();
+        log.write_all(&batch(30)).unwrap();
+
+        assert_eq!(log.read_from(0, 1024).unwrap().len(), 50);
+        assert_eq!(log.read_from(1, 1024).unwrap().len(), 30);
+        assert_eq!(log.read_from(0, 10).unwrap().len(), 10);
+        assert!(log.read_from(2, 1024).unwrap().is_empty());
+
+        // stops short of the limit
+        assert_eq!(log.read_until(0, 1, 1024).unwrap().len(), 20);
+        assert!(log.read_until(1, 1, 1024).unwrap().is_empty());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn slow_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let appends = crate::metrics::LOG_APPEND_LATENCY.count();
+        let mut log = super::Log::new(&dir.path().join("test-0"));
+        log.write_all(&batch(20)).unwrap();
+        assert!(!logs_contain("slow append"));
+        assert!(crate::metrics::LOG_APPEND_LATENCY.count() > appends);
+
+        // every append takes at least no time at all
+        let mut log = log.with_slow_append(Duration::ZERO);
+        log.write_all(&batch(20)).unwrap();
+        assert!(logs_contain("slow append"));
+        assert!(logs_contain("partition=test-0"));
+    }
+
+    #[test]
+    fn cached_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = super::Log::with_segment_bytes(dir.path(), 50).with_cache_bytes(1024);
+        for (i, size) in [20, 30, 40].into_iter().enumerate() {
+            let mut b = batch(size);
+            b[12] = i as u8;
+            log.write_all(&b).unwrap();
+        }
+        assert!((0..3).all(|o| log.cache.lock().unwrap().contains(o)));
+
+        for (offset, limit, max_bytes) in [(0, 3, 1024), (1, 3, 1024), (0, 1, 1024), (0, 3, 25)] {
+            let cached = log.read_cached(offset, limit, max_bytes).unwrap();
+            assert_eq!(cached, log.read_disk(offset, limit, max_bytes).unwrap());
+        }
+
+        log.truncate(1).unwrap();
+        assert_eq!(log.newest_offset(), 1);
+        assert!(log.cache.lock().unwrap().contains(0));
+        assert!(!log.cache.lock().unwrap().contains(1));
+        assert!(!log.cache.lock().unwrap().contains(2));
+        assert!(log.read_from(1, 1024).unwrap().is_empty());
+
+        // appends continue where the log was truncated
+        log.write_all(&batch(12)).unwrap();
+        assert_eq!(log.read_disk(0, 2, 1024).unwrap().len(), 32);
+        assert_eq!(log.read_until(0, 2, 1024).unwrap().len(), 32);
+    }
+
+    /// A record batch holding a record for each key and value, written at `timestamp`.
+    fn keyed_batch(records: &[(&'static str, Option<&'static str>)], timestamp: i64) -> Vec<u8> {
+        let records: Vec<Record> = records
+            .iter()
+            .enumerate()
+            .map(|(i, (key, value))| Record {
+                transactional: false,
+                control: false,
+                partition_leader_epoch: 0,
+                producer_id: -1,
+                producer_epoch: -1,
+                timestamp_type: TimestampType::Creation,
+                offset: i as i64,
+                sequence: -1,
+                timestamp,
+                key: Some(Bytes::from_static(key.as_bytes())),
+                value: value.map(|v| Bytes::from_static(v.as_bytes())),
+                headers: Default::default(),
+            })
+            .collect();
+        super::encode_records(&records).unwrap()
+    }
+
+    /// The keys and values of the batch at `offset`.
+    fn read_batch(log: &super::Log, offset: u64) -> Vec<(Bytes, Option<Bytes>)> {
+        let batch = log.read_until(offset, offset + 1, 1024).unwrap();
+        if batch.is_empty() {
+            return vec![];
+        }
+        super::decode_records(batch)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.key.unwrap(), r.value))
+            .collect()
+    }
+
+    #[test]
+    fn compact() {
+        let dir = tempfile::tempdir().unwrap();
+        // every batch gets a segment of its own
+        let mut log = super::Log::with_segment_bytes(dir.path(), 1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        let hour_ago = now - 60 * 60 * 1000;
+        log.write_all(&keyed_batch(&[("a", Some("1")), ("b", Some("1"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("a", Some("2")), ("c", Some("1"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("b", None)], hour_ago)).unwrap();
+        // only in the active segment, which is never compacted
+        log.write_all(&keyed_batch(&[("c", Some("2")), ("c", Some("3"))], now)).unwrap();
+
+        // the tombstone was written an hour ago, but its retention starts once it can be compacted
+        let start = SystemTime::now();
+        let retention = Duration::from_secs(60);
+        assert_eq!(log.compact_at(retention, start).unwrap(), 3);
+        let value = |v: &'static str| Some(Bytes::from_static(v.as_bytes()));
+        assert!(read_batch(&log, 0).is_empty());
+        assert_eq!(read_batch(&log, 1), vec![(Bytes::from_static(b"a"), value("2"))]);
+        assert_eq!(read_batch(&log, 2), vec![(Bytes::from_static(b"b"), None)]);
+        assert_eq!(read_batch(&log, 3).len(), 2);
+        assert_eq!(log.newest_offset(), 4);
+        assert_eq!(log.compact_at(retention, start + retention / 2).unwrap(), 0);
+        assert_eq!(read_batch(&log, 2), vec![(Bytes::from_static(b"b"), None)]);
+
+        // the tombstone goes once it's past the retention
+        assert_eq!(log.compact_at(retention, start + retention).unwrap(), 1);
+        assert!(read_batch(&log, 2).is_empty());
+        assert_eq!(read_batch(&log, 1), vec![(Bytes::from_static(b"a"), value("2"))]);
+        assert_eq!(log.compact_at(retention, start + retention).unwrap(), 0);
+        assert!(log.tombstones.is_empty());
+    }
+
+    #[test]
+    fn compact_keeps_markers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = super::Log::with_segment_bytes(dir.path(), 1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        // both markers have the same key, which must not make one supersede the other
+        let first = marker_batch(7, 0, false).unwrap();
+        let second = marker_batch(8, 0, false).unwrap();
+        log.write_all(&first).unwrap();
+        log.write_all(&second).unwrap();
+        log.write_all(&keyed_batch(&[("a", Some("1"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("a", Some("2"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("b", Some("1"))], now)).unwrap();
+
+        assert_eq!(log.compact(Duration::from_secs(60)).unwrap(), 1);
+        assert_eq!(log.read_until(0, 1, u64::MAX).unwrap(), &first[..]);
+        assert_eq!(log.read_until(1, 2, u64::MAX).unwrap(), &second[..]);
+        assert!(super::is_control_batch(&log.read_until(1, 2, u64::MAX).unwrap()));
+        assert!(!super::is_control_batch(&log.read_until(3, 4, u64::MAX).unwrap()));
+    }
+
+    #[test]
+    fn open() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        let mut log = super::Log::with_segment_bytes(dir.path(), 1);
+        log.write_all(&keyed_batch(&[("a", Some("1"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("a", Some("2"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("b", Some("1"))], now)).unwrap();
+        log.write_all(&keyed_batch(&[("c", Some("1"))], now)).unwrap();
+        assert_eq!(log.compact(Duration::from_secs(60)).unwrap(), 1);
+        // a crash in the middle of an append leaves part of a batch behind
+        let torn = keyed_batch(&[("d", Some("1"))], now);
+        log.segments[log.active_segment].write_all(&torn[..20]).unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        let mut log = super::Log::open(dir.path(), 1).unwrap();
+        assert_eq!(log.newest_offset(), 4);
+        assert!(read_batch(&log, 0).is_empty());
+        assert_eq!(read_batch(&log, 1).len(), 1);
+        assert_eq!(read_batch(&log, 3)[0].0, Bytes::from_static(b"c"));
+        assert!(log.segments[0].is_dense());
+        assert!(!log.segments[log.active_segment].is_dense());
+
+        log.write_all(&torn).unwrap();
+        assert_eq!(read_batch(&log, 4)[0].0, Bytes::from_static(b"d"));
+        let empty = tempfile::tempdir().unwrap();
+        assert_eq!(super::Log::open(empty.path(), 1).unwrap().newest_offset(), 0);
+    }
 }
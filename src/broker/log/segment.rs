@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::fs;
 use std::fs::OpenOptions;
 use std::io::Error;
 use std::io::Read;
@@ -6,15 +6,24 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use crate::broker::log::entry::Entry;
+use crate::broker::log::file::{MemoryFile, SegmentFile};
 use crate::broker::log::index::Index;
+use crate::broker::log::{Storage, DEFAULT_INDEX_INTERVAL_BYTES};
 
-const MAX_SEGMENT_BYES: u64 = 1024 * 1024 * 1024;
+/// Size of the base offset and batch length fields that start every record batch.
+const BATCH_HEADER_BYTES: u64 = 12;
 
 pub struct Segment {
-    // base_offset: u64,
+    pub base_offset: u64,
     pub next_offset: u64,
     bytes: u64,
-    log: File,
+    last_indexed: Option<u64>,
+    /// Minimum number of bytes written between two index entries.
+    index_interval_bytes: u64,
+    /// Whether every batch has an index entry. Compacted segments are dense, since batches may
+    /// be missing and their offsets can't be worked out by counting.
+    dense: bool,
+    log: Box<dyn SegmentFile>,
     index: Index,
 }
 
@@ -29,40 +38,320 @@ impl Segment {
             .create(true)
             .open(path)
             .expect("Couldn't create segment file.");
+        Segment::with_file(base_offset, Box::new(log), index)
+    }
+
+    /// Creates a segment that is only kept in memory.
+    pub fn in_memory(base_offset: u64) -> Segment {
+        let index = Index::in_memory(base_offset);
+        Segment::with_file(base_offset, Box::<MemoryFile>::default(), index)
+    }
 
+    /// Creates a segment in `path`, or in memory if that is where the log is stored.
+    pub fn create(storage: Storage, path: PathBuf, base_offset: u64) -> Segment {
+        match storage {
+            Storage::Disk => Segment::new(path, base_offset),
+            Storage::Memory => Segment::in_memory(base_offset),
+        }
+    }
+
+    fn with_file(base_offset: u64, log: Box<dyn SegmentFile>, index: Index) -> Segment {
         Segment {
-            // base_offset,
-            next_offset: 0,
+            base_offset,
+            next_offset: base_offset,
             bytes: 0,
+            last_indexed: None,
+            index_interval_bytes: DEFAULT_INDEX_INTERVAL_BYTES,
+            dense: false,
             log,
             index,
         }
     }
 
-    pub fn full(&self) -> bool {
-        self.bytes >= MAX_SEGMENT_BYES
+    /// Opens a segment left in `path` by an earlier run, finding where its batches end by scanning
+    /// their headers. A batch cut short by a crash is truncated away. `next_offset` is where the
+    /// following segment starts, if there is one; a segment holding fewer batches than that was
+    /// compacted, and keeps the index it was written with rather than having it rebuilt.
+    pub fn open(
+        path: PathBuf,
+        base_offset: u64,
+        next_offset: Option<u64>,
+    ) -> Result<Segment, Error> {
+        let log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.join(Segment::log_name(base_offset)))?;
+        let len = SegmentFile::len(&log)?;
+        let index = Index::new(path.clone(), base_offset);
+        let mut segment = Segment::with_file(base_offset, Box::new(log), index);
+        let mut positions = Vec::new();
+        let mut position = 0;
+        while position + BATCH_HEADER_BYTES <= len {
+            let mut length = [0u8; 4];
+            segment.log.read_exact_at(&mut length, position + 8)?;
+            let length = i32::from_be_bytes(length);
+            let end = position + BATCH_HEADER_BYTES + length as u64;
+            if length < 0 || end > len {
+                break;
+            }
+            positions.push(position);
+            position = end;
+        }
+        if position < len {
+            tracing::warn!(
+                ?path,
+                base_offset,
+                bytes = len - position,
+                "truncating torn batch"
+            );
+        }
+        // appends continue from the end of the last whole batch
+        segment.log.truncate(position)?;
+        segment.bytes = position;
+
+        let count = positions.len() as u64;
+        match next_offset {
+            Some(next_offset) if base_offset + count != next_offset => {
+                segment.index.restore(positions.len());
+                let indexed = positions
+                    .iter()
+                    .enumerate()
+                    .all(|(slot, position)| segment.index.read_entry(slot).position == *position);
+                if !indexed {
+                    return Err(Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "index of compacted segment {} doesn't match its log",
+                            base_offset
+                        ),
+                    ));
+                }
+                segment.dense = true;
+                segment.next_offset = next_offset;
+            }
+            _ => {
+                for (i, position) in positions.into_iter().enumerate() {
+                    segment.index_batch(base_offset + i as u64, position);
+                }
+                segment.next_offset = base_offset + count;
+            }
+        }
+        Ok(segment)
+    }
+
+    /// Bounds the segment's index to `index_bytes`, adding an entry at most every
+    /// `index_interval_bytes`.
+    pub fn with_index(mut self, index_bytes: u64, index_interval_bytes: u64) -> Segment {
+        self.index.resize(index_bytes);
+        self.index_interval_bytes = index_interval_bytes;
+        self
+    }
+
+    /// Builds a compacted copy of a segment in `tmp`, holding only `batches` at their original
+    /// offsets, then moves it into `path` over the segment it replaces. In memory the copy simply
+    /// takes the place of the segment.
+    pub fn rewrite(
+        storage: Storage,
+        path: PathBuf,
+        tmp: PathBuf,
+        base_offset: u64,
+        next_offset: u64,
+        batches: Vec<(u64, Vec<u8>)>,
+    ) -> Result<Segment, Error> {
+        if storage == Storage::Disk {
+            fs::create_dir_all(&tmp)?;
+        }
+        let mut segment = Segment::create(storage, tmp.clone(), base_offset);
+        segment.dense = true;
+        for (offset, batch) in batches {
+            segment.index.write_entry(Entry::new(offset, segment.bytes));
+            segment.log.append(&batch)?;
+            segment.bytes += batch.len() as u64;
+        }
+        segment.next_offset = next_offset;
+        if storage == Storage::Memory {
+            return Ok(segment);
+        }
+        segment.log.sync()?;
+        segment.index.sync();
+
+        // the open handles follow the files, so the segment stays usable once they're moved
+        for name in [
+            Segment::log_name(base_offset),
+            Index::file_name(base_offset),
+        ] {
+            fs::rename(tmp.join(&name), path.join(&name))?;
+        }
+        Ok(segment)
+    }
+
+    /// Copies the segment into `path`, replacing any earlier copy there. Batches keep their
+    /// offsets, and a compacted segment stays dense.
+    pub fn copy_to(&self, path: PathBuf) -> Result<Segment, Error> {
+        for name in [
+            Segment::log_name(self.base_offset),
+            Index::file_name(self.base_offset),
+        ] {
+            match fs::remove_file(path.join(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        let mut segment = Segment::new(path, self.base_offset);
+        segment.dense = self.dense;
+        for (offset, batch) in self.batches()? {
+            if self.dense {
+                segment.index.write_entry(Entry::new(offset, segment.bytes));
+                segment.log.append(&batch)?;
+                segment.bytes += batch.len() as u64;
+            } else {
+                segment.write_all(&batch)?;
+            }
+        }
+        segment.next_offset = self.next_offset;
+        segment.flush()?;
+        Ok(segment)
     }
 
-    // pub fn find_entry(&self, offset: u64) -> Option<Entry> {
-    //     self.index.find_entry(offset)
-    // }
+    /// Whether `other` holds the same batches as this segment, as a copy of it would until
+    /// either is written to, truncated or compacted.
+    pub fn same_as(&self, other: &Segment) -> bool {
+        self.base_offset == other.base_offset
+            && self.next_offset == other.next_offset
+            && self.bytes == other.bytes
+            && self.dense == other.dense
+    }
+
+    /// Whether the segment was compacted, so that its offsets may have gaps.
+    pub fn is_dense(&self) -> bool {
+        self.dense
+    }
+
+    /// Removes the batch at `offset`, which must be in this segment, and every batch after it.
+    pub fn truncate(&mut self, offset: u64) -> Result<(), Error> {
+        let position = match offset > self.base_offset {
+            true => self.position_of(offset)?,
+            false => 0,
+        };
+        self.log.truncate(position)?;
+        let kept = offset
+            .checked_sub(1)
+            .and_then(|last| self.index.find_slot(last))
+            .map_or(0, |slot| slot + 1);
+        self.index.truncate(kept);
+        self.last_indexed = kept
+            .checked_sub(1)
+            .map(|slot| self.index.read_entry(slot).position);
+        self.bytes = position;
+        self.next_offset = offset;
+        Ok(())
+    }
+
+    /// The number of bytes written to the segment's log file.
+    pub fn size(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Whether the segment has reached `max_bytes`, or has no room left in its index.
+    pub fn full(&self, max_bytes: u64) -> bool {
+        self.bytes >= max_bytes || self.index.is_full()
+    }
+
+    /// Reads every batch in the segment, along with its offset.
+    pub fn batches(&self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let mut batches = Vec::new();
+        let mut position = 0;
+        while position < self.bytes {
+            let offset = match self.dense {
+                true => self.index.read_entry(batches.len()).offset,
+                false => self.base_offset + batches.len() as u64,
+            };
+            let mut length = [0u8; 4];
+            self.log.read_exact_at(&mut length, position + 8)?;
+            let len = BATCH_HEADER_BYTES + i32::from_be_bytes(length) as u64;
+            batches.push((offset, self.read_at(position, len)?));
+            position += len;
+        }
+        Ok(batches)
+    }
+
+    /// Returns the byte position of the batch with the given offset, starting from the closest
+    /// index entry and scanning forward over batch headers. The offset must be in this segment.
+    pub fn position_of(&self, offset: u64) -> Result<u64, Error> {
+        if self.dense {
+            return Ok(self.dense_position_of(offset));
+        }
+
+        let Entry {
+            offset: mut current,
+            mut position,
+        } = self
+            .index
+            .find_entry(offset)
+            .unwrap_or(Entry::new(self.base_offset, 0));
+
+        while current < offset {
+            let mut length = [0u8; 4];
+            self.log.read_exact_at(&mut length, position + 8)?;
+            position += BATCH_HEADER_BYTES + i32::from_be_bytes(length) as u64;
+            current += 1;
+        }
+
+        Ok(position)
+    }
+
+    /// Looks up the batch with the given offset in a dense index, falling back to the next batch
+    /// if it was compacted away.
+    fn dense_position_of(&self, offset: u64) -> u64 {
+        let slot = match self.index.find_slot(offset) {
+            Some(slot) if self.index.read_entry(slot).offset == offset => slot,
+            Some(slot) => slot + 1,
+            None => 0,
+        };
+        match slot < self.index.count() {
+            true => self.index.read_entry(slot).position,
+            false => self.bytes,
+        }
+    }
+
+    /// Reads at most `max_bytes` from `position` to the end of the segment.
+    pub fn read_at(&self, position: u64, max_bytes: u64) -> Result<Vec<u8>, Error> {
+        let len = self.bytes.saturating_sub(position).min(max_bytes);
+        let mut buf = vec![0u8; len as usize];
+        self.log.read_exact_at(&mut buf, position)?;
+        Ok(buf)
+    }
+
+    /// Adds an index entry for the batch at `position`, if enough has been written since the last
+    /// and the index has room for it.
+    fn index_batch(&mut self, offset: u64, position: u64) {
+        if self
+            .last_indexed
+            .is_none_or(|last| position - last >= self.index_interval_bytes)
+            && !self.index.is_full()
+        {
+            self.index.write_entry(Entry::new(offset, position));
+            self.last_indexed = Some(position);
+        }
+    }
 
-    fn log_name(offset: u64) -> String {
+    pub fn log_name(offset: u64) -> String {
         format!("{}.log", offset)
     }
 }
 
 impl Write for Segment {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        self.log.write_all(buf)?;
-        self.index
-            .write_entry(Entry::new(self.next_offset, self.bytes));
+        self.log.append(buf)?;
+        self.index_batch(self.next_offset, self.bytes);
         self.next_offset += 1;
         self.bytes += buf.len() as u64;
         Result::Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<(), Error> {
+        self.log.sync()?;
+        self.index.sync();
         Ok(())
     }
 }
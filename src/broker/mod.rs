@@ -6,98 +6,291 @@ use kafka_protocol::messages::{RequestKind, ResponseKind};
 use server::Server;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 use derive_more::Display;
 
-use crate::broker::replica::Replica;
+use crate::broker::cache::MetadataCache;
+use crate::broker::fetch_session::FetchSessions;
+use crate::broker::fsm::Transition;
+use crate::broker::purgatory::Purgatory;
+use crate::broker::log::{Log, LogStore, Storage};
+use crate::broker::replica::{LogDirs, Replica};
+use crate::broker::selector::ReplicaSelector;
+use crate::broker::state::group::{Group, GroupError, GroupOp};
+use crate::broker::state::partition::{Partition, PartitionIdx};
 
+use crate::health::Health;
+use crate::kafka::ConnectedKafkaClient;
 use crate::Shutdown;
 use state::Store;
 
+mod cache;
+mod cleaner;
+pub mod compression;
+mod context;
+mod controller;
+mod coordinator;
+pub mod error;
+mod fetch_session;
+mod fetcher;
 pub mod config;
 pub mod fsm;
-mod handler;
-mod log;
+pub(crate) mod handler;
+mod isr;
+pub(crate) mod log;
+mod offsets;
+mod purgatory;
+mod recovery;
 mod replica;
+pub mod selector;
 mod server;
 pub(crate) mod state;
 mod tcp;
+mod txn;
+
+/// How many times syncs start waiting on a group between purges of the ones that are done.
+const SYNC_PURGATORY_PURGE_INTERVAL: usize = 1000;
 
 pub struct JosefineBroker {
     config: BrokerConfig,
+    health: Option<Health>,
 }
 
 impl JosefineBroker {
     pub fn new(config: BrokerConfig) -> Self {
-        JosefineBroker { config }
+        JosefineBroker {
+            config,
+            health: None,
+        }
+    }
+
+    /// Reports to the health endpoints once the logs have been recovered.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
     }
 
     pub async fn run(self, client: RaftClient, store: Store, shutdown: Shutdown) -> Result<()> {
-        let server = Server::new(self.config);
+        let server = Server::new(self.config, self.health);
         server.run(client, store, shutdown).await
     }
 }
 
-pub struct Replicas {
-    replicas: RwLock<HashMap<Uuid, Arc<Mutex<Replica>>>>,
+/// The replicas this broker holds, by partition id and by topic and partition index.
+pub struct Replicas<L = Log> {
+    replicas: RwLock<ReplicaIndex<L>>,
+}
+
+struct ReplicaIndex<L> {
+    by_id: HashMap<Uuid, Arc<Mutex<Replica<L>>>>,
+    by_partition: HashMap<(String, PartitionIdx), Uuid>,
 }
 
-impl Replicas {
+impl<L: LogStore> Replicas<L> {
     pub fn new() -> Self {
         Self {
-            replicas: Default::default(),
+            replicas: RwLock::new(ReplicaIndex {
+                by_id: HashMap::new(),
+                by_partition: HashMap::new(),
+            }),
         }
     }
 
-    pub fn add(&self, id: Uuid, replica: Replica) {
+    /// Adds the replica of a partition, replacing any replica previously held for the same
+    /// topic and partition index.
+    pub fn add(&self, partition: &Partition, replica: Replica<L>) {
         let mut rs = self.replicas.write().unwrap();
-        rs.insert(id, Arc::new(Mutex::new(replica)));
+        let key = (partition.topic.clone(), partition.idx);
+        if let Some(previous) = rs.by_partition.insert(key, partition.id) {
+            rs.by_id.remove(&previous);
+        }
+        rs.by_id.insert(partition.id, Arc::new(Mutex::new(replica)));
     }
 
-    pub fn get(&self, id: Uuid) -> Option<Arc<Mutex<Replica>>> {
+    pub fn get(&self, id: Uuid) -> Option<Arc<Mutex<Replica<L>>>> {
         let rs = self.replicas.read().unwrap();
-        rs.get(&id).map(Clone::clone)
+        rs.by_id.get(&id).map(Clone::clone)
+    }
+
+    pub fn get_partition(&self, topic: &str, idx: PartitionIdx) -> Option<Arc<Mutex<Replica<L>>>> {
+        let rs = self.replicas.read().unwrap();
+        let id = rs.by_partition.get(&(topic.to_string(), idx))?;
+        rs.by_id.get(id).map(Clone::clone)
+    }
+
+    /// Every replica we hold.
+    pub fn all(&self) -> Vec<Arc<Mutex<Replica<L>>>> {
+        let rs = self.replicas.read().unwrap();
+        rs.by_id.values().cloned().collect()
+    }
+
+    /// Stops tracking the replica of a partition, returning it if we held one.
+    pub fn remove(&self, topic: &str, idx: PartitionIdx) -> Option<Arc<Mutex<Replica<L>>>> {
+        let mut rs = self.replicas.write().unwrap();
+        let id = rs.by_partition.remove(&(topic.to_string(), idx))?;
+        rs.by_id.remove(&id)
     }
 }
 
-pub struct Broker {
+/// A broker, whose replicas keep their records in `L`.
+pub struct Broker<L = Log> {
     store: Store,
     client: RaftClient,
     config: BrokerConfig,
-    replicas: Replicas,
+    replicas: Replicas<L>,
+    log_dirs: LogDirs,
+    metadata: MetadataCache,
+    fetch_sessions: FetchSessions,
+    replica_selector: Box<dyn ReplicaSelector>,
+    /// Fetches waiting for records, by partition id.
+    fetch_purgatory: Purgatory<Uuid>,
+    /// Produces with `acks=all` waiting for their appends to be replicated, by partition id.
+    produce_purgatory: Purgatory<Uuid>,
+    /// Members' syncs waiting for the leader's assignment, by group id.
+    sync_purgatory: Purgatory<String>,
+    /// The connection requests are forwarded to the controller over, along with its id.
+    controller: Mutex<Option<(BrokerId, Arc<ConnectedKafkaClient>)>>,
 }
 
-impl Debug for Broker {
+impl<L> Debug for Broker<L> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "Controller {{}}")
     }
 }
 
 impl Broker {
-    pub fn new(store: Store, client: RaftClient, config: BrokerConfig) -> Self {
-        Self {
+    pub fn new(store: Store, client: RaftClient, config: BrokerConfig) -> Result<Self> {
+        Self::with_log_store(store, client, config)
+    }
+
+    /// Creates an empty replica of `partition`, in the next log dir or in memory depending on the
+    /// configured storage.
+    pub(crate) fn new_replica(&self, partition: &Partition) -> Replica {
+        let segment_bytes = self.config.log_segment_bytes;
+        let replica = match self.config.storage {
+            Storage::Disk => Replica::new(
+                self.log_dirs.next(),
+                self.config.id,
+                partition.clone(),
+                segment_bytes,
+            ),
+            Storage::Memory => Replica::in_memory(partition, segment_bytes),
+        };
+        replica
+            .with_cache_bytes(self.config.log_cache_bytes)
+            .with_index(self.config.segment_index_bytes, self.config.index_interval_bytes)
+            .with_slow_append(Duration::from_millis(self.config.slow_append_ms))
+    }
+
+    /// Syncs the logs of every replica to disk, returning the number of logs flushed. A log that
+    /// fails to flush doesn't stop the others from being flushed.
+    pub async fn flush_logs(&self) -> Result<usize> {
+        let mut flushed = 0;
+        let mut failed = None;
+        for replica in self.replicas.all() {
+            match replica.lock().await.log.flush() {
+                Ok(()) => flushed += 1,
+                Err(e) => failed = Some(e),
+            }
+        }
+        match failed {
+            Some(e) => Err(e.into()),
+            None => Ok(flushed),
+        }
+    }
+
+    /// Quarantines the partition directories in our log dirs that no partition in the store
+    /// accounts for, such as those left behind by a crash during a topic delete.
+    pub fn quarantine_orphans(&self) -> Result<()> {
+        let known = self.metadata.get()?.partitions.values().map(|p| p.dir_name()).collect();
+        self.log_dirs.quarantine_orphans(&known)?;
+        Ok(())
+    }
+}
+
+impl<L: LogStore> Broker<L> {
+    /// Creates a broker whose replicas keep their records in `L`, rather than in a [`Log`].
+    pub(crate) fn with_log_store(
+        store: Store,
+        client: RaftClient,
+        config: BrokerConfig,
+    ) -> Result<Self> {
+        let log_dirs = match config.storage {
+            Storage::Disk => LogDirs::new(&config.log_dirs)?,
+            Storage::Memory => LogDirs::default(),
+        };
+        Ok(Self {
+            metadata: MetadataCache::new(store.clone()),
+            fetch_sessions: FetchSessions::new(config.max_fetch_sessions),
+            replica_selector: config.replica_selector.build(),
+            fetch_purgatory: Purgatory::new(config.fetch_purgatory_purge_interval_requests),
+            produce_purgatory: Purgatory::new(config.producer_purgatory_purge_interval_requests),
+            sync_purgatory: Purgatory::new(SYNC_PURGATORY_PURGE_INTERVAL),
             store,
             client,
             config,
             replicas: Replicas::new(),
-        }
+            log_dirs,
+            controller: Mutex::new(None),
+        })
     }
 
-    fn get_broker_ids(&self) -> Vec<BrokerId> {
-        let mut ids: Vec<BrokerId> = self.config.peers.iter().map(|x| x.id).collect();
-        ids.push(self.config.id);
-        ids
+    fn get_broker_ids(&self) -> Result<Vec<BrokerId>> {
+        Ok(self.get_brokers()?.into_iter().map(|b| b.id).collect())
     }
 
-    fn get_brokers(&self) -> Vec<crate::broker::config::Peer> {
+    /// The brokers that are currently registered with the cluster. Until any have registered,
+    /// the configured brokers are assumed to be live.
+    fn get_brokers(&self) -> Result<Vec<crate::broker::config::Peer>> {
+        let registered = self.store.get_registered_brokers()?;
+        if !registered.is_empty() {
+            return Ok(registered.into_values().collect());
+        }
+
         let mut brokers = self.config.peers.clone();
-        brokers.push(crate::broker::config::Peer {
+        brokers.push(self.peer());
+        Ok(brokers)
+    }
+
+    /// How other brokers and clients reach this broker.
+    fn peer(&self) -> crate::broker::config::Peer {
+        crate::broker::config::Peer {
             id: self.config.id,
             ip: self.config.ip,
             port: self.config.port,
-        });
-        brokers
+            rack: self.config.rack.clone(),
+            advertised_host: self.config.advertised_host.clone(),
+            advertised_port: self.config.advertised_port,
+        }
+    }
+}
+
+impl Broker {
+    /// Replicates an operation on a group, returning the updated group or the reason the
+    /// operation was rejected.
+    async fn update_group(
+        &self,
+        id: &str,
+        op: GroupOp,
+    ) -> Result<std::result::Result<Group, GroupError>> {
+        if !self.is_coordinator(id)? {
+            return Ok(Err(GroupError::NotCoordinator));
+        }
+        let res = self
+            .client
+            .propose(
+                Transition::UpdateGroup {
+                    id: id.to_string(),
+                    op,
+                }
+                .serialize()?,
+            )
+            .await?;
+        self.sync_purgatory.complete(&id.to_string());
+        Ok(bincode::deserialize(&res)?)
     }
 
     #[tracing::instrument]
@@ -125,6 +318,74 @@ impl Broker {
                 let res = self.do_handle(req).await?;
                 ResponseKind::FindCoordinatorResponse(res)
             }
+            RequestKind::ProduceRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::ProduceResponse(res)
+            }
+            RequestKind::DescribeClientQuotasRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::DescribeClientQuotasResponse(res)
+            }
+            RequestKind::AlterClientQuotasRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::AlterClientQuotasResponse(res)
+            }
+            RequestKind::FetchRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::FetchResponse(res)
+            }
+            RequestKind::JoinGroupRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::JoinGroupResponse(res)
+            }
+            RequestKind::SyncGroupRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::SyncGroupResponse(res)
+            }
+            RequestKind::HeartbeatRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::HeartbeatResponse(res)
+            }
+            RequestKind::LeaveGroupRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::LeaveGroupResponse(res)
+            }
+            RequestKind::DescribeProducersRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::DescribeProducersResponse(res)
+            }
+            RequestKind::OffsetForLeaderEpochRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::OffsetForLeaderEpochResponse(res)
+            }
+            RequestKind::InitProducerIdRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::InitProducerIdResponse(res)
+            }
+            RequestKind::AddPartitionsToTxnRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::AddPartitionsToTxnResponse(res)
+            }
+            RequestKind::EndTxnRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::EndTxnResponse(res)
+            }
+            RequestKind::DescribeLogDirsRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::DescribeLogDirsResponse(res)
+            }
+            RequestKind::AlterReplicaLogDirsRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::AlterReplicaLogDirsResponse(res)
+            }
+            RequestKind::ListPartitionReassignmentsRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::ListPartitionReassignmentsResponse(res)
+            }
+            RequestKind::ListOffsetsRequest(req) => {
+                let res = self.do_handle(req).await?;
+                ResponseKind::ListOffsetsResponse(res)
+            }
             _ => panic!(),
         };
 
@@ -136,3 +397,199 @@ impl Broker {
     Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Display, Ord, PartialOrd,
 )]
 pub struct BrokerId(pub i32);
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+    use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+    use kafka_protocol::messages::{
+        FetchRequest, FetchResponse, ProduceRequest, ProduceResponse, TopicName,
+    };
+    use uuid::Uuid;
+
+    use crate::broker::config::BrokerConfig;
+    use crate::broker::handler::test::{idempotent_batch, new_broker, new_topic, new_topic_with};
+    use crate::broker::handler::Handler;
+    use crate::broker::log::{LogStore, Storage};
+    use crate::broker::replica::Replica;
+    use crate::broker::state::partition::PartitionIdx;
+    use crate::broker::state::Store;
+    use crate::broker::Broker;
+    use crate::kafka::util::ToStrBytes;
+    use crate::raft::client::RaftClient;
+
+    /// Keeps each batch appended in a vector, at the index of its offset.
+    #[derive(Default)]
+    struct VecLog {
+        batches: Vec<Vec<u8>>,
+    }
+
+    impl LogStore for VecLog {
+        fn append(&mut self, records: &[u8]) -> std::io::Result<()> {
+            self.batches.push(records.to_vec());
+            Ok(())
+        }
+
+        fn read(&self, offset: u64, limit: u64, max_bytes: u64) -> std::io::Result<Vec<u8>> {
+            let mut records = vec![];
+            for batch in self.batches.iter().take(limit as usize).skip(offset as usize) {
+                if !records.is_empty() && (records.len() + batch.len()) as u64 > max_bytes {
+                    break;
+                }
+                records.extend_from_slice(batch);
+            }
+            Ok(records)
+        }
+
+        fn truncate(&mut self, offset: u64) -> std::io::Result<()> {
+            self.batches.truncate(offset as usize);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn start_offset(&self) -> u64 {
+            0
+        }
+
+        fn end_offset(&self) -> u64 {
+            self.batches.len() as u64
+        }
+    }
+
+    /// Produces `batch` to partition 0 of `test`, returning the offset it was appended at.
+    async fn produce<L: LogStore>(broker: &Broker<L>, batch: Bytes) -> Result<i64> {
+        let mut pd = PartitionProduceData::default();
+        pd.records = Some(batch);
+        let mut td = TopicProduceData::default();
+        td.partition_data.push(pd);
+        let mut req = ProduceRequest::default();
+        req.topic_data
+            .insert(TopicName("test".to_string().to_str_bytes()), td);
+        let res = broker.handle(req, ProduceResponse::default()).await?;
+        let partition = &res.responses[0].partition_responses[0];
+        assert_eq!(partition.error_code, 0);
+        Ok(partition.base_offset)
+    }
+
+    /// Fetches partition 0 of `test` from `offset` as a consumer.
+    async fn fetch<L: LogStore>(broker: &Broker<L>, offset: i64) -> Result<Option<Bytes>> {
+        let mut partition = FetchPartition::default();
+        partition.fetch_offset = offset;
+        partition.partition_max_bytes = 1024;
+        let mut topic = FetchTopic::default();
+        topic.topic = TopicName("test".to_string().to_str_bytes());
+        topic.partitions.push(partition);
+        let mut req = FetchRequest::default();
+        req.replica_id = (-1).into();
+        req.topics.push(topic);
+        let res = broker.handle(req, FetchResponse::default()).await?;
+        Ok(res.responses[0].partitions[0].records.clone())
+    }
+
+    #[tokio::test]
+    async fn log_store() -> Result<()> {
+        let (client_tx, _client_rx) = tokio::sync::mpsc::channel(1);
+        let client = RaftClient::new(client_tx, Duration::from_millis(100));
+        let store = Store::new(sled::Config::new().temporary(true).open()?)?;
+        let config = BrokerConfig {
+            storage: Storage::Memory,
+            ..Default::default()
+        };
+        let broker: Broker<VecLog> = Broker::with_log_store(store, client, config)?;
+        new_topic_with(&broker, "test", 1, |_| Replica::with_log(VecLog::default()))?;
+
+        let first = idempotent_batch(-1, -1, 1)?.freeze();
+        let second = idempotent_batch(-1, -1, 2)?.freeze();
+        assert_eq!(produce(&broker, first.clone()).await?, 0);
+        assert_eq!(produce(&broker, second.clone()).await?, 1);
+        assert_eq!(fetch(&broker, 1).await?, Some(second.clone()));
+
+        let replica = broker.replicas.all().pop().unwrap();
+        let replica = replica.lock().await;
+        assert_eq!(replica.log.batches, vec![first.to_vec(), second.to_vec()]);
+        assert_eq!(replica.high_watermark, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn in_memory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log_dir = dir.path().join("logs");
+        let config = BrokerConfig {
+            storage: Storage::Memory,
+            log_dirs: vec![log_dir.clone()],
+            ..Default::default()
+        };
+        let (client_tx, _client_rx) = tokio::sync::mpsc::channel(1);
+        let client = RaftClient::new(client_tx, Duration::from_millis(100));
+        let store = Store::new(sled::Config::new().temporary(true).open()?)?;
+        let broker = Broker::new(store, client, config)?;
+        new_topic(&broker, "test", 1)?;
+
+        let batch = idempotent_batch(-1, -1, 1)?.freeze();
+        assert_eq!(produce(&broker, batch.clone()).await?, 0);
+        assert_eq!(fetch(&broker, 0).await?, Some(batch));
+
+        // nothing was written to the log dir, which was never even created
+        assert!(!log_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn replicas_by_partition() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partitions = new_topic(&broker, "test", 3)?;
+        for partition in &partitions {
+            let replica = broker.replicas.get_partition("test", partition.idx).unwrap();
+            let by_id = broker.replicas.get(partition.id).unwrap();
+            assert!(Arc::ptr_eq(&replica, &by_id));
+        }
+        assert!(broker.replicas.get_partition("test", PartitionIdx(3)).is_none());
+
+        // a partition that is assigned again under a new id replaces the old replica
+        let mut reassigned = partitions[0].clone();
+        reassigned.id = Uuid::new_v4();
+        let dir = broker.log_dirs.next();
+        let replica = Replica::new(dir, broker.config.id, reassigned.clone(), 1024);
+        broker.replicas.add(&reassigned, replica);
+        assert!(broker.replicas.get(partitions[0].id).is_none());
+        assert!(broker.replicas.get(reassigned.id).is_some());
+
+        for partition in &partitions {
+            assert!(broker.replicas.remove("test", partition.idx).is_some());
+            assert!(broker.replicas.get_partition("test", partition.idx).is_none());
+        }
+        assert!(broker.replicas.get(reassigned.id).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_logs() -> Result<()> {
+        let (_rx, broker) = new_broker();
+        let partitions = new_topic(&broker, "test", 1)?;
+        let batch = idempotent_batch(-1, -1, 1)?;
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        replica.lock().await.log.write_all(&batch)?;
+        assert_eq!(broker.flush_logs().await?, 1);
+
+        let dir = partitions[0].dir_name();
+        let path = broker
+            .log_dirs
+            .dirs()
+            .iter()
+            .map(|d| d.join(&dir))
+            .find(|p| p.exists())
+            .unwrap();
+        assert_eq!(std::fs::read(path.join("0.log"))?, batch.to_vec());
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::broker::fsm::Transition;
+use crate::broker::Broker;
+use crate::Shutdown;
+
+impl Broker {
+    /// Deletes the committed offsets of groups without members that are past their expiry,
+    /// returning the number of offsets deleted.
+    pub(crate) async fn expire_offsets(&self) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let retention_ms = (self.config.offsets_retention_minutes * 60 * 1000) as i64;
+        let groups = self.store.get_groups()?;
+
+        let mut expired = Vec::new();
+        for (group, offsets) in self.store.get_offsets()? {
+            // offsets of a group with members are in use, however old they are
+            if groups.get(&group).is_some_and(|g| !g.members.is_empty()) {
+                continue;
+            }
+            let partitions: Vec<(String, i32)> = offsets
+                .into_iter()
+                .filter(|(_, offset)| offset.is_expired(now, retention_ms))
+                .map(|(partition, _)| partition)
+                .collect();
+            if !partitions.is_empty() {
+                expired.push(Transition::DeleteOffsets { group, partitions });
+            }
+        }
+
+        let count = expired
+            .iter()
+            .map(|t| match t {
+                Transition::DeleteOffsets { partitions, .. } => partitions.len(),
+                _ => 0,
+            })
+            .sum();
+        if count > 0 {
+            self.client
+                .propose(Transition::Batch(expired).serialize()?)
+                .await?;
+        }
+        Ok(count)
+    }
+}
+
+/// Periodically expires stale committed offsets while this broker is the controller, so that
+/// only one broker proposes their deletion.
+pub(crate) async fn run(broker: Arc<Broker>, mut shutdown: Shutdown) -> Result<()> {
+    let period = Duration::from_millis(broker.config.offsets_retention_check_interval_ms);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            _ = interval.tick() => {}
+        }
+        if !broker.is_controller() {
+            continue;
+        }
+        match broker.expire_offsets().await {
+            Ok(0) => {}
+            Ok(expired) => tracing::info!(expired, "expired committed offsets"),
+            Err(e) => tracing::error!(%e, "could not expire committed offsets"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use anyhow::Result;
+
+    use crate::broker::handler::test::{apply_proposals, new_broker};
+    use crate::broker::state::group::{GroupOp, Member};
+    use crate::broker::state::offset::CommittedOffset;
+
+    fn committed(age: Duration) -> CommittedOffset {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        CommittedOffset {
+            offset: 1,
+            commit_timestamp: (now - age).as_millis() as i64,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn expires_stale_offsets() -> Result<()> {
+        let (rx, mut broker) = new_broker();
+        broker.config.offsets_retention_minutes = 60;
+        apply_proposals(rx, &broker);
+
+        let stale = committed(Duration::from_secs(2 * 60 * 60));
+        let recent = committed(Duration::from_secs(60));
+        for group in ["empty", "active"] {
+            broker.store.commit_offsets(
+                group,
+                &[
+                    ("test".to_string(), 0, stale.clone()),
+                    ("test".to_string(), 1, recent.clone()),
+                ],
+            )?;
+        }
+        broker
+            .store
+            .update_group(
+                "active",
+                &GroupOp::Join(Member {
+                    id: "member".to_string(),
+                    protocol_type: "consumer".to_string(),
+                    ..Default::default()
+                }),
+            )?
+            .unwrap();
+
+        assert_eq!(broker.expire_offsets().await?, 1);
+        let empty = broker.store.get_group_offsets("empty")?;
+        assert_eq!(empty.len(), 1);
+        assert_eq!(empty.get(&("test".to_string(), 1)), Some(&recent));
+        assert_eq!(broker.store.get_group_offsets("active")?.len(), 2);
+        Ok(())
+    }
+}
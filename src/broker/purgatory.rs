@@ -0,0 +1,228 @@
+//! Requests that can't be answered yet wait in a purgatory, watching the partitions whose
+//! progress may complete them: fetches wait for enough records to be appended, and produces with
+//! `acks=all` for their appends to be replicated. Whatever makes progress on a partition
+//! completes its key, which has the operations watching it check again, and operations that
+//! don't complete by their deadline expire.
+//!
+//! A request waiting here gives up its request handler slot, so that requests parked for long
+//! don't keep the others from being handled.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::sync::{Notify, OwnedSemaphorePermit};
+use tokio::time::Instant;
+
+tokio::task_local! {
+    /// The request handler slot held by the task handling a request, until it waits.
+    static HANDLER_PERMIT: RefCell<Option<OwnedSemaphorePermit>>;
+}
+
+/// Handles a request with `fut` in the request handler slot `permit`, which is given up as soon
+/// as the request waits in a purgatory.
+pub(crate) async fn holding<F: Future>(permit: OwnedSemaphorePermit, fut: F) -> F::Output {
+    HANDLER_PERMIT.scope(RefCell::new(Some(permit)), fut).await
+}
+
+/// Gives up the request handler slot of the current task, if it holds one.
+fn release_handler() {
+    let _ = HANDLER_PERMIT.try_with(|permit| permit.borrow_mut().take());
+}
+
+/// An operation that waits in a [`Purgatory`] until it can complete.
+pub(crate) trait DelayedOperation {
+    type Output;
+
+    /// Completes the operation if what it waits for has happened.
+    async fn try_complete(&mut self) -> Result<Option<Self::Output>>;
+
+    /// Completes the operation once its deadline has passed without it completing.
+    async fn on_expiration(self) -> Result<Self::Output>;
+}
+
+/// Delayed operations watching keys of type `K`, typically partition ids.
+pub(crate) struct Purgatory<K> {
+    watchers: Mutex<Watchers<K>>,
+    purge_interval: usize,
+}
+
+struct Watchers<K> {
+    /// Wakes each operation watching a key. An operation that is done only holds on to its
+    /// waker in the keys that weren't completed since.
+    by_key: HashMap<K, Vec<Arc<Notify>>>,
+    /// How many times operations started watching since the last purge.
+    since_purge: usize,
+}
+
+impl<K: Hash + Eq + Clone> Purgatory<K> {
+    /// Creates a purgatory that drops the wakers of done operations every `purge_interval`
+    /// times an operation starts watching.
+    pub(crate) fn new(purge_interval: usize) -> Self {
+        Self {
+            watchers: Mutex::new(Watchers {
+                by_key: HashMap::new(),
+                since_purge: 0,
+            }),
+            purge_interval: purge_interval.max(1),
+        }
+    }
+
+    /// Completes `op` as soon as it can, checking again whenever one of `keys` is completed,
+    /// and expires it at `deadline`. An operation that can complete straight away never waits.
+    pub(crate) async fn watch<O: DelayedOperation>(
+        &self,
+        keys: &[K],
+        deadline: Instant,
+        mut op: O,
+    ) -> Result<O::Output> {
+        if let Some(output) = op.try_complete().await? {
+            return Ok(output);
+        }
+        release_handler();
+        let waker = Arc::new(Notify::new());
+        loop {
+            // watch before checking, so that a key completed in between isn't missed
+            self.register(keys, &waker);
+            if let Some(output) = op.try_complete().await? {
+                return Ok(output);
+            }
+            if tokio::time::timeout_at(deadline, waker.notified())
+                .await
+                .is_err()
+            {
+                return op.on_expiration().await;
+            }
+        }
+    }
+
+    /// Has the operations watching `key` check whether they can complete, returning how many
+    /// there were.
+    pub(crate) fn complete(&self, key: &K) -> usize {
+        let woken = self.watchers.lock().unwrap().by_key.remove(key);
+        let woken = woken.unwrap_or_default();
+        for waker in &woken {
+            waker.notify_one();
+        }
+        woken.len()
+    }
+
+    /// The number of operations watching a key, including ones that are already done but
+    /// haven't been purged yet.
+    #[cfg(test)]
+    pub(crate) fn watching(&self) -> usize {
+        let watchers = self.watchers.lock().unwrap();
+        watchers.by_key.values().map(Vec::len).sum()
+    }
+
+    fn register(&self, keys: &[K], waker: &Arc<Notify>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        for key in keys {
+            let wakers = watchers.by_key.entry(key.clone()).or_default();
+            if !wakers.iter().any(|w| Arc::ptr_eq(w, waker)) {
+                wakers.push(waker.clone());
+            }
+        }
+        watchers.since_purge += 1;
+        if watchers.since_purge >= self.purge_interval {
+            watchers.since_purge = 0;
+            // the purgatory holds the only reference to the wakers of operations that are done
+            watchers.by_key.retain(|_, wakers| {
+                wakers.retain(|w| Arc::strong_count(w) > 1);
+                !wakers.is_empty()
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::time::Instant;
+
+    use super::{DelayedOperation, Purgatory};
+
+    /// Completes once `ready` is set, reporting whether it expired instead.
+    struct Flagged<'a> {
+        ready: &'a AtomicBool,
+    }
+
+    impl DelayedOperation for Flagged<'_> {
+        type Output = bool;
+
+        async fn try_complete(&mut self) -> Result<Option<bool>> {
+            Ok(self.ready.load(Ordering::SeqCst).then_some(false))
+        }
+
+        async fn on_expiration(self) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn completes_early_or_expires() -> Result<()> {
+        let purgatory = Purgatory::new(1000);
+        let ready = AtomicBool::new(false);
+
+        // completing the key it watches has the operation check again
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(10);
+        let (expired, woken) = tokio::join!(
+            purgatory.watch(&[1, 2], deadline, Flagged { ready: &ready }),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                ready.store(true, Ordering::SeqCst);
+                purgatory.complete(&1)
+            }
+        );
+        assert!(!expired?);
+        assert_eq!(woken, 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // while one that never can complete expires at its deadline
+        ready.store(false, Ordering::SeqCst);
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        assert!(
+            purgatory
+                .watch(&[1], deadline, Flagged { ready: &ready })
+                .await?
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purges_done_operations() -> Result<()> {
+        let purgatory = Purgatory::new(3);
+        let ready = AtomicBool::new(false);
+        let deadline = Instant::now();
+        purgatory
+            .watch(&[1, 2], deadline, Flagged { ready: &ready })
+            .await?;
+        purgatory
+            .watch(&[3], deadline, Flagged { ready: &ready })
+            .await?;
+        assert_eq!(purgatory.watching(), 3);
+        assert_eq!(purgatory.complete(&1), 1);
+
+        // the third operation to watch purges the two before it, which are done
+        let watch = purgatory.watch(
+            &[4],
+            deadline + Duration::from_secs(10),
+            Flagged { ready: &ready },
+        );
+        tokio::pin!(watch);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut watch)
+            .await
+            .is_err());
+        assert_eq!(purgatory.watching(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Recovery of the partition logs left on disk by an earlier run. Each log is recovered by a
+//! single task, segment by segment, while logs in the same log dir are recovered in parallel up
+//! to `num_recovery_threads_per_data_dir` at a time.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::broker::log::LogStore;
+use crate::broker::replica::Replica;
+use crate::broker::Broker;
+
+impl Broker {
+    /// Recovers the logs of the partitions assigned to this broker that have a directory in one
+    /// of its log dirs and no replica yet, returning the number recovered. A log that fails to
+    /// recover doesn't stop the others, and the failures are reported together.
+    pub(crate) async fn recover_logs(&self) -> Result<usize> {
+        let threads = self.config.num_recovery_threads_per_data_dir.max(1);
+        let dirs: Vec<_> = self
+            .log_dirs
+            .dirs()
+            .iter()
+            .map(|dir| (dir.clone(), Arc::new(Semaphore::new(threads))))
+            .collect();
+        let segment_bytes = self.config.log_segment_bytes;
+
+        let mut tasks = JoinSet::new();
+        for partition in self.metadata.get()?.partitions.values() {
+            if !partition.assigned_replicas.contains(&self.config.id.0)
+                || self.replicas.get(partition.id).is_some()
+            {
+                continue;
+            }
+            let found = dirs
+                .iter()
+                .find(|(dir, _)| dir.join(partition.dir_name()).is_dir());
+            let (dir, permits) = match found {
+                Some(found) => found.clone(),
+                None => continue,
+            };
+            let partition = partition.clone();
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                let recovered = tokio::task::spawn_blocking({
+                    let partition = partition.clone();
+                    move || Replica::open(&dir, &partition, segment_bytes)
+                })
+                .await?
+                .map_err(|e| anyhow::anyhow!("{}: {}", partition.dir_name(), e))?;
+                Ok::<_, anyhow::Error>((partition, recovered))
+            });
+        }
+
+        let mut recovered = 0;
+        let mut failed = Vec::new();
+        while let Some(res) = tasks.join_next().await {
+            match res? {
+                Ok((partition, mut replica)) => {
+                    // with no other replica to wait for, everything in the log was committed, while
+                    // followers learn the high watermark from their leader again
+                    if partition.assigned_replicas == [self.config.id.0] {
+                        replica.high_watermark = replica.log.end_offset();
+                    }
+                    let replica = replica
+                        .with_cache_bytes(self.config.log_cache_bytes)
+                        .with_index(
+                            self.config.segment_index_bytes,
+                            self.config.index_interval_bytes,
+                        )
+                        .with_slow_append(Duration::from_millis(self.config.slow_append_ms));
+                    self.replicas.add(&partition, replica);
+                    recovered += 1;
+                }
+                Err(e) => {
+                    tracing::error!(%e, "could not recover log");
+                    failed.push(e.to_string());
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "could not recover {} logs: {}",
+                failed.len(),
+                failed.join("; ")
+            ));
+        }
+        tracing::info!(recovered, "recovered logs");
+        Ok(recovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use anyhow::Result;
+
+    use crate::broker::handler::test::{
+        idempotent_batch, new_broker, new_topic, transactional_batch,
+    };
+    use crate::broker::replica::{AbortedTxn, LogDirs};
+    use crate::broker::state::partition::Partition;
+    use crate::broker::txn::marker_batch;
+    use crate::broker::Broker;
+
+    /// Drops every replica, recovers them, and reads back each partition's log.
+    async fn recover(broker: &Broker, partitions: &[Partition]) -> Result<Vec<Vec<Vec<u8>>>> {
+        for partition in partitions {
+            broker.replicas.remove(&partition.topic, partition.idx);
+        }
+        broker.recover_logs().await?;
+        let mut logs = Vec::new();
+        for partition in partitions {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let replica = replica.lock().await;
+            let batches = (0..replica.log.newest_offset())
+                .map(|offset| replica.log.read_until(offset, offset + 1, u64::MAX))
+                .collect::<Result<_, _>>()?;
+            logs.push(batches);
+        }
+        Ok(logs)
+    }
+
+    #[tokio::test]
+    async fn parallel_recovery_matches_sequential() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker
+            .config
+            .log_dirs
+            .push(tempfile::tempdir()?.into_path());
+        broker.log_dirs = LogDirs::new(&broker.config.log_dirs)?;
+        broker.config.log_segment_bytes = 200;
+        let partitions = new_topic(&broker, "test", 6)?;
+        for (i, partition) in partitions.iter().enumerate() {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            for count in 0..=i as i32 {
+                replica
+                    .log
+                    .write_all(&idempotent_batch(-1, -1, count + 1)?)?;
+            }
+            replica.log.flush()?;
+        }
+
+        let sequential = recover(&broker, &partitions).await?;
+        broker.config.num_recovery_threads_per_data_dir = 4;
+        let parallel = recover(&broker, &partitions).await?;
+        assert_eq!(parallel, sequential);
+        for (i, batches) in sequential.iter().enumerate() {
+            assert_eq!(batches.len(), i + 1);
+        }
+
+        // a log that can't be opened is reported, while the rest are still recovered
+        let broken = &partitions[0];
+        broker.replicas.remove(&broken.topic, broken.idx);
+        let dir = broker
+            .log_dirs
+            .dirs()
+            .iter()
+            .map(|d| d.join(broken.dir_name()))
+            .find(|p| p.exists())
+            .unwrap();
+        std::fs::remove_file(dir.join("0.log"))?;
+        std::fs::create_dir(dir.join("0.log"))?;
+        broker
+            .replicas
+            .remove(&partitions[1].topic, partitions[1].idx);
+        let err = broker.recover_logs().await.unwrap_err();
+        assert!(err.to_string().contains(&broken.dir_name()));
+        assert!(broker.replicas.get(broken.id).is_none());
+        assert!(broker.replicas.get(partitions[1].id).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restores_state_from_log() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.log_dirs = vec![tempfile::tempdir()?.into_path()];
+        broker.log_dirs = LogDirs::new(&broker.config.log_dirs)?;
+        let partitions = new_topic(&broker, "test", 2)?;
+        // partition 1 has a follower as well
+        broker.store.create_partition(Partition {
+            assigned_replicas: vec![1, 2],
+            ..partitions[1].clone()
+        })?;
+        broker.store.metadata_changed();
+        for partition in &partitions {
+            let replica = broker.replicas.get(partition.id).unwrap();
+            let mut replica = replica.lock().await;
+            replica.log.write_all(&idempotent_batch(5, 0, 2)?)?;
+            replica.log.write_all(&transactional_batch(7, 0, 0, 1)?)?;
+            replica.log.flush()?;
+        }
+
+        recover(&broker, &partitions).await?;
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let replica = replica.lock().await;
+        assert_eq!(replica.high_watermark, 2);
+        assert_eq!(replica.producers[&5].last_sequence, 1);
+        // the transaction is still open, so consumers reading committed records stop before it
+        assert_eq!(replica.ongoing_txns.get(&7), Some(&1));
+        assert_eq!(replica.last_stable_offset(), 1);
+
+        let replica = broker.replicas.get(partitions[1].id).unwrap();
+        assert_eq!(replica.lock().await.high_watermark, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_aborted_batches_after_restart() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.log_dirs = vec![tempfile::tempdir()?.into_path()];
+        broker.log_dirs = LogDirs::new(&broker.config.log_dirs)?;
+        let partitions = new_topic(&broker, "test", 1)?;
+        let aborted = transactional_batch(7, 0, 0, 2)?;
+        let marker = marker_batch(7, 0, false)?;
+        let committed = idempotent_batch(-1, -1, 1)?;
+        {
+            let replica = broker.replicas.get(partitions[0].id).unwrap();
+            let mut replica = replica.lock().await;
+            for batch in [&aborted[..], &marker[..], &committed[..]] {
+                replica.log.write_all(batch)?;
+            }
+            replica.log.flush()?;
+        }
+
+        recover(&broker, &partitions).await?;
+        let replica = broker.replicas.get(partitions[0].id).unwrap();
+        let replica = replica.lock().await;
+        let txn = AbortedTxn {
+            producer_id: 7,
+            first_offset: 0,
+            last_offset: 1,
+        };
+        assert_eq!(replica.aborted_txns, vec![txn]);
+        let read = replica.read_committed(0, replica.high_watermark, u64::MAX)?;
+        assert_eq!(read, [&marker[..], &committed[..]].concat());
+        Ok(())
+    }
+}
@@ -1,21 +1,658 @@
-use crate::broker::BrokerId;
-use crate::broker::log::Log;
+use crate::broker::fetcher::whole_batches;
+use crate::broker::log::{Log, LogStore};
 use crate::broker::state::partition::Partition;
-use std::path::Path;
+use crate::broker::BrokerId;
+use anyhow::Result;
+use bytes::Bytes;
+use kafka_protocol::records::RecordBatchDecoder;
+use kafka_protocol::ResponseError;
+use kafka_protocol::ResponseError::{
+    DuplicateSequenceNumber, InvalidProducerEpoch, OutOfOrderSequenceNumber,
+};
+use std::cmp::Ordering::{Equal, Greater, Less};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
 
-pub struct Replica {
+/// The replica of a partition that this broker holds, keeping its records in `L`.
+pub struct Replica<L = Log> {
     // broker_id: BrokerId,
     // partition: Partition,
-    pub log: Log,
+    pub log: L,
+    /// The offset below which records have been replicated to a majority of the in sync
+    /// replicas. Consumers can only read up to it.
+    pub high_watermark: u64,
+    /// The log end offsets of followers, as reported by their fetches.
+    pub follower_offsets: HashMap<i32, u64>,
+    /// When each follower last fetched from at or past our log end offset.
+    pub follower_caught_up: HashMap<i32, Instant>,
+    /// The leader epoch we last led the replica in, if any.
+    pub leading_epoch: Option<i32>,
+    /// When we started leading the replica in `leading_epoch`, which is what followers that have
+    /// not caught up since lag behind since.
+    pub leading_since: Instant,
+    /// The leader's high watermark as of the first fetch we made from it as a follower, which
+    /// our log has to reach before we serve fetches.
+    pub startup_high_watermark: Option<u64>,
+    /// Whether our log has reached `startup_high_watermark`. Until it has, consumers are sent to
+    /// the leader rather than reading possibly stale records from us.
+    pub caught_up: bool,
+    /// The high watermark the leader reported in its latest fetch response, while following.
+    pub leader_high_watermark: u64,
+    /// The latest batch appended by each idempotent producer, by producer id.
+    pub producers: BTreeMap<i64, ProducerState>,
+    /// The offset of the first batch appended in each leader epoch.
+    pub epochs: BTreeMap<i32, u64>,
+    /// The leader epoch our log was last checked against the leader's in, as a follower. Until
+    /// it has been checked in the current epoch, the log may hold batches the leader doesn't.
+    pub checked_epoch: Option<i32>,
+    /// The offset of the first batch of each producer's open transaction, by producer id, so
+    /// there is at most one for each producer.
+    pub ongoing_txns: BTreeMap<i64, u64>,
+    /// The transactions that were aborted, which consumers reading committed records skip. Only
+    /// those with batches still in the log are kept.
+    pub aborted_txns: Vec<AbortedTxn>,
+}
+
+/// The batches a producer appended in a transaction that was aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbortedTxn {
+    pub producer_id: i64,
+    pub first_offset: u64,
+    /// The offset of the abort marker.
+    pub last_offset: u64,
+}
+
+/// How a batch takes part in a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxnBatch {
+    Records,
+    Commit,
+    Abort,
+}
+
+/// What is known about an idempotent producer from the last batch it appended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProducerState {
+    pub epoch: i16,
+    pub last_sequence: i32,
+    pub last_timestamp: i64,
 }
 
+/// The directory within a log dir that partition directories unknown to the store are moved to.
+const ORPHAN_DIR: &str = ".orphan";
+/// The size of a v2 record batch header, up to and including the record count.
+const BATCH_HEADER_BYTES: usize = 61;
+/// The batch attribute set on batches written in a transaction.
+const TRANSACTIONAL: i16 = 0x10;
+/// The batch attribute set on batches holding transaction markers.
+const CONTROL: i16 = 0x20;
+/// The type in the key of a marker that aborts a transaction.
+pub const ABORT_MARKER: i16 = 0;
+/// The type in the key of a marker that commits a transaction.
+pub const COMMIT_MARKER: i16 = 1;
+
 impl Replica {
-    pub fn new(path: &Path, _broker_id: BrokerId, partition: Partition) -> Self {
-        let log = Log::new(&path.join("data").join(format!("{}", partition.id)));
+    pub fn new(
+        log_dir: &Path,
+        _broker_id: BrokerId,
+        partition: Partition,
+        segment_bytes: u64,
+    ) -> Self {
+        let log = Log::with_segment_bytes(&log_dir.join(partition.dir_name()), segment_bytes);
+        Replica::with_log(log)
+    }
+
+    /// Creates a replica whose log is kept in memory rather than in a log dir.
+    pub fn in_memory(partition: &Partition, segment_bytes: u64) -> Self {
+        let log = Log::in_memory(Path::new(&partition.dir_name()), segment_bytes);
+        Replica::with_log(log)
+    }
+
+    /// Opens the replica of `partition` that an earlier run left in `log_dir`, recovering its
+    /// log and replaying it to rebuild producer and transaction state.
+    pub fn open(log_dir: &Path, partition: &Partition, segment_bytes: u64) -> Result<Self> {
+        let log = Log::open(&log_dir.join(partition.dir_name()), segment_bytes)?;
+        let mut replica = Replica::with_log(log);
+        replica.replay()?;
+        Ok(replica)
+    }
+
+    /// Caches up to `cache_bytes` of the log's recently read and appended batches.
+    pub fn with_cache_bytes(mut self, cache_bytes: u64) -> Self {
+        self.log = self.log.with_cache_bytes(cache_bytes);
+        self
+    }
+
+    /// Bounds the log's segment indexes to `index_bytes`, adding an entry at most every
+    /// `index_interval_bytes`.
+    pub fn with_index(mut self, index_bytes: u64, index_interval_bytes: u64) -> Self {
+        self.log = self.log.with_index(index_bytes, index_interval_bytes);
+        self
+    }
+
+    /// Logs a warning for every append to the log that takes at least `slow_append`.
+    pub fn with_slow_append(mut self, slow_append: std::time::Duration) -> Self {
+        self.log = self.log.with_slow_append(slow_append);
+        self
+    }
+}
+
+impl<L: LogStore> Replica<L> {
+    /// Creates a replica that keeps its records in `log`.
+    pub fn with_log(log: L) -> Self {
         Self {
             // broker_id,
             // partition,
             log,
+            high_watermark: 0,
+            follower_offsets: HashMap::new(),
+            follower_caught_up: HashMap::new(),
+            leading_epoch: None,
+            leading_since: Instant::now(),
+            startup_high_watermark: None,
+            caught_up: false,
+            leader_high_watermark: 0,
+            producers: BTreeMap::new(),
+            epochs: BTreeMap::new(),
+            checked_epoch: None,
+            ongoing_txns: BTreeMap::new(),
+            aborted_txns: Vec::new(),
+        }
+    }
+
+    /// Notes that the next batch is appended in `epoch`, if that starts a new epoch.
+    pub fn start_epoch(&mut self, epoch: i32) {
+        if self.epochs.last_key_value().is_none_or(|(last, _)| *last < epoch) {
+            self.epochs.insert(epoch, self.log.end_offset());
+        }
+    }
+
+    /// The latest epoch at or before `epoch` that batches were appended in, along with the offset
+    /// it ended at, which is where a replica that followed it should truncate its log to.
+    pub fn end_offset_for(&self, epoch: i32) -> Option<(i32, u64)> {
+        let (found, _) = self.epochs.range(..=epoch).next_back()?;
+        let end = self
+            .epochs
+            .range(epoch + 1..)
+            .next()
+            .map_or(self.log.end_offset(), |(_, start)| *start);
+        Some((*found, end))
+    }
+
+    /// Removes the batch at `offset` and every batch after it, along with the epochs, producers
+    /// and transactions they were tracked in.
+    pub fn truncate(&mut self, offset: u64) -> Result<()> {
+        if offset >= self.log.end_offset() {
+            return Ok(());
+        }
+        self.log.truncate(offset)?;
+        self.high_watermark = self.high_watermark.min(offset);
+        self.epochs.retain(|_, start| *start < offset);
+        self.producers.clear();
+        self.ongoing_txns.clear();
+        self.aborted_txns.clear();
+        self.replay()
+    }
+
+    /// Updates the state of the producers of the appended record batches. Batches that weren't
+    /// written by an idempotent producer, or use an older format, are skipped.
+    pub fn track_producers(&mut self, records: &[u8]) {
+        for (producer_id, _, state) in producer_batches(records) {
+            self.producers.insert(producer_id, state);
+        }
+    }
+
+    /// Tracks the producers and transactions of every batch in the log, as they were when the
+    /// batches were appended.
+    pub fn replay(&mut self) -> Result<()> {
+        for offset in self.log.start_offset()..self.log.end_offset() {
+            let records = self.log.read(offset, offset + 1, u64::MAX)?;
+            self.track_producers(&records);
+            self.track_transactions(offset, &records);
+        }
+        Ok(())
+    }
+
+    /// Opens and ends transactions for the transactional batches appended at `offset`, and the
+    /// markers among them.
+    pub fn track_transactions(&mut self, offset: u64, records: &[u8]) {
+        for (producer_id, batch) in txn_batches(records) {
+            match batch {
+                TxnBatch::Records => {
+                    self.ongoing_txns.entry(producer_id).or_insert(offset);
+                }
+                TxnBatch::Commit => {
+                    self.ongoing_txns.remove(&producer_id);
+                }
+                TxnBatch::Abort => {
+                    if let Some(first_offset) = self.ongoing_txns.remove(&producer_id) {
+                        self.aborted_txns.push(AbortedTxn {
+                            producer_id,
+                            first_offset,
+                            last_offset: offset,
+                        });
+                    }
+                    // consumers can't read from before the start of the log anymore
+                    let start = self.log.start_offset();
+                    self.aborted_txns.retain(|t| t.last_offset >= start);
+                }
+            }
+        }
+    }
+
+    /// The offset below which every transaction has ended, which is as far as consumers
+    /// reading committed records may read.
+    pub fn last_stable_offset(&self) -> u64 {
+        let first_open = self.ongoing_txns.values().min().copied();
+        first_open.map_or(self.high_watermark, |o| o.min(self.high_watermark))
+    }
+
+    /// Whether `batch`, appended at `offset`, holds records of an aborted transaction. The
+    /// markers ending transactions are never aborted.
+    fn is_aborted(&self, offset: u64, batch: &[u8]) -> bool {
+        let header = match batch.get(..BATCH_HEADER_BYTES) {
+            Some(header) if header[16] == 2 => header,
+            _ => return false,
+        };
+        let attributes = i16::from_be_bytes([header[21], header[22]]);
+        if attributes & TRANSACTIONAL == 0 || attributes & CONTROL != 0 {
+            return false;
+        }
+        let producer_id = i64::from_be_bytes(header[43..51].try_into().unwrap());
+        self.aborted_between(offset, offset + 1)
+            .any(|t| t.producer_id == producer_id)
+    }
+
+    /// Reads like [`LogStore::read`], but leaves out the batches of aborted transactions. When
+    /// every batch read is left out, reading carries on past them so consumers make progress.
+    pub fn read_committed(&self, mut offset: u64, limit: u64, max_bytes: u64) -> Result<Vec<u8>> {
+        loop {
+            let records = self.log.read(offset, limit, max_bytes)?;
+            let mut committed = Vec::with_capacity(records.len());
+            let mut position = 0;
+            for batch in whole_batches(&records) {
+                if !self.is_aborted(offset, batch) {
+                    committed.extend_from_slice(batch);
+                }
+                position += batch.len();
+                offset += 1;
+            }
+            // a batch cut short by `max_bytes` is returned as it would be to any other consumer
+            committed.extend_from_slice(&records[position..]);
+            if !committed.is_empty() || position == 0 {
+                return Ok(committed);
+            }
+        }
+    }
+
+    /// The aborted transactions with batches between `start` and `end`, which consumers
+    /// reading committed records skip the producer's batches of.
+    pub fn aborted_between(&self, start: u64, end: u64) -> impl Iterator<Item = &AbortedTxn> {
+        self.aborted_txns
+            .iter()
+            .filter(move |t| t.first_offset < end && t.last_offset >= start)
+    }
+
+    /// Checks that every idempotent producer's batches continue its sequence, so that batches
+    /// retried out of order are rejected rather than appended out of order. The client then
+    /// retries them in order.
+    pub fn check_sequences(&self, records: &[u8]) -> Result<(), ResponseError> {
+        let mut producers = self.producers.clone();
+        for (producer_id, base_sequence, state) in producer_batches(records) {
+            if let Some(last) = producers.get(&producer_id) {
+                let expected = match state.epoch.cmp(&last.epoch) {
+                    Less => return Err(InvalidProducerEpoch),
+                    Equal => last.last_sequence.wrapping_add(1),
+                    Greater => 0,
+                };
+                match base_sequence.cmp(&expected) {
+                    Less if state.epoch == last.epoch => return Err(DuplicateSequenceNumber),
+                    Less | Greater => return Err(OutOfOrderSequenceNumber),
+                    Equal => {}
+                }
+            }
+            producers.insert(producer_id, state);
+        }
+        Ok(())
+    }
+
+    /// Moves the high watermark up to the highest offset that a majority of the ISR has reached,
+    /// given each member's log end offset. Returns whether it moved.
+    pub fn advance_high_watermark(&mut self, isr_offsets: &[u64]) -> bool {
+        let mut offsets = isr_offsets.to_vec();
+        offsets.sort_unstable_by(|a, b| b.cmp(a));
+        match offsets.get(offsets.len() / 2) {
+            Some(&offset) if offset > self.high_watermark => {
+                self.high_watermark = offset;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Advances the high watermark of a replica we lead, using our own log end offset and the
+    /// offsets followers last fetched from.
+    pub fn update_high_watermark(&mut self, leader: BrokerId, isr: &[i32]) -> bool {
+        let offsets: Vec<u64> = isr
+            .iter()
+            .map(|id| match *id == leader.0 {
+                true => self.log.end_offset(),
+                false => self.follower_offsets.get(id).copied().unwrap_or(0),
+            })
+            .collect();
+        self.advance_high_watermark(&offsets)
+    }
+
+    /// Notes that we lead the replica in `epoch` as of `now`. When that starts a new leadership,
+    /// what followers reported to us before is forgotten and their lag is measured from `now`.
+    pub fn lead(&mut self, epoch: i32, now: Instant) {
+        if self.leading_epoch != Some(epoch) {
+            self.leading_epoch = Some(epoch);
+            self.leading_since = now;
+            self.follower_offsets.clear();
+            self.follower_caught_up.clear();
         }
     }
+
+    /// Records the log end offset a follower reported by fetching at `now`.
+    pub fn update_follower(&mut self, follower: i32, offset: u64, now: Instant) {
+        self.follower_offsets.insert(follower, offset);
+        if offset >= self.log.end_offset() {
+            self.follower_caught_up.insert(follower, now);
+        }
+    }
+
+    /// Whether a follower last caught up with our log more than `max_lag` before `now`, which
+    /// keeps it out of the ISR until it catches up again.
+    pub fn is_lagging(&self, follower: i32, max_lag: Duration, now: Instant) -> bool {
+        let caught_up = self.follower_caught_up.get(&follower).copied();
+        now.saturating_duration_since(caught_up.unwrap_or(self.leading_since)) > max_lag
+    }
+
+    /// Records the high watermark the leader reported in a fetch response, after appending what
+    /// it returned. The replica is caught up once its log reaches the first one it was told.
+    pub fn observe_leader_high_watermark(&mut self, high_watermark: u64) {
+        self.leader_high_watermark = high_watermark;
+        let target = *self.startup_high_watermark.get_or_insert(high_watermark);
+        if self.log.end_offset() >= target {
+            self.caught_up = true;
+        }
+    }
+
+    /// How many offsets the furthest behind follower in the ISR is from our log end offset.
+    pub fn follower_lag(&self, leader: BrokerId, isr: &[i32]) -> u64 {
+        isr.iter()
+            .filter(|id| **id != leader.0)
+            .map(|id| self.follower_offsets.get(id).copied().unwrap_or(0))
+            .map(|offset| self.log.end_offset().saturating_sub(offset))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// The producer id, base sequence and resulting producer state of each batch written by an
+/// idempotent producer.
+fn producer_batches(mut records: &[u8]) -> impl Iterator<Item = (i64, i32, ProducerState)> + '_ {
+    std::iter::from_fn(move || {
+        while records.len() >= BATCH_HEADER_BYTES {
+            let i32_at = |i: usize| i32::from_be_bytes(records[i..i + 4].try_into().unwrap());
+            let i64_at = |i: usize| i64::from_be_bytes(records[i..i + 8].try_into().unwrap());
+            let magic = records[16];
+            let producer_id = i64_at(43);
+            let base_sequence = i32_at(53);
+            let batch = ProducerState {
+                epoch: i16::from_be_bytes([records[51], records[52]]),
+                last_sequence: base_sequence.wrapping_add(i32_at(23)),
+                last_timestamp: i64_at(35),
+            };
+
+            // the length counts everything after the offset and the length itself
+            let len = 12 + i32_at(8).max(0) as usize;
+            records = &records[len.min(records.len())..];
+            if magic == 2 && producer_id >= 0 {
+                return Some((producer_id, base_sequence, batch));
+            }
+        }
+        None
+    })
+}
+
+/// The producer id and part in a transaction of each transactional batch. Batches outside of a
+/// transaction, and markers that can't be decoded, are skipped.
+fn txn_batches(records: &[u8]) -> impl Iterator<Item = (i64, TxnBatch)> + '_ {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        while let Some(header) = records.get(position..position + BATCH_HEADER_BYTES) {
+            let len = 12 + i32::from_be_bytes(header[8..12].try_into().unwrap()).max(0) as usize;
+            let batch = &records[position..(position + len).min(records.len())];
+            position += len;
+            let attributes = i16::from_be_bytes([header[21], header[22]]);
+            let producer_id = i64::from_be_bytes(header[43..51].try_into().unwrap());
+            if header[16] != 2 || attributes & TRANSACTIONAL == 0 {
+                continue;
+            }
+            if attributes & CONTROL == 0 {
+                return Some((producer_id, TxnBatch::Records));
+            }
+            let marker = RecordBatchDecoder::decode(&mut Bytes::copy_from_slice(batch))
+                .ok()
+                .and_then(|r| r.into_iter().next())
+                .and_then(|r| r.key)
+                .filter(|key| key.len() >= 4);
+            match marker.map(|key| i16::from_be_bytes([key[2], key[3]])) {
+                Some(ABORT_MARKER) => return Some((producer_id, TxnBatch::Abort)),
+                Some(COMMIT_MARKER) => return Some((producer_id, TxnBatch::Commit)),
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+/// The directories replica logs are placed in. Brokers that keep their logs in memory have none.
+#[derive(Debug, Default)]
+pub struct LogDirs {
+    dirs: Vec<PathBuf>,
+    next: AtomicUsize,
+}
+
+impl LogDirs {
+    /// Creates any missing directories, skipping those that can't be written to. Fails if none
+    /// of the directories are usable.
+    pub fn new(dirs: &[PathBuf]) -> Result<Self> {
+        let mut usable = Vec::new();
+        for dir in dirs {
+            match Self::check(dir) {
+                Ok(()) => usable.push(dir.clone()),
+                Err(e) => tracing::error!(?dir, %e, "log dir is not writable"),
+            }
+        }
+
+        if usable.is_empty() {
+            return Err(anyhow::anyhow!(
+                "none of the log dirs {:?} are writable",
+                dirs
+            ));
+        }
+        Ok(Self {
+            dirs: usable,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    fn check(dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        tempfile::tempfile_in(dir)?;
+        Ok(())
+    }
+
+    /// The directories that were usable when the broker started.
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Picks the directory for the next replica.
+    pub fn next(&self) -> &Path {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.dirs[i % self.dirs.len()]
+    }
+
+    /// Moves the partition directories whose names aren't in `known` into an `.orphan` directory
+    /// within their log dir, returning where each was moved to. They are kept rather than
+    /// deleted in case the partitions are only missing because the store is behind.
+    pub fn quarantine_orphans(&self, known: &HashSet<String>) -> Result<Vec<PathBuf>> {
+        let mut quarantined = Vec::new();
+        for dir in &self.dirs {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !entry.file_type()?.is_dir() || name.starts_with('.') || known.contains(&name) {
+                    continue;
+                }
+                let orphans = dir.join(ORPHAN_DIR);
+                std::fs::create_dir_all(&orphans)?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+                let to = orphans.join(format!("{}-{}", name, now));
+                std::fs::rename(entry.path(), &to)?;
+                tracing::warn!(from = ?entry.path(), ?to, "quarantined orphan partition dir");
+                quarantined.push(to);
+            }
+        }
+        Ok(quarantined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AbortedTxn, LogDirs, Replica};
+    use crate::broker::handler::test::transactional_batch;
+    use crate::broker::log::LogStore;
+    use crate::broker::txn::marker_batch;
+    use crate::broker::state::partition::{Partition, PartitionIdx};
+    use crate::broker::BrokerId;
+    use anyhow::Result;
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    const SEGMENT_BYTES: u64 = 1024 * 1024;
+
+    fn partition(topic: &str, idx: i32) -> Partition {
+        Partition {
+            id: Uuid::new_v4(),
+            idx: PartitionIdx(idx),
+            topic: topic.to_string(),
+            isr: vec![1],
+            assigned_replicas: vec![1],
+            leader: BrokerId(1),
+            leader_epoch: 0,
+            adding_replicas: vec![],
+            removing_replicas: vec![],
+        }
+    }
+
+    #[test]
+    fn dir_per_topic_partition() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let _a = Replica::new(dir.path(), BrokerId(1), partition("a", 0), SEGMENT_BYTES);
+        let _b = Replica::new(dir.path(), BrokerId(1), partition("b", 0), SEGMENT_BYTES);
+        assert!(dir.path().join("a-0").join("0.log").is_file());
+        assert!(dir.path().join("b-0").join("0.log").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn unwritable() -> Result<()> {
+        // a directory can't be created underneath a regular file
+        let file = tempfile::NamedTempFile::new()?;
+        let dir = file.path().join("log");
+        assert!(LogDirs::new(&[dir]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn round_robin() -> Result<()> {
+        let (a, b) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let missing = a.path().join("missing");
+        let dirs = LogDirs::new(&[missing.clone(), b.path().to_path_buf()])?;
+        assert!(missing.is_dir());
+
+        assert_eq!(dirs.next(), missing);
+        assert_eq!(dirs.next(), b.path());
+        assert_eq!(dirs.next(), missing);
+        Ok(())
+    }
+
+    #[test]
+    fn quarantine_orphans() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dirs = LogDirs::new(&[dir.path().to_path_buf()])?;
+        let _a = Replica::new(dir.path(), BrokerId(1), partition("a", 0), SEGMENT_BYTES);
+        let _b = Replica::new(dir.path(), BrokerId(1), partition("b", 0), SEGMENT_BYTES);
+
+        // the store only knows of a-0
+        let known = HashSet::from(["a-0".to_string()]);
+        let quarantined = dirs.quarantine_orphans(&known)?;
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].join("0.log").is_file());
+        assert!(dir.path().join("a-0").join("0.log").is_file());
+        assert!(!dir.path().join("b-0").exists());
+
+        // quarantined dirs are left alone from then on
+        assert!(dirs.quarantine_orphans(&known)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn high_watermark() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut replica = Replica::new(dir.path(), BrokerId(1), partition("a", 0), SEGMENT_BYTES);
+
+        // only one of three members has the records
+        assert!(!replica.advance_high_watermark(&[3, 0, 0]));
+        assert_eq!(replica.high_watermark, 0);
+
+        assert!(replica.advance_high_watermark(&[3, 2, 0]));
+        assert_eq!(replica.high_watermark, 2);
+
+        // two members need both to have the records
+        assert!(!replica.advance_high_watermark(&[3, 1]));
+        assert!(replica.advance_high_watermark(&[3, 3]));
+        assert_eq!(replica.high_watermark, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn truncate() -> Result<()> {
+        let mut replica = Replica::in_memory(&partition("a", 0), SEGMENT_BYTES);
+        let batches = [
+            (0, transactional_batch(7, 0, 0, 1)?.to_vec()),
+            (0, marker_batch(7, 0, false)?.to_vec()),
+            (1, transactional_batch(7, 0, 1, 1)?.to_vec()),
+        ];
+        for (epoch, batch) in batches {
+            replica.start_epoch(epoch);
+            let offset = replica.log.end_offset();
+            replica.log.append(&batch)?;
+            replica.track_transactions(offset, &batch);
+        }
+        replica.high_watermark = 3;
+        assert_eq!(replica.ongoing_txns.get(&7), Some(&2));
+
+        // the transaction opened in epoch 1 goes with it, the aborted one stays
+        replica.truncate(2)?;
+        assert_eq!(replica.log.end_offset(), 2);
+        assert_eq!(replica.high_watermark, 2);
+        assert_eq!(replica.epochs.keys().collect::<Vec<_>>(), vec![&0]);
+        assert!(replica.ongoing_txns.is_empty());
+        let aborted = AbortedTxn {
+            producer_id: 7,
+            first_offset: 0,
+            last_offset: 1,
+        };
+        assert_eq!(replica.aborted_txns, vec![aborted]);
+        Ok(())
+    }
 }
@@ -0,0 +1,124 @@
+//! Policies for picking the replica a consumer should fetch a partition from.
+
+use std::fmt::Debug;
+
+use crate::broker::config::Peer;
+use crate::broker::state::partition::Partition;
+use crate::broker::BrokerId;
+
+/// Picks which replica of a partition serves a consumer's fetches.
+pub trait ReplicaSelector: Debug + Send + Sync {
+    /// The broker the consumer in `rack` should fetch `partition` from, given the live
+    /// `brokers`. An empty rack means the consumer didn't say where it is.
+    fn select(&self, partition: &Partition, brokers: &[Peer], rack: &str) -> BrokerId;
+}
+
+/// Always sends consumers to the leader.
+#[derive(Debug, Default)]
+pub struct LeaderSelector;
+
+impl ReplicaSelector for LeaderSelector {
+    fn select(&self, partition: &Partition, _brokers: &[Peer], _rack: &str) -> BrokerId {
+        partition.leader
+    }
+}
+
+/// Sends consumers to an in sync replica in their own rack, preferring the leader, and to the
+/// leader when no such replica is live.
+#[derive(Debug, Default)]
+pub struct RackAwareSelector;
+
+impl ReplicaSelector for RackAwareSelector {
+    fn select(&self, partition: &Partition, brokers: &[Peer], rack: &str) -> BrokerId {
+        if rack.is_empty() {
+            return partition.leader;
+        }
+        let in_rack = |id: &BrokerId| {
+            brokers
+                .iter()
+                .any(|b| b.id == *id && b.rack.as_deref() == Some(rack))
+        };
+        if in_rack(&partition.leader) {
+            return partition.leader;
+        }
+        partition
+            .isr
+            .iter()
+            .map(|id| BrokerId(*id))
+            .find(in_rack)
+            .unwrap_or(partition.leader)
+    }
+}
+
+/// The replica selectors that can be configured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaSelectorKind {
+    #[default]
+    Leader,
+    RackAware,
+}
+
+impl ReplicaSelectorKind {
+    pub fn build(&self) -> Box<dyn ReplicaSelector> {
+        match self {
+            ReplicaSelectorKind::Leader => Box::new(LeaderSelector),
+            ReplicaSelectorKind::RackAware => Box::new(RackAwareSelector),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LeaderSelector, RackAwareSelector, ReplicaSelector};
+    use crate::broker::config::Peer;
+    use crate::broker::state::partition::{Partition, PartitionIdx};
+    use crate::broker::BrokerId;
+    use uuid::Uuid;
+
+    fn peer(id: i32, rack: &str) -> Peer {
+        Peer {
+            id: BrokerId(id),
+            ip: "127.0.0.1".parse().unwrap(),
+            port: 8844,
+            rack: Some(rack.to_string()),
+            advertised_host: None,
+            advertised_port: None,
+        }
+    }
+
+    fn partition() -> Partition {
+        Partition {
+            id: Uuid::new_v4(),
+            idx: PartitionIdx(0),
+            topic: "test".to_string(),
+            isr: vec![1, 2],
+            assigned_replicas: vec![1, 2, 3],
+            leader: BrokerId(1),
+            leader_epoch: 0,
+            adding_replicas: vec![],
+            removing_replicas: vec![],
+        }
+    }
+
+    #[test]
+    fn rack_aware() {
+        let brokers = vec![peer(1, "a"), peer(2, "b"), peer(3, "c")];
+        let selector = RackAwareSelector;
+        assert_eq!(selector.select(&partition(), &brokers, "b"), BrokerId(2));
+        assert_eq!(selector.select(&partition(), &brokers, "a"), BrokerId(1));
+        // broker 3 is in the rack, but not in sync
+        assert_eq!(selector.select(&partition(), &brokers, "c"), BrokerId(1));
+        assert_eq!(selector.select(&partition(), &brokers, "d"), BrokerId(1));
+        assert_eq!(selector.select(&partition(), &brokers, ""), BrokerId(1));
+    }
+
+    #[test]
+    fn leader() {
+        let brokers = vec![peer(1, "a"), peer(2, "b")];
+        assert_eq!(
+            LeaderSelector.select(&partition(), &brokers, "b"),
+            BrokerId(1)
+        );
+    }
+}
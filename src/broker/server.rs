@@ -1,67 +1,362 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use futures::FutureExt;
-use tokio::net::TcpListener;
 
-use crate::broker::tcp;
+use crate::broker::{cleaner, controller, fetcher, isr, offsets, purgatory, tcp};
 
 use kafka_protocol::messages::*;
 
 use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, Semaphore};
+use uuid::Uuid;
 
+use crate::broker::fsm::Transition;
 
 use crate::broker::state::Store;
 use crate::raft::client::RaftClient;
 
 use crate::broker::config::BrokerConfig;
+use crate::broker::context::RequestContext;
+use crate::broker::error::{kafka_error_code, JosefineError};
+use crate::broker::handler::api_versions::error_response;
 use crate::broker::Broker;
+use crate::health::Health;
 use crate::Shutdown;
 
 pub struct Server {
     address: SocketAddr,
     config: BrokerConfig,
+    health: Option<Health>,
 }
 
 impl Server {
-    pub fn new(config: BrokerConfig) -> Self {
+    pub fn new(config: BrokerConfig, health: Option<Health>) -> Self {
         let address = SocketAddr::new(config.ip, config.port);
-        Server { address, config }
+        Server {
+            address,
+            config,
+            health,
+        }
     }
 
     pub async fn run(self, client: RaftClient, store: Store, shutdown: Shutdown) -> Result<()> {
         tracing::info!("broker listening on {}:{}", self.config.ip, self.config.port);
-        let listener = TcpListener::bind(self.address).await?;
+        let listener = tcp::bind(self.address, self.config.socket_listen_backlog)?;
         let (in_tx, out_tx) = tokio::sync::mpsc::unbounded_channel();
-        let (task, tcp_receiver) =
-            tcp::receive_task(listener, in_tx, shutdown.clone()).remote_handle();
+        let (task, tcp_receiver) = tcp::receive_task(
+            listener,
+            in_tx,
+            self.config.max_in_flight_requests_per_connection,
+            self.config.num_network_threads,
+            shutdown.clone(),
+        )
+        .remote_handle();
         tokio::spawn(task);
 
-        let ctrl = Broker::new(store, client, self.config);
-        let (task, handle_messages) = handle_messages(ctrl, out_tx, shutdown).remote_handle();
+        let (c, s) = (client.clone(), store.clone());
+        tokio::spawn(async move {
+            match bootstrap_cluster_id(c, s).await {
+                Ok(cluster_id) => tracing::info!(%cluster_id, "cluster id"),
+                Err(e) => tracing::error!(%e, "could not bootstrap cluster id"),
+            }
+        });
+
+        let ctrl = Broker::new(store, client, self.config)?;
+        ctrl.quarantine_orphans()?;
+        ctrl.recover_logs().await?;
+        if let Some(health) = &self.health {
+            health.set_recovered();
+        }
+        let ctrl = Arc::new(ctrl);
+        tokio::spawn(cleaner::run(ctrl.clone(), shutdown.clone()));
+        tokio::spawn(controller::run(ctrl.clone(), shutdown.clone()));
+        tokio::spawn(offsets::run(ctrl.clone(), shutdown.clone()));
+        tokio::spawn(fetcher::run(ctrl.clone(), shutdown.clone()));
+        tokio::spawn(isr::run(ctrl.clone(), shutdown.clone()));
+        tokio::spawn(register(ctrl.clone(), shutdown.clone()));
+        let (task, handle_messages) =
+            handle_messages(ctrl.clone(), out_tx, shutdown).remote_handle();
         tokio::spawn(task);
 
-        let (_, _) = tokio::try_join!(tcp_receiver, handle_messages)?;
-        Ok(())
+        // flush even when serving failed, which is when the logs are most likely to be behind
+        let served = tokio::try_join!(tcp_receiver, handle_messages);
+        flush_logs(&ctrl).await;
+        served.map(|_| ())
+    }
+}
+
+/// Flushes every partition log so that acknowledged writes survive the shutdown, giving up after
+/// the configured timeout.
+async fn flush_logs(broker: &Broker) {
+    let timeout = Duration::from_millis(broker.config.shutdown_flush_timeout_ms);
+    match tokio::time::timeout(timeout, broker.flush_logs()).await {
+        Ok(Ok(flushed)) => tracing::info!(flushed, "flushed logs"),
+        Ok(Err(e)) => tracing::error!(%e, "could not flush logs"),
+        Err(_) => tracing::warn!(?timeout, "timed out flushing logs"),
+    }
+}
+
+/// How long to wait before proposing again after a proposal made at startup fails, which it does
+/// until raft has elected a leader. The wait doubles with every failure, up to the maximum.
+const PROPOSAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_PROPOSAL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Proposes a transition until it is committed, returning the response. Retries share the
+/// request id of the first attempt, so a transition that did commit isn't applied again.
+async fn propose_with_retry(client: &RaftClient, transition: Transition) -> Result<Vec<u8>> {
+    let request_id = Uuid::new_v4();
+    let command = transition.serialize()?;
+    let mut backoff = PROPOSAL_BACKOFF;
+    loop {
+        match client.propose_with_id(request_id, command.clone()).await {
+            Ok(res) => return Ok(res),
+            Err(e) => tracing::debug!(%e, ?backoff, "proposal failed, retrying"),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_PROPOSAL_BACKOFF);
+    }
+}
+
+/// Proposes a new cluster id if one has not been persisted yet. Since the first id to be committed
+/// wins, every node ends up reporting the same value.
+async fn bootstrap_cluster_id(client: RaftClient, store: Store) -> Result<Uuid> {
+    if let Some(cluster_id) = store.get_cluster_id()? {
+        return Ok(cluster_id);
+    }
+
+    let res = propose_with_retry(&client, Transition::SetClusterId(Uuid::new_v4())).await?;
+    Ok(bincode::deserialize(&res)?)
+}
+
+/// Registers the broker as live, retrying until raft has a leader to commit it, and tries to
+/// deregister it again on shutdown.
+async fn register(broker: Arc<Broker>, mut shutdown: Shutdown) -> Result<()> {
+    let id = broker.config.id;
+    let registration = Transition::RegisterBroker(broker.peer());
+    let registration = propose_with_retry(&broker.client, registration);
+    tokio::select! {
+        _ = shutdown.wait() => return Ok(()),
+        res = registration => match res {
+            Ok(_) => tracing::info!(%id, "registered broker"),
+            Err(e) => tracing::error!(%e, "could not register broker"),
+        },
+    }
+
+    shutdown.wait().await?;
+    // raft may be shutting down as well, in which case the broker stays registered
+    if let Err(e) = propose(&broker.client, Transition::DeregisterBroker(id)).await {
+        tracing::warn!(%e, "could not deregister broker");
     }
+    Ok(())
 }
 
+async fn propose(client: &RaftClient, transition: Transition) -> Result<()> {
+    client.propose(transition.serialize()?).await?;
+    Ok(())
+}
+
+/// Dispatches each request to its own task, so that requests for different partitions are
+/// handled concurrently, and a slow request doesn't hold up reading from the network. At most
+/// `request_handlers` requests are handled at once, with the rest waiting their turn, though
+/// requests waiting in a purgatory don't count. Each connection writes its responses back in
+/// request order, however they finish here.
 async fn handle_messages(
-    ctrl: Broker,
-    mut out_tx: UnboundedReceiver<(RequestKind, oneshot::Sender<ResponseKind>)>,
+    ctrl: Arc<Broker>,
+    mut out_tx: UnboundedReceiver<(RequestKind, RequestContext, oneshot::Sender<ResponseKind>)>,
     mut shutdown: Shutdown,
 ) -> Result<()> {
+    let handlers = Arc::new(Semaphore::new(ctrl.config.request_handlers));
     loop {
         tokio::select! {
             _ = shutdown.wait() => break,
 
-            Some((msg, cb)) = out_tx.recv() => {
-                let res = ctrl.handle_request(msg).await?;
-                cb.send(res).unwrap();
+            Some((msg, ctx, cb)) = out_tx.recv() => {
+                let ctrl = ctrl.clone();
+                let handlers = handlers.clone();
+                tokio::spawn(async move {
+                    let Ok(permit) = handlers.acquire_owned().await else {
+                        return;
+                    };
+                    purgatory::holding(permit, respond(&ctrl, msg, ctx, cb)).await;
+                });
             }
         }
     }
 
     Ok(())
 }
+
+/// Handles a request, answering `cb` with the response.
+async fn respond(
+    ctrl: &Broker,
+    msg: RequestKind,
+    ctx: RequestContext,
+    cb: oneshot::Sender<ResponseKind>,
+) {
+    match ctrl.handle_request_within(msg, ctx).await {
+        Ok(res) => {
+            let _ = cb.send(res);
+        }
+        Err(e) => {
+            // answer with the error where the response has room for it, and otherwise leave the
+            // client to find the connection closed
+            let code = kafka_error_code(&JosefineError::classify(&e));
+            tracing::error!(%e, code, "could not handle request");
+            if let Some(res) = ctx.api_key.and_then(|k| error_response(k, code)) {
+                let _ = cb.send(res);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+    use kafka_protocol::messages::{
+        ApiVersionsRequest, FetchRequest, RequestKind, ResponseKind, TopicName,
+    };
+    use tokio::sync::mpsc::Receiver;
+    use tokio::sync::oneshot;
+
+    use crate::broker::fsm::JosefineFsm;
+    use crate::broker::handler::test::{new_broker, new_topic};
+    use crate::broker::state::Store;
+    use crate::raft::client::{ProposalRequest, RaftClient};
+    use crate::raft::fsm::Fsm;
+    use crate::raft::rpc::{Response, ResponseError};
+    use crate::raft::{Entry, EntryType};
+    use crate::kafka::util::ToStrBytes;
+    use crate::Shutdown;
+
+    /// Commits proposals straight to `store` like `apply_proposals`, but only after refusing the
+    /// first `refusals` of them, as happens before raft has elected a leader.
+    fn refuse_then_apply(mut rx: Receiver<ProposalRequest>, store: Store, mut refusals: usize) {
+        let mut fsm = JosefineFsm::new(store);
+        tokio::spawn(async move {
+            let mut index = 0;
+            while let Some((proposal, cb)) = rx.recv().await {
+                if refusals > 0 {
+                    refusals -= 1;
+                    let _ = cb.send(Err(ResponseError::new("no known leader")));
+                    continue;
+                }
+                index += 1;
+                let entry = Entry {
+                    entry_type: EntryType::Data {
+                        data: proposal.get(),
+                    },
+                    term: 1,
+                    index,
+                };
+                let res = fsm.apply(&entry).map(Response::new);
+                let _ = cb.send(res.map_err(|e| ResponseError::new(e.to_string())));
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn bootstraps_once_raft_has_a_leader() -> Result<()> {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let client = RaftClient::new(tx, Duration::from_millis(100));
+        let store = Store::new(sled::open(tempfile::tempdir()?)?)?;
+        refuse_then_apply(rx, store.clone(), 2);
+
+        let cluster_id = super::bootstrap_cluster_id(client, store.clone()).await?;
+        assert_eq!(store.get_cluster_id()?, Some(cluster_id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn registers_once_raft_has_a_leader() -> Result<()> {
+        let (rx, broker) = new_broker();
+        let broker = Arc::new(broker);
+        refuse_then_apply(rx, broker.store.clone(), 2);
+
+        let shutdown = Shutdown::new();
+        tokio::spawn(super::register(broker.clone(), shutdown.clone()));
+        let registered = async {
+            while broker.store.get_registered_brokers()?.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        tokio::time::timeout(Duration::from_secs(5), registered).await??;
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_request_does_not_block_others() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.request_handlers = 2;
+        new_topic(&broker, "test", 1)?;
+        let (in_tx, out_tx) = tokio::sync::mpsc::unbounded_channel();
+        let shutdown = Shutdown::new();
+        tokio::spawn(super::handle_messages(Arc::new(broker), out_tx, shutdown.clone()));
+
+        // waits for records that never arrive
+        let mut t = FetchTopic::default();
+        t.topic = TopicName("test".to_string().to_str_bytes());
+        t.partitions.push(FetchPartition::default());
+        let mut fetch = FetchRequest::default();
+        fetch.replica_id = (-1).into();
+        fetch.max_wait_ms = 10_000;
+        fetch.min_bytes = 1;
+        fetch.topics.push(t);
+        let (slow_tx, mut slow_rx) = oneshot::channel();
+        in_tx.send((RequestKind::FetchRequest(fetch), Default::default(), slow_tx))?;
+
+        let (cb_tx, cb_rx) = oneshot::channel();
+        let req = RequestKind::ApiVersionsRequest(ApiVersionsRequest::default());
+        in_tx.send((req, Default::default(), cb_tx))?;
+        let res = tokio::time::timeout(Duration::from_secs(1), cb_rx).await??;
+        assert!(matches!(res, ResponseKind::ApiVersionsResponse(_)));
+        assert!(slow_rx.try_recv().is_err());
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waiting_requests_give_up_their_handler() -> Result<()> {
+        let (_rx, mut broker) = new_broker();
+        broker.config.request_handlers = 1;
+        new_topic(&broker, "test", 1)?;
+        let (in_tx, out_tx) = tokio::sync::mpsc::unbounded_channel();
+        let shutdown = Shutdown::new();
+        tokio::spawn(super::handle_messages(Arc::new(broker), out_tx, shutdown.clone()));
+
+        // more fetches wait for records than there are handlers
+        let mut waiting = vec![];
+        for _ in 0..3 {
+            let mut t = FetchTopic::default();
+            t.topic = TopicName("test".to_string().to_str_bytes());
+            t.partitions.push(FetchPartition::default());
+            let mut fetch = FetchRequest::default();
+            fetch.replica_id = (-1).into();
+            fetch.max_wait_ms = 10_000;
+            fetch.min_bytes = 1;
+            fetch.topics.push(t);
+            let (slow_tx, slow_rx) = oneshot::channel();
+            in_tx.send((RequestKind::FetchRequest(fetch), Default::default(), slow_tx))?;
+            waiting.push(slow_rx);
+        }
+
+        let (cb_tx, cb_rx) = oneshot::channel();
+        let req = RequestKind::ApiVersionsRequest(ApiVersionsRequest::default());
+        in_tx.send((req, Default::default(), cb_tx))?;
+        let res = tokio::time::timeout(Duration::from_secs(1), cb_rx).await??;
+        assert!(matches!(res, ResponseKind::ApiVersionsResponse(_)));
+        for mut slow_rx in waiting {
+            assert!(slow_rx.try_recv().is_err());
+        }
+        shutdown.shutdown();
+        Ok(())
+    }
+}
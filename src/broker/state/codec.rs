@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The version byte of values serialized with bincode.
+const BINCODE_VERSION: u8 = 1;
+/// The version byte of values serialized as JSON.
+const JSON_VERSION: u8 = 2;
+
+/// The format values are written to the store in. Every value starts with a version byte naming
+/// its format, so values can be read whichever format the store is configured to write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreCodec {
+    /// Compact, but opaque.
+    #[default]
+    Bincode,
+    /// Larger, but easy to inspect.
+    Json,
+}
+
+impl StoreCodec {
+    pub(crate) fn version(&self) -> u8 {
+        match self {
+            StoreCodec::Bincode => BINCODE_VERSION,
+            StoreCodec::Json => JSON_VERSION,
+        }
+    }
+
+    /// The format a value was written in.
+    pub(crate) fn of(bytes: &[u8]) -> Result<StoreCodec> {
+        match bytes.first() {
+            Some(&BINCODE_VERSION) => Ok(StoreCodec::Bincode),
+            Some(&JSON_VERSION) => Ok(StoreCodec::Json),
+            Some(version) => anyhow::bail!("unknown store value version {}", version),
+            None => anyhow::bail!("empty store value"),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut buf = vec![self.version()];
+        match self {
+            StoreCodec::Bincode => bincode::serialize_into(&mut buf, value)?,
+            StoreCodec::Json => serde_json::to_writer(&mut buf, value)?,
+        }
+        Ok(buf)
+    }
+
+    pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        let value = match bytes.split_first() {
+            Some((&BINCODE_VERSION, value)) => bincode::deserialize(value)?,
+            Some((&JSON_VERSION, value)) => serde_json::from_slice(value)?,
+            Some((version, _)) => anyhow::bail!("unknown store value version {}", version),
+            None => anyhow::bail!("empty store value"),
+        };
+        Ok(value)
+    }
+}
+
+/// A map written as a sequence of key value pairs, for keys that JSON, which only has string
+/// keys, can't write. Bincode writes a map and such a sequence alike.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Entries<K: Ord, V>(pub BTreeMap<K, V>);
+
+impl<K: Ord + Serialize, V: Serialize> Serialize for Entries<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(&self.0)
+    }
+}
+
+impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for Entries<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<(K, V)>::deserialize(deserializer)?;
+        Ok(Entries(entries.into_iter().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use uuid::Uuid;
+
+    use super::{Entries, StoreCodec};
+    use crate::broker::state::partition::PartitionIdx;
+    use crate::broker::state::topic::Topic;
+    use crate::broker::BrokerId;
+
+    fn topic() -> Topic {
+        Topic {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            partitions: (0..2).map(|i| (PartitionIdx(i), vec![BrokerId(1)])).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let topic = topic();
+        for codec in [StoreCodec::Bincode, StoreCodec::Json] {
+            let bytes = codec.encode(&topic)?;
+            let decoded: Topic = StoreCodec::decode(&bytes)?;
+            assert_eq!(decoded, topic, "{:?}", codec);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn entries_read_as_map() -> Result<()> {
+        let map = [(("a".to_string(), 0), 1), (("b".to_string(), 1), 2)]
+            .into_iter()
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let bytes = StoreCodec::Bincode.encode(&map)?;
+        let entries: Entries<(String, i32), i32> = StoreCodec::decode(&bytes)?;
+        assert_eq!(entries.0, map);
+        Ok(())
+    }
+
+    #[test]
+    fn json_is_readable() -> Result<()> {
+        let bytes = StoreCodec::Json.encode(&topic())?;
+        let json: serde_json::Value = serde_json::from_slice(&bytes[1..])?;
+        assert_eq!(json["name"], "test");
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_version() -> Result<()> {
+        let mut bytes = StoreCodec::Bincode.encode(&topic())?;
+        bytes[0] = 0xff;
+        let err = StoreCodec::decode::<Topic>(&bytes).unwrap_err();
+        assert!(err.to_string().contains("unknown store value version"));
+        Ok(())
+    }
+}
@@ -1,4 +1,420 @@
-#[derive(Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
+use std::collections::{BTreeMap, BTreeSet};
+
+use kafka_protocol::ResponseError;
+
+/// The lifecycle of a consumer group.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Default)]
+pub enum GroupState {
+    /// The group has no members.
+    #[default]
+    Empty,
+    /// Members have to (re)join before a new generation can start.
+    PreparingRebalance,
+    /// Every member has joined, and the group is waiting on the leader's assignment.
+    CompletingRebalance,
+    /// Every member has an assignment for the current generation.
+    Stable,
+    /// The group has been removed and can no longer be used.
+    Dead,
+}
+
+/// Errors returned when an operation is not allowed in the group's current state.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GroupError {
+    RebalanceInProgress,
+    UnknownMemberId,
+    IllegalGeneration,
+    InconsistentGroupProtocol,
+    CoordinatorNotAvailable,
+    FencedInstanceId,
+    /// The group belongs to an offsets topic partition led by another broker.
+    NotCoordinator,
+}
+
+impl GroupError {
+    pub fn code(&self) -> i16 {
+        match self {
+            GroupError::RebalanceInProgress => ResponseError::RebalanceInProgress.code(),
+            GroupError::UnknownMemberId => ResponseError::UnknownMemberId.code(),
+            GroupError::IllegalGeneration => ResponseError::IllegalGeneration.code(),
+            GroupError::InconsistentGroupProtocol => {
+                ResponseError::InconsistentGroupProtocol.code()
+            }
+            GroupError::CoordinatorNotAvailable => ResponseError::CoordinatorNotAvailable.code(),
+            GroupError::FencedInstanceId => ResponseError::FencedInstanceId.code(),
+            GroupError::NotCoordinator => ResponseError::NotCoordinator.code(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Default)]
+pub struct Member {
+    pub id: String,
+    /// The `group.instance.id` of a static member, which keeps its place in the group across
+    /// restarts.
+    pub instance_id: Option<String>,
+    pub protocol_type: String,
+    /// Supported protocols and their metadata, in order of preference.
+    pub protocols: Vec<(String, Vec<u8>)>,
+    /// How long the member waits on the rest of the group to join or sync during a rebalance.
+    pub rebalance_timeout_ms: i32,
+    /// The assignment for the current generation, set by the leader on sync.
+    pub assignment: Vec<u8>,
+}
+
+/// An operation on a group, replicated so that every broker applies it in the same order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GroupOp {
+    Join(Member),
+    Sync {
+        member_id: String,
+        instance_id: Option<String>,
+        generation_id: i32,
+        assignments: Vec<(String, Vec<u8>)>,
+    },
+    Leave {
+        member_id: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Default)]
 pub struct Group {
     pub id: String,
+    pub state: GroupState,
+    pub generation_id: i32,
+    pub protocol_type: Option<String>,
+    pub protocol: Option<String>,
+    pub leader: Option<String>,
+    pub members: BTreeMap<String, Member>,
+    /// Members that still have to rejoin before the rebalance can complete.
+    pub pending: BTreeSet<String>,
+    /// The member id currently holding each static instance id.
+    pub static_members: BTreeMap<String, String>,
+}
+
+impl Group {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&mut self, op: &GroupOp) -> Result<(), GroupError> {
+        match op {
+            GroupOp::Join(member) => self.join(member.clone()),
+            GroupOp::Sync {
+                member_id,
+                instance_id,
+                generation_id,
+                assignments,
+            } => {
+                self.check_instance(member_id, instance_id.as_deref())?;
+                self.sync(member_id, *generation_id, assignments)
+            }
+            GroupOp::Leave { member_id } => self.leave(member_id),
+        }
+    }
+
+    /// Adds or updates a member, starting a rebalance if the membership changed.
+    pub fn join(&mut self, member: Member) -> Result<(), GroupError> {
+        self.check_alive()?;
+        if let Some(protocol_type) = &self.protocol_type {
+            if !self.members.is_empty() && protocol_type != &member.protocol_type {
+                return Err(GroupError::InconsistentGroupProtocol);
+            }
+        }
+
+        if let Some(instance_id) = &member.instance_id {
+            match self.static_members.get(instance_id).cloned() {
+                Some(id) if id != member.id && self.members.contains_key(&member.id) => {
+                    return Err(GroupError::FencedInstanceId);
+                }
+                Some(id) if id != member.id => return self.replace_static_member(&id, member),
+                _ => {
+                    self.static_members
+                        .insert(instance_id.clone(), member.id.clone());
+                }
+            }
+        }
+
+        let changed = self.members.get(&member.id).map(|m| &m.protocols) != Some(&member.protocols);
+        self.protocol_type = Some(member.protocol_type.clone());
+        let id = member.id.clone();
+        self.members.insert(id.clone(), member);
+
+        if (changed || self.state == GroupState::Empty)
+            && self.state != GroupState::PreparingRebalance
+        {
+            self.prepare_rebalance();
+        }
+        if self.state == GroupState::PreparingRebalance {
+            self.pending.remove(&id);
+            self.maybe_complete_join();
+        }
+        Ok(())
+    }
+
+    /// Hands a static member's place in the group, along with its assignment, to the new member
+    /// id it rejoined with, fencing the old one. The group only rebalances if the member's
+    /// protocols changed while it was away.
+    fn replace_static_member(
+        &mut self,
+        old_id: &str,
+        mut member: Member,
+    ) -> Result<(), GroupError> {
+        let old = match self.members.remove(old_id) {
+            Some(old) => old,
+            None => return Err(GroupError::UnknownMemberId),
+        };
+        let id = member.id.clone();
+        if let Some(instance_id) = &member.instance_id {
+            self.static_members.insert(instance_id.clone(), id.clone());
+        }
+        if self.leader.as_deref() == Some(old_id) {
+            self.leader = Some(id.clone());
+        }
+        if self.pending.remove(old_id) {
+            self.pending.insert(id.clone());
+        }
+
+        let changed = old.protocols != member.protocols;
+        member.assignment = old.assignment;
+        self.members.insert(id.clone(), member);
+        if changed && self.state != GroupState::PreparingRebalance {
+            self.prepare_rebalance();
+        }
+        if self.state == GroupState::PreparingRebalance {
+            self.pending.remove(&id);
+            self.maybe_complete_join();
+        }
+        Ok(())
+    }
+
+    /// Fails with `FencedInstanceId` if a newer member has taken over the static instance id.
+    pub fn check_instance(
+        &self,
+        member_id: &str,
+        instance_id: Option<&str>,
+    ) -> Result<(), GroupError> {
+        match instance_id.and_then(|i| self.static_members.get(i)) {
+            Some(id) if id != member_id => Err(GroupError::FencedInstanceId),
+            _ => Ok(()),
+        }
+    }
+
+    /// Accepts the leader's assignments for the current generation.
+    pub fn sync(
+        &mut self,
+        member_id: &str,
+        generation_id: i32,
+        assignments: &[(String, Vec<u8>)],
+    ) -> Result<(), GroupError> {
+        self.check_member(member_id, generation_id)?;
+        match self.state {
+            GroupState::PreparingRebalance => Err(GroupError::RebalanceInProgress),
+            GroupState::CompletingRebalance if self.leader.as_deref() == Some(member_id) => {
+                for (id, assignment) in assignments {
+                    if let Some(member) = self.members.get_mut(id) {
+                        member.assignment = assignment.clone();
+                    }
+                }
+                self.state = GroupState::Stable;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn heartbeat(&self, member_id: &str, generation_id: i32) -> Result<(), GroupError> {
+        self.check_member(member_id, generation_id)?;
+        match self.state {
+            GroupState::PreparingRebalance => Err(GroupError::RebalanceInProgress),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes a member, rebalancing the remaining members.
+    pub fn leave(&mut self, member_id: &str) -> Result<(), GroupError> {
+        self.check_alive()?;
+        if self.members.remove(member_id).is_none() {
+            return Err(GroupError::UnknownMemberId);
+        }
+        self.pending.remove(member_id);
+        self.static_members.retain(|_, id| id != member_id);
+
+        if self.members.is_empty() {
+            self.state = GroupState::Empty;
+            self.generation_id += 1;
+            self.leader = None;
+            self.protocol = None;
+            return Ok(());
+        }
+
+        match self.state {
+            GroupState::PreparingRebalance => self.maybe_complete_join(),
+            _ => self.prepare_rebalance(),
+        }
+        Ok(())
+    }
+
+    fn check_alive(&self) -> Result<(), GroupError> {
+        match self.state {
+            GroupState::Dead => Err(GroupError::CoordinatorNotAvailable),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_member(&self, member_id: &str, generation_id: i32) -> Result<(), GroupError> {
+        self.check_alive()?;
+        if !self.members.contains_key(member_id) {
+            return Err(GroupError::UnknownMemberId);
+        }
+        if generation_id != self.generation_id {
+            return Err(GroupError::IllegalGeneration);
+        }
+        Ok(())
+    }
+
+    fn prepare_rebalance(&mut self) {
+        self.state = GroupState::PreparingRebalance;
+        self.pending = self.members.keys().cloned().collect();
+        for member in self.members.values_mut() {
+            member.assignment.clear();
+        }
+    }
+
+    fn maybe_complete_join(&mut self) {
+        if !self.pending.is_empty() {
+            return;
+        }
+
+        self.generation_id += 1;
+        if !self
+            .leader
+            .as_ref()
+            .is_some_and(|l| self.members.contains_key(l))
+        {
+            self.leader = self.members.keys().next().cloned();
+        }
+        self.protocol = self.select_protocol();
+        self.state = GroupState::CompletingRebalance;
+    }
+
+    /// Picks the leader's most preferred protocol that every member supports.
+    fn select_protocol(&self) -> Option<String> {
+        let leader = self.members.get(self.leader.as_ref()?)?;
+        leader
+            .protocols
+            .iter()
+            .map(|(name, _)| name)
+            .find(|name| {
+                self.members
+                    .values()
+                    .all(|m| m.protocols.iter().any(|(n, _)| &n == name))
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Group, GroupError, GroupState, Member};
+
+    fn member(id: &str) -> Member {
+        Member {
+            id: id.to_string(),
+            protocol_type: "consumer".to_string(),
+            protocols: vec![("range".to_string(), vec![])],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rebalance() -> Result<(), GroupError> {
+        let mut group = Group::new("group");
+        assert_eq!(group.state, GroupState::Empty);
+
+        // the only member has joined, so the rebalance completes straight away
+        group.join(member("a"))?;
+        assert_eq!(group.state, GroupState::CompletingRebalance);
+        assert_eq!(group.generation_id, 1);
+        assert_eq!(group.leader.as_deref(), Some("a"));
+        assert_eq!(group.protocol.as_deref(), Some("range"));
+        assert_eq!(group.heartbeat("a", 1), Ok(()));
+
+        group.sync("a", 1, &[("a".to_string(), vec![1])])?;
+        assert_eq!(group.state, GroupState::Stable);
+        assert_eq!(group.members["a"].assignment, vec![1]);
+
+        // a new member triggers a rebalance that waits on the existing one
+        group.join(member("b"))?;
+        assert_eq!(group.state, GroupState::PreparingRebalance);
+        assert_eq!(
+            group.sync("a", 1, &[]),
+            Err(GroupError::RebalanceInProgress)
+        );
+        assert_eq!(
+            group.heartbeat("a", 1),
+            Err(GroupError::RebalanceInProgress)
+        );
+
+        group.join(member("a"))?;
+        assert_eq!(group.state, GroupState::CompletingRebalance);
+        assert_eq!(group.generation_id, 2);
+        assert_eq!(group.sync("a", 1, &[]), Err(GroupError::IllegalGeneration));
+        Ok(())
+    }
+
+    #[test]
+    fn leave() -> Result<(), GroupError> {
+        let mut group = Group::new("group");
+        group.join(member("a"))?;
+        group.sync("a", 1, &[])?;
+
+        assert_eq!(group.leave("b"), Err(GroupError::UnknownMemberId));
+        group.leave("a")?;
+        assert_eq!(group.state, GroupState::Empty);
+        assert_eq!(group.heartbeat("a", 2), Err(GroupError::UnknownMemberId));
+        Ok(())
+    }
+
+    #[test]
+    fn static_member_rejoins() -> Result<(), GroupError> {
+        let static_member = |id: &str| Member {
+            instance_id: Some("instance".to_string()),
+            ..member(id)
+        };
+        let mut group = Group::new("group");
+        group.join(static_member("a"))?;
+        group.join(member("b"))?;
+        group.join(static_member("a"))?;
+        group.sync("a", 2, &[("a".to_string(), vec![1]), ("b".to_string(), vec![2])])?;
+        assert_eq!(group.state, GroupState::Stable);
+
+        // restarted, the member comes back under a new id and takes over the old one's place
+        group.join(static_member("c"))?;
+        assert_eq!(group.state, GroupState::Stable);
+        assert_eq!(group.generation_id, 2);
+        assert_eq!(group.leader.as_deref(), Some("c"));
+        assert_eq!(group.members["c"].assignment, vec![1]);
+        assert!(!group.members.contains_key("a"));
+        assert_eq!(group.heartbeat("c", 2), Ok(()));
+
+        // while the old instance is fenced
+        assert_eq!(
+            group.check_instance("a", Some("instance")),
+            Err(GroupError::FencedInstanceId)
+        );
+        assert_eq!(
+            group.apply(&super::GroupOp::Sync {
+                member_id: "a".to_string(),
+                instance_id: Some("instance".to_string()),
+                generation_id: 2,
+                assignments: vec![],
+            }),
+            Err(GroupError::FencedInstanceId)
+        );
+        Ok(())
+    }
 }
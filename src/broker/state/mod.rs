@@ -1,22 +1,56 @@
+pub mod codec;
 pub mod group;
+pub mod offset;
 pub mod partition;
+pub mod quota;
+mod schema;
 pub mod topic;
+pub mod txn;
 mod broker;
 
-use crate::broker::state::group::Group;
+use crate::broker::fsm::Transition;
+use crate::broker::state::codec::{Entries, StoreCodec};
+use crate::broker::state::group::{Group, GroupError, GroupOp};
+use crate::broker::state::offset::{CommittedOffset, GroupOffsets};
 use crate::broker::state::partition::{Partition, PartitionIdx};
+use crate::broker::state::quota::{QuotaEntity, QuotaValues};
 use crate::broker::state::topic::Topic;
+use crate::broker::state::txn::{Txn, TxnError, TxnOp};
 use anyhow::Result;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
 use sled::Db;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use tokio::sync::watch;
+use uuid::Uuid;
 use crate::broker::config::Peer;
+use crate::broker::BrokerId;
+
+type TxResult<T> = ConflictableTransactionResult<T, anyhow::Error>;
+
+/// The offsets committed by every group, as they are stored.
+type StoredOffsets = BTreeMap<String, Entries<(String, i32), CommittedOffset>>;
+/// The quotas of every entity, as they are stored.
+type StoredQuotas = Entries<QuotaEntity, QuotaValues>;
+
+/// The key of the index entry holding the name of the topic a partition belongs to.
+fn partition_topic_key(id: Uuid) -> String {
+    format!("partition_topic:{}", id)
+}
 
 #[derive(Clone)]
 pub struct Store {
     db: Db,
+    /// A version that is bumped whenever topic or partition metadata changes.
+    metadata: Arc<watch::Sender<u64>>,
+    /// The format values are written in. Values in either format can be read.
+    codec: StoreCodec,
 }
 
 impl Debug for Store {
@@ -26,20 +60,37 @@ impl Debug for Store {
 }
 
 impl Store {
-    pub fn new(db: Db) -> Self {
-        Self { db }
+    /// Opens the store, first migrating it if it was written by an older version.
+    pub fn new(db: Db) -> Result<Self> {
+        schema::migrate(&db)?;
+        let (metadata, _) = watch::channel(0);
+        Ok(Self {
+            db,
+            metadata: Arc::new(metadata),
+            codec: StoreCodec::default(),
+        })
+    }
+
+    /// Writes values in the given format from now on.
+    pub fn with_codec(mut self, codec: StoreCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Subscribes to changes of topic or partition metadata.
+    pub fn subscribe_metadata(&self) -> watch::Receiver<u64> {
+        self.metadata.subscribe()
+    }
+
+    /// Notifies subscribers that topic or partition metadata has changed.
+    pub fn metadata_changed(&self) {
+        self.metadata.send_modify(|version| *version += 1);
     }
 
     #[tracing::instrument]
     pub fn create_topic(&self, topic: Topic) -> Result<Topic> {
         tracing::debug!(?topic, "create topic");
-        let mut topics = self.get_topics()?;
-
-        if !topics.contains_key(&topic.name) {
-            topics.insert(topic.name.clone(), topic.clone());
-        }
-
-        self.insert("topics", &topics)?;
+        self.transaction(|tx| self.put_topic(tx, &topic))?;
         Ok(topic)
     }
 
@@ -59,35 +110,684 @@ impl Store {
         Ok(self.get("groups")?.unwrap_or_default())
     }
 
+    pub fn get_group(&self, id: &str) -> Result<Option<Group>> {
+        Ok(self.get_groups()?.remove(id))
+    }
+
+    /// Applies an operation to a group, creating the group if needed. The group is only written
+    /// if the operation is allowed in the group's current state.
+    #[tracing::instrument]
+    pub fn update_group(&self, id: &str, op: &GroupOp) -> Result<std::result::Result<Group, GroupError>> {
+        self.transaction(|tx| self.put_group(tx, id, op))
+    }
+
+    pub fn get_txns(&self) -> Result<BTreeMap<String, Txn>> {
+        Ok(self.get("txns")?.unwrap_or_default())
+    }
+
+    pub fn get_txn(&self, transactional_id: &str) -> Result<Option<Txn>> {
+        Ok(self.get_txns()?.remove(transactional_id))
+    }
+
+    /// Applies an operation to a transactional id, allocating it a producer id when it is first
+    /// initialized. The transaction is only written if the operation is allowed in its current
+    /// state.
+    #[tracing::instrument]
+    pub fn update_txn(
+        &self,
+        transactional_id: &str,
+        op: &TxnOp,
+    ) -> Result<std::result::Result<Txn, TxnError>> {
+        self.transaction(|tx| self.put_txn(tx, transactional_id, op))
+    }
+
+    /// Allocates a producer id that has never been handed out before.
+    #[tracing::instrument]
+    pub fn allocate_producer_id(&self) -> Result<i64> {
+        self.transaction(|tx| self.next_producer_id(tx))
+    }
+
+    /// The offsets committed by every group, by group id.
+    pub fn get_offsets(&self) -> Result<BTreeMap<String, GroupOffsets>> {
+        let offsets: StoredOffsets = self.get("offsets")?.unwrap_or_default();
+        Ok(offsets.into_iter().map(|(group, offsets)| (group, offsets.0)).collect())
+    }
+
+    pub fn get_group_offsets(&self, group: &str) -> Result<GroupOffsets> {
+        Ok(self.get_offsets()?.remove(group).unwrap_or_default())
+    }
+
+    #[tracing::instrument]
+    pub fn commit_offsets(
+        &self,
+        group: &str,
+        offsets: &[(String, i32, CommittedOffset)],
+    ) -> Result<()> {
+        self.transaction(|tx| self.put_offsets(tx, group, offsets))
+    }
+
+    #[tracing::instrument]
+    pub fn delete_offsets(&self, group: &str, partitions: &[(String, i32)]) -> Result<()> {
+        self.transaction(|tx| self.remove_offsets(tx, group, partitions))
+    }
+
     #[tracing::instrument]
     pub fn create_partition(&self, partition: Partition) -> Result<Partition> {
         tracing::debug!(?partition, "create partition");
-        let key = format!("{}:partition:{}", partition.topic, partition.idx);
-        self.insert(&key, &partition)?;
+        self.transaction(|tx| self.put_partition(tx, &partition))?;
         Ok(partition)
     }
 
     pub fn create_broker(&self, broker: Peer) -> Result<Peer> {
-        let key = format!("broker:{}", broker.id);
-        self.insert(&key, &broker)?;
+        self.transaction(|tx| self.put_broker(tx, &broker))?;
         Ok(broker)
     }
 
+    pub fn register_broker(&self, broker: &Peer) -> Result<()> {
+        self.transaction(|tx| self.put_registered_broker(tx, broker))
+    }
+
+    pub fn deregister_broker(&self, id: BrokerId) -> Result<()> {
+        self.transaction(|tx| self.remove_registered_broker(tx, id))
+    }
+
+    /// The brokers that have registered themselves as live, by id.
+    pub fn get_registered_brokers(&self) -> Result<BTreeMap<BrokerId, Peer>> {
+        Ok(self.get("registered_brokers")?.unwrap_or_default())
+    }
+
+    /// Sets the cluster id if it has not been set yet, returning the id that is persisted. The
+    /// first id to be written always wins, so concurrent bootstraps agree on a single value.
+    #[tracing::instrument]
+    pub fn set_cluster_id(&self, cluster_id: Uuid) -> Result<Uuid> {
+        self.transaction(|tx| self.put_cluster_id(tx, cluster_id))
+    }
+
+    pub fn get_cluster_id(&self) -> Result<Option<Uuid>> {
+        self.get("cluster_id")
+    }
+
+    /// Sets a single quota value for an entity, or removes it when `value` is `None`.
+    #[tracing::instrument]
+    pub fn set_client_quota(&self, entity: &QuotaEntity, key: &str, value: Option<f64>) -> Result<()> {
+        self.transaction(|tx| self.put_client_quota(tx, entity, key, value))
+    }
+
+    pub fn get_client_quotas(&self) -> Result<BTreeMap<QuotaEntity, QuotaValues>> {
+        let quotas: StoredQuotas = self.get("quotas")?.unwrap_or_default();
+        Ok(quotas.0)
+    }
+
+    /// Applies a group of transitions atomically. Either every transition is written, or, if any
+    /// of them fails, none are.
+    #[tracing::instrument]
+    pub fn apply_batch(&self, transitions: &[Transition]) -> Result<()> {
+        tracing::debug!(len = transitions.len(), "apply batch");
+        self.transaction(|tx| {
+            for transition in transitions {
+                self.apply_transition(tx, transition)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Replaces the ISR of a partition if it is still at `leader_epoch` with the ISR `from`,
+    /// returning whether it was replaced.
+    pub fn update_isr(
+        &self,
+        topic: &str,
+        idx: PartitionIdx,
+        leader_epoch: i32,
+        from: &[i32],
+        isr: &[i32],
+    ) -> Result<bool> {
+        self.transaction(|tx| self.put_isr(tx, topic, idx, leader_epoch, from, isr))
+    }
+
     pub fn get_partition(&self, topic: &str, idx: PartitionIdx) -> Result<Option<Partition>> {
         self.get(format!("{}:partition:{}", topic, idx))
     }
 
+    /// The name of the topic a partition belongs to, looked up by the partition's id.
+    pub fn topic_of_partition(&self, id: Uuid) -> Result<Option<String>> {
+        self.get(partition_topic_key(id))
+    }
+
+    /// Serializes every key in the store.
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        let entries = self
+            .db
+            .iter()
+            .map(|x| x.map(|(k, v)| (k.to_vec(), v.to_vec())))
+            .collect::<std::result::Result<Vec<(Vec<u8>, Vec<u8>)>, _>>()?;
+        Ok(bincode::serialize(&entries)?)
+    }
+
+    /// Atomically replaces the contents of the store with a snapshot.
+    pub fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = bincode::deserialize(snapshot)?;
+        let mut batch = sled::Batch::default();
+        for key in self.db.iter().keys() {
+            batch.remove(key?);
+        }
+        for (k, v) in entries {
+            batch.insert(k, v);
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn transaction<T>(&self, f: impl Fn(&TransactionalTree) -> TxResult<T>) -> Result<T> {
+        self.db.transaction(f).map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn apply_transition(&self, tx: &TransactionalTree, transition: &Transition) -> TxResult<()> {
+        match transition {
+            Transition::EnsureTopic(topic) => self.put_topic(tx, topic),
+            Transition::EnsurePartition(partition) => self.put_partition(tx, partition),
+            Transition::EnsureBroker(broker) => self.put_broker(tx, broker),
+            Transition::RegisterBroker(broker) => self.put_registered_broker(tx, broker),
+            Transition::DeregisterBroker(id) => self.remove_registered_broker(tx, *id),
+            Transition::SetClusterId(cluster_id) => {
+                self.put_cluster_id(tx, *cluster_id)?;
+                Ok(())
+            }
+            Transition::SetClientQuota { entity, key, value } => {
+                self.put_client_quota(tx, entity, key, *value)
+            }
+            Transition::UpdateGroup { id, op } => {
+                self.put_group(tx, id, op)?.map_err(|e| {
+                    ConflictableTransactionError::Abort(anyhow::anyhow!("{:?}", e))
+                })?;
+                Ok(())
+            }
+            Transition::UpdateTxnState {
+                transactional_id,
+                op,
+            } => {
+                self.put_txn(tx, transactional_id, op)?.map_err(|e| {
+                    ConflictableTransactionError::Abort(anyhow::anyhow!("{:?}", e))
+                })?;
+                Ok(())
+            }
+            Transition::AllocateProducerId => {
+                self.next_producer_id(tx)?;
+                Ok(())
+            }
+            Transition::CommitOffsets { group, offsets } => {
+                self.put_offsets(tx, group, offsets)
+            }
+            Transition::DeleteOffsets { group, partitions } => {
+                self.remove_offsets(tx, group, partitions)
+            }
+            Transition::Batch(transitions) => {
+                for transition in transitions {
+                    self.apply_transition(tx, transition)?;
+                }
+                Ok(())
+            }
+            Transition::UpdateIsr {
+                topic,
+                idx,
+                leader_epoch,
+                from,
+                isr,
+            } => {
+                self.put_isr(tx, topic, *idx, *leader_epoch, from, isr)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn put_topic(&self, tx: &TransactionalTree, topic: &Topic) -> TxResult<()> {
+        let mut topics: HashMap<String, Topic> = self.tx_get(tx, "topics")?.unwrap_or_default();
+
+        if !topics.contains_key(&topic.name) {
+            topics.insert(topic.name.clone(), topic.clone());
+        }
+
+        self.tx_insert(tx, "topics", &topics)
+    }
+
+    fn put_partition(&self, tx: &TransactionalTree, partition: &Partition) -> TxResult<()> {
+        let topics: HashMap<String, Topic> = self.tx_get(tx, "topics")?.unwrap_or_default();
+        let topic = topics.get(&partition.topic).ok_or_else(|| {
+            ConflictableTransactionError::Abort(anyhow::anyhow!(
+                "topic {} does not exist",
+                partition.topic
+            ))
+        })?;
+        partition
+            .validate(topic)
+            .map_err(ConflictableTransactionError::Abort)?;
+
+        let key = format!("{}:partition:{}", partition.topic, partition.idx);
+        self.tx_insert(tx, key, partition)?;
+        self.tx_insert(tx, partition_topic_key(partition.id), &partition.topic)
+    }
+
+    fn put_isr(
+        &self,
+        tx: &TransactionalTree,
+        topic: &str,
+        idx: PartitionIdx,
+        leader_epoch: i32,
+        from: &[i32],
+        isr: &[i32],
+    ) -> TxResult<bool> {
+        let key = format!("{}:partition:{}", topic, idx);
+        let mut partition: Partition = match self.tx_get(tx, &key)? {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        if partition.leader_epoch != leader_epoch || partition.isr != from {
+            tracing::debug!(%topic, %idx, leader_epoch, ?from, "isr changed since, not updating");
+            return Ok(false);
+        }
+        partition.isr = isr.to_vec();
+        self.tx_insert(tx, key, &partition)?;
+        Ok(true)
+    }
+
+    fn put_broker(&self, tx: &TransactionalTree, broker: &Peer) -> TxResult<()> {
+        let key = format!("broker:{}", broker.id);
+        self.tx_insert(tx, key, broker)
+    }
+
+    fn put_registered_broker(&self, tx: &TransactionalTree, broker: &Peer) -> TxResult<()> {
+        let mut brokers: BTreeMap<BrokerId, Peer> =
+            self.tx_get(tx, "registered_brokers")?.unwrap_or_default();
+        brokers.insert(broker.id, broker.clone());
+        self.tx_insert(tx, "registered_brokers", &brokers)
+    }
+
+    fn remove_registered_broker(&self, tx: &TransactionalTree, id: BrokerId) -> TxResult<()> {
+        let mut brokers: BTreeMap<BrokerId, Peer> =
+            self.tx_get(tx, "registered_brokers")?.unwrap_or_default();
+        brokers.remove(&id);
+        self.tx_insert(tx, "registered_brokers", &brokers)
+    }
+
+    fn put_cluster_id(&self, tx: &TransactionalTree, cluster_id: Uuid) -> TxResult<Uuid> {
+        if let Some(existing) = self.tx_get(tx, "cluster_id")? {
+            return Ok(existing);
+        }
+
+        self.tx_insert(tx, "cluster_id", &cluster_id)?;
+        Ok(cluster_id)
+    }
+
+    fn put_client_quota(
+        &self,
+        tx: &TransactionalTree,
+        entity: &QuotaEntity,
+        key: &str,
+        value: Option<f64>,
+    ) -> TxResult<()> {
+        let Entries(mut quotas): StoredQuotas = self.tx_get(tx, "quotas")?.unwrap_or_default();
+
+        let values = quotas.entry(entity.clone()).or_default();
+        match value {
+            Some(value) => {
+                values.insert(key.to_string(), value);
+            }
+            None => {
+                values.remove(key);
+            }
+        }
+        if values.is_empty() {
+            quotas.remove(entity);
+        }
+
+        self.tx_insert(tx, "quotas", &Entries(quotas))
+    }
+
+    fn put_group(
+        &self,
+        tx: &TransactionalTree,
+        id: &str,
+        op: &GroupOp,
+    ) -> TxResult<std::result::Result<Group, GroupError>> {
+        let mut groups: HashMap<String, Group> = self.tx_get(tx, "groups")?.unwrap_or_default();
+        let mut group = groups.get(id).cloned().unwrap_or_else(|| Group::new(id));
+        if let Err(e) = group.apply(op) {
+            return Ok(Err(e));
+        }
+
+        groups.insert(id.to_string(), group.clone());
+        self.tx_insert(tx, "groups", &groups)?;
+        Ok(Ok(group))
+    }
+
+    fn put_txn(
+        &self,
+        tx: &TransactionalTree,
+        transactional_id: &str,
+        op: &TxnOp,
+    ) -> TxResult<std::result::Result<Txn, TxnError>> {
+        let mut txns: BTreeMap<String, Txn> = self.tx_get(tx, "txns")?.unwrap_or_default();
+        let mut txn = match txns.get(transactional_id) {
+            Some(txn) => txn.clone(),
+            None if matches!(op, TxnOp::Init { .. }) => {
+                Txn::new(transactional_id, self.next_producer_id(tx)?)
+            }
+            None => return Ok(Err(TxnError::InvalidProducerIdMapping)),
+        };
+        if let Err(e) = txn.apply(op) {
+            return Ok(Err(e));
+        }
+
+        txns.insert(transactional_id.to_string(), txn.clone());
+        self.tx_insert(tx, "txns", &txns)?;
+        Ok(Ok(txn))
+    }
+
+    fn next_producer_id(&self, tx: &TransactionalTree) -> TxResult<i64> {
+        let id: i64 = self.tx_get(tx, "producer_id")?.unwrap_or_default();
+        self.tx_insert(tx, "producer_id", &(id + 1))?;
+        Ok(id)
+    }
+
+    fn put_offsets(
+        &self,
+        tx: &TransactionalTree,
+        group: &str,
+        offsets: &[(String, i32, CommittedOffset)],
+    ) -> TxResult<()> {
+        let mut all: StoredOffsets = self.tx_get(tx, "offsets")?.unwrap_or_default();
+        let Entries(committed) = all.entry(group.to_string()).or_default();
+        for (topic, partition, offset) in offsets {
+            committed.insert((topic.clone(), *partition), offset.clone());
+        }
+        self.tx_insert(tx, "offsets", &all)
+    }
+
+    fn remove_offsets(
+        &self,
+        tx: &TransactionalTree,
+        group: &str,
+        partitions: &[(String, i32)],
+    ) -> TxResult<()> {
+        let mut all: StoredOffsets = self.tx_get(tx, "offsets")?.unwrap_or_default();
+        if let Some(Entries(committed)) = all.get_mut(group) {
+            for partition in partitions {
+                committed.remove(partition);
+            }
+            if committed.is_empty() {
+                all.remove(group);
+            }
+        }
+        self.tx_insert(tx, "offsets", &all)
+    }
+
+    fn tx_get<T: DeserializeOwned, K: AsRef<[u8]>>(
+        &self,
+        tx: &TransactionalTree,
+        key: K,
+    ) -> TxResult<Option<T>> {
+        tx.get(key.as_ref())?
+            .map(|x| StoreCodec::decode(&x).map_err(ConflictableTransactionError::Abort))
+            .transpose()
+    }
+
+    fn tx_insert<T: Serialize, K: AsRef<[u8]>>(
+        &self,
+        tx: &TransactionalTree,
+        key: K,
+        value: &T,
+    ) -> TxResult<()> {
+        let value = self
+            .codec
+            .encode(value)
+            .map_err(ConflictableTransactionError::Abort)?;
+        tx.insert(key.as_ref(), value)?;
+        Ok(())
+    }
+
     fn get<T: DeserializeOwned, K: AsRef<[u8]>>(&self, key: K) -> Result<Option<T>> {
         self.db
             .get(key.as_ref())?
-            .map(|x| {
-                bincode::deserialize(&x).map_err(|e| anyhow::anyhow!("could not deserialize {}", e))
-            })
+            .map(|x| StoreCodec::decode(&x))
             .transpose()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::config::Peer;
+    use crate::broker::fsm::Transition;
+    use crate::broker::state::group::{GroupOp, Member};
+    use crate::broker::state::offset::CommittedOffset;
+    use crate::broker::state::partition::{Partition, PartitionIdx};
+    use crate::broker::state::quota::QuotaEntity;
+    use crate::broker::state::topic::Topic;
+    use crate::broker::state::txn::TxnOp;
+    use crate::broker::state::codec::StoreCodec;
+    use crate::broker::state::Store;
+    use crate::broker::BrokerId;
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    fn topic(name: &str) -> Topic {
+        Topic {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            partitions: (0..2).map(|i| (PartitionIdx(i), vec![BrokerId(1)])).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn partition(topic: &str, idx: i32) -> Partition {
+        Partition {
+            id: Uuid::new_v4(),
+            idx: PartitionIdx(idx),
+            topic: topic.to_string(),
+            isr: vec![1],
+            assigned_replicas: vec![1],
+            leader: BrokerId(1),
+            leader_epoch: 0,
+            adding_replicas: vec![],
+            removing_replicas: vec![],
+        }
+    }
+
+    #[test]
+    fn apply_batch() -> Result<()> {
+        let store = Store::new(sled::open(tempdir()?)?)?;
+        store.apply_batch(&[
+            Transition::EnsureTopic(topic("a")),
+            Transition::EnsurePartition(partition("a", 0)),
+            Transition::EnsurePartition(partition("a", 1)),
+        ])?;
+        assert!(store.topic_exists("a")?);
+        assert!(store.get_partition("a", PartitionIdx(0))?.is_some());
+        assert!(store.get_partition("a", PartitionIdx(1))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn topic_of_partition() -> Result<()> {
+        let store = Store::new(sled::open(tempdir()?)?)?;
+        let partitions = [
+            partition("a", 0),
+            partition("a", 1),
+            partition("b", 0),
+            partition("b", 1),
+        ];
+        let mut transitions = vec![
+            Transition::EnsureTopic(topic("a")),
+            Transition::EnsureTopic(topic("b")),
+        ];
+        transitions.extend(partitions.iter().cloned().map(Transition::EnsurePartition));
+        store.apply_batch(&transitions)?;
+        for partition in &partitions {
+            assert_eq!(store.topic_of_partition(partition.id)?, Some(partition.topic.clone()));
+        }
+        assert_eq!(store.topic_of_partition(Uuid::new_v4())?, None);
+
+        // a partition that fails to be written leaves no index entry
+        let missing = partition("c", 0);
+        assert!(store.create_partition(missing.clone()).is_err());
+        assert_eq!(store.topic_of_partition(missing.id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn codec_change() -> Result<()> {
+        let db = sled::open(tempdir()?)?;
+        let json = Store::new(db.clone())?.with_codec(StoreCodec::Json);
+        json.create_topic(topic("a"))?;
+
+        // values already written stay readable once the store writes bincode
+        let bincode = Store::new(db)?;
+        bincode.create_topic(topic("b"))?;
+        assert!(bincode.topic_exists("a")?);
+        assert!(json.topic_exists("b")?);
+        Ok(())
+    }
+
+    #[test]
+    fn every_value_under_both_codecs() -> Result<()> {
+        for codec in [StoreCodec::Bincode, StoreCodec::Json] {
+            let store = Store::new(sled::open(tempdir()?)?)?.with_codec(codec);
+            let peer = Peer {
+                id: BrokerId(1),
+                ip: "127.0.0.1".parse()?,
+                port: 8844,
+                rack: None,
+                advertised_host: None,
+                advertised_port: None,
+            };
+            let entity = QuotaEntity::new([("user".to_string(), Some("alice".to_string()))]);
+            let offset = CommittedOffset {
+                offset: 5,
+                ..Default::default()
+            };
+            store.apply_batch(&[
+                Transition::EnsureTopic(topic("a")),
+                Transition::EnsurePartition(partition("a", 0)),
+                Transition::EnsureBroker(peer.clone()),
+                Transition::RegisterBroker(peer),
+                Transition::SetClusterId(Uuid::new_v4()),
+                Transition::SetClientQuota {
+                    entity: entity.clone(),
+                    key: "producer_byte_rate".to_string(),
+                    value: Some(1024.0),
+                },
+                Transition::UpdateGroup {
+                    id: "group".to_string(),
+                    op: GroupOp::Join(Member {
+                        id: "member".to_string(),
+                        ..Default::default()
+                    }),
+                },
+                Transition::UpdateTxnState {
+                    transactional_id: "txn".to_string(),
+                    op: TxnOp::Init { timeout_ms: 1000 },
+                },
+                Transition::AllocateProducerId,
+                Transition::CommitOffsets {
+                    group: "group".to_string(),
+                    offsets: vec![("a".to_string(), 0, offset.clone())],
+                },
+            ])?;
+
+            assert!(store.topic_exists("a")?, "{:?}", codec);
+            assert!(store.get_partition("a", PartitionIdx(0))?.is_some());
+            assert!(store.get::<Peer, _>("broker:1")?.is_some());
+            assert!(store.get_registered_brokers()?.contains_key(&BrokerId(1)));
+            assert!(store.get_cluster_id()?.is_some());
+            assert_eq!(store.get_client_quotas()?[&entity]["producer_byte_rate"], 1024.0);
+            assert!(store.get_group("group")?.unwrap().members.contains_key("member"));
+            assert!(store.get_txn("txn")?.is_some());
+            assert_eq!(store.allocate_producer_id()?, 2);
+            let offsets = store.get_group_offsets("group")?;
+            assert_eq!(offsets[&("a".to_string(), 0)], offset);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apply_batch_failure_is_atomic() -> Result<()> {
+        let store = Store::new(sled::open(tempdir()?)?)?;
+        let res = store.apply_batch(&[
+            Transition::EnsureTopic(topic("a")),
+            Transition::EnsurePartition(partition("a", 0)),
+            // partition of a topic that does not exist
+            Transition::EnsurePartition(partition("b", 0)),
+            Transition::EnsureTopic(topic("c")),
+        ]);
+        assert!(res.is_err());
+        assert!(store.get_topics()?.is_empty());
+        assert!(store.get_partition("a", PartitionIdx(0))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_partition() -> Result<()> {
+        let store = Store::new(sled::open(tempdir()?)?)?;
+        store.create_topic(topic("a"))?;
+        store.create_partition(partition("a", 0))?;
+
+        let out_of_range = partition("a", 2);
+        let negative = partition("a", -1);
+        let leader_not_assigned = Partition {
+            leader: BrokerId(2),
+            ..partition("a", 1)
+        };
+        let isr_not_assigned = Partition {
+            isr: vec![1, 2],
+            ..partition("a", 1)
+        };
+        let empty_isr = Partition {
+            isr: vec![],
+            ..partition("a", 1)
+        };
+        for invalid in [
+            out_of_range,
+            negative,
+            leader_not_assigned,
+            isr_not_assigned,
+            empty_isr,
+        ] {
+            assert!(store.create_partition(invalid).is_err());
+        }
+        assert!(store.get_partition("a", PartitionIdx(1))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_restore() -> Result<()> {
+        let store = Store::new(sled::open(tempdir()?)?)?;
+        store.create_topic(topic("a"))?;
+        let snapshot = store.snapshot()?;
+
+        let restored = Store::new(sled::open(tempdir()?)?)?;
+        restored.create_topic(topic("b"))?;
+        restored.restore(&snapshot)?;
+        assert!(restored.topic_exists("a")?);
+        assert!(!restored.topic_exists("b")?);
+        Ok(())
+    }
+
+    #[test]
+    fn cluster_id_persists() -> Result<()> {
+        let dir = tempdir()?;
+        // without the flusher thread, which can hold the db's lock after it is dropped
+        let open = || sled::Config::new().path(dir.path()).flush_every_ms(None).open();
+        let first = {
+            let store = Store::new(open()?)?;
+            assert!(store.get_cluster_id()?.is_none());
+            store.set_cluster_id(Uuid::new_v4())?
+        };
 
-    fn insert<T: Serialize, K: AsRef<[u8]>>(&self, key: K, value: &T) -> Result<()> {
-        self.db.insert(key.as_ref(), bincode::serialize(&value)?)?;
+        let store = Store::new(open()?)?;
+        assert_eq!(store.get_cluster_id()?, Some(first));
+        assert_eq!(store.set_cluster_id(Uuid::new_v4())?, first);
         Ok(())
     }
 }
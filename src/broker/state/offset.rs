@@ -0,0 +1,24 @@
+/// An offset committed by a consumer group for a single partition.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct CommittedOffset {
+    pub offset: i64,
+    pub metadata: Option<String>,
+    /// When the offset was committed, in milliseconds since the epoch.
+    pub commit_timestamp: i64,
+    /// When the offset may be expired, in milliseconds since the epoch. Without one, the offset
+    /// expires once it is older than the broker's retention.
+    pub expire_timestamp: Option<i64>,
+}
+
+impl CommittedOffset {
+    /// Whether the offset has outlived its expiry, or `retention_ms` if it has none, at `now`.
+    pub fn is_expired(&self, now: i64, retention_ms: i64) -> bool {
+        let expiry = self
+            .expire_timestamp
+            .unwrap_or_else(|| self.commit_timestamp.saturating_add(retention_ms));
+        now >= expiry
+    }
+}
+
+/// The offsets committed by a group, by topic and partition.
+pub type GroupOffsets = std::collections::BTreeMap<(String, i32), CommittedOffset>;
@@ -1,4 +1,6 @@
+use crate::broker::state::topic::Topic;
 use crate::broker::BrokerId;
+use anyhow::Result;
 use derive_more::Display;
 use uuid::Uuid;
 
@@ -15,4 +17,65 @@ pub struct Partition {
     pub isr: Vec<i32>,
     pub assigned_replicas: Vec<i32>,
     pub leader: BrokerId,
+    /// Incremented every time leadership of the partition moves, so that requests meant for an
+    /// earlier leader can be fenced.
+    pub leader_epoch: i32,
+    /// The replicas a reassignment in progress is adding. They are already assigned, and the
+    /// reassignment completes once all of them are in sync.
+    #[serde(default)]
+    pub adding_replicas: Vec<i32>,
+    /// The assigned replicas a reassignment in progress removes once it completes.
+    #[serde(default)]
+    pub removing_replicas: Vec<i32>,
+}
+
+impl Partition {
+    /// The name of the directory the partition's log is stored in, following Kafka's
+    /// `<topic>-<partition>` convention.
+    pub fn dir_name(&self) -> String {
+        format!("{}-{}", self.topic, self.idx)
+    }
+
+    /// Checks that the partition is well formed and belongs to `topic`.
+    pub fn validate(&self, topic: &Topic) -> Result<()> {
+        if self.idx.0 < 0 || self.idx.0 as usize >= topic.partitions.len() {
+            return Err(anyhow::anyhow!(
+                "partition {} is out of range for topic {} with {} partitions",
+                self.idx,
+                topic.name,
+                topic.partitions.len()
+            ));
+        }
+        if !self.assigned_replicas.contains(&self.leader.0) {
+            return Err(anyhow::anyhow!(
+                "leader {} is not an assigned replica of partition {}",
+                self.leader,
+                self.idx
+            ));
+        }
+        if self.isr.is_empty() {
+            return Err(anyhow::anyhow!("partition {} has no in sync replicas", self.idx));
+        }
+        if let Some(replica) = self.isr.iter().find(|r| !self.assigned_replicas.contains(r)) {
+            return Err(anyhow::anyhow!(
+                "in sync replica {} is not an assigned replica of partition {}",
+                replica,
+                self.idx
+            ));
+        }
+        let reassigning = self.adding_replicas.iter().chain(&self.removing_replicas);
+        if let Some(replica) = reassigning.clone().find(|r| !self.assigned_replicas.contains(r)) {
+            return Err(anyhow::anyhow!(
+                "reassigned replica {} is not an assigned replica of partition {}",
+                replica,
+                self.idx
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a reassignment of the partition's replicas is in progress.
+    pub fn is_reassigning(&self) -> bool {
+        !self.adding_replicas.is_empty() || !self.removing_replicas.is_empty()
+    }
 }
@@ -0,0 +1,15 @@
+use std::collections::BTreeMap;
+
+/// The entity a quota applies to, as a map of entity type (e.g. `user` or `client-id`) to
+/// name. A `None` name is the default entity for that type.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct QuotaEntity(pub BTreeMap<String, Option<String>>);
+
+/// Quota values keyed by name, e.g. `producer_byte_rate`.
+pub type QuotaValues = BTreeMap<String, f64>;
+
+impl QuotaEntity {
+    pub fn new<I: IntoIterator<Item = (String, Option<String>)>>(components: I) -> Self {
+        Self(components.into_iter().collect())
+    }
+}
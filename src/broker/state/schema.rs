@@ -0,0 +1,526 @@
+//! Versions the layout of the values in the store, upgrading stores written by older versions
+//! when they are opened.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::{Batch, Db};
+
+use crate::broker::state::codec::StoreCodec;
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::state::partition_topic_key;
+use pinned::{PartitionV0, PartitionV1, PartitionV2, TopicV0, TopicV1, TopicV2};
+
+/// The key the schema version is kept under.
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Upgrades the values of a store by one version, returning the writes that do so.
+pub(crate) type Migration = fn(&Db) -> Result<Batch>;
+
+/// The migrations from each version to the next, so the version this code reads and writes is
+/// the number of migrations.
+const MIGRATIONS: &[Migration] = &[
+    prefix_codec_version,
+    index_partition_topics,
+    add_topic_delete_retention,
+    add_partition_reassignments,
+];
+
+/// The version the store was written at. Stores from before versioning have no version, which is
+/// taken to be 0.
+pub(crate) fn version(db: &Db) -> Result<u32> {
+    match db.get(SCHEMA_VERSION_KEY)? {
+        Some(v) => Ok(u32::from_be_bytes(v.as_ref().try_into()?)),
+        None => Ok(0),
+    }
+}
+
+/// Brings the store up to the current version, running each migration atomically along with
+/// the bump of the version. A new, empty store is simply tagged with the current version.
+pub(crate) fn migrate(db: &Db) -> Result<()> {
+    migrate_with(db, MIGRATIONS)
+}
+
+pub(crate) fn migrate_with(db: &Db, migrations: &[Migration]) -> Result<()> {
+    let current = migrations.len() as u32;
+    if db.is_empty() {
+        db.insert(SCHEMA_VERSION_KEY, &current.to_be_bytes())?;
+        return Ok(());
+    }
+
+    let mut version = version(db)?;
+    if version > current {
+        anyhow::bail!(
+            "store is at schema version {}, newer than the supported {}",
+            version,
+            current
+        );
+    }
+    for migration in &migrations[version as usize..] {
+        let mut batch = migration(db)?;
+        version += 1;
+        batch.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes());
+        db.apply_batch(batch)?;
+        tracing::info!(version, "migrated store");
+    }
+    Ok(())
+}
+
+/// Values were written as bare bincode before they started with the version byte of their codec.
+/// Topics and partitions were also still in their baseline layout, before topics gained their
+/// compaction policy and message size override, and partitions their leader epoch.
+fn prefix_codec_version(db: &Db) -> Result<Batch> {
+    let mut batch = Batch::default();
+    let topics: HashMap<String, TopicV0> = match db.get("topics")? {
+        Some(topics) => bincode::deserialize(&topics)?,
+        None => HashMap::new(),
+    };
+    let mut reshaped = HashSet::new();
+    for topic in topics.values() {
+        for idx in topic.partitions.keys() {
+            let key = partition_key(&topic.name, *idx);
+            if let Some(partition) = db.get(&key)? {
+                let partition: PartitionV0 = bincode::deserialize(&partition)?;
+                let partition = PartitionV1::from(partition);
+                batch.insert(key.as_bytes(), StoreCodec::Bincode.encode(&partition)?);
+            }
+            reshaped.insert(key.into_bytes());
+        }
+    }
+    if !topics.is_empty() {
+        let topics: HashMap<String, TopicV1> = topics
+            .into_iter()
+            .map(|(name, topic)| (name, topic.into()))
+            .collect();
+        batch.insert("topics", StoreCodec::Bincode.encode(&topics)?);
+        reshaped.insert(b"topics".to_vec());
+    }
+
+    for entry in db.iter() {
+        let (key, value) = entry?;
+        if key == SCHEMA_VERSION_KEY || reshaped.contains(key.as_ref()) {
+            continue;
+        }
+        let mut prefixed = vec![StoreCodec::Bincode.version()];
+        prefixed.extend_from_slice(&value);
+        batch.insert(key, prefixed);
+    }
+    Ok(batch)
+}
+
+/// Partitions were only kept under their topic before they were also indexed by id.
+fn index_partition_topics(db: &Db) -> Result<Batch> {
+    let mut batch = Batch::default();
+    let topics: HashMap<String, TopicV1> = match db.get("topics")? {
+        Some(topics) => StoreCodec::decode(&topics)?,
+        None => return Ok(batch),
+    };
+    for topic in topics.values() {
+        for idx in topic.partitions.keys() {
+            if let Some(partition) = db.get(partition_key(&topic.name, *idx))? {
+                let partition: PartitionV1 = StoreCodec::decode(&partition)?;
+                let name = StoreCodec::Bincode.encode(&partition.topic)?;
+                batch.insert(partition_topic_key(partition.id).as_bytes(), name);
+            }
+        }
+    }
+    Ok(batch)
+}
+
+/// Topics gained a `delete.retention.ms` override.
+fn add_topic_delete_retention(db: &Db) -> Result<Batch> {
+    let mut batch = Batch::default();
+    reshape(db, &mut batch, "topics", |topics: HashMap<String, TopicV1>| {
+        let reshaped: HashMap<String, TopicV2> = topics
+            .into_iter()
+            .map(|(name, topic)| (name, topic.into()))
+            .collect();
+        reshaped
+    })?;
+    Ok(batch)
+}
+
+/// Partitions gained the replicas a reassignment in progress adds and removes.
+fn add_partition_reassignments(db: &Db) -> Result<Batch> {
+    let mut batch = Batch::default();
+    let topics: HashMap<String, TopicV2> = match db.get("topics")? {
+        Some(topics) => StoreCodec::decode(&topics)?,
+        None => return Ok(batch),
+    };
+    for topic in topics.values() {
+        for idx in topic.partitions.keys() {
+            let key = partition_key(&topic.name, *idx);
+            reshape(db, &mut batch, &key, |p: PartitionV1| PartitionV2::from(p))?;
+        }
+    }
+    Ok(batch)
+}
+
+/// The key a partition is kept under.
+fn partition_key(topic: &str, idx: PartitionIdx) -> String {
+    format!("{}:partition:{}", topic, idx)
+}
+
+/// Rewrites the value under `key`, if there is one, in the format it was written in.
+fn reshape<Old, New>(
+    db: &Db,
+    batch: &mut Batch,
+    key: &str,
+    f: impl FnOnce(Old) -> New,
+) -> Result<()>
+where
+    Old: DeserializeOwned,
+    New: Serialize,
+{
+    if let Some(value) = db.get(key)? {
+        let codec = StoreCodec::of(&value)?;
+        let reshaped = f(StoreCodec::decode(&value)?);
+        batch.insert(key.as_bytes(), codec.encode(&reshaped)?);
+    }
+    Ok(())
+}
+
+/// The layouts values had at earlier versions of the store. Migrations read and write these
+/// rather than the current types, which keep changing after the migrations are written.
+mod pinned {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use crate::broker::state::partition::PartitionIdx;
+    use crate::broker::BrokerId;
+
+    /// A partition as written before the store was versioned.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub(super) struct PartitionV0 {
+        pub(super) id: Uuid,
+        pub(super) idx: PartitionIdx,
+        pub(super) topic: String,
+        pub(super) isr: Vec<i32>,
+        pub(super) assigned_replicas: Vec<i32>,
+        pub(super) leader: BrokerId,
+    }
+
+    impl From<PartitionV0> for PartitionV1 {
+        fn from(partition: PartitionV0) -> Self {
+            Self {
+                id: partition.id,
+                idx: partition.idx,
+                topic: partition.topic,
+                isr: partition.isr,
+                assigned_replicas: partition.assigned_replicas,
+                leader: partition.leader,
+                leader_epoch: 0,
+            }
+        }
+    }
+
+    /// A partition as written up to version 3, once it had gained its leader epoch.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub(super) struct PartitionV1 {
+        pub(super) id: Uuid,
+        pub(super) idx: PartitionIdx,
+        pub(super) topic: String,
+        pub(super) isr: Vec<i32>,
+        pub(super) assigned_replicas: Vec<i32>,
+        pub(super) leader: BrokerId,
+        pub(super) leader_epoch: i32,
+    }
+
+    /// A partition as written from version 4.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub(super) struct PartitionV2 {
+        pub(super) id: Uuid,
+        pub(super) idx: PartitionIdx,
+        pub(super) topic: String,
+        pub(super) isr: Vec<i32>,
+        pub(super) assigned_replicas: Vec<i32>,
+        pub(super) leader: BrokerId,
+        pub(super) leader_epoch: i32,
+        pub(super) adding_replicas: Vec<i32>,
+        pub(super) removing_replicas: Vec<i32>,
+    }
+
+    impl From<PartitionV1> for PartitionV2 {
+        fn from(partition: PartitionV1) -> Self {
+            Self {
+                id: partition.id,
+                idx: partition.idx,
+                topic: partition.topic,
+                isr: partition.isr,
+                assigned_replicas: partition.assigned_replicas,
+                leader: partition.leader,
+                leader_epoch: partition.leader_epoch,
+                adding_replicas: vec![],
+                removing_replicas: vec![],
+            }
+        }
+    }
+
+    /// A topic as written before the store was versioned.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+    pub(super) struct TopicV0 {
+        pub(super) id: Uuid,
+        pub(super) name: String,
+        pub(super) partitions: HashMap<PartitionIdx, Vec<BrokerId>>,
+        pub(super) internal: bool,
+    }
+
+    impl From<TopicV0> for TopicV1 {
+        fn from(topic: TopicV0) -> Self {
+            Self {
+                id: topic.id,
+                name: topic.name,
+                partitions: topic.partitions,
+                internal: topic.internal,
+                compacted: false,
+                max_message_bytes: None,
+            }
+        }
+    }
+
+    /// A topic as written up to version 2, once it had gained its compaction policy and message
+    /// size override.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+    pub(super) struct TopicV1 {
+        pub(super) id: Uuid,
+        pub(super) name: String,
+        pub(super) partitions: HashMap<PartitionIdx, Vec<BrokerId>>,
+        pub(super) internal: bool,
+        pub(super) compacted: bool,
+        pub(super) max_message_bytes: Option<u32>,
+    }
+
+    /// A topic as written from version 3.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+    pub(super) struct TopicV2 {
+        pub(super) id: Uuid,
+        pub(super) name: String,
+        pub(super) partitions: HashMap<PartitionIdx, Vec<BrokerId>>,
+        pub(super) internal: bool,
+        pub(super) compacted: bool,
+        pub(super) max_message_bytes: Option<u32>,
+        pub(super) delete_retention_ms: Option<u64>,
+    }
+
+    impl From<TopicV1> for TopicV2 {
+        fn from(topic: TopicV1) -> Self {
+            Self {
+                id: topic.id,
+                name: topic.name,
+                partitions: topic.partitions,
+                internal: topic.internal,
+                compacted: topic.compacted,
+                max_message_bytes: topic.max_message_bytes,
+                delete_retention_ms: None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::Result;
+    use sled::{Batch, Db};
+    use tempfile::tempdir;
+
+    use uuid::Uuid;
+
+    use super::pinned::{PartitionV0, PartitionV1, TopicV0, TopicV1, TopicV2};
+    use super::{migrate_with, version, MIGRATIONS, SCHEMA_VERSION_KEY};
+    use crate::broker::state::codec::StoreCodec;
+    use crate::broker::state::partition::{Partition, PartitionIdx};
+    use crate::broker::state::topic::Topic;
+    use crate::broker::state::Store;
+    use crate::broker::BrokerId;
+
+    #[test]
+    fn new_store() -> Result<()> {
+        let db = sled::open(tempdir()?)?;
+        Store::new(db.clone())?;
+        assert_eq!(version(&db)?, MIGRATIONS.len() as u32);
+        Ok(())
+    }
+
+    #[test]
+    fn unversioned_store() -> Result<()> {
+        let db = sled::open(tempdir()?)?;
+        let topic = TopicV0 {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            partitions: [(PartitionIdx(0), vec![BrokerId(1)])].into(),
+            internal: false,
+        };
+        let partition = PartitionV0 {
+            id: Uuid::new_v4(),
+            idx: PartitionIdx(0),
+            topic: "test".to_string(),
+            isr: vec![1],
+            assigned_replicas: vec![1],
+            leader: BrokerId(1),
+        };
+        let topics: HashMap<String, TopicV0> = [(topic.name.clone(), topic.clone())].into();
+        db.insert("topics", bincode::serialize(&topics)?)?;
+        db.insert("test:partition:0", bincode::serialize(&partition)?)?;
+        let cluster_id = Uuid::new_v4();
+        db.insert("cluster_id", bincode::serialize(&cluster_id)?)?;
+
+        let store = Store::new(db.clone())?;
+        assert_eq!(version(&db)?, MIGRATIONS.len() as u32);
+        let migrated = store.get_topic("test")?.unwrap();
+        assert_eq!(
+            migrated,
+            Topic {
+                id: topic.id,
+                name: topic.name,
+                partitions: topic.partitions,
+                ..Default::default()
+            }
+        );
+        let migrated = store.get_partition("test", PartitionIdx(0))?.unwrap();
+        assert_eq!(
+            migrated,
+            Partition {
+                id: partition.id,
+                idx: partition.idx,
+                topic: partition.topic,
+                isr: partition.isr,
+                assigned_replicas: partition.assigned_replicas,
+                leader: partition.leader,
+                leader_epoch: 0,
+                adding_replicas: vec![],
+                removing_replicas: vec![],
+            }
+        );
+        assert_eq!(store.topic_of_partition(partition.id)?.as_deref(), Some("test"));
+        // values of other types are only prefixed
+        assert_eq!(store.get_cluster_id()?, Some(cluster_id));
+        Ok(())
+    }
+
+    #[test]
+    fn indexes_existing_partitions() -> Result<()> {
+        for codec in [StoreCodec::Bincode, StoreCodec::Json] {
+            let db = sled::open(tempdir()?)?;
+            let topic = TopicV1 {
+                name: "test".to_string(),
+                partitions: [(PartitionIdx(0), vec![BrokerId(1)])].into(),
+                ..Default::default()
+            };
+            let partition = PartitionV1 {
+                id: Uuid::new_v4(),
+                idx: PartitionIdx(0),
+                topic: "test".to_string(),
+                isr: vec![1],
+                assigned_replicas: vec![1],
+                leader: BrokerId(1),
+                leader_epoch: 0,
+            };
+            let topics: HashMap<String, TopicV1> = [(topic.name.clone(), topic)].into();
+            db.insert("topics", codec.encode(&topics)?)?;
+            db.insert("test:partition:0", codec.encode(&partition)?)?;
+            db.insert(SCHEMA_VERSION_KEY, &1u32.to_be_bytes())?;
+
+            let store = Store::new(db)?;
+            let name = store.topic_of_partition(partition.id)?;
+            assert_eq!(name.as_deref(), Some("test"), "{:?}", codec);
+            let migrated = store.get_partition("test", PartitionIdx(0))?.unwrap();
+            assert_eq!(migrated.id, partition.id);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn adds_topic_delete_retention() -> Result<()> {
+        for codec in [StoreCodec::Bincode, StoreCodec::Json] {
+            let db = sled::open(tempdir()?)?;
+            let topic = TopicV1 {
+                id: Uuid::new_v4(),
+                name: "test".to_string(),
+                partitions: [(PartitionIdx(0), vec![BrokerId(1)])].into(),
+                compacted: true,
+                max_message_bytes: Some(1024),
+                ..Default::default()
+            };
+            let topics: HashMap<String, TopicV1> = [(topic.name.clone(), topic.clone())].into();
+            db.insert("topics", codec.encode(&topics)?)?;
+            db.insert(SCHEMA_VERSION_KEY, &2u32.to_be_bytes())?;
+
+            let store = Store::new(db)?;
+            let migrated = store.get_topic("test")?.unwrap();
+            assert_eq!(migrated.id, topic.id, "{:?}", codec);
+            assert_eq!(migrated.partitions, topic.partitions);
+            assert!(migrated.compacted);
+            assert_eq!(migrated.max_message_bytes, Some(1024));
+            assert_eq!(migrated.delete_retention_ms, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn adds_partition_reassignments() -> Result<()> {
+        for codec in [StoreCodec::Bincode, StoreCodec::Json] {
+            let db = sled::open(tempdir()?)?;
+            let topic = TopicV2 {
+                name: "test".to_string(),
+                partitions: [(PartitionIdx(0), vec![BrokerId(1), BrokerId(2)])].into(),
+                ..Default::default()
+            };
+            let partition = PartitionV1 {
+                id: Uuid::new_v4(),
+                idx: PartitionIdx(0),
+                topic: "test".to_string(),
+                isr: vec![1, 2],
+                assigned_replicas: vec![1, 2],
+                leader: BrokerId(2),
+                leader_epoch: 3,
+            };
+            let topics: HashMap<String, TopicV2> = [(topic.name.clone(), topic)].into();
+            db.insert("topics", codec.encode(&topics)?)?;
+            db.insert("test:partition:0", codec.encode(&partition)?)?;
+            db.insert(SCHEMA_VERSION_KEY, &3u32.to_be_bytes())?;
+
+            let store = Store::new(db)?;
+            let migrated = store.get_partition("test", PartitionIdx(0))?.unwrap();
+            assert_eq!(migrated.id, partition.id, "{:?}", codec);
+            assert_eq!(migrated.isr, vec![1, 2]);
+            assert_eq!((migrated.leader, migrated.leader_epoch), (BrokerId(2), 3));
+            assert!(!migrated.is_reassigning());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn runs_pending_migrations() -> Result<()> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        fn migration(_: &Db) -> Result<Batch> {
+            RUNS.fetch_add(1, Ordering::SeqCst);
+            Ok(Batch::default())
+        }
+
+        let db = sled::open(tempdir()?)?;
+        db.insert(SCHEMA_VERSION_KEY, &1u32.to_be_bytes())?;
+        migrate_with(&db, &[migration, migration, migration])?;
+        // the first migration had already run
+        assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+        assert_eq!(version(&db)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_store() -> Result<()> {
+        let db = sled::open(tempdir()?)?;
+        db.insert(
+            SCHEMA_VERSION_KEY,
+            &(MIGRATIONS.len() as u32 + 1).to_be_bytes(),
+        )?;
+        let err = Store::new(db).unwrap_err();
+        assert!(err.to_string().contains("newer than the supported"));
+        Ok(())
+    }
+}
@@ -12,4 +12,13 @@ pub struct Topic {
     // Config TopicConfig
     // Internal, e.g. group metadata topic
     pub internal: bool,
+    /// Whether the topic's logs are compacted (`cleanup.policy=compact`), keeping the latest
+    /// record for each key rather than expiring them by age.
+    pub compacted: bool,
+    /// The largest record batch the topic accepts (`max.message.bytes`), if it overrides the
+    /// broker's `message_max_bytes`.
+    pub max_message_bytes: Option<u32>,
+    /// How long tombstones are kept once they can be compacted (`delete.retention.ms`), if it
+    /// overrides the broker's `delete_retention_ms`.
+    pub delete_retention_ms: Option<u64>,
 }
@@ -0,0 +1,171 @@
+use std::collections::BTreeSet;
+
+use kafka_protocol::ResponseError;
+
+/// The lifecycle of a transactional producer's transactions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum TxnState {
+    /// No transaction is open.
+    #[default]
+    Empty,
+    /// Partitions have been added to the transaction, and records may be written to them.
+    Ongoing,
+    /// The transaction is committing, and markers are being written to its partitions.
+    PrepareCommit,
+    /// The transaction is aborting, and markers are being written to its partitions.
+    PrepareAbort,
+    /// The last transaction was committed.
+    CompleteCommit,
+    /// The last transaction was aborted.
+    CompleteAbort,
+}
+
+/// Errors returned when an operation is not allowed in the transaction's current state.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TxnError {
+    ConcurrentTransactions,
+    InvalidProducerEpoch,
+    InvalidProducerIdMapping,
+    InvalidTxnState,
+}
+
+impl TxnError {
+    pub fn code(&self) -> i16 {
+        match self {
+            TxnError::ConcurrentTransactions => ResponseError::ConcurrentTransactions.code(),
+            TxnError::InvalidProducerEpoch => ResponseError::InvalidProducerEpoch.code(),
+            TxnError::InvalidProducerIdMapping => ResponseError::InvalidProducerIdMapping.code(),
+            TxnError::InvalidTxnState => ResponseError::InvalidTxnState.code(),
+        }
+    }
+}
+
+/// An operation on a transactional id, replicated so that every broker applies it in the same
+/// order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TxnOp {
+    /// Bumps the producer epoch, fencing any older producer with the same transactional id.
+    Init { timeout_ms: i32 },
+    AddPartitions {
+        producer_id: i64,
+        producer_epoch: i16,
+        partitions: Vec<(String, i32)>,
+    },
+    End {
+        producer_id: i64,
+        producer_epoch: i16,
+        commit: bool,
+    },
+    /// Records that the markers ending the transaction have been written.
+    Complete,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Txn {
+    pub transactional_id: String,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub timeout_ms: i32,
+    pub state: TxnState,
+    /// The partitions written to in the current transaction.
+    pub partitions: BTreeSet<(String, i32)>,
+}
+
+impl Txn {
+    /// A transactional id that has not been initialized yet, given a newly allocated producer id.
+    pub fn new(transactional_id: &str, producer_id: i64) -> Self {
+        Self {
+            transactional_id: transactional_id.to_string(),
+            producer_id,
+            producer_epoch: -1,
+            timeout_ms: 0,
+            state: TxnState::Empty,
+            partitions: BTreeSet::new(),
+        }
+    }
+
+    pub fn apply(&mut self, op: &TxnOp) -> Result<(), TxnError> {
+        match op {
+            TxnOp::Init { timeout_ms } => self.init(*timeout_ms),
+            TxnOp::AddPartitions {
+                producer_id,
+                producer_epoch,
+                partitions,
+            } => {
+                self.check_producer(*producer_id, *producer_epoch)?;
+                self.add_partitions(partitions)
+            }
+            TxnOp::End {
+                producer_id,
+                producer_epoch,
+                commit,
+            } => {
+                self.check_producer(*producer_id, *producer_epoch)?;
+                self.end(*commit)
+            }
+            TxnOp::Complete => self.complete(),
+        }
+    }
+
+    // open transactions aren't timed out yet, so a producer that dies mid transaction has to
+    // end it before its transactional id can be initialized again
+    fn init(&mut self, timeout_ms: i32) -> Result<(), TxnError> {
+        if self.is_open() {
+            return Err(TxnError::ConcurrentTransactions);
+        }
+        self.producer_epoch += 1;
+        self.timeout_ms = timeout_ms;
+        self.state = TxnState::Empty;
+        self.partitions.clear();
+        Ok(())
+    }
+
+    fn add_partitions(&mut self, partitions: &[(String, i32)]) -> Result<(), TxnError> {
+        if self.is_ending() {
+            return Err(TxnError::ConcurrentTransactions);
+        }
+        self.state = TxnState::Ongoing;
+        self.partitions.extend(partitions.iter().cloned());
+        Ok(())
+    }
+
+    fn end(&mut self, commit: bool) -> Result<(), TxnError> {
+        self.state = match (self.state, commit) {
+            (TxnState::Ongoing, true) => TxnState::PrepareCommit,
+            (TxnState::Ongoing, false) => TxnState::PrepareAbort,
+            // retries of an end that is already underway
+            (TxnState::PrepareCommit | TxnState::CompleteCommit, true) => self.state,
+            (TxnState::PrepareAbort | TxnState::CompleteAbort, false) => self.state,
+            _ => return Err(TxnError::InvalidTxnState),
+        };
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<(), TxnError> {
+        self.state = match self.state {
+            TxnState::PrepareCommit => TxnState::CompleteCommit,
+            TxnState::PrepareAbort => TxnState::CompleteAbort,
+            _ => return Err(TxnError::InvalidTxnState),
+        };
+        self.partitions.clear();
+        Ok(())
+    }
+
+    fn check_producer(&self, producer_id: i64, producer_epoch: i16) -> Result<(), TxnError> {
+        if producer_id != self.producer_id {
+            return Err(TxnError::InvalidProducerIdMapping);
+        }
+        if producer_epoch != self.producer_epoch {
+            return Err(TxnError::InvalidProducerEpoch);
+        }
+        Ok(())
+    }
+
+    fn is_ending(&self) -> bool {
+        matches!(self.state, TxnState::PrepareCommit | TxnState::PrepareAbort)
+    }
+
+    fn is_open(&self) -> bool {
+        self.state == TxnState::Ongoing || self.is_ending()
+    }
+}
@@ -1,22 +1,62 @@
+use crate::broker::context::RequestContext;
+use crate::broker::handler::api_versions::{supported_versions, unsupported_version};
 use crate::kafka::codec::KafkaServerCodec;
+use crate::kafka::error::ErrorKind;
 use anyhow::Result;
+use futures::stream::FuturesOrdered;
 use futures::SinkExt;
-use kafka_protocol::messages::{RequestKind, ResponseHeader, ResponseKind};
+use kafka_protocol::messages::{ApiKey, RequestKind, ResponseHeader, ResponseKind};
 
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::oneshot;
+use tokio::task::JoinSet;
 
 use crate::Shutdown;
 
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc::UnboundedSender,
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
 };
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+/// Binds a listener to `addr` that queues up to `backlog` connections waiting to be accepted.
+pub fn bind(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(backlog)?)
+}
+
+/// Accepts connections on `listener` with `acceptors` tasks sharing it, so that a burst of
+/// connections isn't held up behind a single accept loop, until shutdown.
 pub async fn receive_task(
     listener: TcpListener,
-    in_tx: UnboundedSender<(RequestKind, oneshot::Sender<ResponseKind>)>,
+    in_tx: UnboundedSender<(RequestKind, RequestContext, oneshot::Sender<ResponseKind>)>,
+    max_in_flight: usize,
+    acceptors: usize,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let listener = Arc::new(listener);
+    let mut tasks = JoinSet::new();
+    for _ in 0..acceptors.max(1) {
+        let accept = accept(listener.clone(), in_tx.clone(), max_in_flight, shutdown.clone());
+        tasks.spawn(accept);
+    }
+    while let Some(res) = tasks.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+async fn accept(
+    listener: Arc<TcpListener>,
+    in_tx: UnboundedSender<(RequestKind, RequestContext, oneshot::Sender<ResponseKind>)>,
+    max_in_flight: usize,
     mut shutdown: Shutdown,
 ) -> Result<()> {
     loop {
@@ -26,7 +66,7 @@ pub async fn receive_task(
             Ok((s, _addr)) = listener.accept() => {
                 let peer_in_tx = in_tx.clone();
                 tokio::spawn(async move {
-                    match stream_messages(s, peer_in_tx).await {
+                    match stream_messages(s, peer_in_tx, max_in_flight).await {
                         Ok(()) => {  }
                         Err(_err) => {  }
                     }
@@ -38,22 +78,305 @@ pub async fn receive_task(
     Ok(())
 }
 
+/// Reads requests off a connection and writes back their responses. Up to `max_in_flight`
+/// requests are handled at once, but responses are always written in the order the requests
+/// arrived, as Kafka clients expect. Produce requests are only ever handled one at a time, so
+/// that the batches a connection sends are appended in the order they were sent.
 async fn stream_messages(
     mut stream: TcpStream,
-    in_tx: UnboundedSender<(RequestKind, oneshot::Sender<ResponseKind>)>,
+    in_tx: UnboundedSender<(RequestKind, RequestContext, oneshot::Sender<ResponseKind>)>,
+    max_in_flight: usize,
 ) -> Result<()> {
     let (r, w) = stream.split();
-    let mut stream_in = FramedRead::new(r, KafkaServerCodec::new());
-    let mut stream_out = FramedWrite::new(w, KafkaServerCodec::new());
-    while let Some((header, message)) = stream_in.try_next().await? {
-        let (cb_tx, cb_rx) = oneshot::channel();
-        in_tx.send((message, cb_tx))?;
-        let res = cb_rx.await?;
-        let version = header.request_api_version;
-        let correlation_id = header.correlation_id;
+    let versions = supported_versions();
+    let mut stream_in = FramedRead::new(r, KafkaServerCodec::new(versions.clone()));
+    let mut stream_out = FramedWrite::new(w, KafkaServerCodec::new(versions));
+    let mut in_flight = FuturesOrdered::new();
+    let (produce_tx, produce_rx) = unbounded_channel();
+    tokio::spawn(handle_produces(produce_rx, in_tx.clone()));
+    loop {
+        tokio::select! {
+            req = stream_in.try_next(), if in_flight.len() < max_in_flight.max(1) => {
+                let (header, message) = match req? {
+                    Some(req) => req,
+                    None => break,
+                };
+                let (cb_tx, cb_rx) = oneshot::channel();
+                let version = match message {
+                    Ok(message) => {
+                        let mut ctx = RequestContext::new(&message);
+                        if let Ok(api_key) = ApiKey::try_from(header.request_api_key) {
+                            ctx = ctx.with_api_key(api_key);
+                        }
+                        match message {
+                            RequestKind::ProduceRequest(_) => {
+                                produce_tx.send((message, ctx, cb_tx))?
+                            }
+                            _ => in_tx.send((message, ctx, cb_tx))?,
+                        }
+                        header.request_api_version
+                    }
+                    Err(err @ ErrorKind::UnsupportedVersion { .. }) => {
+                        let (version, res) = ApiKey::try_from(header.request_api_key)
+                            .ok()
+                            .and_then(|key| unsupported_version(key, header.request_api_version))
+                            .ok_or(err)?;
+                        let _ = cb_tx.send(res);
+                        version
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let correlation_id = header.correlation_id;
+                in_flight.push_back(async move { (version, correlation_id, cb_rx.await) });
+            }
+            Some((version, correlation_id, res)) = in_flight.next() => {
+                let mut header = ResponseHeader::default();
+                header.correlation_id = correlation_id;
+                stream_out.send((version, header, res?)).await?;
+            }
+        }
+    }
+    // the client stopped sending, but may still be reading
+    while let Some((version, correlation_id, res)) = in_flight.next().await {
         let mut header = ResponseHeader::default();
         header.correlation_id = correlation_id;
-        stream_out.send((version, header, res)).await?;
+        stream_out.send((version, header, res?)).await?;
     }
     Ok(())
 }
+
+/// Hands a connection's produce requests on to be handled one at a time, each once the one
+/// before it has been answered.
+async fn handle_produces(
+    mut produces: UnboundedReceiver<(RequestKind, RequestContext, oneshot::Sender<ResponseKind>)>,
+    in_tx: UnboundedSender<(RequestKind, RequestContext, oneshot::Sender<ResponseKind>)>,
+) {
+    while let Some((req, ctx, cb)) = produces.recv().await {
+        let (res_tx, res_rx) = oneshot::channel();
+        if in_tx.send((req, ctx, res_tx)).is_err() {
+            break;
+        }
+        // a request that couldn't be answered leaves the connection to find out from `cb`
+        if let Ok(res) = res_rx.await {
+            let _ = cb.send(res);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, receive_task};
+    use crate::Shutdown;
+    use anyhow::Result;
+    use bytes::BytesMut;
+    use futures::future::try_join_all;
+    use kafka_protocol::messages::{
+        ApiKey, ApiVersionsResponse, DescribeLogDirsResponse, ProduceRequest, ProduceResponse,
+        RequestHeader, RequestKind, ResponseHeader, ResponseKind,
+    };
+    use kafka_protocol::protocol::{Decodable, Encodable};
+    use kafka_protocol::ResponseError::UnsupportedVersion;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc::UnboundedReceiver;
+    use std::time::Duration;
+
+    async fn write_request(
+        stream: &mut TcpStream,
+        api_key: ApiKey,
+        version: i16,
+        correlation_id: i32,
+    ) -> Result<()> {
+        let mut header = RequestHeader::default();
+        header.request_api_key = api_key as i16;
+        header.request_api_version = version;
+        header.correlation_id = correlation_id;
+        let mut body = BytesMut::new();
+        header.encode(&mut body, api_key.request_header_version(version))?;
+
+        stream.write_i32(body.len() as i32).await?;
+        stream.write_all(&body).await?;
+        Ok(())
+    }
+
+    /// Writes a produce request without any records.
+    async fn write_produce(stream: &mut TcpStream, correlation_id: i32) -> Result<()> {
+        let mut header = RequestHeader::default();
+        header.request_api_key = ApiKey::ProduceKey as i16;
+        header.request_api_version = 1;
+        header.correlation_id = correlation_id;
+        let mut body = BytesMut::new();
+        header.encode(&mut body, ApiKey::ProduceKey.request_header_version(1))?;
+        ProduceRequest::default().encode(&mut body, 1)?;
+
+        stream.write_i32(body.len() as i32).await?;
+        stream.write_all(&body).await?;
+        Ok(())
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> Result<BytesMut> {
+        let len = stream.read_i32().await?;
+        let mut res = vec![0u8; len as usize];
+        stream.read_exact(&mut res).await?;
+        Ok(BytesMut::from(&res[..]))
+    }
+
+    async fn send(
+        stream: &mut TcpStream,
+        api_key: ApiKey,
+        version: i16,
+        correlation_id: i32,
+    ) -> Result<BytesMut> {
+        write_request(stream, api_key, version, correlation_id).await?;
+        read_response(stream).await
+    }
+
+    #[tokio::test]
+    async fn unsupported_version() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (in_tx, mut in_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(receive_task(listener, in_tx, 1, 1, Shutdown::new()));
+        tokio::spawn(async move {
+            while let Some((_req, _ctx, cb)) = in_rx.recv().await {
+                let res = ApiVersionsResponse::default();
+                let _ = cb.send(ResponseKind::ApiVersionsResponse(res));
+            }
+        });
+
+        // the error is sent at the closest version we support to the one asked for
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut res = send(&mut stream, ApiKey::DescribeLogDirsKey, 5, 1).await?;
+        let version = ApiKey::DescribeLogDirsKey.response_header_version(4);
+        let header = ResponseHeader::decode(&mut res, version)?;
+        assert_eq!(header.correlation_id, 1);
+        let res = DescribeLogDirsResponse::decode(&mut res, 4)?;
+        assert_eq!(res.error_code, UnsupportedVersion.code());
+
+        // clients retry ApiVersions at v0 and read the supported versions from the error
+        let mut res = send(&mut stream, ApiKey::ApiVersionsKey, 9, 2).await?;
+        let header = ResponseHeader::decode(&mut res, 0)?;
+        assert_eq!(header.correlation_id, 2);
+        let res = ApiVersionsResponse::decode(&mut res, 0)?;
+        assert_eq!(res.error_code, UnsupportedVersion.code());
+        let create_topics = &res.api_keys[&(ApiKey::CreateTopicsKey as i16)];
+        assert_eq!(create_topics.max_version, 7);
+
+        // and the connection is still usable
+        let mut res = send(&mut stream, ApiKey::ApiVersionsKey, 0, 3).await?;
+        assert_eq!(ResponseHeader::decode(&mut res, 0)?.correlation_id, 3);
+        let res = ApiVersionsResponse::decode(&mut res, 0)?;
+        assert_eq!(res.error_code, 0);
+
+        // a response with nowhere to put the error isn't sent, the connection is closed instead
+        let mut stream = TcpStream::connect(addr).await?;
+        assert!(send(&mut stream, ApiKey::CreateTopicsKey, 8, 4).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_connects() -> Result<()> {
+        let listener = bind("127.0.0.1:0".parse()?, 128)?;
+        let addr = listener.local_addr()?;
+        let (in_tx, _in_rx) = tokio::sync::mpsc::unbounded_channel();
+        let shutdown = Shutdown::new();
+        let acceptors = tokio::spawn(receive_task(listener, in_tx, 1, 4, shutdown.clone()));
+
+        // each client is only answered once its connection has been accepted
+        let clients = (0..64).map(|i| async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            let mut res = send(&mut stream, ApiKey::ApiVersionsKey, 9, i).await?;
+            Ok::<_, anyhow::Error>(ResponseHeader::decode(&mut res, 0)?.correlation_id)
+        });
+        let ids = tokio::time::timeout(Duration::from_secs(5), try_join_all(clients)).await??;
+        assert_eq!(ids, (0..64).collect::<Vec<_>>());
+
+        // every acceptor stops on shutdown
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), acceptors).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_keep_request_order() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (in_tx, mut in_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(receive_task(listener, in_tx, 3, 1, Shutdown::new()));
+        // waits until every request is in flight, then answers them newest first, tagging each
+        // response with the order it was answered in
+        tokio::spawn(async move {
+            let mut pending = Vec::new();
+            while let Some((_req, _ctx, cb)) = in_rx.recv().await {
+                pending.push(cb);
+                if pending.len() == 3 {
+                    for (i, cb) in pending.drain(..).rev().enumerate() {
+                        let mut res = ApiVersionsResponse::default();
+                        res.throttle_time_ms = i as i32;
+                        let _ = cb.send(ResponseKind::ApiVersionsResponse(res));
+                    }
+                }
+            }
+        });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        for correlation_id in 1..=3 {
+            write_request(&mut stream, ApiKey::ApiVersionsKey, 1, correlation_id).await?;
+        }
+        for (correlation_id, answered) in (1..=3).zip([2, 1, 0]) {
+            let mut res = read_response(&mut stream).await?;
+            assert_eq!(
+                ResponseHeader::decode(&mut res, 0)?.correlation_id,
+                correlation_id
+            );
+            let res = ApiVersionsResponse::decode(&mut res, 1)?;
+            assert_eq!(res.throttle_time_ms, answered);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn produces_are_handled_one_at_a_time() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (in_tx, mut in_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(receive_task(listener, in_tx, 3, 1, Shutdown::new()));
+
+        // two produces with an ApiVersions between them
+        let mut stream = TcpStream::connect(addr).await?;
+        write_produce(&mut stream, 1).await?;
+        write_request(&mut stream, ApiKey::ApiVersionsKey, 1, 2).await?;
+        write_produce(&mut stream, 3).await?;
+
+        async fn recv<T>(rx: &mut UnboundedReceiver<T>) -> Option<T> {
+            let next = tokio::time::timeout(Duration::from_millis(200), rx.recv());
+            next.await.ok().flatten()
+        }
+        // the ApiVersions is handled alongside the first produce, while the second waits for it
+        let mut first_cb = None;
+        let mut versions_cb = None;
+        for _ in 0..2 {
+            match recv(&mut in_rx).await.unwrap() {
+                (RequestKind::ProduceRequest(_), _, cb) => first_cb = Some(cb),
+                (RequestKind::ApiVersionsRequest(_), _, cb) => versions_cb = Some(cb),
+                (req, _, _) => panic!("unexpected {:?}", req),
+            }
+        }
+        let (first_cb, versions_cb) = (first_cb.unwrap(), versions_cb.unwrap());
+        assert!(recv(&mut in_rx).await.is_none());
+
+        let _ = first_cb.send(ResponseKind::ProduceResponse(ProduceResponse::default()));
+        let (second, _, second_cb) = recv(&mut in_rx).await.unwrap();
+        assert!(matches!(second, RequestKind::ProduceRequest(_)));
+        let _ = second_cb.send(ResponseKind::ProduceResponse(ProduceResponse::default()));
+        let res = ApiVersionsResponse::default();
+        let _ = versions_cb.send(ResponseKind::ApiVersionsResponse(res));
+
+        for correlation_id in 1..=3 {
+            let mut res = read_response(&mut stream).await?;
+            let header = ResponseHeader::decode(&mut res, 0)?;
+            assert_eq!(header.correlation_id, correlation_id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! A minimal transaction coordinator. Transaction state is replicated through raft, so any
+//! broker can coordinate any transactional id, while markers are written straight to the logs
+//! of the partitions in the transaction.
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+
+use crate::broker::fsm::Transition;
+use crate::broker::replica::{ABORT_MARKER, COMMIT_MARKER};
+use crate::broker::state::partition::PartitionIdx;
+use crate::broker::state::txn::{Txn, TxnError, TxnOp};
+use crate::broker::Broker;
+
+/// A batch holding the marker that commits or aborts a producer's transaction.
+pub(crate) fn marker_batch(producer_id: i64, producer_epoch: i16, commit: bool) -> Result<Bytes> {
+    let marker = match commit {
+        true => COMMIT_MARKER,
+        false => ABORT_MARKER,
+    };
+    let mut key = Vec::with_capacity(4);
+    key.extend_from_slice(&0i16.to_be_bytes());
+    key.extend_from_slice(&marker.to_be_bytes());
+    let mut value = Vec::with_capacity(6);
+    value.extend_from_slice(&0i16.to_be_bytes());
+    // the coordinator epoch, which only matters once coordinators can move
+    value.extend_from_slice(&0i32.to_be_bytes());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+    let record = Record {
+        transactional: true,
+        control: true,
+        partition_leader_epoch: 0,
+        producer_id,
+        producer_epoch,
+        timestamp_type: TimestampType::Creation,
+        offset: 0,
+        sequence: -1,
+        timestamp: now.as_millis() as i64,
+        key: Some(Bytes::from(key)),
+        value: Some(Bytes::from(value)),
+        headers: Default::default(),
+    };
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(&mut buf, [record].iter(), &options)?;
+    Ok(buf.freeze())
+}
+
+impl Broker {
+    /// Replicates an operation on a transactional id, returning the updated transaction or the
+    /// reason the operation was rejected.
+    pub(crate) async fn update_txn(
+        &self,
+        transactional_id: &str,
+        op: TxnOp,
+    ) -> Result<std::result::Result<Txn, TxnError>> {
+        let res = self
+            .client
+            .propose(
+                Transition::UpdateTxnState {
+                    transactional_id: transactional_id.to_string(),
+                    op,
+                }
+                .serialize()?,
+            )
+            .await?;
+        Ok(bincode::deserialize(&res)?)
+    }
+
+    /// Hands out a producer id to a producer that isn't transactional.
+    pub(crate) async fn allocate_producer_id(&self) -> Result<i64> {
+        let res = self
+            .client
+            .propose(Transition::AllocateProducerId.serialize()?)
+            .await?;
+        Ok(bincode::deserialize(&res)?)
+    }
+
+    /// Writes the marker ending the transaction to each of its partitions, failing if any of
+    /// them couldn't be written. Markers are written again when the end is retried, which is
+    /// harmless for the partitions that already have one.
+    // there is no WriteTxnMarkers yet, so transactions with partitions led by other brokers
+    // can't end
+    pub(crate) async fn write_markers(&self, txn: &Txn, commit: bool) -> Result<()> {
+        let marker = marker_batch(txn.producer_id, txn.producer_epoch, commit)?;
+        let mut failed = vec![];
+        for (topic, idx) in &txn.partitions {
+            let partition = match self.store.get_partition(topic, PartitionIdx(*idx))? {
+                Some(partition) if partition.leader == self.config.id => partition,
+                _ => {
+                    tracing::warn!(%topic, idx, "cannot write marker to a partition we don't lead");
+                    failed.push(format!("{}-{}", topic, idx));
+                    continue;
+                }
+            };
+            let replica = match self.replicas.get(partition.id) {
+                Some(replica) => replica,
+                None => {
+                    failed.push(partition.dir_name());
+                    continue;
+                }
+            };
+            let mut replica = replica.lock().await;
+            replica.start_epoch(partition.leader_epoch);
+            let offset = replica.log.newest_offset();
+            if let Err(e) = replica.log.write_all(&marker) {
+                tracing::error!(%e, %topic, idx, "could not write marker");
+                failed.push(partition.dir_name());
+                continue;
+            }
+            replica.track_transactions(offset, &marker);
+            if replica.update_high_watermark(partition.leader, &partition.isr) {
+                self.produce_purgatory.complete(&partition.id);
+            }
+            self.fetch_purgatory.complete(&partition.id);
+        }
+        if !failed.is_empty() {
+            anyhow::bail!("could not write markers to {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
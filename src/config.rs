@@ -1,4 +1,5 @@
 use crate::broker::config::BrokerConfig;
+use crate::health::HealthConfig;
 use crate::raft::config::RaftConfig;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -6,6 +7,7 @@ use crate::raft::config::RaftConfig;
 pub struct JosefineConfig {
     pub raft: RaftConfig,
     pub broker: BrokerConfig,
+    pub health: HealthConfig,
 }
 
 pub fn config<P: AsRef<std::path::Path>>(config_path: P) -> JosefineConfig {
@@ -0,0 +1,179 @@
+//! HTTP endpoints for orchestrators to probe: `/healthz` answers as long as the process is
+//! serving, while `/readyz` only reports ready once the node has joined the cluster and, if it
+//! is the controller, has finished recovering its logs.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::raft::client::RaftClient;
+use crate::Shutdown;
+
+/// The most bytes of a request that are read, which is plenty for a probe.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// The address the health endpoints listen on. Unset, they aren't served.
+    pub addr: Option<SocketAddr>,
+}
+
+/// What the health endpoints report on: the raft status of the node, and whether the broker has
+/// recovered its logs.
+#[derive(Clone, Debug)]
+pub struct Health {
+    client: RaftClient,
+    recovered: Arc<AtomicBool>,
+}
+
+impl Health {
+    pub fn new(client: RaftClient) -> Self {
+        Self {
+            client,
+            recovered: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Marks the broker's logs as recovered.
+    pub fn set_recovered(&self) {
+        self.recovered.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the node knows the leader of the cluster, and has recovered if it leads it.
+    pub fn is_ready(&self) -> bool {
+        let status = self.client.status();
+        status.leader.is_some() && (!status.is_leader() || self.recovered.load(Ordering::SeqCst))
+    }
+
+    fn respond(&self, path: &str) -> (&'static str, &'static str) {
+        match path {
+            "/healthz" => ("200 OK", "ok"),
+            "/readyz" if self.is_ready() => ("200 OK", "ready"),
+            "/readyz" => ("503 Service Unavailable", "not ready"),
+            _ => ("404 Not Found", "not found"),
+        }
+    }
+}
+
+/// Serves the health endpoints until shutdown, answering one request per connection.
+pub async fn serve(listener: TcpListener, health: Health, mut shutdown: Shutdown) -> Result<()> {
+    tracing::info!(addr = ?listener.local_addr()?, "serving health endpoints");
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            accepted = listener.accept() => {
+                // a failed accept only loses that connection, so keep serving the next ones
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!(%e, "could not accept health probe");
+                        continue;
+                    }
+                };
+                let health = health.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer(stream, &health).await {
+                        tracing::debug!(%e, "could not answer health probe");
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn answer(mut stream: TcpStream, health: &Health) -> Result<()> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_BYTES {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let (status, body) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", path, ..] => health.respond(path),
+        _ => ("405 Method Not Allowed", "method not allowed"),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{mpsc, watch};
+
+    use super::{serve, Health};
+    use crate::raft::client::RaftClient;
+    use crate::raft::Status;
+    use crate::Shutdown;
+
+    async fn get(addr: SocketAddr, path: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn ready_once_joined() -> Result<()> {
+        let (status_tx, status_rx) = watch::channel(Status {
+            id: 1,
+            ..Default::default()
+        });
+        let client =
+            RaftClient::new(mpsc::channel(1).0, Duration::from_secs(1)).with_status(status_rx);
+        let health = Health::new(client);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(listener, health.clone(), shutdown.clone()));
+
+        assert_eq!(get(addr, "/healthz").await?, "HTTP/1.1 200 OK");
+        assert_eq!(
+            get(addr, "/readyz").await?,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+
+        // the node joins a cluster led by another node
+        status_tx.send_modify(|status| status.leader = Some(2));
+        assert_eq!(get(addr, "/readyz").await?, "HTTP/1.1 200 OK");
+
+        // once it leads, it has to have recovered as well
+        status_tx.send_modify(|status| status.leader = Some(1));
+        assert_eq!(
+            get(addr, "/readyz").await?,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        health.set_recovered();
+        assert_eq!(get(addr, "/readyz").await?, "HTTP/1.1 200 OK");
+        assert_eq!(get(addr, "/missing").await?, "HTTP/1.1 404 Not Found");
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), server).await???;
+        Ok(())
+    }
+}
@@ -1,50 +1,92 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use bytes::BytesMut;
-use kafka_protocol::messages::api_versions_response::ApiVersionsResponse;
+use kafka_protocol::messages::api_versions_response::{ApiVersion, ApiVersionsResponse};
 use kafka_protocol::messages::*;
 
 use kafka_protocol::protocol::buf::ByteBuf;
 use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion};
+use kafka_protocol::ResponseError::UnsupportedVersion;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tokio_util::codec;
 
 use crate::kafka::error::ErrorKind;
-use crate::kafka::error::ErrorKind::{DecodeError, EncodeError};
+use crate::kafka::error::ErrorKind::EncodeError;
+
+/// The largest request a client may send, as Kafka's `socket.request.max.bytes`. Frames
+/// claiming to be larger are rejected before any buffer is reserved for them.
+pub const MAX_REQUEST_BYTES: usize = 100 * 1024 * 1024;
 
 pub struct KafkaServerCodec {
+    versions: BTreeMap<i16, ApiVersion>,
     length_codec: codec::LengthDelimitedCodec,
 }
 
 impl KafkaServerCodec {
-    pub fn new() -> Self {
+    /// Creates a codec that accepts the given range of versions for each API.
+    pub fn new(versions: BTreeMap<i16, ApiVersion>) -> Self {
         Self {
+            versions,
             length_codec: codec::LengthDelimitedCodec::builder()
-                .max_frame_length(i32::MAX as usize)
+                .max_frame_length(MAX_REQUEST_BYTES)
                 .length_field_length(4)
                 .new_codec(),
         }
     }
 
+    /// Decodes a request from `buf`, returning `None` until a whole frame has arrived. Unlike
+    /// the [`codec::Decoder`] impl, a request with an unsupported version is an error. Malformed
+    /// input is an error rather than a panic, so this is the entry point for fuzzing.
+    ///
+    /// kafka-protocol reserves space for arrays by their declared length before reading them, so
+    /// an absurd length can still fail to allocate.
+    pub fn try_decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<(RequestHeader, RequestKind)>, ErrorKind> {
+        match codec::Decoder::decode(self, buf)? {
+            Some((header, request)) => Ok(Some((header, request?))),
+            None => Ok(None),
+        }
+    }
+
     fn read_version(src: &mut BytesMut) -> Result<i16, ErrorKind> {
-        let mut bytes = src.peek_bytes(2..4);
+        let mut bytes = src.try_peek_bytes(2..4)?;
         Ok(bytes.try_get_i16()?)
     }
+
+    fn supports(&self, api_key: i16, version: i16) -> bool {
+        self.versions
+            .get(&api_key)
+            .is_none_or(|v| (v.min_version..=v.max_version).contains(&version))
+    }
 }
 
+/// Decodes requests, yielding requests with an unsupported version as an
+/// [`ErrorKind::UnsupportedVersion`] item so the connection can stay open.
 impl codec::Decoder for KafkaServerCodec {
-    type Item = (RequestHeader, RequestKind);
+    type Item = (RequestHeader, Result<RequestKind, ErrorKind>);
     type Error = ErrorKind;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if let Some(mut bytes) = self.length_codec.decode(src)? {
             let version = Self::read_version(&mut bytes)?;
             let header = RequestHeader::decode(&mut bytes, version)?;
+            if !self.supports(header.request_api_key, version) {
+                let err = ErrorKind::UnsupportedVersion {
+                    api_key: header.request_api_key,
+                    version,
+                };
+                return Ok(Some((header, Err(err))));
+            }
             let api_key = ApiKey::try_from(header.request_api_key)?;
             let request = decode(&mut bytes, api_key, version)?;
-            Ok(Some((header, request)))
+            Ok(Some((header, Ok(request))))
         } else {
             Ok(None)
         }
@@ -95,6 +137,75 @@ fn encode(
             header.encode(bytes, FindCoordinatorResponse::header_version(version))?;
             res.encode(bytes, version)?;
         }
+        ResponseKind::ProduceResponse(res) => {
+            header.encode(bytes, ProduceResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::DescribeClientQuotasResponse(res) => {
+            header.encode(bytes, DescribeClientQuotasResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::AlterClientQuotasResponse(res) => {
+            header.encode(bytes, AlterClientQuotasResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::FetchResponse(res) => {
+            header.encode(bytes, FetchResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::JoinGroupResponse(res) => {
+            header.encode(bytes, JoinGroupResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::SyncGroupResponse(res) => {
+            header.encode(bytes, SyncGroupResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::HeartbeatResponse(res) => {
+            header.encode(bytes, HeartbeatResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::LeaveGroupResponse(res) => {
+            header.encode(bytes, LeaveGroupResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::DescribeProducersResponse(res) => {
+            header.encode(bytes, DescribeProducersResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::OffsetForLeaderEpochResponse(res) => {
+            header.encode(bytes, OffsetForLeaderEpochResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::InitProducerIdResponse(res) => {
+            header.encode(bytes, InitProducerIdResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::AddPartitionsToTxnResponse(res) => {
+            header.encode(bytes, AddPartitionsToTxnResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::EndTxnResponse(res) => {
+            header.encode(bytes, EndTxnResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::DescribeLogDirsResponse(res) => {
+            header.encode(bytes, DescribeLogDirsResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::AlterReplicaLogDirsResponse(res) => {
+            header.encode(bytes, AlterReplicaLogDirsResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::ListPartitionReassignmentsResponse(res) => {
+            let header_version = ListPartitionReassignmentsResponse::header_version(version);
+            header.encode(bytes, header_version)?;
+            res.encode(bytes, version)?;
+        }
+        ResponseKind::ListOffsetsResponse(res) => {
+            header.encode(bytes, ListOffsetsResponse::header_version(version))?;
+            res.encode(bytes, version)?;
+        }
         _ => return Err(ErrorKind::UnsupportedOperation),
     };
 
@@ -123,19 +234,172 @@ fn decode(bytes: &mut BytesMut, api_key: ApiKey, version: i16) -> Result<Request
             let req = FindCoordinatorRequest::decode(bytes, version)?;
             Ok(RequestKind::FindCoordinatorRequest(req))
         }
+        ApiKey::ProduceKey => {
+            let req = ProduceRequest::decode(bytes, version)?;
+            Ok(RequestKind::ProduceRequest(req))
+        }
+        ApiKey::DescribeClientQuotasKey => {
+            let req = DescribeClientQuotasRequest::decode(bytes, version)?;
+            Ok(RequestKind::DescribeClientQuotasRequest(req))
+        }
+        ApiKey::AlterClientQuotasKey => {
+            let req = AlterClientQuotasRequest::decode(bytes, version)?;
+            Ok(RequestKind::AlterClientQuotasRequest(req))
+        }
+        ApiKey::FetchKey => {
+            let req = FetchRequest::decode(bytes, version)?;
+            Ok(RequestKind::FetchRequest(req))
+        }
+        ApiKey::JoinGroupKey => {
+            let req = JoinGroupRequest::decode(bytes, version)?;
+            Ok(RequestKind::JoinGroupRequest(req))
+        }
+        ApiKey::SyncGroupKey => {
+            let req = SyncGroupRequest::decode(bytes, version)?;
+            Ok(RequestKind::SyncGroupRequest(req))
+        }
+        ApiKey::HeartbeatKey => {
+            let req = HeartbeatRequest::decode(bytes, version)?;
+            Ok(RequestKind::HeartbeatRequest(req))
+        }
+        ApiKey::LeaveGroupKey => {
+            let req = LeaveGroupRequest::decode(bytes, version)?;
+            Ok(RequestKind::LeaveGroupRequest(req))
+        }
+        ApiKey::DescribeProducersKey => {
+            let req = DescribeProducersRequest::decode(bytes, version)?;
+            Ok(RequestKind::DescribeProducersRequest(req))
+        }
+        ApiKey::OffsetForLeaderEpochKey => {
+            let req = OffsetForLeaderEpochRequest::decode(bytes, version)?;
+            Ok(RequestKind::OffsetForLeaderEpochRequest(req))
+        }
+        ApiKey::InitProducerIdKey => {
+            let req = InitProducerIdRequest::decode(bytes, version)?;
+            Ok(RequestKind::InitProducerIdRequest(req))
+        }
+        ApiKey::AddPartitionsToTxnKey => {
+            let req = AddPartitionsToTxnRequest::decode(bytes, version)?;
+            Ok(RequestKind::AddPartitionsToTxnRequest(req))
+        }
+        ApiKey::EndTxnKey => {
+            let req = EndTxnRequest::decode(bytes, version)?;
+            Ok(RequestKind::EndTxnRequest(req))
+        }
+        ApiKey::DescribeLogDirsKey => {
+            let req = DescribeLogDirsRequest::decode(bytes, version)?;
+            Ok(RequestKind::DescribeLogDirsRequest(req))
+        }
+        ApiKey::AlterReplicaLogDirsKey => {
+            let req = AlterReplicaLogDirsRequest::decode(bytes, version)?;
+            Ok(RequestKind::AlterReplicaLogDirsRequest(req))
+        }
+        ApiKey::ListPartitionReassignmentsKey => {
+            let req = ListPartitionReassignmentsRequest::decode(bytes, version)?;
+            Ok(RequestKind::ListPartitionReassignmentsRequest(req))
+        }
+        ApiKey::ListOffsetsKey => {
+            let req = ListOffsetsRequest::decode(bytes, version)?;
+            Ok(RequestKind::ListOffsetsRequest(req))
+        }
         _ => Err(ErrorKind::UnsupportedOperation),
     }
 }
 
+/// The requests a client has sent and not had a response to, by correlation id, along with the
+/// callbacks waiting on their responses. At most `max` are kept, and any older than `ttl` are
+/// evicted, so a server that stops responding can't make them grow without bound. Evicting a
+/// request drops its callback, which fails the wait for its response.
+#[derive(Debug)]
+pub struct InFlightRequests {
+    requests: HashMap<i32, InFlight>,
+    max: usize,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    header: RequestHeader,
+    sent: Instant,
+    cb: oneshot::Sender<ResponseKind>,
+}
+
+impl InFlightRequests {
+    pub fn new(max: usize, ttl: Duration) -> Self {
+        Self {
+            requests: HashMap::new(),
+            max: max.max(1),
+            ttl,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Tracks a request that is about to be sent, evicting the oldest request if there are
+    /// already as many as allowed.
+    pub fn insert(&mut self, header: RequestHeader, cb: oneshot::Sender<ResponseKind>) {
+        self.evict_expired();
+        if self.requests.len() >= self.max {
+            let oldest = self
+                .requests
+                .iter()
+                .min_by_key(|(_, r)| r.sent)
+                .map(|(id, _)| *id);
+            if let Some(id) = oldest {
+                tracing::warn!(correlation_id = id, "evicted request, too many in flight");
+                self.requests.remove(&id);
+            }
+        }
+        let request = InFlight {
+            header,
+            sent: Instant::now(),
+            cb,
+        };
+        self.requests.insert(request.header.correlation_id, request);
+    }
+
+    /// The header of the request with the given correlation id, if it is still in flight.
+    pub fn header(&self, correlation_id: i32) -> Option<&RequestHeader> {
+        self.requests.get(&correlation_id).map(|r| &r.header)
+    }
+
+    /// Hands a response to whoever is waiting on it.
+    pub fn complete(&mut self, correlation_id: i32, res: ResponseKind) {
+        if let Some(request) = self.requests.remove(&correlation_id) {
+            let _ = request.cb.send(res);
+        }
+    }
+
+    /// Evicts the requests that have waited longer than the ttl, returning how many there were.
+    pub fn evict_expired(&mut self) -> usize {
+        let ttl = self.ttl;
+        let before = self.requests.len();
+        self.requests.retain(|id, r| {
+            let expired = r.sent.elapsed() >= ttl;
+            if expired {
+                tracing::warn!(correlation_id = id, "evicted request, no response in time");
+            }
+            !expired
+        });
+        before - self.requests.len()
+    }
+}
+
 #[derive(Debug)]
 pub struct KafkaClientCodec {
     correlation_id: AtomicI32,
-    requests: Arc<Mutex<HashMap<i32, RequestHeader>>>,
+    requests: Arc<Mutex<InFlightRequests>>,
     length_codec: codec::LengthDelimitedCodec,
 }
 
 impl KafkaClientCodec {
-    pub fn new(requests: Arc<Mutex<HashMap<i32, RequestHeader>>>) -> Self {
+    pub fn new(requests: Arc<Mutex<InFlightRequests>>) -> Self {
         Self {
             correlation_id: Default::default(),
             requests,
@@ -147,28 +411,43 @@ impl KafkaClientCodec {
     }
 }
 
+/// Decodes responses to requests still in flight. Responses to requests that were evicted are
+/// skipped, as there is no one waiting on them and their version is no longer known.
 impl codec::Decoder for KafkaClientCodec {
     type Item = (ResponseHeader, ResponseKind);
     type Error = ErrorKind;
 
     #[tracing::instrument]
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if let Some(mut bytes) = self.length_codec.decode(src)? {
-            let header = ResponseHeader::decode(&mut bytes, 1)?;
-            let mut request_header = self.requests.lock().unwrap();
-            let request_header = request_header
-                .remove(&header.correlation_id)
-                .ok_or(DecodeError)?;
+        while let Some(mut bytes) = self.length_codec.decode(src)? {
+            // every header version starts with the correlation id, which tells us the version
+            let correlation_id = bytes.try_peek_bytes(0..4)?.try_get_i32()?;
+            let requests = self.requests.lock().unwrap();
+            let request_header = match requests.header(correlation_id) {
+                Some(request_header) => request_header,
+                None => continue,
+            };
             let api_key = ApiKey::try_from(request_header.request_api_key)?;
-            let response =
-                decode_response(&mut bytes, api_key, request_header.request_api_version)?;
-            Ok(Some((header, response)))
-        } else {
-            Ok(None)
+            let version = request_header.request_api_version;
+            let header_version = api_key.response_header_version(version);
+            let header = ResponseHeader::decode(&mut bytes, header_version)?;
+            let response = decode_response(&mut bytes, api_key, version)?;
+            return Ok(Some((header, response)));
         }
+        Ok(None)
     }
 }
 
+/// A broker answers an ApiVersions request at a version it doesn't support with an error at
+/// version 0, which lists the versions it does. The error code leads the body in every version.
+fn api_versions_response_version(bytes: &mut BytesMut, requested: i16) -> Result<i16, ErrorKind> {
+    let error_code = bytes.try_peek_bytes(0..2)?.try_get_i16()?;
+    Ok(match error_code == UnsupportedVersion.code() {
+        true => 0,
+        false => requested,
+    })
+}
+
 fn decode_response(
     bytes: &mut BytesMut,
     api_key: ApiKey,
@@ -176,40 +455,45 @@ fn decode_response(
 ) -> Result<ResponseKind, ErrorKind> {
     match api_key {
         ApiKey::ApiVersionsKey => {
-            let res =
-                ApiVersionsResponse::decode(bytes, CreateTopicsResponse::header_version(version))?;
+            let version = api_versions_response_version(bytes, version)?;
+            let res = ApiVersionsResponse::decode(bytes, version)?;
             Ok(ResponseKind::ApiVersionsResponse(res))
         }
         ApiKey::LeaderAndIsrKey => {
-            let res =
-                LeaderAndIsrResponse::decode(bytes, LeaderAndIsrResponse::header_version(version))?;
+            let res = LeaderAndIsrResponse::decode(bytes, version)?;
             Ok(ResponseKind::LeaderAndIsrResponse(res))
         }
         ApiKey::CreateTopicsKey => {
-            let res =
-                CreateTopicsResponse::decode(bytes, CreateTopicsResponse::header_version(version))?;
+            let res = CreateTopicsResponse::decode(bytes, version)?;
             Ok(ResponseKind::CreateTopicsResponse(res))
         }
+        ApiKey::FetchKey => {
+            let res = FetchResponse::decode(bytes, version)?;
+            Ok(ResponseKind::FetchResponse(res))
+        }
         _ => Err(ErrorKind::UnsupportedOperation),
     }
 }
 
-impl codec::Encoder<(RequestHeader, RequestKind)> for KafkaClientCodec {
+/// Encodes requests, tracking each as in flight along with the callback for its response.
+impl codec::Encoder<(RequestHeader, RequestKind, oneshot::Sender<ResponseKind>)>
+    for KafkaClientCodec
+{
     type Error = ErrorKind;
 
     #[tracing::instrument]
     fn encode(
         &mut self,
-        item: (RequestHeader, RequestKind),
+        item: (RequestHeader, RequestKind, oneshot::Sender<ResponseKind>),
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let (mut header, request) = item;
+        let (mut header, request, cb) = item;
         header.correlation_id = self.correlation_id.fetch_add(1, Ordering::SeqCst);
         let mut bytes = BytesMut::new();
         let api_version = header.request_api_version;
-        let mut requests = self.requests.lock().unwrap();
-        requests.insert(header.correlation_id, header.clone());
-        encode_request(&mut bytes, header, request, api_version)?;
+        // a request that fails to encode is never sent, and its callback is dropped with it
+        encode_request(&mut bytes, header.clone(), request, api_version)?;
+        self.requests.lock().unwrap().insert(header, cb);
         self.length_codec
             .encode(bytes.get_bytes(bytes.len()), dst)?;
         Ok(())
@@ -224,7 +508,7 @@ fn encode_request(
 ) -> Result<(), ErrorKind> {
     match request {
         RequestKind::ApiVersionsRequest(req) => {
-            header.encode(bytes, LeaderAndIsrRequest::header_version(version))?;
+            header.encode(bytes, ApiVersionsRequest::header_version(version))?;
             req.encode(bytes, version)?;
         }
         RequestKind::LeaderAndIsrRequest(req) => {
@@ -235,8 +519,207 @@ fn encode_request(
             header.encode(bytes, CreateTopicsRequest::header_version(version))?;
             req.encode(bytes, version)?;
         }
+        RequestKind::FetchRequest(req) => {
+            header.encode(bytes, FetchRequest::header_version(version))?;
+            req.encode(bytes, version)?;
+        }
         _ => return Err(EncodeError),
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{InFlightRequests, KafkaClientCodec, KafkaServerCodec, MAX_REQUEST_BYTES};
+    use crate::broker::handler::api_versions::{supported_versions, unsupported_version};
+    use crate::kafka::error::ErrorKind;
+    use bytes::{BufMut, BytesMut};
+    use kafka_protocol::messages::{
+        ApiKey, ApiVersionsRequest, ApiVersionsResponse, MetadataRequest, RequestHeader,
+        RequestKind, ResponseHeader, ResponseKind,
+    };
+    use kafka_protocol::protocol::{Encodable, HeaderVersion, StrBytes};
+    use kafka_protocol::ResponseError::UnsupportedVersion;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn codec() -> KafkaServerCodec {
+        KafkaServerCodec::new(BTreeMap::new())
+    }
+
+    fn frame(body: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u32(body.len() as u32);
+        buf.put_slice(body);
+        buf
+    }
+
+    fn metadata_request() -> BytesMut {
+        let version = 9;
+        let mut header = RequestHeader::default();
+        header.request_api_key = ApiKey::MetadataKey as i16;
+        header.request_api_version = version;
+        header.client_id = Some(StrBytes::from_str("test"));
+        let mut body = BytesMut::new();
+        header
+            .encode(&mut body, MetadataRequest::header_version(version))
+            .unwrap();
+        MetadataRequest::default()
+            .encode(&mut body, version)
+            .unwrap();
+        frame(&body)
+    }
+
+    #[test]
+    fn decodes_request() {
+        let mut buf = metadata_request();
+        let (header, request) = codec().try_decode(&mut buf).unwrap().unwrap();
+        assert_eq!(header.request_api_key, ApiKey::MetadataKey as i16);
+        assert!(matches!(request, RequestKind::MetadataRequest(_)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn truncated_frames() {
+        let request = metadata_request();
+        for len in 0..request.len() {
+            let mut buf = BytesMut::from(&request[..len]);
+            assert!(codec().try_decode(&mut buf).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn empty_and_short_frames() {
+        for body in [&[][..], &[0], &[0, 3], &[0, 3, 0]] {
+            let res = codec().try_decode(&mut frame(body));
+            assert!(matches!(res, Err(ErrorKind::DecodeError)), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_REQUEST_BYTES as u32 + 1);
+        assert!(matches!(
+            codec().try_decode(&mut buf),
+            Err(ErrorKind::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn garbage_bodies() {
+        let bodies: [&[u8]; 4] = [
+            &[0xff; 32],
+            b"not a kafka request at all",
+            &[0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0xff, 0x80],
+            &[0, 99, 0, 0, 0, 0, 0, 1, 0, 0],
+        ];
+        for body in bodies {
+            let res = codec().try_decode(&mut frame(body));
+            assert!(res.is_err(), "{:?}", body);
+        }
+
+        // a valid header followed by a body that ends early
+        let request = metadata_request();
+        let mut body = BytesMut::from(&request[4..request.len() - 1]);
+        body.put_u8(0xff);
+        assert!(codec().try_decode(&mut frame(&body)).is_err());
+    }
+
+    fn header(correlation_id: i32) -> RequestHeader {
+        let mut header = RequestHeader::default();
+        header.correlation_id = correlation_id;
+        header
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_oldest_request() {
+        let mut requests = InFlightRequests::new(2, Duration::from_secs(60));
+        let mut waiting = Vec::new();
+        for correlation_id in 0..3 {
+            let (tx, rx) = oneshot::channel();
+            requests.insert(header(correlation_id), tx);
+            waiting.push(rx);
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(requests.len(), 2);
+        assert!(requests.header(0).is_none());
+        assert!(waiting.remove(0).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_expired_requests() {
+        let requests = Arc::new(Mutex::new(InFlightRequests::new(
+            8,
+            Duration::from_secs(60),
+        )));
+        let mut codec = KafkaClientCodec::new(requests.clone());
+        let mut header = RequestHeader::default();
+        header.request_api_key = ApiKey::ApiVersionsKey as i16;
+        let req = RequestKind::ApiVersionsRequest(ApiVersionsRequest::default());
+        let (tx, rx) = oneshot::channel();
+        codec
+            .encode((header, req, tx), &mut BytesMut::new())
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(requests.lock().unwrap().evict_expired(), 0);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(requests.lock().unwrap().evict_expired(), 1);
+        assert!(rx.await.is_err());
+
+        // a late response has no one to go to, and is skipped
+        let mut body = BytesMut::new();
+        ResponseHeader::default().encode(&mut body, 1).unwrap();
+        let mut buf = frame(&body);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    /// Has a client send an ApiVersions request at `version` to a server supporting what this
+    /// broker does, returning the response the client decodes.
+    fn api_versions_round_trip(version: i16) -> ApiVersionsResponse {
+        let requests = Arc::new(Mutex::new(InFlightRequests::new(8, Duration::from_secs(60))));
+        let mut client = KafkaClientCodec::new(requests);
+        let mut server = KafkaServerCodec::new(supported_versions());
+        let mut header = RequestHeader::default();
+        header.request_api_key = ApiKey::ApiVersionsKey as i16;
+        header.request_api_version = version;
+        let req = RequestKind::ApiVersionsRequest(ApiVersionsRequest::default());
+        let mut buf = BytesMut::new();
+        client.encode((header, req, oneshot::channel().0), &mut buf).unwrap();
+
+        let (header, req) = server.decode(&mut buf).unwrap().unwrap();
+        let (version, res) = match req {
+            Ok(_) => {
+                let mut res = ApiVersionsResponse::default();
+                res.api_keys = supported_versions().into_iter().collect();
+                (version, ResponseKind::ApiVersionsResponse(res))
+            }
+            Err(_) => unsupported_version(ApiKey::ApiVersionsKey, version).unwrap(),
+        };
+        let mut res_header = ResponseHeader::default();
+        res_header.correlation_id = header.correlation_id;
+        server.encode((version, res_header, res), &mut buf).unwrap();
+
+        match client.decode(&mut buf).unwrap() {
+            Some((_, ResponseKind::ApiVersionsResponse(res))) => res,
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    #[test]
+    fn api_versions_round_trip_falls_back_to_v0() {
+        let res = api_versions_round_trip(3);
+        assert_eq!(res.error_code, 0);
+
+        // the broker answers at v0 to a version it doesn't know, which the client still decodes
+        let res = api_versions_round_trip(6);
+        assert_eq!(res.error_code, UnsupportedVersion.code());
+        assert!(res.api_keys.contains_key(&(ApiKey::ApiVersionsKey as i16)));
+    }
+}
@@ -8,6 +8,7 @@ pub enum ErrorKind {
     DecodeError,
     EncodeError,
     UnsupportedOperation,
+    UnsupportedVersion { api_key: i16, version: i16 },
     IoError(std::io::Error),
 }
 
@@ -23,6 +24,9 @@ impl Display for ErrorKind {
             ErrorKind::UnsupportedOperation => {
                 writeln!(f, "Unsupported API")
             }
+            ErrorKind::UnsupportedVersion { api_key, version } => {
+                writeln!(f, "Unsupported version {} of API {}", version, api_key)
+            }
             ErrorKind::IoError(err) => {
                 writeln!(f, "IoError: {}", err)
             }
@@ -22,6 +22,11 @@ pub struct ConnectedKafkaClient {
 }
 
 impl ConnectedKafkaClient {
+    /// Whether the connection has closed, so that nothing more can be sent over it.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
     #[tracing::instrument]
     pub async fn send(
         &self,
@@ -31,7 +36,9 @@ impl ConnectedKafkaClient {
         tracing::trace!(?header, ?req, "send client request");
         let (cb_tx, cb_rx) = tokio::sync::oneshot::channel();
         self.tx.send((header, req, cb_tx))?;
-        let res = cb_rx.await?;
+        let res = cb_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("request was given up on without a response"))?;
         tracing::trace!(?res, "receive client response");
         Ok(res)
     }
@@ -1,8 +1,8 @@
 use futures::SinkExt;
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::kafka::codec::KafkaClientCodec;
+use crate::kafka::codec::{InFlightRequests, KafkaClientCodec};
 use crate::Shutdown;
 use kafka_protocol::messages::{RequestHeader, RequestKind, ResponseKind};
 use tokio::net::TcpStream;
@@ -12,46 +12,61 @@ use tokio::sync::oneshot;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+/// The most requests a client keeps waiting on a response for.
+const MAX_IN_FLIGHT: usize = 1024;
+/// How long a client waits on a response before giving up on the request.
+const REQUEST_TTL: Duration = Duration::from_secs(30);
+
 #[tracing::instrument]
 pub async fn send_messages(
     stream: TcpStream,
     mut rx: UnboundedReceiver<(RequestHeader, RequestKind, oneshot::Sender<ResponseKind>)>,
     mut shutdown: Shutdown,
 ) -> anyhow::Result<()> {
-    let requests: Arc<Mutex<HashMap<i32, RequestHeader>>> = Default::default();
+    let requests = Arc::new(Mutex::new(InFlightRequests::new(
+        MAX_IN_FLIGHT,
+        REQUEST_TTL,
+    )));
     let (r, w) = stream.into_split();
     let mut stream_in = FramedRead::new(r, KafkaClientCodec::new(requests.clone()));
     let mut stream_out = FramedWrite::new(w, KafkaClientCodec::new(requests.clone()));
 
-    let cbs: Arc<Mutex<HashMap<i32, oneshot::Sender<ResponseKind>>>> = Default::default();
-    let cbs1 = cbs.clone();
     let write = tokio::spawn(async move {
-        while let Some((header, req, cb)) = rx.recv().await {
-            let correlation_id = header.correlation_id;
-            stream_out.send((header, req)).await?;
-            let mut cbs = cbs1.lock().unwrap();
-            cbs.insert(correlation_id, cb);
+        while let Some(req) = rx.recv().await {
+            stream_out.send(req).await?;
         }
         anyhow::Result::<_, anyhow::Error>::Ok(())
     });
 
-    let cbs2 = cbs.clone();
+    let requests1 = requests.clone();
     let read = tokio::spawn(async move {
         while let Some((header, res)) = stream_in.try_next().await? {
-            let mut cbs = cbs2.lock().unwrap();
-            let cb = cbs
-                .remove(&header.correlation_id)
-                .expect("unknown correlation id");
-            cb.send(res).unwrap();
+            requests1
+                .lock()
+                .unwrap()
+                .complete(header.correlation_id, res);
         }
         anyhow::Result::<_, anyhow::Error>::Ok(())
     });
 
+    // requests are only evicted as new ones are sent, so a server that stops responding to the
+    // last few would otherwise keep them waiting forever
+    let evict = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REQUEST_TTL / 2);
+        loop {
+            interval.tick().await;
+            requests.lock().unwrap().evict_expired();
+        }
+    });
+
     let shutdown = tokio::spawn(async move {
         shutdown.wait().await?;
         anyhow::Result::<_, anyhow::Error>::Ok(())
     });
 
-    let _ = futures::future::select_all(vec![read, write, shutdown]).await;
+    // once either direction closes, stop the other so that senders see the connection is gone
+    let (_, _, rest) = futures::future::select_all(vec![read, write, shutdown]).await;
+    rest.iter().for_each(|task| task.abort());
+    evict.abort();
     Ok(())
 }
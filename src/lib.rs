@@ -1,11 +1,15 @@
 pub mod broker;
 pub mod config;
+pub mod health;
 pub mod kafka;
+pub mod metrics;
 pub mod raft;
 pub mod util;
 
+use crate::broker::log::Storage;
 use crate::broker::JosefineBroker;
 use crate::config::JosefineConfig;
+use crate::health::Health;
 use crate::raft::client::RaftClient;
 use anyhow::Result;
 use futures::FutureExt;
@@ -31,18 +35,29 @@ pub async fn josefine<P: AsRef<std::path::Path>>(config_path: P, shutdown: Shutd
 #[tracing::instrument]
 pub async fn run(config: JosefineConfig, shutdown: Shutdown) -> Result<()> {
     tracing::debug!("start");
-    let db = sled::open(&config.broker.state_file).unwrap();
+    let db = match config.broker.storage {
+        Storage::Disk => sled::open(&config.broker.state_file).unwrap(),
+        Storage::Memory => sled::Config::new().temporary(true).open()?,
+    };
 
-    let (client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
-    let client = RaftClient::new(client_tx);
-    let josefine_broker = JosefineBroker::new(config.broker);
-    let broker = broker::state::Store::new(db);
+    let (client_tx, client_rx) = tokio::sync::mpsc::channel(config.raft.proposal_queue_size);
+    let raft = JosefineRaft::new(config.raft.clone());
+    let client = RaftClient::new(client_tx, config.raft.proposal_timeout)
+        .with_lease(raft.lease())
+        .with_status(raft.status())
+        .with_applied(raft.applied());
+    let broker = broker::state::Store::new(db)?.with_codec(config.broker.store_codec);
+    let health = Health::new(client.clone());
+    if let Some(addr) = config.health.addr {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(health::serve(listener, health.clone(), shutdown.clone()));
+    }
+    let josefine_broker = JosefineBroker::new(config.broker).with_health(health);
     let (task, b) = josefine_broker
         .run(client, broker.clone(), shutdown.clone())
         .remote_handle();
     tokio::spawn(task);
 
-    let raft = JosefineRaft::new(config.raft);
     let (task, raft) = raft
         .run(
             crate::broker::fsm::JosefineFsm::new(broker),
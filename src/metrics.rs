@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in microseconds. Anything slower than the last bound
+/// is counted in the last bucket.
+const BUCKETS_US: [u64; 12] = [
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, u64::MAX,
+];
+
+/// How long appending a batch to a partition log takes.
+pub static LOG_APPEND_LATENCY: Histogram = Histogram::new();
+
+/// Counts durations in fixed, exponentially sized buckets. Recording is lock free, so it can be
+/// shared by every partition.
+#[derive(Debug)]
+pub struct Histogram {
+    counts: [AtomicU64; BUCKETS_US.len()],
+    sum_us: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; BUCKETS_US.len()],
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = BUCKETS_US.iter().position(|bound| us <= *bound).unwrap_or(0);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// The number of durations recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// The total of the durations recorded.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.load(Ordering::Relaxed))
+    }
+
+    /// The upper bound of each bucket, along with the number of durations in it.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        BUCKETS_US
+            .iter()
+            .zip(&self.counts)
+            .map(|(bound, count)| (Duration::from_micros(*bound), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::from_micros(5));
+        histogram.record(Duration::from_micros(700));
+        histogram.record(Duration::from_secs(10));
+
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.sum(), Duration::from_micros(10_000_705));
+        let counts: Vec<u64> = histogram.buckets().into_iter().map(|(_, c)| c).collect();
+        assert_eq!(counts, vec![1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+}
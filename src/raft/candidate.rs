@@ -2,13 +2,14 @@ use std::time::Instant;
 
 use anyhow::{Error, Result};
 
-use crate::raft::election::{Election, ElectionStatus};
+use crate::raft::election::{DefeatReason, Election, ElectionStatus};
 use crate::raft::follower::Follower;
 use crate::raft::leader::Leader;
 use crate::raft::progress::ReplicationProgress;
+use crate::raft::recent::Recent;
 
 use crate::raft::chain::BlockId;
-use crate::raft::rpc::Address;
+use crate::raft::rpc::{Address, RECENT_PROPOSALS};
 use crate::raft::{Apply, ClientRequest, RaftHandle, RaftRole, Term};
 use crate::raft::{Command, NodeId};
 use crate::raft::{Raft, Role};
@@ -24,19 +25,24 @@ impl Raft<Candidate> {
     pub(crate) fn seek_election(mut self) -> Result<RaftHandle> {
         self.state.voted_for = Some(self.id);
         self.state.current_term += 1;
+        self.span.record("term", self.state.current_term);
         let from = self.id;
         let term = self.state.current_term;
+        // a restarted candidate mustn't vote for another in the term it voted for itself in
+        self.chain.save_vote(term, self.state.voted_for)?;
 
-        for _node in &self.config.nodes {
+        if !self.config.nodes.is_empty() {
+            let head = self.chain.get_head();
+            let last_term = self.chain.get(&head)?.map_or(0, |b| b.term);
             self.send_all(Command::VoteRequest {
                 term,
                 candidate_id: from,
-                last_term: term,
-                head: self.chain.get_head(),
+                last_term,
+                head,
             })?;
         }
 
-        // Vote for self,
+        // vote for ourself, which wins the election straight away if we are the only voter
         self.apply(Command::VoteResponse {
             from,
             term,
@@ -54,13 +60,8 @@ impl Raft<Candidate> {
                     let raft: Raft<Follower> = Raft::from(self);
                     Ok(raft.apply(Command::Timeout)?)
                 }
-                ElectionStatus::Defeated => {
-                    tracing::info!("defeat");
-                    self.state.voted_for = None;
-                    let raft: Raft<Follower> = Raft::from(self);
-                    Ok(raft.apply(Command::Timeout)?)
-                }
-                _ => panic!("this should never happen"),
+                ElectionStatus::Defeated(reason) => self.defeat(reason),
+                ElectionStatus::Elected => self.elect(),
             };
         }
 
@@ -88,30 +89,55 @@ impl Raft<Candidate> {
     }
 
     #[tracing::instrument(skip(self))]
-    fn apply_vote_response(mut self, granted: bool, from: NodeId) -> Result<RaftHandle, Error> {
-        self.role.election.vote(from, granted);
+    fn apply_vote_response(
+        mut self,
+        granted: bool,
+        from: NodeId,
+        term: Term,
+    ) -> Result<RaftHandle, Error> {
+        if !granted && term > self.state.current_term {
+            self.role.election.reject_with_term(from, term);
+        } else {
+            self.role.election.vote(from, granted);
+        }
+
         match self.role.election.election_status() {
             ElectionStatus::Elected => {
                 self.elect()
             }
             ElectionStatus::Voting => Ok(RaftHandle::Candidate(self)),
-            ElectionStatus::Defeated => {
-                self.defeat()
+            ElectionStatus::Defeated(reason) => {
+                self.defeat(reason)
             }
         }
     }
 
     #[tracing::instrument(skip(self))]
-    fn defeat(mut self) -> Result<RaftHandle, Error> {
-        tracing::info!("defeated in election");
+    fn defeat(mut self, reason: DefeatReason) -> Result<RaftHandle, Error> {
+        let (granted, rejected) = self.role.election.tally();
+        tracing::info!(?reason, granted, rejected, "defeated in election");
         self.state.voted_for = None;
-        Ok(RaftHandle::Follower(Raft::from(self)))
+        if let DefeatReason::HigherTerm(term) = reason {
+            self.term(term);
+        }
+
+        let mut raft: Raft<Follower> = Raft::from(self);
+        if reason == DefeatReason::Split {
+            // nobody won, so wait longer than usual before trying again to let another node win
+            raft.state.split_votes += 1;
+            raft.backoff_election_timeout();
+        } else {
+            // restart the clock, so we don't stand again on the next tick
+            raft.set_election_timeout();
+        }
+        Ok(RaftHandle::Follower(raft))
     }
 
     #[tracing::instrument(skip(self))]
     fn elect(mut self) -> Result<RaftHandle, Error> {
         tracing::info!("elected leader");
-        let raft = Raft::from(self);
+        self.state.split_votes = 0;
+        let mut raft = Raft::from(self);
         raft.heartbeat()?;
         Ok(RaftHandle::Leader(raft))
     }
@@ -126,7 +152,8 @@ impl Raft<Candidate> {
         // state.
         if term >= self.state.current_term {
             tracing::trace!("");
-            let raft: Raft<Follower> = Raft::from(self);
+            let mut raft: Raft<Follower> = Raft::from(self);
+            raft.state.split_votes = 0;
             //                    raft.io.append(entries)?;
             return Ok(RaftHandle::Follower(raft));
         }
@@ -143,18 +170,22 @@ impl Raft<Candidate> {
         term: Term,
         leader_id: NodeId,
         commit: BlockId,
+        round: u64,
     ) -> Result<RaftHandle, Error> {
         tracing::trace!("receive higher term");
         let has_committed = self.chain.has(&commit)?;
         let commit = self.chain.get_commit();
         self.term(term);
         self.state.voted_for = Some(leader_id);
+        self.state.split_votes = 0;
         let raft: Raft<Follower> = Raft::from(self);
         raft.send(
             Address::Peer(leader_id),
             Command::HeartbeatResponse {
+                node_id: raft.id,
                 commit,
                 has_committed,
+                round,
             },
         )?;
         Ok(RaftHandle::Follower(raft))
@@ -181,7 +212,9 @@ impl Apply for Raft<Candidate> {
             Command::VoteRequest {
                 candidate_id, term, ..
             } => self.apply_vote_request(candidate_id, term),
-            Command::VoteResponse { granted, from, .. } => self.apply_vote_response(granted, from),
+            Command::VoteResponse {
+                granted, from, term,
+            } => self.apply_vote_response(granted, from, term),
             Command::AppendEntries {
                 blocks: _, term, ..
             } => self.apply_append_entries(term),
@@ -189,7 +222,8 @@ impl Apply for Raft<Candidate> {
                 term,
                 leader_id,
                 commit,
-            } => self.apply_heartbeat(term, leader_id, commit),
+                round,
+            } => self.apply_heartbeat(term, leader_id, commit, round),
             Command::ClientRequest(req) => {
                 self.role.queued_reqs.push(req);
                 Ok(RaftHandle::Candidate(self))
@@ -201,7 +235,7 @@ impl Apply for Raft<Candidate> {
 
 impl From<Raft<Candidate>> for Raft<Follower> {
     fn from(val: Raft<Candidate>) -> Raft<Follower> {
-        Raft {
+        let raft = Raft {
             id: val.id,
             state: val.state,
             role: Follower {
@@ -213,7 +247,11 @@ impl From<Raft<Candidate>> for Raft<Follower> {
             chain: val.chain,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
-        }
+            lease: val.lease,
+            span: val.span,
+        };
+        raft.record_role();
+        raft
     }
 }
 
@@ -221,7 +259,10 @@ impl From<Raft<Candidate>> for Raft<Leader> {
     fn from(val: Raft<Candidate>) -> Raft<Leader> {
         let mut nodes: Vec<NodeId> = val.config.nodes.iter().map(|x| x.id).collect();
         nodes.push(val.id);
-        let progress = ReplicationProgress::new(nodes);
+        let mut progress = ReplicationProgress::new(nodes);
+        for learner in &val.config.learners {
+            progress.insert_learner(learner.id);
+        }
         let leader = Raft {
             id: val.id,
             state: val.state,
@@ -229,12 +270,22 @@ impl From<Raft<Candidate>> for Raft<Leader> {
                 progress,
                 heartbeat_time: Instant::now(),
                 heartbeat_timeout: val.config.heartbeat_timeout,
+                round: 0,
+                round_start: Instant::now(),
+                round_acks: HashSet::new(),
+                queued_reads: Vec::new(),
+                round_reads: Vec::new(),
+                joining: Vec::new(),
+                proposals: Recent::new(RECENT_PROPOSALS),
             },
             config: val.config,
             chain: val.chain,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
+            lease: val.lease,
+            span: val.span,
         };
+        leader.record_role();
 
         // run any transition specific logic
         leader.on_transition().unwrap()
@@ -243,15 +294,18 @@ impl From<Raft<Candidate>> for Raft<Leader> {
 
 #[cfg(test)]
 mod tests {
-    use crate::raft::chain::BlockId;
-    use crate::raft::test::new_candidate;
-    use crate::raft::Command;
+    use crate::raft::chain::{BlockId, UnappendedBlock};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::raft::test::{new_candidate, new_follower};
+    use crate::raft::{Apply, Command, Node, RaftHandle};
 
     #[tokio::test]
     async fn apply_heartbeat() -> anyhow::Result<()> {
         let ((mut rpc_rx, _), candidate) = new_candidate();
         let follower = candidate
-            .apply_heartbeat(11, 6, BlockId::new(1))?
+            .apply_heartbeat(11, 6, BlockId::new(1), 1)?
             .get_follower()
             .unwrap();
         // we voted for the leader
@@ -263,10 +317,146 @@ mod tests {
         assert_eq!(
             msg.command,
             Command::HeartbeatResponse {
+                node_id: follower.id,
                 commit: BlockId::new(0),
-                has_committed: false
+                has_committed: false,
+                round: 1
             }
         );
         Ok(())
     }
+
+    #[test]
+    fn split_vote_backoff() -> anyhow::Result<()> {
+        let ((_rpc_rx, _fsm_rx), mut follower) = new_follower();
+        follower.config.nodes = (2..5)
+            .map(|id| Node {
+                id,
+                addr: SocketAddr::from(([127, 0, 0, 1], 6669 + id as u16)),
+            })
+            .collect();
+        let base = follower.backoff_range();
+
+        // each candidate votes for itself, and one other node votes for us
+        let mut ranges = vec![];
+        for _ in 0..2 {
+            let candidate = follower.apply(Command::Timeout)?;
+            let term = candidate.status().term;
+            let candidate = candidate.apply(Command::VoteResponse {
+                from: 2,
+                term,
+                granted: true,
+            })?;
+            let candidate = candidate.apply(Command::VoteResponse {
+                from: 3,
+                term,
+                granted: false,
+            })?;
+            follower = candidate
+                .apply(Command::VoteResponse {
+                    from: 4,
+                    term,
+                    granted: false,
+                })?
+                .get_follower()
+                .unwrap();
+            ranges.push(follower.backoff_range());
+        }
+        assert_eq!(follower.state.split_votes, 2);
+        assert_eq!(ranges[0], base);
+        assert!(ranges[1].end > ranges[0].end);
+        assert!(follower.state.election_timeout.unwrap() >= Duration::from_millis(1000));
+
+        let candidate = follower.apply(Command::Timeout)?;
+        let term = candidate.status().term;
+        let leader = candidate
+            .apply(Command::VoteResponse {
+                from: 2,
+                term,
+                granted: true,
+            })?
+            .apply(Command::VoteResponse {
+                from: 3,
+                term,
+                granted: true,
+            })?
+            .get_leader()
+            .unwrap();
+        assert_eq!(leader.state.split_votes, 0);
+        Ok(())
+    }
+
+    /// A candidate in a three node cluster whose election timeout has long passed.
+    fn stale_candidate() -> anyhow::Result<RaftHandle> {
+        let ((_rpc_rx, _fsm_rx), mut follower) = new_follower();
+        follower.config.nodes = (2..4)
+            .map(|id| Node {
+                id,
+                addr: SocketAddr::from(([127, 0, 0, 1], 6669 + id as u16)),
+            })
+            .collect();
+        let mut candidate = follower.apply(Command::Timeout)?.get_candidate().unwrap();
+        candidate.state.election_time = Some(Instant::now() - Duration::from_secs(10));
+        Ok(RaftHandle::Candidate(candidate))
+    }
+
+    #[test]
+    fn defeat() -> anyhow::Result<()> {
+        // rejected by both peers, and by one of them with a higher term
+        for higher in [0, 3] {
+            let candidate = stale_candidate()?;
+            let term = candidate.status().term;
+            let follower = candidate
+                .apply(Command::VoteResponse {
+                    from: 2,
+                    term,
+                    granted: false,
+                })?
+                .apply(Command::VoteResponse {
+                    from: 3,
+                    term: term + higher,
+                    granted: false,
+                })?
+                .get_follower()
+                .unwrap();
+            assert_eq!(follower.state.current_term, term + higher);
+            assert_eq!(follower.state.voted_for, None);
+
+            // the defeat restarted the election clock
+            assert!(!follower.needs_election());
+            let node = follower.apply(Command::Tick)?;
+            assert!(node.is_follower());
+            assert_eq!(node.status().term, term + higher);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shorter_chain_loses_vote() -> anyhow::Result<()> {
+        // the voter has a block from term 1, which the candidate never got
+        let ((mut voter_rx, _voter_fsm_rx), mut voter) = new_follower();
+        voter.id = 2;
+        voter.chain.append(UnappendedBlock::new(1, vec![]))?;
+        let ((mut candidate_rx, _fsm_rx), mut candidate) = new_follower();
+        candidate.state.current_term = 1;
+        candidate.config.nodes = vec![Node {
+            id: voter.id,
+            addr: SocketAddr::from(([127, 0, 0, 1], 6671)),
+        }];
+
+        let candidate = candidate.apply(Command::Timeout)?;
+        assert!(candidate.is_candidate());
+        let request = candidate_rx.try_recv()?.command;
+        match &request {
+            Command::VoteRequest { term, last_term, .. } => assert_eq!((*term, *last_term), (2, 0)),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+
+        voter.apply(request)?;
+        match voter_rx.try_recv()?.command {
+            Command::VoteResponse { granted, .. } => assert!(!granted),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        Ok(())
+    }
 }
@@ -9,6 +9,8 @@ use bytes::Bytes;
 use anyhow::Result;
 use serde::{Deserialize, Deserializer, Serializer};
 
+use crate::raft::{Entry, EntryType, LogIndex, NodeId, Term};
+
 #[derive(Debug)]
 struct IdGenerator {
     id: AtomicU64,
@@ -56,14 +58,20 @@ fn deserialize_block_id<'de, D>(deserializer: D) -> std::result::Result<Bytes, D
 where
     D: Deserializer<'de>,
 {
-    let bs: &'de [u8] = Deserialize::deserialize(deserializer)?;
-    Ok(Bytes::from(bs.to_vec()))
+    // an owned buffer, since not every format (e.g. json) can lend out a byte slice
+    let bs: Vec<u8> = Deserialize::deserialize(deserializer)?;
+    Ok(Bytes::from(bs))
 }
 
 impl BlockId {
     pub(crate) fn new(val: u64) -> Self {
         BlockId(Bytes::from(val.to_be_bytes().to_vec()))
     }
+
+    /// The position of the block in the order blocks were created.
+    pub fn index(&self) -> LogIndex {
+        u64::from_be_bytes(self.0.as_ref().try_into().unwrap())
+    }
 }
 
 impl AsRef<[u8]> for BlockId {
@@ -74,12 +82,17 @@ impl AsRef<[u8]> for BlockId {
 
 #[derive(Debug)]
 pub struct UnappendedBlock {
-    data: Vec<u8>,
+    term: Term,
+    entry_type: EntryType,
 }
 
 impl UnappendedBlock {
-    pub fn new(data: Vec<u8>) -> Self {
-        UnappendedBlock { data }
+    pub fn new(term: Term, data: Vec<u8>) -> Self {
+        Self::with_type(term, EntryType::Data { data })
+    }
+
+    pub fn with_type(term: Term, entry_type: EntryType) -> Self {
+        UnappendedBlock { term, entry_type }
     }
 }
 
@@ -87,12 +100,24 @@ impl UnappendedBlock {
 pub struct Block {
     pub id: BlockId,
     pub next: BlockId,
-    pub data: Vec<u8>,
+    /// The term of the leader that appended the block.
+    pub term: Term,
+    pub entry_type: EntryType,
 }
 
 impl Block {
-    pub fn new(data: Vec<u8>) -> UnappendedBlock {
-        UnappendedBlock { data }
+    pub fn new(term: Term, data: Vec<u8>) -> UnappendedBlock {
+        UnappendedBlock::new(term, data)
+    }
+}
+
+impl From<Block> for Entry {
+    fn from(block: Block) -> Self {
+        Entry {
+            entry_type: block.entry_type,
+            term: block.term,
+            index: block.id.index(),
+        }
     }
 }
 
@@ -101,6 +126,11 @@ pub struct Chain {
     id_gen: IdGenerator,
     commit: BlockId,
     head: BlockId,
+    /// The newest block known to be on disk.
+    durable: BlockId,
+    /// Called after each flush that wrote something, so tests can tell what happened first.
+    #[cfg(test)]
+    pub on_flush: Option<Box<dyn FnMut() + Send>>,
 }
 
 impl Debug for Chain {
@@ -115,7 +145,7 @@ impl Debug for Chain {
 
 impl Chain {
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        let db = sled::open(path.as_ref()).unwrap();
+        let db = sled::open(path.as_ref())?;
         let commit = db
             .get("commit")
             .unwrap()
@@ -127,6 +157,9 @@ impl Chain {
             id_gen: IdGenerator::new(commit),
             commit: BlockId::new(commit),
             head: BlockId::new(commit),
+            durable: BlockId::new(commit),
+            #[cfg(test)]
+            on_flush: None,
         };
 
         if commit == 0 {
@@ -143,7 +176,8 @@ impl Chain {
         let block = Block {
             id: BlockId::new(id),
             next: BlockId::new(id),
-            data: vec![],
+            term: 0,
+            entry_type: EntryType::Noop,
         };
         self.db
             .insert(&block.id, bincode::serialize(&block).unwrap())
@@ -156,6 +190,29 @@ impl Chain {
         Ok(self.db.contains_key(block_id)?)
     }
 
+    pub fn get(&self, block_id: &BlockId) -> Result<Option<Block>> {
+        self.db
+            .get(block_id)?
+            .map(|b| Ok(bincode::deserialize(&b)?))
+            .transpose()
+    }
+
+    /// Durably records the vote cast in `term`, so it is honoured after a restart.
+    pub fn save_vote(&self, term: Term, voted_for: Option<NodeId>) -> Result<()> {
+        let state = self.db.open_tree("state")?;
+        state.insert("vote", bincode::serialize(&(term, voted_for))?)?;
+        state.flush()?;
+        Ok(())
+    }
+
+    pub fn get_vote(&self) -> Result<Option<(Term, Option<NodeId>)>> {
+        let state = self.db.open_tree("state")?;
+        state
+            .get("vote")?
+            .map(|v| Ok(bincode::deserialize(&v)?))
+            .transpose()
+    }
+
     #[tracing::instrument]
     pub fn append(&mut self, block: UnappendedBlock) -> Result<BlockId> {
         let id = self.id_gen.next();
@@ -164,7 +221,8 @@ impl Chain {
         let block = Block {
             id,
             next: self.head.clone(),
-            data: block.data,
+            term: block.term,
+            entry_type: block.entry_type,
         };
         tracing::debug!(?block, "append");
         self.db
@@ -218,6 +276,8 @@ impl Chain {
                     panic!()
                 })
             })
+            // the commit pointer shares the tree, and sorts after every block
+            .filter(|(k, _)| k.len() == std::mem::size_of::<u64>())
             .map(|(k, v)| {
                 bincode::deserialize(&v).unwrap_or_else(|e| {
                     let block_id = BlockId(Bytes::from(k.to_vec()));
@@ -231,6 +291,27 @@ impl Chain {
         self.head.clone()
     }
 
+    /// Waits until every appended block is on disk, returning the head as of then. Blocks must
+    /// be flushed before they are acknowledged or counted towards a commit, or a crash could
+    /// lose an entry a quorum was thought to hold.
+    #[tracing::instrument]
+    pub fn flush(&mut self) -> Result<BlockId> {
+        if self.durable != self.head {
+            self.db.flush()?;
+            self.durable = self.head.clone();
+            #[cfg(test)]
+            if let Some(on_flush) = &mut self.on_flush {
+                on_flush();
+            }
+        }
+        Ok(self.durable.clone())
+    }
+
+    /// The newest block known to be on disk.
+    pub fn get_durable(&self) -> BlockId {
+        self.durable.clone()
+    }
+
     pub fn get_commit(&self) -> BlockId {
         self.commit.clone()
     }
@@ -256,6 +337,7 @@ impl Chain {
 #[cfg(test)]
 mod tests {
     use crate::raft::chain::{Block, BlockId, Chain, UnappendedBlock};
+    use crate::raft::EntryType;
     use tempfile::tempdir;
 
     #[test]
@@ -266,10 +348,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn vote() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let chain = Chain::new(dir.path())?;
+        assert_eq!(chain.get_vote()?, None);
+        chain.save_vote(3, Some(2))?;
+        assert_eq!(chain.get_vote()?, Some((3, Some(2))));
+        drop(chain);
+
+        // sled's background threads may hold the lock on the directory for a moment after the
+        // chain is dropped
+        let mut reopened = Chain::new(dir.path());
+        for _ in 0..100 {
+            if reopened.is_ok() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            reopened = Chain::new(dir.path());
+        }
+        let chain = reopened?;
+        assert_eq!(chain.get_vote()?, Some((3, Some(2))));
+        chain.save_vote(4, None)?;
+        assert_eq!(chain.get_vote()?, Some((4, None)));
+        Ok(())
+    }
+
     #[test]
     fn append() -> anyhow::Result<()> {
         let mut chain = Chain::new(tempdir()?)?;
-        chain.append(UnappendedBlock::new(vec![]))?;
+        chain.append(UnappendedBlock::new(0, vec![]))?;
         assert_eq!(chain.get_commit(), BlockId::new(0));
         assert_eq!(chain.get_head(), BlockId::new(1));
         Ok(())
@@ -278,7 +386,7 @@ mod tests {
     #[test]
     fn commit() -> anyhow::Result<()> {
         let mut chain = Chain::new(tempdir()?)?;
-        chain.append(Block::new(vec![]))?;
+        chain.append(Block::new(0, vec![]))?;
         chain.commit(&BlockId::new(1))?;
         assert_eq!(chain.get_commit(), BlockId::new(1));
         assert_eq!(chain.get_head(), BlockId::new(1));
@@ -291,7 +399,8 @@ mod tests {
         chain.extend(Block {
             id: BlockId::new(1),
             next: BlockId::new(0),
-            data: vec![],
+            term: 0,
+            entry_type: EntryType::Noop,
         })?;
         assert_eq!(chain.get_commit(), BlockId::new(0));
         assert_eq!(chain.get_head(), BlockId::new(1));
@@ -304,7 +413,8 @@ mod tests {
         chain.extend(Block {
             id: BlockId::new(1),
             next: BlockId::new(0),
-            data: vec![],
+            term: 0,
+            entry_type: EntryType::Noop,
         })?;
         let blocks: Vec<Block> = chain.range(..).collect();
         assert_eq!(blocks.len(), 2);
@@ -317,7 +427,8 @@ mod tests {
         chain.extend(Block {
             id: BlockId::new(1),
             next: BlockId::new(0),
-            data: vec![],
+            term: 0,
+            entry_type: EntryType::Noop,
         })?;
         assert!(chain.has(&BlockId::new(1))?);
         Ok(())
@@ -332,7 +443,8 @@ mod tests {
             chain.extend(Block {
                 id: BlockId::new(id),
                 next: BlockId::new(next),
-                data: vec![],
+                term: 0,
+                entry_type: EntryType::Noop,
             })?;
         }
         assert!(chain.has(&BlockId::new(4))?);
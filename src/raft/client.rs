@@ -1,38 +1,226 @@
+use crate::raft::health::ClusterHealth;
+use crate::raft::lease::Lease;
 use crate::raft::rpc::{Proposal, Response, ResponseError};
+use crate::raft::Status;
 use anyhow::Result;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::oneshot;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, watch};
+use uuid::Uuid;
 
-#[derive(Debug)]
+/// A proposal along with the channel its response is delivered on.
+pub type ProposalRequest = (
+    Proposal,
+    oneshot::Sender<std::result::Result<Response, ResponseError>>,
+);
+
+/// Returned when a proposal could not be queued before the send timeout elapsed, or the leader
+/// refused it because too many proposals are waiting to be committed. Either way it may succeed
+/// if retried later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded;
+
+impl Display for Overloaded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "raft proposal queue is full")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+/// How a read was confirmed to be safe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Read {
+    /// The leader holds a lease, so the read could be served without a round trip.
+    Lease,
+    /// The leader confirmed with a quorum that it was still leader.
+    ReadIndex,
+}
+
+#[derive(Debug, Clone)]
 pub struct RaftClient {
-    request_tx: UnboundedSender<(
-        Proposal,
-        oneshot::Sender<std::result::Result<Response, ResponseError>>,
-    )>,
+    request_tx: Sender<ProposalRequest>,
+    send_timeout: Duration,
+    lease: Lease,
+    status: watch::Receiver<Status>,
+    applied: Option<watch::Receiver<u64>>,
 }
 
 impl RaftClient {
-    /// Creates a new Raft client.
-    pub fn new(
-        request_tx: UnboundedSender<(
-            Proposal,
-            oneshot::Sender<std::result::Result<Response, ResponseError>>,
-        )>,
-    ) -> Self {
-        Self { request_tx }
+    /// Creates a new Raft client that waits at most `send_timeout` for room in the proposal queue.
+    pub fn new(request_tx: Sender<ProposalRequest>, send_timeout: Duration) -> Self {
+        Self {
+            request_tx,
+            send_timeout,
+            lease: Lease::default(),
+            status: watch::channel(Status::default()).1,
+            applied: None,
+        }
+    }
+
+    /// Serves reads locally while the given lease is held.
+    pub fn with_lease(mut self, lease: Lease) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Reports leadership as published by the given raft node.
+    pub fn with_status(mut self, status: watch::Receiver<Status>) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Has reads wait for the local state machine, as followed by the given receiver of its
+    /// applied index. Without one, reads only wait for the read to be confirmed.
+    pub fn with_applied(mut self, applied: watch::Receiver<u64>) -> Self {
+        self.applied = Some(applied);
+        self
+    }
+
+    /// Who the local raft node believes leads the cluster.
+    pub fn status(&self) -> Status {
+        *self.status.borrow()
+    }
+
+    /// Follows changes in leadership.
+    pub fn watch_status(&self) -> watch::Receiver<Status> {
+        self.status.clone()
     }
 
     /// Executes a request against the Raft cluster.
     async fn request(&self, request: Proposal) -> Result<Response> {
         let (response_tx, response_rx) = oneshot::channel();
-        self.request_tx.send((request, response_tx))?;
-        response_rx
-            .await?
-            .map_err(|e| anyhow::anyhow!("error executing request {}", e))
+        tokio::time::timeout(self.send_timeout, self.request_tx.send((request, response_tx)))
+            .await
+            .map_err(|_| Overloaded)?
+            .map_err(|_| anyhow::anyhow!("raft is not running"))?;
+        response_rx.await?.map_err(|e| match e.is_backlog_full() {
+            true => anyhow::Error::from(Overloaded),
+            false => anyhow::anyhow!("error executing request {}", e),
+        })
     }
 
     /// Proposes a state transition to the Raft state machine.
     pub async fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>> {
-        Ok(self.request(Proposal::new(command)).await?.get())
+        self.propose_with_id(Uuid::new_v4(), command).await
+    }
+
+    /// Proposes a state transition under a request id chosen by the caller. Retrying with the
+    /// same id returns the original response instead of applying the transition again.
+    pub async fn propose_with_id(&self, request_id: Uuid, command: Vec<u8>) -> Result<Vec<u8>> {
+        let proposal = Proposal::with_request_id(request_id, command);
+        Ok(self.request(proposal).await?.get())
+    }
+
+    /// Waits until local state is safe to read, meaning it reflects every committed transition.
+    pub async fn read(&self) -> Result<Read> {
+        let (read, index) = match self.lease.read_index() {
+            Some(index) => (Read::Lease, index),
+            None => {
+                let res = self.request(Proposal::read()).await?;
+                (Read::ReadIndex, bincode::deserialize(&res.get())?)
+            }
+        };
+        if let Some(mut applied) = self.applied.clone() {
+            applied.wait_for(|&applied| applied >= index).await?;
+        }
+        Ok(read)
+    }
+
+    /// Asks the leader for the health of the cluster. Followers forward the request to the
+    /// leader, so this can be called on any node that knows the leader.
+    pub async fn describe_cluster(&self) -> Result<ClusterHealth> {
+        let res = self.request(Proposal::describe()).await?;
+        Ok(bincode::deserialize(&res.get())?)
+    }
+
+    /// Commits a no-op entry through the full raft path, returning how long it took from
+    /// proposal to commit. Useful as a liveness and latency probe.
+    pub async fn noop(&self) -> Result<Duration> {
+        let start = Instant::now();
+        self.request(Proposal::noop()).await?;
+        Ok(start.elapsed())
+    }
+
+    /// Makes the local node stand for election straight away, as if its election timeout had
+    /// elapsed. Returns once the election has started, not once it is won.
+    pub async fn force_election(&self) -> Result<()> {
+        self.request(Proposal::elect()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overloaded, RaftClient, Read};
+    use crate::raft::lease::Lease;
+    use crate::raft::rpc::{Response, ResponseError};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn overloaded() -> anyhow::Result<()> {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let client = RaftClient::new(tx, Duration::from_millis(50));
+
+        // occupies the only slot in the queue, and never gets a response
+        let c = client.clone();
+        tokio::spawn(async move { c.propose(vec![1]).await });
+        while client.request_tx.capacity() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let err = client.propose(vec![2]).await.unwrap_err();
+        assert!(err.is::<Overloaded>());
+
+        // as is a proposal the leader refuses while its backlog is full
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let client = RaftClient::new(tx, Duration::from_millis(50));
+        tokio::spawn(async move {
+            while let Some((_, cb)) = rx.recv().await {
+                let _ = cb.send(Err(ResponseError::backlog_full()));
+            }
+        });
+        let err = client.propose(vec![3]).await.unwrap_err();
+        assert!(err.is::<Overloaded>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read() -> anyhow::Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let lease = Lease::default();
+        let (applied_tx, applied_rx) = tokio::sync::watch::channel(0);
+        let client = RaftClient::new(tx, Duration::from_millis(50))
+            .with_lease(lease.clone())
+            .with_applied(applied_rx);
+        tokio::spawn(async move {
+            while let Some((proposal, cb)) = rx.recv().await {
+                assert!(proposal.is_read());
+                let _ = cb.send(Ok(Response::new(bincode::serialize(&2u64).unwrap())));
+            }
+        });
+
+        lease.extend(Instant::now() + Duration::from_millis(100));
+        assert_eq!(client.read().await?, Read::Lease);
+
+        // a read waits for the state machine to apply what the leader had committed
+        lease.commit(1);
+        let read = tokio::spawn({
+            let client = client.clone();
+            async move { client.read().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!read.is_finished());
+        applied_tx.send_replace(1);
+        assert_eq!(read.await??, Read::Lease);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let read = tokio::spawn(async move { client.read().await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!read.is_finished());
+        applied_tx.send_replace(2);
+        assert_eq!(read.await??, Read::ReadIndex);
+        Ok(())
     }
 }
@@ -1,9 +1,10 @@
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 use std::time::Duration;
 use tempfile::tempdir;
 
+use crate::raft::rpc::Encoding;
 use crate::raft::Node;
 use crate::raft::NodeId;
 use anyhow::Result;
@@ -24,7 +25,22 @@ pub struct RaftConfig {
     pub port: u16,
     /// A list of addresses to query for cluster membership.
     pub nodes: Vec<Node>,
-    /// The version of the protocol spoken by this instance.
+    /// Nodes that are replicated to without voting or counting towards commits, until they are
+    /// promoted to members. A node that is a learner itself doesn't start elections.
+    pub learners: Vec<Node>,
+    /// Whether the leader promotes a learner to a member once it has stayed caught up for
+    /// `learner_stable_period`.
+    pub auto_promote_learners: bool,
+    /// How many entries behind the leader's last one a learner may be and still be caught up.
+    pub learner_catch_up_entries: u64,
+    /// How long a learner has to stay caught up before it is promoted.
+    pub learner_stable_period: Duration,
+    /// Addresses of existing members that a starting node asks for the membership of the
+    /// cluster, replacing `nodes` when one of them answers.
+    pub seeds: Vec<SocketAddr>,
+    /// The version of the protocol spoken by this instance. Features that came after it are
+    /// left unused, so that peers still on it during a rolling upgrade can understand every
+    /// message. Raise it once every node runs a version that supports the new level.
     pub protocol_version: u32,
     /// The default timeout for a heartbeat.
     pub heartbeat_timeout: Duration,
@@ -32,15 +48,59 @@ pub struct RaftConfig {
     pub election_timeout: Duration,
     ///
     pub commit_timeout: Duration,
-    /// Maximum number of entries that can be sent in an append message.
+    /// Maximum number of entries that can be sent in an append message. A follower that is
+    /// further behind is caught up over several rounds.
     pub max_append_entries: u64,
     ///
     pub snapshot_interval: Duration,
     ///
     pub snapshot_threshold: u64,
+    /// The wire encoding used for messages between nodes, either `bincode` or `json`.
+    pub encoding: Encoding,
+    /// Whether this node only replicates the chain, without ever voting or becoming leader.
+    pub observer: bool,
+    /// Maximum number of client proposals queued before proposers have to wait.
+    pub proposal_queue_size: usize,
+    /// How long a proposer waits for room in the proposal queue before giving up.
+    pub proposal_timeout: Duration,
+    /// The most entries the leader holds beyond its commit index before refusing proposals
+    /// until more of them commit, or none for no limit.
+    pub max_uncommitted_entries: Option<u64>,
+    /// How long after a heartbeat round the leader may serve reads locally. Must be shorter
+    /// than the shortest election timeout a follower picks, so no new leader can be elected
+    /// while the lease is held.
+    pub lease_timeout: Duration,
+    /// How strongly this node is preferred as leader, up to `MAX_ELECTION_PRIORITY`. Each step
+    /// below the maximum delays the election timeout by the width of the randomized timeout
+    /// range, so a more preferred node that is up times out first.
+    pub election_priority: u8,
+    /// The most times the range election timeouts are picked from doubles in width after split
+    /// votes in a row.
+    pub max_election_backoff: u32,
+    /// How often the state machine is ticked, which drives elections and heartbeats.
+    pub tick_interval_ms: u64,
+    /// How long to wait before reconnecting to a node the first time a connection fails. The
+    /// wait doubles with every failure in a row.
+    pub reconnect_backoff: Duration,
+    /// The longest wait between reconnection attempts, or none to keep doubling.
+    pub reconnect_backoff_max: Option<Duration>,
+    /// The fraction of each wait between reconnection attempts that is randomized, between 0
+    /// and 1, so that nodes restarted together don't all reconnect at once.
+    pub reconnect_jitter: f64,
 }
 
-const MAX_PROTOCOL_VERSION: u32 = 0;
+/// The first protocol version in which leaders append membership and no-op entries, and
+/// proposals may ask for reads, the cluster's health or a no-op rather than only writes.
+pub const ENTRY_TYPES_PROTOCOL_VERSION: u32 = 1;
+/// The first protocol version in which starting nodes discover the cluster from seeds.
+pub const DISCOVERY_PROTOCOL_VERSION: u32 = 2;
+const MAX_PROTOCOL_VERSION: u32 = DISCOVERY_PROTOCOL_VERSION;
+
+/// The highest election priority, which adds no delay to the election timeout.
+pub const MAX_ELECTION_PRIORITY: u8 = 10;
+
+/// The most the election timeout range may be doubled, keeping backed off timeouts sensible.
+const MAX_ELECTION_BACKOFF: u32 = 16;
 
 impl RaftConfig {
     pub fn config(config_path: &std::path::Path) -> RaftConfig {
@@ -53,6 +113,17 @@ impl RaftConfig {
         config.try_deserialize().expect("Could not create configuration")
     }
 
+    /// Whether the protocol version in use includes the feature introduced in `version`.
+    pub fn supports(&self, version: u32) -> bool {
+        self.protocol_version >= version
+    }
+
+    /// The shortest election timeout a follower picks. Timeouts are randomized between this
+    /// and `election_timeout`.
+    pub fn min_election_timeout(&self) -> Duration {
+        self.election_timeout / 2
+    }
+
     /// Validates the configuration, ensuring all values make sense.
     pub fn validate(&self) -> Result<()> {
         if self.protocol_version > MAX_PROTOCOL_VERSION {
@@ -76,6 +147,47 @@ impl RaftConfig {
         if self.snapshot_interval < Duration::from_millis(5) {
             return Err(anyhow::anyhow!("snapshot interval is too low"));
         }
+        if self.lease_timeout >= self.min_election_timeout() {
+            return Err(anyhow::anyhow!(
+                "lease timeout must be shorter than half the election timeout"
+            ));
+        }
+        if self.tick_interval_ms == 0 {
+            return Err(anyhow::anyhow!("tick interval cannot be 0"));
+        }
+        if Duration::from_millis(self.tick_interval_ms) > self.heartbeat_timeout {
+            return Err(anyhow::anyhow!(
+                "tick interval must not be longer than the heartbeat timeout"
+            ));
+        }
+        if self.election_priority > MAX_ELECTION_PRIORITY {
+            return Err(anyhow::anyhow!("election priority is too high"));
+        }
+        if self.max_election_backoff > MAX_ELECTION_BACKOFF {
+            return Err(anyhow::anyhow!("max election backoff is too high"));
+        }
+        if self.max_append_entries == 0 {
+            return Err(anyhow::anyhow!("max append entries cannot be 0"));
+        }
+        if !(0.0..=1.0).contains(&self.reconnect_jitter) {
+            return Err(anyhow::anyhow!("reconnect jitter must be between 0 and 1"));
+        }
+        if self.reconnect_backoff.is_zero() {
+            return Err(anyhow::anyhow!("reconnect backoff cannot be 0"));
+        }
+        if self.proposal_queue_size == 0 {
+            return Err(anyhow::anyhow!("proposal queue size cannot be 0"));
+        }
+        if self.max_uncommitted_entries == Some(0) {
+            return Err(anyhow::anyhow!("max uncommitted entries cannot be 0"));
+        }
+        if self
+            .learners
+            .iter()
+            .any(|l| self.nodes.iter().any(|n| n.id == l.id))
+        {
+            return Err(anyhow::anyhow!("a node cannot be both a member and a learner"));
+        }
 
         Ok(())
     }
@@ -97,13 +209,30 @@ impl Default for RaftConfig {
             ip,
             port: 6669,
             nodes: vec![],
-            protocol_version: 0,
+            learners: vec![],
+            auto_promote_learners: false,
+            learner_catch_up_entries: 16,
+            learner_stable_period: Duration::from_secs(10),
+            seeds: vec![],
+            protocol_version: MAX_PROTOCOL_VERSION,
             heartbeat_timeout: Duration::from_millis(100),
             election_timeout: Duration::from_millis(1000),
             commit_timeout: Duration::from_millis(50),
             max_append_entries: 64,
             snapshot_interval: Duration::from_secs(120),
             snapshot_threshold: 8192,
+            encoding: Encoding::Bincode,
+            observer: false,
+            proposal_queue_size: 1024,
+            proposal_timeout: Duration::from_secs(5),
+            max_uncommitted_entries: None,
+            lease_timeout: Duration::from_millis(250),
+            election_priority: MAX_ELECTION_PRIORITY,
+            max_election_backoff: 4,
+            tick_interval_ms: 100,
+            reconnect_backoff: Duration::from_secs(1),
+            reconnect_backoff_max: Some(Duration::from_secs(30)),
+            reconnect_jitter: 0.2,
         }
     }
 }
@@ -121,6 +250,7 @@ mod tests {
     use std::time::Duration;
 
     use super::RaftConfig;
+    use crate::raft::Node;
 
     #[test]
     fn default() {
@@ -147,5 +277,39 @@ mod tests {
 
         let res = config.validate();
         assert_eq!(true, res.is_err());
+
+        // followers may time out after half the election timeout
+        for lease_timeout in [Duration::from_secs(2), Duration::from_millis(500)] {
+            let config = RaftConfig {
+                lease_timeout,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err());
+        }
+
+        let config = RaftConfig {
+            election_priority: 11,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        for tick_interval_ms in [0, 101] {
+            let config = RaftConfig {
+                tick_interval_ms,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err());
+        }
+
+        let node = Node {
+            id: 2,
+            addr: ([127, 0, 0, 1], 6002).into(),
+        };
+        let config = RaftConfig {
+            nodes: vec![node],
+            learners: vec![node],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
     }
 }
@@ -0,0 +1,117 @@
+//! Finding the cluster through seed addresses, for nodes started without a static list of peers.
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::raft::rpc::{Address, Encoding, Message};
+use crate::raft::{Command, Node, NodeId};
+
+/// How long a seed has to answer before the next one is tried.
+const SEED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The members of the cluster as seen by one of them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Membership {
+    /// The leader of the current term, if the member knows of one.
+    pub leader: Option<NodeId>,
+    /// Every member, including the one that answered.
+    pub nodes: Vec<Node>,
+}
+
+/// Asks each seed in turn for the membership of the cluster on behalf of `node`, returning the
+/// first answer. The seed has the leader add `node` to the membership.
+pub async fn discover(seeds: &[SocketAddr], node: Node, encoding: Encoding) -> Result<Membership> {
+    for seed in seeds {
+        match tokio::time::timeout(SEED_TIMEOUT, ask(*seed, node, encoding)).await {
+            Ok(Ok(membership)) => return Ok(membership),
+            Ok(Err(e)) => tracing::warn!(%seed, %e, "could not discover cluster from seed"),
+            Err(_) => tracing::warn!(%seed, "seed did not answer discovery"),
+        }
+    }
+    Err(anyhow::anyhow!("none of the seeds {:?} answered", seeds))
+}
+
+async fn ask(seed: SocketAddr, node: Node, encoding: Encoding) -> Result<Membership> {
+    let stream = TcpStream::connect(seed).await?;
+    let mut frames = Framed::new(stream, LengthDelimitedCodec::new());
+    let req = Message::new(Address::Local, Address::Peers, Command::Discover(node));
+    frames.send(encoding.encode(&req)?.into()).await?;
+
+    let frame = frames
+        .try_next()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("seed closed the connection"))?;
+    match encoding.decode(&frame)?.command {
+        Command::Discovered(membership) => Ok(membership),
+        command => Err(anyhow::anyhow!("unexpected answer {}", command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, watch};
+
+    use super::{discover, Membership};
+    use crate::raft::rpc::Encoding;
+    use crate::raft::{tcp, Command, Node};
+    use crate::Shutdown;
+
+    #[tokio::test]
+    async fn from_seed() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let seed = listener.local_addr()?;
+        let membership = Membership {
+            leader: Some(2),
+            nodes: vec![
+                Node { id: 1, addr: seed },
+                Node {
+                    id: 2,
+                    addr: "127.0.0.1:6670".parse()?,
+                },
+            ],
+        };
+        let (_membership_tx, membership_rx) = watch::channel(membership.clone());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let shutdown = Shutdown::new();
+        tokio::spawn(tcp::receive_task(
+            shutdown.clone(),
+            listener,
+            tx,
+            Encoding::Bincode,
+            membership_rx,
+        ));
+
+        // a seed that is down is skipped
+        let down = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let joining = Node {
+            id: 3,
+            addr: "127.0.0.1:6671".parse()?,
+        };
+        let discovered = discover(&[down, seed], joining, Encoding::Bincode).await?;
+        assert_eq!(discovered, membership);
+        // the seed passes the joining node on, for the leader to add
+        let passed = rx.recv().await.unwrap().command;
+        assert!(matches!(passed, Command::Discover(node) if node == joining));
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_seed_answers() -> Result<()> {
+        let down = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let joining = Node {
+            id: 3,
+            addr: "127.0.0.1:6671".parse()?,
+        };
+        assert!(discover(&[down], joining, Encoding::Bincode).await.is_err());
+        Ok(())
+    }
+}
@@ -1,18 +1,31 @@
 use std::collections::HashMap;
 
-use crate::raft::NodeId;
+use crate::raft::{quorum, NodeId, Term};
 
 #[derive(Debug)]
 pub struct Election {
     voter_ids: Vec<NodeId>,
     votes: HashMap<NodeId, bool>,
+    /// The highest term seen in a rejected vote, if it was higher than the candidate's own.
+    higher_term: Option<Term>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ElectionStatus {
     Elected,
     Voting,
-    Defeated,
+    Defeated(DefeatReason),
+}
+
+/// Why a candidate lost an election.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefeatReason {
+    /// A majority of voters rejected the candidate.
+    Rejected,
+    /// Every voter responded, but the candidate did not receive a majority.
+    Split,
+    /// A voter responded with a term higher than the candidate's own.
+    HigherTerm(Term),
 }
 
 impl Election {
@@ -20,6 +33,7 @@ impl Election {
         let mut election = Election {
             voter_ids,
             votes: HashMap::new(),
+            higher_term: None,
         };
 
         election.reset();
@@ -28,47 +42,116 @@ impl Election {
 
     pub fn reset(&mut self) {
         self.votes.clear();
+        self.higher_term = None;
     }
 
+    /// Records a vote. Votes from nodes that aren't voters, such as learners, don't count.
     pub fn vote(&mut self, id: NodeId, vote: bool) {
-        self.votes.insert(id, vote);
+        if self.voter_ids.contains(&id) {
+            self.votes.insert(id, vote);
+        }
+    }
+
+    /// Record a rejected vote from a voter that has seen a higher term than the candidate.
+    pub fn reject_with_term(&mut self, id: NodeId, term: Term) {
+        self.vote(id, false);
+        self.higher_term = self.higher_term.max(Some(term));
+    }
+
+    /// The number of granted and rejected votes received so far.
+    pub fn tally(&self) -> (usize, usize) {
+        let granted = self.votes.values().filter(|vote| **vote).count();
+        (granted, self.votes.len() - granted)
     }
 
     pub fn election_status(&self) -> ElectionStatus {
-        let (votes, total) =
-            self.votes
-                .iter()
-                .fold((0, 0), |(mut votes, mut total), (_id, vote)| {
-                    if *vote {
-                        votes += 1;
-                    }
-
-                    total += 1;
-                    (votes, total)
-                });
-
-        if votes >= self.quorum_size() {
+        let (granted, rejected) = self.tally();
+
+        if granted >= self.quorum_size() {
             ElectionStatus::Elected
-        } else if total - votes == self.quorum_size() {
-            ElectionStatus::Defeated
+        } else if let Some(term) = self.higher_term {
+            ElectionStatus::Defeated(DefeatReason::HigherTerm(term))
+        } else if rejected >= self.quorum_size() {
+            ElectionStatus::Defeated(DefeatReason::Rejected)
+        } else if granted + rejected >= self.voters_size() {
+            ElectionStatus::Defeated(DefeatReason::Split)
         } else {
             ElectionStatus::Voting
         }
     }
 
     #[inline]
-    #[allow(dead_code)]
     fn voters_size(&self) -> usize {
         self.voter_ids.len()
     }
 
+    /// The votes needed to win. A single node cluster is won with the candidate's own vote,
+    /// rather than before it is cast.
     #[inline]
     fn quorum_size(&self) -> usize {
-        // If we are a single node cluster, we always win the election
-        if self.voter_ids.len() == 1 {
-            return 0;
-        }
+        quorum(self.voter_ids.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefeatReason, Election, ElectionStatus};
+
+    #[test]
+    fn majority() {
+        let mut election = Election::new(vec![1, 2, 3]);
+        election.vote(1, true);
+        assert_eq!(election.election_status(), ElectionStatus::Voting);
+        election.vote(2, true);
+        assert_eq!(election.election_status(), ElectionStatus::Elected);
+        assert_eq!(election.tally(), (2, 0));
+    }
+
+    #[test]
+    fn tie() {
+        let mut election = Election::new(vec![1, 2, 3, 4]);
+        election.vote(1, true);
+        election.vote(2, true);
+        election.vote(3, false);
+        assert_eq!(election.election_status(), ElectionStatus::Voting);
+        election.vote(4, false);
+        assert_eq!(
+            election.election_status(),
+            ElectionStatus::Defeated(DefeatReason::Split)
+        );
+        assert_eq!(election.tally(), (2, 2));
+    }
+
+    #[test]
+    fn higher_term() {
+        let mut election = Election::new(vec![1, 2, 3]);
+        election.vote(1, true);
+        election.reject_with_term(2, 5);
+        assert_eq!(
+            election.election_status(),
+            ElectionStatus::Defeated(DefeatReason::HigherTerm(5))
+        );
+        assert_eq!(election.tally(), (1, 1));
+    }
+
+    #[test]
+    fn single_node() {
+        let mut election = Election::new(vec![1]);
+        assert_eq!(election.election_status(), ElectionStatus::Voting);
+        election.vote(1, true);
+        assert_eq!(election.election_status(), ElectionStatus::Elected);
+    }
 
-        (self.voter_ids.len() / 2) + 1
+    #[test]
+    fn two_nodes() {
+        let mut election = Election::new(vec![1, 2]);
+        election.vote(1, true);
+        assert_eq!(election.election_status(), ElectionStatus::Voting);
+        // a learner's vote doesn't count
+        election.vote(3, true);
+        assert_eq!(election.election_status(), ElectionStatus::Voting);
+        assert_eq!(election.tally(), (1, 0));
+        election.vote(2, true);
+        assert_eq!(election.election_status(), ElectionStatus::Elected);
     }
 }
@@ -1,3 +1,4 @@
+use std::ops::Range;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -7,11 +8,13 @@ use crate::raft::candidate::Candidate;
 use crate::raft::chain::{Block, BlockId, Chain};
 use crate::raft::election::Election;
 use crate::raft::fsm::Instruction;
+use crate::raft::lease::Lease;
 use crate::raft::rpc::{Address, Message, Response, ResponseError};
 use crate::raft::Command::VoteResponse;
 use crate::raft::{Apply, ClientRequest, ClientResponse, RaftHandle, RaftRole, Term};
+use crate::raft::config::MAX_ELECTION_PRIORITY;
 use crate::raft::{ClientRequestId, RaftConfig};
-use crate::raft::{Command, NodeId, Raft, Role, State};
+use crate::raft::{Command, Node, NodeId, Raft, Role, State};
 use anyhow::Result;
 use std::collections::HashSet;
 use tokio::sync::mpsc::UnboundedSender;
@@ -48,16 +51,18 @@ impl Apply for Raft<Follower> {
                 leader_id,
                 term,
                 commit,
-            } => self.apply_heartbeat(leader_id, term, commit),
+                round,
+            } => self.apply_heartbeat(leader_id, term, commit, round),
             Command::VoteRequest {
+                term,
                 candidate_id,
                 last_term,
                 head,
-                ..
-            } => self.apply_vote_request(candidate_id, last_term, head),
+            } => self.apply_vote_request(candidate_id, term, last_term, head),
             Command::Timeout => self.apply_timeout(),
             Command::ClientRequest(req) => self.apply_client_request(req),
             Command::ClientResponse(res) => self.apply_client_response(res.id, res.res),
+            Command::Discover(node) => self.apply_discover(node),
             _ => self.apply_self(),
         }
     }
@@ -72,10 +77,15 @@ impl Raft<Follower> {
     ) -> Result<Raft<Follower>> {
         config.validate()?;
         let chain = Chain::new(&config.data_directory)?;
+        let mut state = State::new(&config);
+        if let Some((term, voted_for)) = chain.get_vote()? {
+            state.current_term = term;
+            state.voted_for = voted_for;
+        }
         let mut raft = Raft {
             id: config.id,
             config,
-            state: State::default(),
+            state,
             role: Follower {
                 leader_id: None,
                 proxied_reqs: HashSet::new(),
@@ -84,6 +94,8 @@ impl Raft<Follower> {
             chain,
             rpc_tx,
             fsm_tx,
+            lease: Lease::default(),
+            span: tracing::Span::none(),
         };
 
         raft.init();
@@ -94,24 +106,71 @@ impl Raft<Follower> {
         self.set_election_timeout();
     }
 
-    fn can_vote(&self, last_term: Term, head: BlockId) -> bool {
-        !(self.state.voted_for.is_some()
-            || self.state.current_term > last_term
-            || self.chain.get_commit() > head)
+    /// A vote can be granted once per term, and only to a candidate whose chain is at least as
+    /// up to date as our own.
+    fn can_vote(
+        &self,
+        candidate_id: NodeId,
+        term: Term,
+        last_term: Term,
+        head: BlockId,
+    ) -> Result<bool> {
+        if term < self.state.current_term {
+            return Ok(false);
+        }
+        if self
+            .state
+            .voted_for
+            .is_some_and(|voted_for| voted_for != candidate_id)
+        {
+            return Ok(false);
+        }
+
+        let our_head = self.chain.get_head();
+        let our_last_term = self.chain.get(&our_head)?.map_or(0, |b| b.term);
+        Ok((last_term, head) >= (our_last_term, our_head))
     }
 
     fn get_randomized_timeout(&self) -> Duration {
         let _prev_timeout = self.state.election_timeout;
         let timeout = rand::thread_rng()
             .gen_range(self.state.min_election_timeout..self.state.max_election_timeout);
-        Duration::from_millis(timeout as u64)
+        Duration::from_millis(timeout as u64) + self.priority_delay()
     }
 
-    fn set_election_timeout(&mut self) {
+    /// The delay added to the election timeout of a less preferred node. Since each priority
+    /// step is as wide as the randomized range, the timeouts of nodes with different priorities
+    /// never overlap.
+    fn priority_delay(&self) -> Duration {
+        let steps = MAX_ELECTION_PRIORITY.saturating_sub(self.config.election_priority);
+        let range = self.state.max_election_timeout - self.state.min_election_timeout;
+        Duration::from_millis((steps as usize * range) as u64)
+    }
+
+    pub(crate) fn set_election_timeout(&mut self) {
         self.state.election_timeout = Some(self.get_randomized_timeout());
         self.state.election_time = Some(Instant::now());
     }
 
+    /// Restarts the election timer with a timeout longer than any regular timeout, used after a
+    /// split vote so that another candidate has a chance to win first.
+    pub(crate) fn backoff_election_timeout(&mut self) {
+        let timeout = rand::thread_rng().gen_range(self.backoff_range());
+        self.state.election_timeout =
+            Some(Duration::from_millis(timeout as u64) + self.priority_delay());
+        self.state.election_time = Some(Instant::now());
+    }
+
+    /// The range the election timeout is picked from after a split vote. It starts above any
+    /// regular timeout, and doubles in width with every further split vote in a row, up to
+    /// `max_election_backoff` times.
+    pub(crate) fn backoff_range(&self) -> Range<usize> {
+        let doublings = self.state.split_votes.saturating_sub(1);
+        let doublings = doublings.min(self.config.max_election_backoff);
+        let max = self.state.max_election_timeout;
+        max..max + (max << doublings)
+    }
+
     fn apply_self(self) -> Result<RaftHandle> {
         Ok(RaftHandle::Follower(self))
     }
@@ -127,51 +186,70 @@ impl Raft<Follower> {
         self.apply_self()
     }
 
+    /// Recognizes `leader_id` as the leader of `term`, which every append and heartbeat from it
+    /// does alike: the election clock restarts, and requests queued while no leader was known
+    /// are sent on to it.
+    fn follow(&mut self, leader_id: NodeId, term: Term) -> Result<()> {
+        self.set_election_timeout();
+        self.term(term);
+        self.role.leader_id = Some(leader_id);
+        self.state.voted_for = Some(leader_id);
+        self.state.split_votes = 0;
+
+        // send any queued requests
+        for req in std::mem::take(&mut self.role.queued_reqs).into_iter() {
+            let id = req.id;
+            self.send(
+                Address::Peer(leader_id),
+                Command::ClientRequest(req.clone()),
+            )?;
+            self.role.proxied_reqs.insert(id);
+        }
+        Ok(())
+    }
+
+    /// Appends the leader's blocks, as long as the block they follow on from is in our chain.
+    /// An append without any blocks is a heartbeat in all but name: the leader is followed the
+    /// same way, and there is nothing to check, append or acknowledge.
     fn apply_append_entries(
         mut self,
         blocks: Vec<Block>,
         leader_id: NodeId,
         term: Term,
     ) -> Result<RaftHandle> {
-        // If we haven't voted and the rpc term is greater, set term to that term.
-        if self.state.voted_for.is_none() && term >= self.state.current_term {
-            self.term(term);
-
-            // Vote for leader and reset election timeout
-            self.state.election_time = Some(Instant::now());
-            self.role.leader_id = Some(leader_id);
-            self.state.voted_for = Some(leader_id);
+        if term < self.state.current_term {
+            // our term tells the stale leader that it has been replaced
+            return self.respond_to_append(leader_id, false);
         }
+        self.follow(leader_id, term)?;
 
-        // If we voted for someone...
-        if let Some(voted_for) = self.state.voted_for {
-            // And the entries rpc is from another "leader" with a lower term
-            assert!(
-                !(voted_for != leader_id && term < self.state.current_term),
-                "{:?}",
-                ..
-            );
-        }
-
-        // If there are entries...
-        if !blocks.is_empty() {
+        // only the parent of the first block is checked, which has to be in our chain for the
+        // blocks to be linked into it
+        let parent_known = match blocks.first() {
+            Some(block) => self.chain.has(&block.next)?,
+            None => return self.apply_self(),
+        };
+        if parent_known {
             for block in blocks {
-                self.chain.extend(block)?; // append the entry
+                self.chain.extend(block)?;
             }
-
-            // confirm append
-            self.rpc_tx.send(Message::new(
-                Address::Peer(self.id),
-                Address::Peer(leader_id),
-                Command::AppendResponse {
-                    node_id: self.id,
-                    term: self.state.current_term,
-                    head: self.chain.get_head(),
-                    success: true,
-                },
-            ))?;
         }
+        self.respond_to_append(leader_id, parent_known)
+    }
 
+    /// Acknowledges an append with our head once it is on disk, which tells the leader where
+    /// to continue from.
+    fn respond_to_append(mut self, leader_id: NodeId, success: bool) -> Result<RaftHandle> {
+        let head = self.chain.flush()?;
+        self.send(
+            Address::Peer(leader_id),
+            Command::AppendResponse {
+                node_id: self.id,
+                term: self.state.current_term,
+                head,
+                success,
+            },
+        )?;
         self.apply_self()
     }
 
@@ -180,37 +258,29 @@ impl Raft<Follower> {
         leader_id: NodeId,
         term: Term,
         commit: BlockId,
+        round: u64,
     ) -> Result<RaftHandle> {
-        self.set_election_timeout();
-        self.term(term);
-        self.role.leader_id = Some(leader_id);
-        self.state.voted_for = Some(leader_id);
-
-        // send any queued requests
-        for req in std::mem::take(&mut self.role.queued_reqs).into_iter() {
-            let id = req.id;
-            self.send(
-                Address::Peer(leader_id),
-                Command::ClientRequest(req.clone()),
-            )?;
-            self.role.proxied_reqs.insert(id);
-        }
+        self.follow(leader_id, term)?;
 
         // apply entries to state machine if leader has advanced commit index
         let has_committed = self.chain.has(&commit)?;
         if has_committed && &commit > &self.chain.get_commit() {
             let prev = self.chain.get_commit();
             self.chain.commit(&commit)?;
-            self.chain.range(prev..commit).for_each(|block| {
-                self.fsm_tx.send(Instruction::Apply { block }).unwrap();
-            });
+            self.record_commit();
+            for block in self.chain.range(prev..=commit.clone()).skip(1) {
+                self.on_commit(&block);
+                self.fsm_tx.send(Instruction::Apply { block })?;
+            }
         }
 
         self.send(
             Address::Peer(leader_id),
             Command::HeartbeatResponse {
+                node_id: self.id,
                 commit: self.chain.get_commit(),
                 has_committed,
+                round,
             },
         )?;
         self.apply_self()
@@ -219,10 +289,15 @@ impl Raft<Follower> {
     fn apply_vote_request(
         mut self,
         candidate_id: NodeId,
+        term: Term,
         last_term: Term,
         head: BlockId,
     ) -> Result<RaftHandle> {
-        if self.can_vote(last_term, head) {
+        if self.can_vote(candidate_id, term, last_term, head)? {
+            // the vote has to survive a crash before the candidate can count it
+            self.state.voted_for = Some(candidate_id);
+            self.chain
+                .save_vote(self.state.current_term, self.state.voted_for)?;
             self.send(
                 Address::Peer(candidate_id),
                 VoteResponse {
@@ -231,7 +306,6 @@ impl Raft<Follower> {
                     granted: true,
                 },
             )?;
-            self.state.voted_for = Some(candidate_id);
         } else {
             self.send(
                 Address::Peer(candidate_id),
@@ -246,7 +320,10 @@ impl Raft<Follower> {
     }
 
     fn apply_timeout(mut self) -> Result<RaftHandle> {
-        if self.state.voted_for.is_none() {
+        // learners wait to be promoted before they stand for election. Having voted this term
+        // doesn't hold us back, or a cluster restarted after everyone voted would never elect.
+        let learner = self.config.learners.iter().any(|n| n.id == self.id);
+        if !learner {
             self.set_election_timeout(); // start a new election
             let raft: Raft<Candidate> = Raft::from(self);
             return raft.seek_election();
@@ -268,6 +345,16 @@ impl Raft<Follower> {
         self.apply_self()
     }
 
+    /// Passes a node that discovered the cluster through us on to the leader, which adds it to
+    /// the membership. Without a leader it is dropped, and the node has to ask again when it
+    /// restarts.
+    fn apply_discover(self, node: Node) -> Result<RaftHandle> {
+        if let Some(leader_id) = self.role.leader_id {
+            self.send(Address::Peer(leader_id), Command::Discover(node))?;
+        }
+        self.apply_self()
+    }
+
     fn apply_client_response(
         mut self,
         id: ClientRequestId,
@@ -288,7 +375,7 @@ impl From<Raft<Follower>> for Raft<Candidate> {
         node_ids.push(val.id);
         let election = Election::new(node_ids);
 
-        Raft {
+        let raft = Raft {
             id: val.id,
             state: val.state,
             role: Candidate {
@@ -299,7 +386,11 @@ impl From<Raft<Follower>> for Raft<Candidate> {
             chain: val.chain,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
-        }
+            lease: val.lease,
+            span: val.span,
+        };
+        raft.record_role();
+        raft
     }
 }
 
@@ -307,10 +398,14 @@ impl From<Raft<Follower>> for Raft<Candidate> {
 mod tests {
     use super::Command;
     use super::RaftHandle;
-    use crate::raft::chain::BlockId;
+    use crate::raft::chain::{Block, BlockId, UnappendedBlock};
     use crate::raft::test::new_follower;
-    use crate::raft::Apply;
-    use std::time::Instant;
+    use crate::raft::config::MAX_ELECTION_PRIORITY;
+    use crate::raft::fsm::Instruction;
+    use crate::raft::rpc::Address;
+    use crate::raft::{Apply, EntryType, Node};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn follower_to_leader() {
@@ -320,9 +415,47 @@ mod tests {
             RaftHandle::Follower(_) => panic!(),
             RaftHandle::Candidate(_) => panic!(),
             RaftHandle::Leader(leader) => assert_eq!(id, leader.id),
+            RaftHandle::Observer(_) => panic!(),
         }
     }
 
+    #[test]
+    fn commits_prior_terms_with_noop() -> anyhow::Result<()> {
+        let ((_rpc_rx, mut fsm_rx), mut follower) = new_follower();
+        let earlier = follower.chain.append(UnappendedBlock::new(1, vec![1]))?;
+        let noop = Block {
+            id: BlockId::new(2),
+            next: earlier.clone(),
+            term: 2,
+            entry_type: EntryType::Noop,
+        };
+
+        // the new leader of term 2 replicates its no-op, and commits it once a majority has it
+        let follower = follower
+            .apply(Command::AppendEntries {
+                term: 2,
+                leader_id: 2,
+                blocks: vec![noop.clone()],
+            })?
+            .apply(Command::Heartbeat {
+                term: 2,
+                commit: noop.id.clone(),
+                leader_id: 2,
+                round: 1,
+            })?
+            .get_follower()
+            .unwrap();
+        assert_eq!(follower.chain.get_commit(), noop.id);
+        let applied: Vec<BlockId> = std::iter::from_fn(|| fsm_rx.try_recv().ok())
+            .filter_map(|i| match i {
+                Instruction::Apply { block } => Some(block.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(applied, vec![earlier, noop.id]);
+        Ok(())
+    }
+
     #[test]
     fn follower_noop() {
         let (_, follower) = new_follower();
@@ -331,14 +464,16 @@ mod tests {
             RaftHandle::Follower(follower) => assert_eq!(id, follower.id),
             RaftHandle::Candidate(_) => panic!(),
             RaftHandle::Leader(_) => panic!(),
+            RaftHandle::Observer(_) => panic!(),
         }
     }
 
     #[tokio::test]
     async fn apply_heartbeat() -> anyhow::Result<()> {
-        let ((mut rpc_rx, _), follower) = new_follower();
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let id = follower.id;
         let follower = follower
-            .apply_heartbeat(11, 12, BlockId::new(1))?
+            .apply_heartbeat(11, 12, BlockId::new(1), 1)?
             .get_follower()
             .unwrap();
         // we voted for the leader
@@ -350,8 +485,10 @@ mod tests {
         assert_eq!(
             msg.command,
             Command::HeartbeatResponse {
+                node_id: id,
                 commit: BlockId::new(0),
-                has_committed: false
+                has_committed: false,
+                round: 1
             }
         );
         Ok(())
@@ -359,27 +496,30 @@ mod tests {
 
     #[tokio::test]
     async fn apply_vote_request() -> anyhow::Result<()> {
-        let ((mut rpc_rx, _), follower) = new_follower();
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let id = follower.id;
         let mut follower = follower
-            .apply_vote_request(11, 12, BlockId::new(1))?
+            .apply_vote_request(11, 0, 12, BlockId::new(1))?
             .get_follower()
             .unwrap();
         // we voted for the leader
         assert!(follower.state.voted_for.is_some());
         assert_eq!(follower.state.voted_for.unwrap(), 11);
+        // and will remember it after a restart
+        assert_eq!(follower.chain.get_vote()?, Some((0, Some(11))));
         let msg = rpc_rx.recv().await.unwrap();
         // we granted the request
         assert_eq!(
             msg.command,
             Command::VoteResponse {
                 term: 0,
-                from: 1,
+                from: id,
                 granted: true
             }
         );
         follower.state.voted_for = Some(2);
         let _follower = follower
-            .apply_vote_request(11, 12, BlockId::new(1))
+            .apply_vote_request(11, 0, 12, BlockId::new(1))
             .unwrap();
         let msg = rpc_rx.recv().await.unwrap();
         // we already voted
@@ -387,7 +527,31 @@ mod tests {
             msg.command,
             Command::VoteResponse {
                 term: 0,
-                from: 1,
+                from: id,
+                granted: false
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_vote_request_stale_log() -> anyhow::Result<()> {
+        let ((mut rpc_rx, _fsm_rx), mut follower) = new_follower();
+        let id = follower.id;
+        follower.chain.append(UnappendedBlock::new(2, vec![]))?;
+
+        // the candidate's last block is from an older term than ours
+        let follower = follower
+            .apply_vote_request(11, 3, 1, BlockId::new(5))?
+            .get_follower()
+            .unwrap();
+        assert_eq!(follower.state.voted_for, None);
+        let msg = rpc_rx.recv().await.unwrap();
+        assert_eq!(
+            msg.command,
+            Command::VoteResponse {
+                term: 0,
+                from: id,
                 granted: false
             }
         );
@@ -396,15 +560,30 @@ mod tests {
 
     #[test]
     fn apply_timeout() -> anyhow::Result<()> {
-        let ((_rpc_rx, _), follower) = new_follower();
+        let ((_rpc_rx, _fsm_rx), follower) = new_follower();
         let _leader = follower.apply_timeout()?.get_leader().unwrap();
 
         Ok(())
     }
 
+    #[test]
+    fn timeout_after_voting() -> anyhow::Result<()> {
+        // as if we restarted having voted for another node in the latest term
+        let ((_rpc_rx, _fsm_rx), mut follower) = new_follower();
+        follower.state.current_term = 3;
+        follower.state.voted_for = Some(7);
+        let id = follower.id;
+
+        let leader = follower.apply_timeout()?.get_leader().unwrap();
+        assert_eq!(leader.state.current_term, 4);
+        // the vote for ourself is on disk before anyone is asked for theirs
+        assert_eq!(leader.chain.get_vote()?, Some((4, Some(id))));
+        Ok(())
+    }
+
     #[test]
     fn apply_tick() -> anyhow::Result<()> {
-        let ((_rpc_rx, _), mut follower) = new_follower();
+        let ((_rpc_rx, _fsm_rx), mut follower) = new_follower();
         follower.state.election_time = Some(Instant::now());
         follower.state.election_timeout = Some(follower.config.election_timeout);
         let follower = follower.apply_tick()?.get_follower().unwrap();
@@ -415,7 +594,7 @@ mod tests {
 
     #[test]
     fn apply_append_entries() -> anyhow::Result<()> {
-        let ((_rpc_rx, _), mut follower) = new_follower();
+        let ((_rpc_rx, _fsm_rx), mut follower) = new_follower();
         follower.state.election_time = Some(Instant::now());
         follower.state.election_timeout = Some(follower.config.election_timeout);
         let follower = follower.apply_tick()?.get_follower().unwrap();
@@ -423,4 +602,180 @@ mod tests {
         let _leader = follower.apply_tick()?.get_leader().unwrap();
         Ok(())
     }
+
+    #[test]
+    fn flushes_before_ack() -> anyhow::Result<()> {
+        let ((rpc_rx, _fsm_rx), mut follower) = new_follower();
+        // records the messages sent so far as they are, and each flush as None, in order
+        let rpc_rx = Arc::new(Mutex::new(rpc_rx));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let record_sent = {
+            let (rpc_rx, events) = (rpc_rx.clone(), events.clone());
+            move || {
+                while let Ok(msg) = rpc_rx.lock().unwrap().try_recv() {
+                    events.lock().unwrap().push(Some(msg.command));
+                }
+            }
+        };
+        follower.chain.on_flush = Some(Box::new({
+            let (record_sent, events) = (record_sent.clone(), events.clone());
+            move || {
+                record_sent();
+                events.lock().unwrap().push(None);
+            }
+        }));
+
+        let blocks: Vec<Block> = (1..=3)
+            .map(|i| Block {
+                id: BlockId::new(i),
+                next: BlockId::new(i - 1),
+                term: 1,
+                entry_type: EntryType::Data { data: vec![] },
+            })
+            .collect();
+        follower.apply(Command::AppendEntries {
+            term: 1,
+            leader_id: 2,
+            blocks,
+        })?;
+        record_sent();
+
+        let events = events.lock().unwrap();
+        match &events[..] {
+            [None, Some(Command::AppendResponse { head, success, .. })] => {
+                assert_eq!(*head, BlockId::new(3));
+                assert!(success);
+            }
+            events => panic!("expected a flush and then the ack, got {:?}", events),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn empty_append_is_heartbeat() -> anyhow::Result<()> {
+        let ((_rpc_rx, _fsm_rx), mut appended) = new_follower();
+        let ((_rpc_rx2, _fsm_rx2), mut heartbeat) = new_follower();
+        let started = Instant::now() - Duration::from_secs(1);
+        for follower in [&mut appended, &mut heartbeat] {
+            // we voted for a candidate that lost, and are waiting out the election clock
+            follower.state.current_term = 1;
+            follower.state.voted_for = Some(3);
+            follower.state.split_votes = 2;
+            follower.state.election_time = Some(started);
+        }
+        let appended = RaftHandle::Follower(appended)
+            .apply(Command::AppendEntries {
+                term: 1,
+                leader_id: 2,
+                blocks: vec![],
+            })?
+            .get_follower()
+            .unwrap();
+        let heartbeat = RaftHandle::Follower(heartbeat)
+            .apply(Command::Heartbeat {
+                term: 1,
+                leader_id: 2,
+                commit: BlockId::new(0),
+                round: 1,
+            })?
+            .get_follower()
+            .unwrap();
+
+        for follower in [&appended, &heartbeat] {
+            assert_eq!(follower.role.leader_id, Some(2));
+            assert_eq!(follower.state.voted_for, Some(2));
+            assert_eq!(follower.state.split_votes, 0);
+            assert!(follower.state.election_time.unwrap() > started);
+        }
+        assert_eq!(appended.chain.get_head(), heartbeat.chain.get_head());
+        Ok(())
+    }
+
+    #[test]
+    fn append_needs_known_parent() -> anyhow::Result<()> {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let block = |id: u64, next: u64| Block {
+            id: BlockId::new(id),
+            next: BlockId::new(next),
+            term: 1,
+            entry_type: EntryType::Data { data: vec![] },
+        };
+        let mut append = |follower: RaftHandle, blocks: Vec<Block>| {
+            let follower = follower.apply(Command::AppendEntries {
+                term: 1,
+                leader_id: 2,
+                blocks,
+            })?;
+            match rpc_rx.try_recv()?.command {
+                Command::AppendResponse { head, success, .. } => {
+                    Ok::<_, anyhow::Error>((follower, head, success))
+                }
+                cmd => panic!("unexpected {:?}", cmd),
+            }
+        };
+
+        // blocks that follow on from our head are appended
+        let follower = RaftHandle::Follower(follower);
+        let (follower, head, success) = append(follower, vec![block(1, 0), block(2, 1)])?;
+        assert_eq!((head, success), (BlockId::new(2), true));
+
+        // while ones that skip over blocks we don't have are refused, leaving the chain alone
+        let (follower, head, success) = append(follower, vec![block(5, 4)])?;
+        assert_eq!((head, success), (BlockId::new(2), false));
+        let follower = follower.get_follower().unwrap();
+        assert_eq!(follower.role.leader_id, Some(2));
+        assert!(!follower.chain.has(&BlockId::new(5))?);
+        Ok(())
+    }
+
+    #[test]
+    fn election_priority() -> anyhow::Result<()> {
+        let ((_rpc_rx, _fsm_rx), mut preferred) = new_follower();
+        let ((_rpc_rx2, _fsm_rx2), mut other) = new_follower();
+        other.config.election_priority = MAX_ELECTION_PRIORITY - 1;
+        for node in [&mut preferred, &mut other] {
+            node.state.min_election_timeout = 50;
+            node.state.max_election_timeout = 100;
+        }
+
+        for _ in 0..5 {
+            preferred.set_election_timeout();
+            other.set_election_timeout();
+            loop {
+                std::thread::sleep(Duration::from_millis(5));
+                if preferred.needs_election() {
+                    break;
+                }
+                assert!(!other.needs_election(), "the less preferred node timed out first");
+            }
+        }
+
+        assert!(preferred.apply_tick()?.is_leader());
+        Ok(())
+    }
+
+    #[test]
+    fn forwards_discovered_node_to_leader() -> anyhow::Result<()> {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let joining = Node {
+            id: 3,
+            addr: "127.0.0.1:6003".parse()?,
+        };
+        // there is no leader to pass it on to yet
+        let follower = follower.apply(Command::Discover(joining))?;
+        assert!(rpc_rx.try_recv().is_err());
+
+        let follower = follower.apply(Command::Heartbeat {
+            term: 1,
+            commit: BlockId::new(0),
+            leader_id: 2,
+            round: 1,
+        })?;
+        while rpc_rx.try_recv().is_ok() {}
+        follower.apply(Command::Discover(joining))?;
+        let msg = rpc_rx.try_recv()?;
+        assert_eq!(msg.to, Address::Peer(2));
+        assert!(matches!(msg.command, Command::Discover(node) if node == joining));
+        Ok(())
+    }
 }
@@ -1,19 +1,30 @@
 use std::fmt;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
 use crate::raft::chain::{Block, BlockId};
-use crate::raft::rpc::ResponseError;
+use crate::raft::recent::Recent;
+use crate::raft::rpc::{ResponseError, RECENT_PROPOSALS};
 use crate::raft::{
     rpc::{self, Address, Message, Response},
-    ClientRequestId, ClientResponse, Command,
+    ClientRequestId, ClientResponse, Command, Entry,
 };
 use crate::Shutdown;
 use anyhow::Result;
 use std::collections::HashMap;
+use uuid::Uuid;
 
+/// A state machine driven by the entries committed to the Raft chain. Implementations are free
+/// to interpret entries however they like, so the Raft core can host any kind of state.
 pub trait Fsm: Send + Sync + fmt::Debug {
-    fn transition(&mut self, data: Vec<u8>) -> Result<Vec<u8>>;
+    /// Applies a committed entry, returning a response that is sent back to the proposer.
+    fn apply(&mut self, entry: &Entry) -> Result<Vec<u8>>;
+
+    /// Serializes the current state of the state machine.
+    fn snapshot(&self) -> Result<Vec<u8>>;
+
+    /// Replaces the current state of the state machine with the provided snapshot.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()>;
 }
 
 #[derive(Debug)]
@@ -23,6 +34,7 @@ pub enum Instruction {
     },
     Notify {
         id: ClientRequestId,
+        request_id: Uuid,
         client_address: Address,
         block_id: BlockId,
     },
@@ -32,7 +44,14 @@ pub struct Driver<T: Fsm> {
     fsm_rx: mpsc::UnboundedReceiver<Instruction>,
     rpc_tx: mpsc::UnboundedSender<rpc::Message>,
     fsm: T,
-    notifications: HashMap<BlockId, (Address, ClientRequestId)>,
+    notifications: HashMap<BlockId, Vec<(Address, ClientRequestId, Uuid)>>,
+    /// Responses to recently applied blocks, to answer proposals that are retried after
+    /// their block has been applied.
+    responses: Recent<BlockId, Result<Vec<u8>, ResponseError>>,
+    /// The index of the last applied block.
+    applied: u64,
+    /// Publishes the index of the last applied block once it has been applied.
+    applied_tx: watch::Sender<u64>,
 }
 
 impl<T: Fsm> Driver<T> {
@@ -46,9 +65,18 @@ impl<T: Fsm> Driver<T> {
             rpc_tx,
             fsm,
             notifications: HashMap::new(),
+            responses: Recent::new(RECENT_PROPOSALS),
+            applied: 0,
+            applied_tx: watch::channel(0).0,
         }
     }
 
+    /// Publishes the index of each block on `applied_tx` once it has been applied.
+    pub fn with_applied(mut self, applied_tx: watch::Sender<u64>) -> Self {
+        self.applied_tx = applied_tx;
+        self
+    }
+
     pub async fn run(mut self, mut shutdown: Shutdown) -> Result<T> {
         loop {
             tokio::select! {
@@ -62,22 +90,29 @@ impl<T: Fsm> Driver<T> {
                                 continue
                             }
 
-                            let id = block.id.clone();
-                            let res = self.exec(block);
-                            if let Some((to, id)) = self.notifications.remove(&id) {
-                                self.rpc_tx.send(Message {
-                                    to,
-                                    from: Address::Local,
-                                    command: Command::ClientResponse(ClientResponse {
-                                        id,
-                                        res: res.map(Response::new).map_err(|_e| ResponseError {}),
-                                    })
-                                })?;
+                            let block_id = block.id.clone();
+                            self.applied = block_id.index();
+                            let res = self.exec(block).map_err(|e| ResponseError::new(e.to_string()));
+                            self.applied_tx.send_replace(self.applied);
+                            for (to, id, request_id) in self.notifications.remove(&block_id).unwrap_or_default() {
+                                self.respond(to, id, request_id, res.clone())?;
                             }
+                            self.responses.insert(block_id, res);
                         }
-                        Instruction::Notify { block_id, id, client_address } => {
+                        Instruction::Notify { block_id, id, request_id, client_address } => {
                             tracing::debug!("notify");
-                            self.notifications.insert(block_id, (client_address, id));
+                            if let Some(res) = self.responses.get(&block_id) {
+                                let res = res.clone();
+                                self.respond(client_address, id, request_id, res)?;
+                            } else if block_id.index() <= self.applied {
+                                let res = Err(ResponseError::new("response no longer available"));
+                                self.respond(client_address, id, request_id, res)?;
+                            } else {
+                                self.notifications
+                                    .entry(block_id)
+                                    .or_default()
+                                    .push((client_address, id, request_id));
+                            }
                         }
                     };
                 }
@@ -87,8 +122,26 @@ impl<T: Fsm> Driver<T> {
         Ok(self.fsm)
     }
 
+    fn respond(
+        &self,
+        to: Address,
+        id: ClientRequestId,
+        request_id: Uuid,
+        res: Result<Vec<u8>, ResponseError>,
+    ) -> Result<()> {
+        self.rpc_tx.send(Message {
+            to,
+            from: Address::Local,
+            command: Command::ClientResponse(ClientResponse {
+                id,
+                res: res.map(|data| Response::new(data).with_request_id(request_id)),
+            }),
+        })?;
+        Ok(())
+    }
+
     pub fn exec(&mut self, block: Block) -> Result<Vec<u8>> {
-        self.fsm.transition(block.data)
+        self.fsm.apply(&Entry::from(block))
     }
 }
 
@@ -97,6 +150,7 @@ mod test {
     use tokio::sync::mpsc::unbounded_channel;
 
     use super::*;
+    use crate::raft::EntryType;
 
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     enum TestState {
@@ -104,6 +158,23 @@ mod test {
         B,
     }
 
+    impl TestState {
+        fn parse(input: &[u8]) -> Result<Self> {
+            match std::str::from_utf8(input)? {
+                "A" => Ok(TestState::A),
+                "B" => Ok(TestState::B),
+                state => Err(anyhow::anyhow!("unknown state {}", state)),
+            }
+        }
+
+        fn as_bytes(&self) -> &'static [u8] {
+            match self {
+                TestState::A => b"A",
+                TestState::B => b"B",
+            }
+        }
+    }
+
     #[derive(Debug, PartialEq, Eq, Clone)]
     struct TestFsm {
         state: TestState,
@@ -118,16 +189,41 @@ mod test {
     }
 
     impl Fsm for TestFsm {
-        fn transition(&mut self, input: Vec<u8>) -> Result<Vec<u8>> {
-            let state = std::str::from_utf8(&input).unwrap();
-            match state {
-                "A" => self.state = TestState::A,
-                "B" => self.state = TestState::B,
-                _ => panic!(),
+        fn apply(&mut self, entry: &Entry) -> Result<Vec<u8>> {
+            let input = match &entry.entry_type {
+                EntryType::Data { data } => data,
+                _ => return Ok(Vec::new()),
             };
-
+            self.state = TestState::parse(input)?;
             Ok(Vec::new())
         }
+
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            Ok(self.state.as_bytes().to_vec())
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+            self.state = TestState::parse(snapshot)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn snapshot_restore() -> Result<()> {
+        let mut fsm = TestFsm::new();
+        fsm.apply(&Entry {
+            entry_type: EntryType::Data {
+                data: "B".as_bytes().to_owned(),
+            },
+            term: 1,
+            index: 1,
+        })?;
+
+        let mut restored = TestFsm::new();
+        restored.restore(&fsm.snapshot()?)?;
+        assert_eq!(restored, fsm);
+        assert!(restored.restore(b"C").is_err());
+        Ok(())
     }
 
     #[tokio::test]
@@ -143,7 +239,10 @@ mod test {
             block: Block {
                 id: BlockId::new(2),
                 next: BlockId::new(1),
-                data: "B".as_bytes().to_owned(),
+                term: 1,
+                entry_type: EntryType::Data {
+                    data: "B".as_bytes().to_owned(),
+                },
             },
         })?;
 
@@ -0,0 +1,32 @@
+use crate::raft::{LogIndex, NodeId, RaftRole, Term};
+
+/// The health of the raft cluster as seen by its leader.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterHealth {
+    /// The leader that reported the health.
+    pub leader: NodeId,
+    /// The leader's term.
+    pub term: Term,
+    /// Every node the leader knows of, including itself, by id.
+    pub nodes: Vec<NodeHealth>,
+}
+
+impl ClusterHealth {
+    pub fn node(&self, id: NodeId) -> Option<&NodeHealth> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+}
+
+/// How far a single node has replicated the leader's chain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeHealth {
+    pub id: NodeId,
+    /// The role the node last held as far as the leader knows. Peers that replicate from the
+    /// leader are taken to be followers.
+    pub role: RaftRole,
+    /// The index of the newest block the leader knows the node to have.
+    pub match_index: LogIndex,
+    /// Whether the node has responded to the leader within the election timeout, and so counts
+    /// towards a quorum.
+    pub in_contact: bool,
+}
@@ -5,19 +5,26 @@ use std::time::Instant;
 use anyhow::{Error, Result};
 
 use crate::raft::follower::Follower;
-use crate::raft::progress::{NodeProgress, MAX_INFLIGHT};
+use crate::raft::progress::NodeProgress;
 use crate::raft::progress::{ReplicationProgress};
+use crate::raft::recent::Recent;
 
-use crate::raft::{ClientRequest, Command, Raft};
+use crate::raft::{quorum, ClientRequest, ClientResponse, Command, Raft};
 
 use crate::raft::chain::{BlockId, UnappendedBlock};
+use crate::raft::config::ENTRY_TYPES_PROTOCOL_VERSION;
 use crate::raft::fsm::Instruction;
+use crate::raft::health::{ClusterHealth, NodeHealth};
 use crate::raft::rpc::Address;
 use crate::raft::rpc::Message;
+use crate::raft::rpc::ProposalKind;
+use crate::raft::rpc::{Response, ResponseError};
 use crate::raft::Role;
 use crate::raft::Term;
-use crate::raft::{Apply, NodeId, RaftHandle, RaftRole};
+use crate::raft::{Apply, EntryType, Node, NodeId, RaftHandle, RaftRole};
 use std::collections::HashSet;
+use std::net::SocketAddr;
+use uuid::Uuid;
 
 ///
 #[derive(Debug)]
@@ -27,12 +34,26 @@ pub struct Leader {
     pub heartbeat_time: Instant,
     /// The timeout since the last heartbeat.
     pub heartbeat_timeout: Duration,
+    /// The number of the current heartbeat round, so that late answers to earlier rounds aren't
+    /// counted towards it.
+    pub round: u64,
+    /// When the current heartbeat round was sent.
+    pub round_start: Instant,
+    /// The nodes that have responded to the current heartbeat round.
+    pub round_acks: HashSet<NodeId>,
+    /// Reads that arrived since the current heartbeat round was sent.
+    pub queued_reads: Vec<ClientRequest>,
+    /// Reads that are answered once a quorum responds to the current heartbeat round.
+    pub round_reads: Vec<ClientRequest>,
+    /// Nodes that discovered the cluster through a seed and wait to be added to its membership,
+    /// one change at a time.
+    pub joining: Vec<Node>,
+    /// The blocks recent proposals were appended as, so that retries are not appended again.
+    pub proposals: Recent<Uuid, BlockId>,
 }
 
 impl Role for Leader {
-    fn term(&mut self, _term: u64) {
-        unimplemented!()
-    }
+    fn term(&mut self, _term: u64) {}
 
     fn role(&self) -> RaftRole {
         RaftRole::Leader
@@ -40,41 +61,156 @@ impl Role for Leader {
 }
 
 impl Raft<Leader> {
+    /// Starts a new heartbeat round. Once a quorum responds, the lease is extended and any
+    /// reads that arrived before the round was sent are answered.
     #[tracing::instrument]
-    pub(crate) fn heartbeat(&self) -> Result<()> {
+    pub(crate) fn heartbeat(&mut self) -> Result<()> {
+        self.role.round += 1;
+        self.role.round_start = Instant::now();
+        self.role.round_acks = HashSet::from([self.id]);
+        let queued = std::mem::take(&mut self.role.queued_reads);
+        self.role.round_reads.extend(queued);
+
         self.send_all(Command::Heartbeat {
             term: self.state.current_term,
             commit: self.chain.get_commit(),
             leader_id: self.id,
+            round: self.role.round,
         })?;
+        self.confirm_round()
+    }
+
+    fn quorum_size(&self) -> usize {
+        // the configured peers plus ourself
+        quorum(self.config.nodes.len() + 1)
+    }
+
+    /// Acts on the current heartbeat round if a quorum has responded to it, which confirms we
+    /// were still leader when it was sent. Reads are answered with the commit index they have
+    /// to see applied.
+    fn confirm_round(&mut self) -> Result<()> {
+        // until an entry of our own term commits, we may not know of everything committed
+        if self.role.round_acks.len() < self.quorum_size() || !self.committed_in_term()? {
+            return Ok(());
+        }
+
+        self.lease
+            .extend(self.role.round_start + self.config.lease_timeout);
+        let commit = bincode::serialize(&self.chain.get_commit().index())?;
+        for req in std::mem::take(&mut self.role.round_reads) {
+            let res = Response::new(commit.clone()).with_request_id(req.proposal.request_id());
+            self.send(
+                req.address,
+                Command::ClientResponse(ClientResponse {
+                    id: req.id,
+                    res: Ok(res),
+                }),
+            )?;
+        }
         Ok(())
     }
 
-    pub(crate) fn on_transition(self) -> Result<Raft<Leader>> {
-        // let term = self.state.current_term;
-        // let next_index = self.log.next_index();
-        // let entry = Entry {
-        //     entry_type: EntryType::Control,
-        //     term,
-        //     index: next_index,
-        // };
-        // self.state.last_applied = self.log.append(entry)?;
-        //
-        // let node_id = self.id;
-        // let index = self.state.last_applied;
-        // self.send(
-        //     Address::Local,
-        //     Command::AppendResponse {
-        //         node_id,
-        //         term,
-        //         index,
-        //         success: true,
-        //     },
-        // )?;
+    /// Whether an entry of the current term has been committed, such as the no-op appended on
+    /// election.
+    fn committed_in_term(&self) -> Result<bool> {
+        let commit = self.chain.get(&self.chain.get_commit())?;
+        Ok(commit.is_some_and(|b| b.term == self.state.current_term))
+    }
 
+    /// Appends a no-op entry in the new term. Entries of earlier terms are only committed once
+    /// an entry of the leader's own term is, so this commits them without waiting on a proposal.
+    /// Followers on a protocol without no-op entries leave them to the next proposal instead.
+    pub(crate) fn on_transition(mut self) -> Result<Raft<Leader>> {
+        if !self.config.supports(ENTRY_TYPES_PROTOCOL_VERSION) {
+            return Ok(self);
+        }
+        let term = self.state.current_term;
+        self.chain
+            .append(UnappendedBlock::with_type(term, EntryType::Noop))?;
+        let head = self.chain.flush()?;
+        self.role.progress.advance(self.id, head);
+        self.commit()?;
         Ok(self)
     }
 
+    /// Appends a new membership for the cluster, which takes effect once it is committed.
+    pub(crate) fn change_membership(&mut self, nodes: Vec<Node>) -> Result<BlockId> {
+        if !self.config.supports(ENTRY_TYPES_PROTOCOL_VERSION) {
+            return Err(anyhow::anyhow!("protocol version is too old to change the membership"));
+        }
+        let term = self.state.current_term;
+        let block_id = self
+            .chain
+            .append(UnappendedBlock::with_type(term, EntryType::Config { nodes }))?;
+        let head = self.chain.flush()?;
+        self.role.progress.advance(self.id, head);
+        self.commit()?;
+        Ok(block_id)
+    }
+
+    /// Tracks the progress of nodes that joined the cluster, counting learners that were promoted
+    /// towards commits, and forgets nodes that left.
+    fn sync_progress(&mut self) {
+        let nodes: HashSet<NodeId> = self.config.nodes.iter().map(|n| n.id).collect();
+        for node in &nodes {
+            match self.role.progress.get(*node) {
+                Some(progress) if progress.is_learner() => self.role.progress.promote(*node),
+                Some(_) => {}
+                None => self.role.progress.insert(*node),
+            }
+        }
+        let progress = &self.role.progress;
+        let removed: Vec<NodeId> = progress
+            .node_ids()
+            .filter(|id| *id != self.id && !nodes.contains(id) && !progress.is_learner(*id))
+            .collect();
+        for node in removed {
+            self.role.progress.remove(node);
+        }
+    }
+
+    /// The health of the cluster, with every node we track the progress of.
+    pub(crate) fn cluster_health(&self) -> ClusterHealth {
+        let mut nodes: Vec<NodeHealth> = self
+            .role
+            .progress
+            .node_ids()
+            .filter_map(|id| {
+                let progress = self.role.progress.get(id).filter(|p| !p.is_learner())?;
+                let (role, in_contact) = match id == self.id {
+                    true => (RaftRole::Leader, true),
+                    false => (
+                        RaftRole::Follower,
+                        progress
+                            .last_contact()
+                            .is_some_and(|t| t.elapsed() < self.config.election_timeout),
+                    ),
+                };
+                Some(NodeHealth {
+                    id,
+                    role,
+                    match_index: progress.head().index(),
+                    in_contact,
+                })
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.id);
+        ClusterHealth {
+            leader: self.id,
+            term: self.state.current_term,
+            nodes,
+        }
+    }
+
+    /// Whether as many entries as allowed are waiting to be committed, so that proposals have
+    /// to wait for followers to catch up.
+    fn backlog_full(&self) -> bool {
+        let uncommitted = self.chain.get_head().index() - self.chain.get_commit().index();
+        self.config
+            .max_uncommitted_entries
+            .is_some_and(|max| uncommitted >= max)
+    }
+
     fn needs_heartbeat(&self) -> bool {
         self.role.heartbeat_time.elapsed() > self.role.heartbeat_timeout
     }
@@ -86,13 +222,25 @@ impl Raft<Leader> {
     #[tracing::instrument]
     fn commit(&mut self) -> Result<BlockId> {
         let quorum_idx = self.role.progress.committed_index();
-        if quorum_idx > self.chain.get_commit() {
+        // only entries of the current term are committed by counting replicas
+        let quorum_term = self.chain.get(&quorum_idx)?.map(|b| b.term);
+        if quorum_idx > self.chain.get_commit() && quorum_term == Some(self.state.current_term) {
             tracing::trace!(?quorum_idx, "commit");
             let prev = self.chain.get_commit();
             let new = self.chain.commit(&quorum_idx)?;
-            self.chain.range(prev..=new).skip(1).for_each(|block| {
-                self.fsm_tx.send(Instruction::Apply { block }).unwrap();
-            });
+            self.record_commit();
+            // before the blocks are applied, so a read never misses a write that was answered
+            self.lease.commit(new.index());
+            let mut membership_changed = false;
+            for block in self.chain.range(prev..=new).skip(1) {
+                membership_changed |= self.on_commit(&block);
+                self.fsm_tx.send(Instruction::Apply { block })?;
+            }
+            if membership_changed {
+                self.sync_progress();
+            }
+            // the round may have been waiting on the first commit of the term
+            self.confirm_round()?;
         }
 
         Ok(quorum_idx)
@@ -122,72 +270,234 @@ impl Raft<Leader> {
 
     #[tracing::instrument]
     fn replicate(&mut self) -> Result<()> {
-        for node in &self.config.nodes {
-            if let Some(mut progress) = self.role.progress.get_mut(node.id) {
-                if !progress.is_active() {
-                    continue;
+        for node in self.config.nodes.iter().chain(&self.config.learners) {
+            let progress = match self.role.progress.get(node.id) {
+                Some(progress) if progress.is_active() => progress,
+                _ => continue,
+            };
+            let blocks = match progress {
+                NodeProgress::Probe(progress) => {
+                    tracing::info!(?progress, chain=?self.chain, "replicate probe");
+                    self.chain
+                        .range(progress.head.clone()..)
+                        .nth(1)
+                        .into_iter()
+                        .collect()
                 }
+                NodeProgress::Replicate(progress) => self
+                    .chain
+                    .range(progress.head.clone()..)
+                    .skip(1)
+                    .take(self.config.max_append_entries as usize)
+                    .collect(),
+                _ => continue,
+            };
+            self.rpc_tx.send(Message::new(
+                Address::Peer(self.id),
+                Address::Peer(node.id),
+                Command::AppendEntries {
+                    term: self.state.current_term,
+                    leader_id: self.id,
+                    blocks,
+                },
+            ))?;
+        }
 
-                match &mut progress {
-                    NodeProgress::Probe(progress) => {
-                        tracing::info!(?progress, chain=?self.chain, "replicate probe");
-                        let blocks =
-                            if let Some(block) = self.chain.range(progress.head.clone()..).nth(1) {
-                                vec![block]
-                            } else {
-                                vec![]
-                            };
-
-                        self.rpc_tx.send(Message::new(
-                            Address::Peer(self.id),
-                            Address::Peer(node.id),
-                            Command::AppendEntries {
-                                term: self.state.current_term,
-                                leader_id: self.id,
-                                blocks,
-                            },
-                        ))?;
-                    }
-                    NodeProgress::Replicate(progress) => {
-                        let blocks = self
-                            .chain
-                            .range(progress.head.clone()..)
-                            .skip(1)
-                            .take(MAX_INFLIGHT as usize)
-                            .collect();
-                        self.rpc_tx.send(Message::new(
-                            Address::Peer(self.id),
-                            Address::Peer(node.id),
-                            Command::AppendEntries {
-                                term: self.state.current_term,
-                                leader_id: self.id,
-                                blocks,
-                            },
-                        ))?;
-                    }
-                    _ => {}
-                }
-            }
+        Ok(())
+    }
+
+    /// Queues a node that discovered the cluster to be added to its membership, unless it is a
+    /// member already.
+    fn apply_discover(mut self, node: Node) -> Result<RaftHandle> {
+        let known = node.id == self.id
+            || self.config.nodes.iter().any(|n| n.id == node.id)
+            || self.role.joining.iter().any(|n| n.id == node.id);
+        if !known {
+            self.role.joining.push(node);
+            self.add_joining()?;
+        }
+        Ok(RaftHandle::Leader(self))
+    }
+
+    /// Adds the next joining node to the membership, or promotes it if it is a learner, once
+    /// every earlier membership change has been committed.
+    fn add_joining(&mut self) -> Result<()> {
+        if self.role.joining.is_empty() || !self.config.supports(ENTRY_TYPES_PROTOCOL_VERSION) {
+            return Ok(());
+        }
+        let commit = self.chain.get_commit();
+        let pending = self
+            .chain
+            .range(commit.clone()..)
+            .any(|b| b.id > commit && matches!(b.entry_type, EntryType::Config { .. }));
+        if pending {
+            return Ok(());
         }
 
+        let node = self.role.joining.remove(0);
+        if self.config.nodes.iter().any(|n| n.id == node.id) {
+            return Ok(());
+        }
+        if self.config.learners.iter().any(|n| n.id == node.id) {
+            self.promote_learner(node.id)?;
+            return Ok(());
+        }
+        tracing::info!(node_id = node.id, "adding discovered node");
+        let this = Node {
+            id: self.id,
+            addr: SocketAddr::new(self.config.ip, self.config.port),
+        };
+        let mut nodes = vec![this];
+        nodes.extend(self.config.nodes.iter().copied());
+        nodes.push(node);
+        self.change_membership(nodes)?;
+        Ok(())
+    }
+
+    /// Promotes a learner to a member of the cluster, which takes effect once the new
+    /// membership is committed.
+    pub(crate) fn promote_learner(&mut self, node_id: NodeId) -> Result<BlockId> {
+        let learner = self
+            .config
+            .learners
+            .iter()
+            .find(|n| n.id == node_id)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a learner", node_id))?;
+        tracing::info!(node_id, "promoting learner");
+        // it's only proposed again if the promotion isn't committed within another stable period
+        self.role.progress.caught_up(node_id, false, Instant::now());
+
+        let this = Node {
+            id: self.id,
+            addr: SocketAddr::new(self.config.ip, self.config.port),
+        };
+        let mut nodes = vec![this];
+        nodes.extend(self.config.nodes.iter().copied());
+        nodes.push(learner);
+        self.change_membership(nodes)
+    }
+
+    /// Promotes the learners that have been within `learner_catch_up_entries` of our last entry
+    /// for `learner_stable_period` as of `now`, if auto-promotion is on. A learner that falls
+    /// further behind starts over.
+    fn promote_caught_up_learners(&mut self, now: Instant) -> Result<()> {
+        if !self.config.supports(ENTRY_TYPES_PROTOCOL_VERSION) {
+            return Ok(());
+        }
+        let last = self.chain.get_head().index();
+        let mut promoted = vec![];
+        for learner in &self.config.learners {
+            let caught_up = self.role.progress.get(learner.id).is_some_and(|p| {
+                p.head().index() + self.config.learner_catch_up_entries >= last
+            });
+            let since = self.role.progress.caught_up(learner.id, caught_up, now);
+            if self.config.auto_promote_learners
+                && since.is_some_and(|since| {
+                    now.saturating_duration_since(since) >= self.config.learner_stable_period
+                })
+            {
+                promoted.push(learner.id);
+            }
+        }
+        for node_id in promoted {
+            self.promote_learner(node_id)?;
+        }
         Ok(())
     }
 
     #[tracing::instrument]
     fn apply_client_request(mut self, req: ClientRequest) -> Result<RaftHandle> {
+        let kind = req.proposal.kind();
+        if matches!(kind, ProposalKind::Read | ProposalKind::Describe | ProposalKind::Noop)
+            && !self.config.supports(ENTRY_TYPES_PROTOCOL_VERSION)
+        {
+            // nodes on the older protocol only ever forward writes
+            let e = ResponseError::new(format!("protocol version is too old for {:?}", kind));
+            self.send(
+                req.address,
+                Command::ClientResponse(ClientResponse {
+                    id: req.id,
+                    res: Err(e),
+                }),
+            )?;
+            return Ok(RaftHandle::Leader(self));
+        }
+
+        match kind {
+            ProposalKind::Read => {
+                self.role.queued_reads.push(req);
+                return Ok(RaftHandle::Leader(self));
+            }
+            ProposalKind::Describe => {
+                let health = bincode::serialize(&self.cluster_health())?;
+                let res = Response::new(health).with_request_id(req.proposal.request_id());
+                self.send(
+                    req.address,
+                    Command::ClientResponse(ClientResponse {
+                        id: req.id,
+                        res: Ok(res),
+                    }),
+                )?;
+                return Ok(RaftHandle::Leader(self));
+            }
+            ProposalKind::Elect => {
+                // elections are forced by the event loop of the node they are proposed on, so
+                // one that reaches us was forwarded by mistake
+                let e = ResponseError::new("elections can only be forced on the local node");
+                self.send(
+                    req.address,
+                    Command::ClientResponse(ClientResponse {
+                        id: req.id,
+                        res: Err(e),
+                    }),
+                )?;
+                return Ok(RaftHandle::Leader(self));
+            }
+            ProposalKind::Write | ProposalKind::Noop => {}
+        }
+
+        let request_id = req.proposal.request_id();
+        if let Some(block_id) = self.role.proposals.get(&request_id) {
+            self.fsm_tx.send(Instruction::Notify {
+                id: req.id,
+                request_id,
+                block_id: block_id.clone(),
+                client_address: req.address,
+            })?;
+            return Ok(RaftHandle::Leader(self));
+        }
+
+        if self.backlog_full() {
+            self.send(
+                req.address,
+                Command::ClientResponse(ClientResponse {
+                    id: req.id,
+                    res: Err(ResponseError::backlog_full()),
+                }),
+            )?;
+            return Ok(RaftHandle::Leader(self));
+        }
+
         let term = self.state.current_term;
-        let block = UnappendedBlock::new(req.proposal.get());
+        let block = match req.proposal.kind() {
+            ProposalKind::Noop => UnappendedBlock::with_type(term, EntryType::Noop),
+            _ => UnappendedBlock::new(term, req.proposal.get()),
+        };
         let block_id = self.chain.append(block)?;
+        self.role.proposals.insert(request_id, block_id.clone());
 
         let node_id = self.id;
 
         self.fsm_tx.send(Instruction::Notify {
             id: req.id,
+            request_id,
             block_id,
             client_address: req.address,
         })?;
 
-        let head = self.chain.get_head();
+        // our own copy only counts towards the commit once it is on disk
+        let head = self.chain.flush()?;
         self.apply(Command::AppendResponse {
             node_id,
             term,
@@ -207,12 +517,26 @@ impl Raft<Leader> {
         Ok(RaftHandle::Leader(self))
     }
 
+    /// Records how far a follower's chain has come. A follower in a later term has replaced us,
+    /// and the head of a follower that refused our blocks may not be in our chain at all, so it
+    /// can't count towards committing anything.
     #[tracing::instrument]
     fn apply_append_response(
         mut self,
         node_id: NodeId,
+        term: Term,
         head: BlockId,
+        success: bool,
     ) -> Result<RaftHandle, Error> {
+        if term > self.state.current_term {
+            self.term(term);
+            return Ok(RaftHandle::Follower(Raft::from(self)));
+        }
+        self.role.progress.contacted(node_id);
+        if !success {
+            tracing::debug!(node_id, ?head, "append refused");
+            return Ok(RaftHandle::Leader(self));
+        }
         self.role.progress.advance(node_id, head);
         self.commit()?;
         Ok(RaftHandle::Leader(self))
@@ -221,9 +545,18 @@ impl Raft<Leader> {
     #[tracing::instrument]
     fn apply_heartbeat_response(
         mut self,
+        node_id: NodeId,
         commit: BlockId,
         has_committed: bool,
+        round: u64,
     ) -> Result<RaftHandle, Error> {
+        self.role.progress.contacted(node_id);
+        // learners don't count towards the quorum confirming the round, and neither do answers
+        // to earlier rounds, which the node may have sent before this round started
+        let learner = self.role.progress.is_learner(node_id);
+        if !learner && round == self.role.round && self.role.round_acks.insert(node_id) {
+            self.confirm_round()?;
+        }
         if !has_committed && commit > BlockId::new(0) {
             self.replicate()?;
         }
@@ -240,6 +573,8 @@ impl Raft<Leader> {
         }
 
         self.replicate()?;
+        self.promote_caught_up_learners(Instant::now())?;
+        self.add_joining()?;
 
         Ok(RaftHandle::Leader(self))
     }
@@ -252,22 +587,42 @@ impl Apply for Raft<Leader> {
         match cmd {
             Command::Tick => self.apply_tick(),
             Command::HeartbeatResponse {
+                node_id,
                 commit,
                 has_committed,
-            } => self.apply_heartbeat_response(commit, has_committed),
-            Command::AppendResponse { node_id, head, .. } => {
-                self.apply_append_response(node_id, head)
-            }
+                round,
+            } => self.apply_heartbeat_response(node_id, commit, has_committed, round),
+            Command::AppendResponse {
+                node_id,
+                term,
+                head,
+                success,
+            } => self.apply_append_response(node_id, term, head, success),
             Command::AppendEntries { term, .. } => self.apply_append_entries(term),
             Command::ClientRequest(req) => self.apply_client_request(req),
+            Command::Discover(node) => self.apply_discover(node),
             _ => Ok(RaftHandle::Leader(self)),
         }
     }
 }
 
 impl From<Raft<Leader>> for Raft<Follower> {
-    fn from(val: Raft<Leader>) -> Raft<Follower> {
-        Raft {
+    fn from(mut val: Raft<Leader>) -> Raft<Follower> {
+        val.lease.revoke();
+        let reads = std::mem::take(&mut val.role.round_reads)
+            .into_iter()
+            .chain(std::mem::take(&mut val.role.queued_reads));
+        for req in reads {
+            let _ = val.send(
+                req.address,
+                Command::ClientResponse(ClientResponse {
+                    id: req.id,
+                    res: Err(ResponseError::new("no longer leader")),
+                }),
+            );
+        }
+
+        let raft = Raft {
             id: val.id,
             state: val.state,
             role: Follower {
@@ -279,7 +634,11 @@ impl From<Raft<Leader>> for Raft<Follower> {
             chain: val.chain,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
-        }
+            lease: val.lease,
+            span: val.span,
+        };
+        raft.record_role();
+        raft
     }
 }
 
@@ -287,11 +646,20 @@ impl From<Raft<Leader>> for Raft<Follower> {
 mod tests {
     use crate::raft::rpc::Address;
     use crate::raft::test::new_follower;
-    use crate::raft::ClientRequest;
+    use crate::raft::chain::BlockId;
+    use crate::raft::chain::UnappendedBlock;
+    use crate::raft::config::{RaftConfig, ENTRY_TYPES_PROTOCOL_VERSION};
+    use crate::raft::follower::Follower;
+    use crate::raft::leader::Leader;
+    use crate::raft::health::ClusterHealth;
+    use crate::raft::{ClientRequest, EntryType, Node, NodeId, Raft, RaftRole};
     use crate::{
         raft::{fsm::Instruction, rpc::Proposal},
         raft::{Apply, Command, RaftHandle},
     };
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc::unbounded_channel;
     use uuid::Uuid;
 
     #[test]
@@ -311,14 +679,18 @@ mod tests {
             }))
             .unwrap();
         let node = node.apply(Command::Tick).unwrap();
+        let data = EntryType::Data {
+            data: vec![magic_number],
+        };
         if let RaftHandle::Leader(leader) = node {
-            let block = leader.chain.range(..).take(2).last().unwrap();
-            assert_eq!(block.data, vec![magic_number]);
-            let _ = fsm_rx.blocking_recv().unwrap();
+            // the block after the leader's no-op
+            let block = leader.chain.range(..).nth(2).unwrap();
+            assert_eq!(block.entry_type, data);
+            let _noop = fsm_rx.blocking_recv().unwrap();
+            let _notify = fsm_rx.blocking_recv().unwrap();
             let instruction = fsm_rx.blocking_recv().unwrap();
-            // todo 2 vs 3
             if let Instruction::Apply { block } = instruction {
-                assert_eq!(block.data, vec![magic_number]);
+                assert_eq!(block.entry_type, data);
             } else {
                 panic!()
             }
@@ -326,4 +698,615 @@ mod tests {
             panic!()
         }
     }
+
+    fn cluster_config(size: NodeId) -> RaftConfig {
+        let nodes = (2..=size)
+            .map(|id| Node {
+                id,
+                addr: SocketAddr::from(([127, 0, 0, 1], 6000 + id as u16)),
+            })
+            .collect();
+        RaftConfig {
+            nodes,
+            ..Default::default()
+        }
+    }
+
+    fn leader(node: &RaftHandle) -> &Raft<Leader> {
+        match node {
+            RaftHandle::Leader(leader) => leader,
+            _ => panic!("not leader"),
+        }
+    }
+
+    #[test]
+    fn noop_commits_earlier_terms() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, mut fsm_rx) = unbounded_channel();
+        let mut follower: Raft<Follower> = Raft::new(cluster_config(3), rpc_tx, fsm_tx)?;
+        // left uncommitted by the leader of term 1
+        follower.term(1);
+        let earlier = follower.chain.append(UnappendedBlock::new(1, vec![1]))?;
+
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let term = match &node {
+            RaftHandle::Candidate(candidate) => candidate.state.current_term,
+            _ => panic!(),
+        };
+        let node = node.apply(Command::VoteResponse {
+            term,
+            from: 2,
+            granted: true,
+        })?;
+        let noop = leader(&node).chain.get_head();
+        let head = leader(&node).chain.get(&noop)?.unwrap();
+        assert_eq!(head.entry_type, EntryType::Noop);
+
+        // a majority holding the earlier entry isn't enough to commit it
+        let node = node.apply(Command::AppendResponse {
+            node_id: 2,
+            term,
+            success: true,
+            head: earlier.clone(),
+        })?;
+        assert_eq!(leader(&node).chain.get_commit(), BlockId::new(0));
+
+        let node = node.apply(Command::AppendResponse {
+            node_id: 2,
+            term,
+            success: true,
+            head: noop.clone(),
+        })?;
+        assert_eq!(leader(&node).chain.get_commit(), noop);
+        let applied: Vec<BlockId> = std::iter::from_fn(|| fsm_rx.try_recv().ok())
+            .filter_map(|i| match i {
+                Instruction::Apply { block } => Some(block.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(applied, vec![earlier, noop]);
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_refused_appends() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(cluster_config(3), rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let node = node.apply(Command::VoteResponse {
+            term: 1,
+            from: 2,
+            granted: true,
+        })?;
+        let noop = leader(&node).chain.get_head();
+
+        // a follower refusing our blocks reports a head that isn't ours to count
+        let mut raft = node.get_leader().unwrap();
+        raft.role.progress.advance(raft.id, noop.clone());
+        let node = raft.apply(Command::AppendResponse {
+            node_id: 2,
+            term: 1,
+            success: false,
+            head: noop.clone(),
+        })?;
+        assert_eq!(leader(&node).chain.get_commit(), BlockId::new(0));
+        assert_eq!(leader(&node).role.progress.get(2).unwrap().head(), BlockId::new(0));
+
+        let node = node.apply(Command::AppendResponse {
+            node_id: 2,
+            term: 1,
+            success: true,
+            head: noop.clone(),
+        })?;
+        assert_eq!(leader(&node).chain.get_commit(), noop);
+        Ok(())
+    }
+
+    #[test]
+    fn steps_down_on_later_term_in_append_response() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(cluster_config(3), rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let node = node.apply(Command::VoteResponse {
+            term: 1,
+            from: 2,
+            granted: true,
+        })?;
+
+        // applied to the leader itself rather than through the handle
+        let raft = node.get_leader().unwrap();
+        let node = raft.apply(Command::AppendResponse {
+            node_id: 2,
+            term: 2,
+            success: false,
+            head: BlockId::new(0),
+        })?;
+        assert_eq!(node.status().role, RaftRole::Follower);
+        assert_eq!(node.status().term, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn describes_cluster() -> anyhow::Result<()> {
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            id: 1,
+            ..cluster_config(3)
+        };
+        let follower: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let mut node = node.apply(Command::VoteResponse {
+            term: 1,
+            from: 2,
+            granted: true,
+        })?;
+        let mut blocks = vec![];
+        if let RaftHandle::Leader(leader) = &mut node {
+            for i in 0..3 {
+                blocks.push(leader.chain.append(UnappendedBlock::new(1, vec![i]))?);
+            }
+            let head = leader.chain.get_head();
+            leader.role.progress.advance(leader.id, head);
+        }
+        // node 2 has every block, node 3 has only the first and hasn't been heard from
+        let mut node = node.apply(Command::AppendResponse {
+            node_id: 2,
+            term: 1,
+            success: true,
+            head: blocks[2].clone(),
+        })?;
+        if let RaftHandle::Leader(leader) = &mut node {
+            leader.role.progress.advance(3, blocks[0].clone());
+        }
+
+        let id = Uuid::new_v4();
+        let _node = node.apply(Command::ClientRequest(ClientRequest {
+            id,
+            address: Address::Client,
+            proposal: Proposal::describe(),
+        }))?;
+        let res = std::iter::from_fn(|| rpc_rx.try_recv().ok())
+            .find_map(|msg| match msg.command {
+                Command::ClientResponse(res) if res.id == id => Some(res),
+                _ => None,
+            })
+            .unwrap();
+        let health: ClusterHealth = bincode::deserialize(&res.res?.get())?;
+
+        assert_eq!(health.leader, 1);
+        let ids: Vec<NodeId> = health.nodes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        let leader = health.node(1).unwrap();
+        assert_eq!(leader.role, RaftRole::Leader);
+        assert_eq!(leader.match_index, blocks[2].index());
+        let caught_up = health.node(2).unwrap();
+        assert_eq!(caught_up.match_index, blocks[2].index());
+        assert!(caught_up.in_contact);
+        let behind = health.node(3).unwrap();
+        assert_eq!(behind.role, RaftRole::Follower);
+        assert_eq!(behind.match_index, blocks[0].index());
+        assert!(!behind.in_contact);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_forwarded_election() -> anyhow::Result<()> {
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(cluster_config(1), rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        assert_eq!(node.status().role, RaftRole::Leader);
+
+        let id = Uuid::new_v4();
+        let node = node.apply(Command::ClientRequest(ClientRequest {
+            id,
+            address: Address::Client,
+            proposal: Proposal::elect(),
+        }))?;
+        let res = std::iter::from_fn(|| rpc_rx.try_recv().ok())
+            .find_map(|msg| match msg.command {
+                Command::ClientResponse(res) if res.id == id => Some(res),
+                _ => None,
+            })
+            .unwrap();
+        assert!(res.res.is_err());
+        assert_eq!(node.status().role, RaftRole::Leader);
+        Ok(())
+    }
+
+    #[test]
+    fn old_protocol_appends_only_data() -> anyhow::Result<()> {
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            protocol_version: ENTRY_TYPES_PROTOCOL_VERSION - 1,
+            ..cluster_config(1)
+        };
+        let follower: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        // no no-op is appended on election
+        assert_eq!(leader(&node).chain.get_head(), BlockId::new(0));
+
+        let id = Uuid::new_v4();
+        let node = node.apply(Command::ClientRequest(ClientRequest {
+            id,
+            address: Address::Client,
+            proposal: Proposal::noop(),
+        }))?;
+        let res = std::iter::from_fn(|| rpc_rx.try_recv().ok())
+            .find_map(|msg| match msg.command {
+                Command::ClientResponse(res) if res.id == id => Some(res),
+                _ => None,
+            })
+            .unwrap();
+        assert!(res.res.is_err());
+        assert_eq!(leader(&node).chain.get_head(), BlockId::new(0));
+
+        let mut leader = node.get_leader().unwrap();
+        let nodes = cluster_config(2).nodes;
+        assert!(leader.change_membership(nodes).is_err());
+        assert_eq!(leader.chain.get_head(), BlockId::new(0));
+        Ok(())
+    }
+
+    #[test]
+    fn catches_up_in_capped_rounds() -> anyhow::Result<()> {
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            max_append_entries: 100,
+            ..cluster_config(2)
+        };
+        let follower: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let mut node = node.apply(Command::VoteResponse {
+            term: 1,
+            from: 2,
+            granted: true,
+        })?;
+        // the follower is a thousand entries behind, counting the leader's no-op
+        if let RaftHandle::Leader(leader) = &mut node {
+            for i in 0..999 {
+                leader.chain.append(UnappendedBlock::new(1, vec![i as u8]))?;
+            }
+            let head = leader.chain.get_head();
+            leader.role.progress.advance(leader.id, head);
+        }
+        let head = leader(&node).chain.get_head();
+
+        let mut rounds = vec![];
+        while leader(&node).chain.get_commit() < head {
+            node = node.apply(Command::Tick)?;
+            let blocks = std::iter::from_fn(|| rpc_rx.try_recv().ok())
+                .find_map(|msg| match msg.command {
+                    Command::AppendEntries { blocks, .. } => Some(blocks),
+                    _ => None,
+                })
+                .unwrap();
+            rounds.push(blocks.len());
+            node = node.apply(Command::AppendResponse {
+                node_id: 2,
+                term: 1,
+                success: true,
+                head: blocks.last().unwrap().id.clone(),
+            })?;
+        }
+        // a single block while probing, then full batches
+        assert_eq!(rounds.len(), 11);
+        assert_eq!(rounds.iter().sum::<usize>(), 1000);
+        assert!(rounds.iter().all(|len| *len <= 100));
+        Ok(())
+    }
+
+    #[test]
+    fn config_entry_changes_membership() -> anyhow::Result<()> {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower();
+        let mut leader = match node.apply(Command::Timeout)? {
+            RaftHandle::Leader(leader) => leader,
+            _ => panic!(),
+        };
+        let nodes = cluster_config(2).nodes;
+        let this = Node {
+            id: leader.id,
+            addr: SocketAddr::from(([127, 0, 0, 1], 6001)),
+        };
+
+        // a lone leader commits the new membership straight away
+        let config = leader.change_membership(vec![this, nodes[0]])?;
+        assert_eq!(leader.chain.get_commit(), config);
+        assert_eq!(leader.config.nodes, nodes);
+        assert!(leader.role.progress.get(2).is_some());
+
+        // from now on, entries need node 2 to be committed
+        let block = leader.change_membership(vec![this])?;
+        assert_eq!(leader.chain.get_commit(), config);
+        let node = RaftHandle::Leader(leader).apply(Command::AppendResponse {
+            node_id: 2,
+            term: 1,
+            success: true,
+            head: block.clone(),
+        })?;
+        let leader = node.get_leader().unwrap();
+        assert_eq!(leader.chain.get_commit(), block);
+        assert!(leader.config.nodes.is_empty());
+        assert!(leader.role.progress.get(2).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn adds_discovered_nodes_one_at_a_time() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(cluster_config(1), rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let nodes = cluster_config(4).nodes;
+
+        // a lone leader commits the change straight away, and ignores the node asking again
+        let node = node.apply(Command::Discover(nodes[0]))?;
+        let node = node.apply(Command::Discover(nodes[0]))?;
+        assert_eq!(leader(&node).config.nodes, vec![nodes[0]]);
+
+        // node 4 waits for node 3 to be added, which needs node 2 to commit
+        let node = node.apply(Command::Discover(nodes[1]))?;
+        let adding = leader(&node).chain.get_head();
+        let node = node.apply(Command::Discover(nodes[2]))?;
+        assert_eq!(leader(&node).chain.get_head(), adding);
+        assert_eq!(leader(&node).role.joining, vec![nodes[2]]);
+
+        let node = node.apply(Command::AppendResponse {
+            node_id: 2,
+            term: 1,
+            success: true,
+            head: adding.clone(),
+        })?;
+        assert_eq!(leader(&node).config.nodes, nodes[..2].to_vec());
+        let node = node.apply(Command::Tick)?;
+        let head = leader(&node).chain.get_head();
+        assert!(head > adding);
+        let block = leader(&node).chain.get(&head)?.unwrap();
+        assert!(matches!(block.entry_type, EntryType::Config { nodes: n } if n.len() == 4));
+        assert!(leader(&node).role.joining.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn auto_promotes_caught_up_learners() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            learners: cluster_config(3).nodes,
+            auto_promote_learners: true,
+            learner_catch_up_entries: 2,
+            learner_stable_period: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let follower: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx)?;
+        let mut leader = match RaftHandle::Follower(follower).apply(Command::Timeout)? {
+            RaftHandle::Leader(leader) => leader,
+            _ => panic!(),
+        };
+        let append = |leader: &mut Raft<Leader>, count: u8| -> anyhow::Result<BlockId> {
+            for i in 0..count {
+                leader.chain.append(UnappendedBlock::new(1, vec![i]))?;
+            }
+            let head = leader.chain.flush()?;
+            leader.role.progress.advance(leader.id, head.clone());
+            Ok(head)
+        };
+
+        // both learners start out caught up
+        let start = Instant::now();
+        let head = append(&mut leader, 3)?;
+        leader.role.progress.advance(2, head.clone());
+        leader.role.progress.advance(3, head);
+        leader.promote_caught_up_learners(start)?;
+
+        // 3 falls behind for a while, while 2 keeps up
+        let head = append(&mut leader, 5)?;
+        leader.role.progress.advance(2, head.clone());
+        leader.promote_caught_up_learners(start + Duration::from_secs(5))?;
+        leader.role.progress.advance(3, head);
+        leader.promote_caught_up_learners(start + Duration::from_secs(6))?;
+
+        // only 2 has been caught up for the whole stable period
+        leader.promote_caught_up_learners(start + Duration::from_secs(10))?;
+        let members: Vec<NodeId> = leader.config.nodes.iter().map(|n| n.id).collect();
+        assert_eq!(members, vec![2]);
+        let learners: Vec<NodeId> = leader.config.learners.iter().map(|n| n.id).collect();
+        assert_eq!(learners, vec![3]);
+        assert!(!leader.role.progress.is_learner(2));
+        assert!(leader.role.progress.is_learner(3));
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_proposals_past_backlog() -> anyhow::Result<()> {
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            max_uncommitted_entries: Some(3),
+            ..cluster_config(3)
+        };
+        let follower: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx)?;
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let mut node = node.apply(Command::VoteResponse {
+            term: 1,
+            from: 2,
+            granted: true,
+        })?;
+        let mut propose = |node: RaftHandle| -> anyhow::Result<(RaftHandle, bool)> {
+            let id = Uuid::new_v4();
+            let node = node.apply(Command::ClientRequest(ClientRequest {
+                id,
+                address: Address::Client,
+                proposal: Proposal::new(vec![1]),
+            }))?;
+            let refused = std::iter::from_fn(|| rpc_rx.try_recv().ok()).any(|msg| {
+                matches!(msg.command, Command::ClientResponse(res)
+                    if res.id == id && res.res.as_ref().is_err_and(|e| e.is_backlog_full()))
+            });
+            Ok((node, !refused))
+        };
+
+        // with the followers paused, the no-op and two proposals fill the backlog
+        for accepted in [true, true, false, false] {
+            let res = propose(node)?;
+            node = res.0;
+            assert_eq!(res.1, accepted);
+        }
+        let head = leader(&node).chain.get_head();
+        assert_eq!(head.index() - leader(&node).chain.get_commit().index(), 3);
+
+        // once a follower catches up and the backlog commits, proposals are taken again
+        node = node.apply(Command::AppendResponse {
+            node_id: 2,
+            term: 1,
+            success: true,
+            head: head.clone(),
+        })?;
+        assert_eq!(leader(&node).chain.get_commit(), head);
+        let (node, accepted) = propose(node)?;
+        assert!(accepted);
+        assert!(leader(&node).chain.get_head() > head);
+        Ok(())
+    }
+
+    #[test]
+    fn step_down_on_higher_term() -> anyhow::Result<()> {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower();
+        let node = node.apply(Command::Timeout)?;
+        let term = match &node {
+            RaftHandle::Leader(leader) => leader.state.current_term,
+            _ => panic!(),
+        };
+
+        let node = node.apply(Command::Heartbeat {
+            term: term + 1,
+            commit: BlockId::new(0),
+            leader_id: 2,
+            round: 1,
+        })?;
+        let follower = node.get_follower().unwrap();
+        assert_eq!(follower.state.current_term, term + 1);
+        assert_eq!(follower.role.leader_id, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn lease() -> anyhow::Result<()> {
+        let ((mut rpc_rx, _fsm_rx), node) = new_follower();
+        let lease = node.lease.clone();
+        assert!(!lease.is_valid());
+
+        // a single node is its own quorum, so it holds the lease as soon as it's elected
+        let node = node.apply(Command::Timeout)?;
+        assert!(node.is_leader());
+        assert!(lease.is_valid());
+
+        // reads are answered after the next heartbeat round
+        let id = Uuid::new_v4();
+        let node = node.apply(Command::ClientRequest(ClientRequest {
+            id,
+            address: Address::Client,
+            proposal: Proposal::read(),
+        }))?;
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        let node = node.apply(Command::Tick)?;
+        let res = std::iter::from_fn(|| rpc_rx.try_recv().ok())
+            .find_map(|msg| match msg.command {
+                Command::ClientResponse(res) => Some(res),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(res.id, id);
+        assert!(res.res.is_ok());
+
+        // and the lease is given up along with leadership
+        let node = node.apply(Command::Heartbeat {
+            term: 100,
+            commit: BlockId::new(0),
+            leader_id: 2,
+            round: 1,
+        })?;
+        assert!(node.is_follower());
+        assert!(!lease.is_valid());
+        Ok(())
+    }
+
+    /// Has node 2 elect the follower, which is left yet to commit its no-op.
+    fn elect(follower: Raft<Follower>) -> anyhow::Result<RaftHandle> {
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        let term = node.status().term;
+        node.apply(Command::VoteResponse {
+            term,
+            from: 2,
+            granted: true,
+        })
+    }
+
+    fn heartbeat_response(node: &RaftHandle, round: u64) -> Command {
+        Command::HeartbeatResponse {
+            node_id: 2,
+            commit: leader(node).chain.get_commit(),
+            has_committed: true,
+            round,
+        }
+    }
+
+    fn append_noop(node: RaftHandle) -> anyhow::Result<RaftHandle> {
+        let cmd = Command::AppendResponse {
+            node_id: 2,
+            term: node.status().term,
+            success: true,
+            head: leader(&node).chain.get_head(),
+        };
+        node.apply(cmd)
+    }
+
+    #[test]
+    fn lease_waits_for_commit_in_term() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(cluster_config(3), rpc_tx, fsm_tx)?;
+        let lease = follower.lease.clone();
+        let node = elect(follower)?;
+        let round = leader(&node).role.round;
+
+        // a quorum confirms we lead, but we may not know of all that the last leader committed
+        let ack = heartbeat_response(&node, round);
+        let node = node.apply(ack)?;
+        assert!(!lease.is_valid());
+
+        let node = append_noop(node)?;
+        assert_eq!(leader(&node).chain.get_commit(), leader(&node).chain.get_head());
+        assert_eq!(lease.read_index(), Some(leader(&node).chain.get_commit().index()));
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_acks_to_earlier_rounds() -> anyhow::Result<()> {
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(cluster_config(3), rpc_tx, fsm_tx)?;
+        let lease = follower.lease.clone();
+        let node = append_noop(elect(follower)?)?;
+        let mut leader = node.get_leader().unwrap();
+        let round = leader.role.round;
+        leader.heartbeat()?;
+        let node = RaftHandle::Leader(leader);
+
+        // an answer to the round before doesn't say we were leader when this one was sent
+        let ack = heartbeat_response(&node, round);
+        let node = node.apply(ack)?;
+        assert!(!lease.is_valid());
+
+        let ack = heartbeat_response(&node, round + 1);
+        let node = node.apply(ack)?;
+        assert!(node.is_leader());
+        assert!(lease.is_valid());
+        Ok(())
+    }
 }
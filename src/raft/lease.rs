@@ -0,0 +1,43 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A time bounded promise that no other node can have become leader, which lets the leader
+/// serve reads locally until it expires. Shared between the leader and its clients.
+#[derive(Debug, Clone, Default)]
+pub struct Lease(Arc<Mutex<Held>>);
+
+#[derive(Debug, Default)]
+struct Held {
+    expiry: Option<Instant>,
+    /// The leader's commit index, which reads served under the lease have to see applied.
+    commit: u64,
+}
+
+impl Lease {
+    /// Extends the lease until `until`, unless it is already held for longer.
+    pub fn extend(&self, until: Instant) {
+        let mut held = self.0.lock().unwrap();
+        held.expiry = held.expiry.max(Some(until));
+    }
+
+    pub fn revoke(&self) {
+        self.0.lock().unwrap().expiry = None;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.read_index().is_some()
+    }
+
+    /// Records that the leader committed up to `index`.
+    pub fn commit(&self, index: u64) {
+        self.0.lock().unwrap().commit = index;
+    }
+
+    /// The commit index a read has to wait to be applied, if the lease is held.
+    pub fn read_index(&self) -> Option<u64> {
+        let held = self.0.lock().unwrap();
+        held.expiry
+            .is_some_and(|expiry| Instant::now() < expiry)
+            .then_some(held.commit)
+    }
+}
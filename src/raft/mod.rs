@@ -41,19 +41,23 @@ use std::net::SocketAddr;
 use std::time::Duration;
 use std::time::Instant;
 
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::oneshot;
+use tokio::sync::watch;
 
 use rpc::Response;
 
 use crate::raft::chain::{Block, BlockId, Chain};
 use crate::raft::config::RaftConfig;
+use crate::raft::discovery::Membership;
 use crate::raft::follower::Follower;
 use crate::raft::fsm::Instruction;
 use crate::raft::leader::Leader;
+use crate::raft::lease::Lease;
+use crate::raft::observer::Observer;
 use crate::raft::rpc::{Address, Message, ResponseError};
 use crate::raft::server::{Server, ServerRunOpts};
+use crate::raft::client::ProposalRequest;
 use crate::raft::{candidate::Candidate, rpc::Proposal};
 use crate::Shutdown;
 use anyhow::Result;
@@ -64,14 +68,20 @@ mod candidate;
 mod chain;
 pub mod client;
 pub mod config;
+pub mod discovery;
 mod election;
 mod follower;
 pub mod fsm;
+pub mod health;
 mod leader;
+pub mod lease;
+mod observer;
 mod progress;
+mod recent;
 pub mod rpc;
 mod server;
 mod tcp;
+#[cfg(test)]
 mod test;
 
 #[derive(Debug)]
@@ -86,14 +96,28 @@ impl JosefineRaft {
         }
     }
 
+    /// The read lease held while this node is leader, to be shared with clients.
+    pub fn lease(&self) -> Lease {
+        self.server.lease()
+    }
+
+    /// Follows changes of term, role and leader, to be shared with clients and anything else
+    /// that has to react to them.
+    pub fn status(&self) -> watch::Receiver<Status> {
+        self.server.status()
+    }
+
+    /// Follows the index of the last block applied to the state machine, so that clients can
+    /// wait for their reads to reflect what was committed.
+    pub fn applied(&self) -> watch::Receiver<u64> {
+        self.server.applied()
+    }
+
     #[tracing::instrument]
     pub async fn run<T: 'static + fsm::Fsm>(
         self,
         fsm: T,
-        client_rx: UnboundedReceiver<(
-            Proposal,
-            oneshot::Sender<std::result::Result<Response, ResponseError>>,
-        )>,
+        client_rx: Receiver<ProposalRequest>,
         shutdown: Shutdown,
     ) -> Result<RaftHandle> {
         self.server
@@ -110,10 +134,7 @@ impl JosefineRaft {
         self,
         duration: Duration,
         fsm: T,
-        client_rx: UnboundedReceiver<(
-            Proposal,
-            oneshot::Sender<std::result::Result<Response, ResponseError>>,
-        )>,
+        client_rx: Receiver<ProposalRequest>,
         shutdown: Shutdown,
     ) -> Result<RaftHandle> {
         let s = shutdown.clone();
@@ -142,6 +163,11 @@ pub type LogIndex = u64;
 
 pub type ClientRequestId = Uuid;
 
+/// The number of nodes out of `nodes` that make up a majority.
+pub fn quorum(nodes: usize) -> usize {
+    nodes / 2 + 1
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ClientRequest {
     id: ClientRequestId,
@@ -208,12 +234,18 @@ pub enum Command {
         commit: BlockId,
         /// The id of the node sending a heartbeat.
         leader_id: NodeId,
+        /// The leader's heartbeat round, which the response echoes.
+        round: u64,
     },
     HeartbeatResponse {
+        /// The id of the responding node.
+        node_id: NodeId,
         /// The leader's commit index
         commit: BlockId,
         /// Whether this node needs replication of committed entries
         has_committed: bool,
+        /// The heartbeat round this answers.
+        round: u64,
     },
     /// Timeout on an event (i.e. election).
     Timeout,
@@ -224,6 +256,26 @@ pub enum Command {
     // Respond to a client.
     // this is a bit weird, since this isn't ever applied to a raft node, but received and proxied by the server event loop
     ClientResponse(ClientResponse),
+    /// Asks a node for the membership of the cluster on behalf of a node that is starting up.
+    /// Answered directly on the connection it arrived on, since the asking node isn't a peer
+    /// yet, then passed on to the leader, which adds the node to the membership.
+    Discover(Node),
+    /// The answer to a `Discover`.
+    Discovered(Membership),
+}
+
+impl Command {
+    /// The term of the sending node, for commands exchanged between peers.
+    pub fn term(&self) -> Option<Term> {
+        match self {
+            Command::VoteRequest { term, .. }
+            | Command::VoteResponse { term, .. }
+            | Command::AppendEntries { term, .. }
+            | Command::AppendResponse { term, .. }
+            | Command::Heartbeat { term, .. } => Some(*term),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Command {
@@ -241,8 +293,12 @@ pub trait Role: Debug {
 
 #[derive(Serialize, PartialEq, Deserialize, Debug, Clone)]
 pub enum EntryType {
-    Entry { data: Vec<u8> },
-    Control,
+    /// A transition of the state machine.
+    Data { data: Vec<u8> },
+    /// The membership of the cluster, listing every node including the leader.
+    Config { nodes: Vec<Node> },
+    /// Appended by a new leader, so that committing it commits the entries of earlier terms.
+    Noop,
 }
 
 /// An entry in the commit log.
@@ -257,7 +313,7 @@ pub struct Entry {
 }
 
 /// Contains information about nodes in raft cluster.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Node {
     /// The id of the node.
     pub id: NodeId,
@@ -284,6 +340,9 @@ pub struct State {
     pub min_election_timeout: usize,
     /// The min timeout that can be selected randomly.
     pub max_election_timeout: usize,
+    /// The number of elections in a row that ended in a split vote, each of which widens the
+    /// range the next election timeout is picked from.
+    pub split_votes: u32,
 }
 
 impl Debug for State {
@@ -306,7 +365,17 @@ impl Debug for State {
     }
 }
 
-impl State {}
+impl State {
+    /// The state of a node that hasn't voted yet, which picks its election timeouts from the
+    /// range `config` allows.
+    pub fn new(config: &RaftConfig) -> Self {
+        State {
+            min_election_timeout: config.min_election_timeout().as_millis() as usize,
+            max_election_timeout: config.election_timeout.as_millis() as usize,
+            ..Default::default()
+        }
+    }
+}
 
 impl Default for State {
     fn default() -> Self {
@@ -317,6 +386,7 @@ impl Default for State {
             election_timeout: None,
             min_election_timeout: 500,
             max_election_timeout: 1000,
+            split_votes: 0,
         }
     }
 }
@@ -338,6 +408,11 @@ pub struct Raft<T: Role + Debug> {
     pub rpc_tx: UnboundedSender<Message>,
     /// Channel to send entries to fsm driver.
     pub fsm_tx: UnboundedSender<Instruction>,
+    /// The read lease held while this node is leader.
+    pub lease: Lease,
+    /// The span for the command being applied, carrying the node's id, role, term and commit
+    /// index so that they are attached to everything logged while applying it.
+    pub span: tracing::Span,
 }
 
 impl<T: Role + Debug> Debug for Raft<T> {
@@ -362,6 +437,47 @@ impl<T: Role> Raft<T> {
         self.state.current_term = term;
 
         self.role.term(term);
+        self.span.record("term", term);
+    }
+
+    /// Starts a new span for the next command, with the node's current context.
+    fn enter_span(&mut self) -> tracing::span::EnteredSpan {
+        self.span = tracing::info_span!(
+            "raft",
+            id = self.id,
+            role = %self.role.role(),
+            term = self.state.current_term,
+            commit_index = self.chain.get_commit().index(),
+        );
+        self.span.clone().entered()
+    }
+
+    /// Records a role transition on the span, so the rest of the command's log lines carry the
+    /// new role.
+    pub(crate) fn record_role(&self) {
+        self.span.record("role", tracing::field::display(self.role.role()));
+    }
+
+    /// Records a new commit index on the span.
+    pub(crate) fn record_commit(&self) {
+        self.span.record("commit_index", self.chain.get_commit().index());
+    }
+
+    /// Acts on a block that was just committed, before it is applied to the state machine.
+    /// Returns whether it changed the membership of the cluster.
+    pub(crate) fn on_commit(&mut self, block: &Block) -> bool {
+        match &block.entry_type {
+            EntryType::Config { nodes } => {
+                tracing::info!(?nodes, "membership changed");
+                self.config.nodes = nodes.iter().filter(|n| n.id != self.id).copied().collect();
+                // learners that became members are promoted
+                self.config
+                    .learners
+                    .retain(|l| !nodes.iter().any(|n| n.id == l.id));
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn log_command(&self, cmd: &Command) {
@@ -400,10 +516,43 @@ impl<T: Role> Raft<T> {
     }
 }
 
+/// Who this node believes leads the cluster, as of some term. Watchers of the status see every
+/// change of term, role or leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Status {
+    /// The id of this node.
+    pub id: NodeId,
+    pub term: Term,
+    /// The role this node holds in the current term.
+    pub role: RaftRole,
+    /// The leader of the current term, if one is known.
+    pub leader: Option<NodeId>,
+}
+
+impl Status {
+    pub fn is_leader(&self) -> bool {
+        self.leader == Some(self.id)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub enum RaftRole {
+    #[default]
     Follower,
     Candidate,
     Leader,
+    Observer,
+}
+
+impl fmt::Display for RaftRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RaftRole::Follower => write!(f, "follower"),
+            RaftRole::Candidate => write!(f, "candidate"),
+            RaftRole::Leader => write!(f, "leader"),
+            RaftRole::Observer => write!(f, "observer"),
+        }
+    }
 }
 
 /// Handle to some variant of the state machine. Commands should always be dispatched to the
@@ -421,17 +570,27 @@ pub enum RaftHandle {
     Candidate(Raft<Candidate>),
     /// An instance of the state machine in the leader role.
     Leader(Raft<Leader>),
+    /// An instance of the state machine that only replicates, and never votes or leads.
+    Observer(Raft<Observer>),
 }
 
 impl RaftHandle {
-    /// Obtain a new instance of raft initialized in the default follower state.
+    /// Obtain a new instance of raft initialized in the default follower state, or as an observer
+    /// if configured as one.
     pub fn new(
         config: RaftConfig,
         rpc_tx: UnboundedSender<Message>,
         fsm_tx: UnboundedSender<Instruction>,
+        lease: Lease,
     ) -> RaftHandle {
-        let raft = Raft::new(config, rpc_tx, fsm_tx);
-        RaftHandle::Follower(raft.unwrap())
+        let observer = config.observer;
+        let mut raft: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx).unwrap();
+        raft.lease = lease;
+        if observer {
+            RaftHandle::Observer(Raft::from(raft))
+        } else {
+            RaftHandle::Follower(raft)
+        }
     }
 
     pub fn is_follower(&self) -> bool {
@@ -446,6 +605,103 @@ impl RaftHandle {
         matches!(self, Self::Leader(_))
     }
 
+    pub fn is_observer(&self) -> bool {
+        matches!(self, Self::Observer(_))
+    }
+
+    /// The members of the cluster as far as this node knows, including itself.
+    pub fn membership(&self) -> Membership {
+        let config = match self {
+            RaftHandle::Follower(raft) => &raft.config,
+            RaftHandle::Candidate(raft) => &raft.config,
+            RaftHandle::Leader(raft) => &raft.config,
+            RaftHandle::Observer(raft) => &raft.config,
+        };
+        let this = Node {
+            id: config.id,
+            addr: SocketAddr::new(config.ip, config.port),
+        };
+        let mut nodes = config.nodes.clone();
+        nodes.push(this);
+        Membership {
+            leader: self.status().leader,
+            nodes,
+        }
+    }
+
+    /// The node's current view of leadership.
+    pub fn status(&self) -> Status {
+        let (id, term, leader) = match self {
+            RaftHandle::Follower(raft) => (raft.id, raft.state.current_term, raft.role.leader_id),
+            RaftHandle::Candidate(raft) => (raft.id, raft.state.current_term, None),
+            RaftHandle::Leader(raft) => (raft.id, raft.state.current_term, Some(raft.id)),
+            RaftHandle::Observer(raft) => (raft.id, raft.state.current_term, raft.role.leader_id),
+        };
+        let role = match self {
+            RaftHandle::Follower(_) => RaftRole::Follower,
+            RaftHandle::Candidate(_) => RaftRole::Candidate,
+            RaftHandle::Leader(_) => RaftRole::Leader,
+            RaftHandle::Observer(_) => RaftRole::Observer,
+        };
+        Status {
+            id,
+            term,
+            role,
+            leader,
+        }
+    }
+
+    fn current_term(&self) -> Term {
+        match self {
+            RaftHandle::Follower(raft) => raft.state.current_term,
+            RaftHandle::Candidate(raft) => raft.state.current_term,
+            RaftHandle::Leader(raft) => raft.state.current_term,
+            RaftHandle::Observer(raft) => raft.state.current_term,
+        }
+    }
+
+    /// The index of the last block known to be committed.
+    pub fn commit_index(&self) -> u64 {
+        let commit = match self {
+            RaftHandle::Follower(raft) => raft.chain.get_commit(),
+            RaftHandle::Candidate(raft) => raft.chain.get_commit(),
+            RaftHandle::Leader(raft) => raft.chain.get_commit(),
+            RaftHandle::Observer(raft) => raft.chain.get_commit(),
+        };
+        commit.index()
+    }
+
+    /// Moves to `term` if it is newer than our own, with leaders and candidates stepping down to
+    /// follower, as every role must on seeing a higher term.
+    fn observe_term(self, term: Term) -> RaftHandle {
+        if term <= self.current_term() {
+            return self;
+        }
+
+        tracing::debug!(term, "observed higher term");
+        match self {
+            RaftHandle::Follower(mut raft) => {
+                raft.term(term);
+                RaftHandle::Follower(raft)
+            }
+            RaftHandle::Candidate(mut raft) => {
+                raft.term(term);
+                let mut raft: Raft<Follower> = Raft::from(raft);
+                // the election is lost, so wait a full timeout before standing again
+                raft.set_election_timeout();
+                RaftHandle::Follower(raft)
+            }
+            RaftHandle::Leader(mut raft) => {
+                raft.term(term);
+                RaftHandle::Follower(Raft::from(raft))
+            }
+            RaftHandle::Observer(mut raft) => {
+                raft.term(term);
+                RaftHandle::Observer(raft)
+            }
+        }
+    }
+
     pub fn get_follower(self) -> Option<Raft<Follower>> {
         match self {
             RaftHandle::Follower(r) => Some(r),
@@ -470,10 +726,27 @@ impl RaftHandle {
 
 impl Apply for RaftHandle {
     fn apply(self, cmd: Command) -> Result<RaftHandle> {
-        match self {
-            RaftHandle::Follower(raft) => raft.apply(cmd),
-            RaftHandle::Candidate(raft) => raft.apply(cmd),
-            RaftHandle::Leader(raft) => raft.apply(cmd),
+        let handle = match cmd.term() {
+            Some(term) => self.observe_term(term),
+            None => self,
+        };
+        match handle {
+            RaftHandle::Follower(mut raft) => {
+                let _span = raft.enter_span();
+                raft.apply(cmd)
+            }
+            RaftHandle::Candidate(mut raft) => {
+                let _span = raft.enter_span();
+                raft.apply(cmd)
+            }
+            RaftHandle::Leader(mut raft) => {
+                let _span = raft.enter_span();
+                raft.apply(cmd)
+            }
+            RaftHandle::Observer(mut raft) => {
+                let _span = raft.enter_span();
+                raft.apply(cmd)
+            }
         }
     }
 }
@@ -491,9 +764,16 @@ pub trait Apply {
 #[cfg(test)]
 mod tests {
     use crate::raft::chain::Chain;
+    use crate::raft::client::{RaftClient, Read};
+    use crate::raft::config::RaftConfig;
+    use crate::raft::fsm::Fsm;
+    use crate::raft::lease::Lease;
     use crate::raft::rpc::Address;
-    use crate::raft::{Command, Raft, RaftRole, Role, Term};
-    use std::time::Instant;
+    use crate::raft::test::{new_follower, CounterFsm};
+    use crate::raft::{Apply, Command, JosefineRaft, Raft, RaftHandle, RaftRole, Role, Term};
+    use crate::Shutdown;
+    use rand::Rng;
+    use std::time::{Duration, Instant};
     use tempfile::tempdir;
 
     #[derive(Debug)]
@@ -523,6 +803,8 @@ mod tests {
             chain: Chain::new(tempdir().unwrap()).unwrap(),
             rpc_tx,
             fsm_tx,
+            lease: Lease::default(),
+            span: tracing::Span::none(),
         };
         raft.state.election_time = Some(Instant::now());
         raft.state.election_timeout = Some(raft.config.election_timeout);
@@ -542,6 +824,8 @@ mod tests {
             chain: Chain::new(tempdir()?)?,
             rpc_tx,
             fsm_tx,
+            lease: Lease::default(),
+            span: tracing::Span::none(),
         };
         raft.send_all(Command::Noop)?;
         let msg = rpc_rx.recv().await.unwrap();
@@ -563,8 +847,145 @@ mod tests {
             chain: Chain::new(tempdir().unwrap()).unwrap(),
             rpc_tx,
             fsm_tx,
+            lease: Lease::default(),
+            span: tracing::Span::none(),
         };
         raft.term(11);
         assert_eq!(raft.role.inner, 11);
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drives_fsm() -> anyhow::Result<()> {
+        let config = RaftConfig {
+            port: rand::thread_rng().gen_range(1025..65535),
+            ..Default::default()
+        };
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(config.proposal_queue_size);
+        let client = RaftClient::new(client_tx, config.proposal_timeout);
+        let shutdown = Shutdown::new();
+        let raft = tokio::spawn(JosefineRaft::new(config).run(
+            CounterFsm::default(),
+            client_rx,
+            shutdown.clone(),
+        ));
+
+        // wait for the single node to elect itself
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        for i in 1..=3u64 {
+            let res = client.propose(vec![i as u8]).await?;
+            assert_eq!(bincode::deserialize::<u64>(&res)?, i);
+        }
+
+        shutdown.shutdown();
+        raft.await??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dedups_retried_proposals() -> anyhow::Result<()> {
+        let config = RaftConfig {
+            port: rand::thread_rng().gen_range(1025..65535),
+            ..Default::default()
+        };
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(config.proposal_queue_size);
+        let client = RaftClient::new(client_tx, config.proposal_timeout);
+        let shutdown = Shutdown::new();
+        let raft = tokio::spawn(JosefineRaft::new(config).run(
+            CounterFsm::default(),
+            client_rx,
+            shutdown.clone(),
+        ));
+
+        // wait for the single node to elect itself
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let request_id = uuid::Uuid::new_v4();
+        for _ in 0..2 {
+            let res = client.propose_with_id(request_id, vec![1]).await?;
+            assert_eq!(bincode::deserialize::<u64>(&res)?, 1);
+        }
+        let res = client.propose(vec![1]).await?;
+        assert_eq!(bincode::deserialize::<u64>(&res)?, 2);
+
+        shutdown.shutdown();
+        raft.await??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn noop() -> anyhow::Result<()> {
+        let config = RaftConfig {
+            port: rand::thread_rng().gen_range(1025..65535),
+            ..Default::default()
+        };
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(config.proposal_queue_size);
+        let client = RaftClient::new(client_tx, config.proposal_timeout);
+        let shutdown = Shutdown::new();
+        let raft = tokio::spawn(JosefineRaft::new(config).run(
+            CounterFsm::default(),
+            client_rx,
+            shutdown.clone(),
+        ));
+
+        // wait for the single node to elect itself
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        client.propose(vec![1]).await?;
+        let latency = client.noop().await?;
+        assert!(latency > Duration::ZERO);
+        // the no-op was committed without counting as a transition
+        let res = client.propose(vec![1]).await?;
+        assert_eq!(bincode::deserialize::<u64>(&res)?, 2);
+
+        shutdown.shutdown();
+        raft.await??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_applied_state() -> anyhow::Result<()> {
+        let config = RaftConfig {
+            port: rand::thread_rng().gen_range(1025..65535),
+            ..Default::default()
+        };
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(config.proposal_queue_size);
+        let client = RaftClient::new(client_tx, config.proposal_timeout);
+        let raft = JosefineRaft::new(config);
+        let applied = raft.applied();
+        let client = client.with_lease(raft.lease()).with_applied(raft.applied());
+        let shutdown = Shutdown::new();
+        let raft = tokio::spawn(raft.run(CounterFsm::default(), client_rx, shutdown.clone()));
+
+        // wait for the single node to elect itself
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        client.propose(vec![1]).await?;
+        // the leader's no-op commits on election, so it holds the lease
+        assert_eq!(client.read().await?, Read::Lease);
+        assert_eq!(*applied.borrow(), 2);
+
+        shutdown.shutdown();
+        raft.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn fsm_snapshot() -> anyhow::Result<()> {
+        let mut fsm = CounterFsm::default();
+        fsm.restore(&bincode::serialize(&11u64)?)?;
+        assert_eq!(bincode::deserialize::<u64>(&fsm.snapshot()?)?, 11);
+        Ok(())
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn log_context() -> anyhow::Result<()> {
+        let (_rx, follower) = new_follower();
+        let node = RaftHandle::Follower(follower).apply(Command::Timeout)?;
+        assert!(node.is_leader());
+        // the transition is recorded on the span of the command that caused it
+        assert!(logs_contain("role=candidate term=1"));
+
+        // the leader's no-op is committed as soon as it is elected
+        node.apply(Command::Tick)?;
+        assert!(logs_contain("role=leader term=1 commit_index=1}"));
+        Ok(())
+    }
 }
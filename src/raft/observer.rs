@@ -0,0 +1,223 @@
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::raft::chain::{Block, BlockId};
+use crate::raft::follower::Follower;
+use crate::raft::fsm::Instruction;
+use crate::raft::rpc::{Address, Message, Response, ResponseError};
+use crate::raft::{Apply, ClientRequest, ClientRequestId, ClientResponse, RaftHandle, RaftRole};
+use crate::raft::{Command, NodeId, Raft, Role, Term};
+
+/// A read replica that replicates the chain from the leader, but never votes or stands for
+/// election.
+#[derive(Debug)]
+pub struct Observer {
+    pub leader_id: Option<NodeId>,
+}
+
+impl Role for Observer {
+    fn term(&mut self, _term: u64) {
+        self.leader_id = None;
+    }
+
+    fn role(&self) -> RaftRole {
+        RaftRole::Observer
+    }
+}
+
+impl Apply for Raft<Observer> {
+    #[tracing::instrument(skip(self))]
+    fn apply(self, cmd: Command) -> Result<RaftHandle> {
+        self.log_command(&cmd);
+        match cmd {
+            Command::AppendEntries {
+                blocks,
+                leader_id,
+                term,
+            } => self.apply_append_entries(blocks, leader_id, term),
+            Command::Heartbeat {
+                leader_id,
+                term,
+                commit,
+                round,
+            } => self.apply_heartbeat(leader_id, term, commit, round),
+            Command::ClientRequest(req) => self.apply_client_request(req),
+            Command::ClientResponse(res) => self.apply_client_response(res.id, res.res),
+            // observers never take part in elections
+            _ => self.apply_self(),
+        }
+    }
+}
+
+impl Raft<Observer> {
+    fn apply_self(self) -> Result<RaftHandle> {
+        Ok(RaftHandle::Observer(self))
+    }
+
+    fn follow(&mut self, leader_id: NodeId, term: Term) {
+        if term > self.state.current_term {
+            self.term(term);
+        }
+        self.role.leader_id = Some(leader_id);
+        self.state.election_time = Some(Instant::now());
+    }
+
+    fn apply_append_entries(
+        mut self,
+        blocks: Vec<Block>,
+        leader_id: NodeId,
+        term: Term,
+    ) -> Result<RaftHandle> {
+        if term < self.state.current_term {
+            return self.apply_self();
+        }
+        self.follow(leader_id, term);
+
+        if !blocks.is_empty() {
+            for block in blocks {
+                self.chain.extend(block)?;
+            }
+
+            self.rpc_tx.send(Message::new(
+                Address::Peer(self.id),
+                Address::Peer(leader_id),
+                Command::AppendResponse {
+                    node_id: self.id,
+                    term: self.state.current_term,
+                    head: self.chain.flush()?,
+                    success: true,
+                },
+            ))?;
+        }
+
+        self.apply_self()
+    }
+
+    fn apply_heartbeat(
+        mut self,
+        leader_id: NodeId,
+        term: Term,
+        commit: BlockId,
+        round: u64,
+    ) -> Result<RaftHandle> {
+        if term < self.state.current_term {
+            return self.apply_self();
+        }
+        self.follow(leader_id, term);
+
+        let has_committed = self.chain.has(&commit)?;
+        if has_committed && commit > self.chain.get_commit() {
+            let prev = self.chain.get_commit();
+            self.chain.commit(&commit)?;
+            self.record_commit();
+            for block in self.chain.range(prev..=commit.clone()).skip(1) {
+                self.on_commit(&block);
+                self.fsm_tx.send(Instruction::Apply { block })?;
+            }
+        }
+
+        self.send(
+            Address::Peer(leader_id),
+            Command::HeartbeatResponse {
+                node_id: self.id,
+                commit: self.chain.get_commit(),
+                has_committed,
+                round,
+            },
+        )?;
+        self.apply_self()
+    }
+
+    fn apply_client_request(self, mut req: ClientRequest) -> Result<RaftHandle> {
+        match self.role.leader_id {
+            Some(leader_id) => {
+                // rewrite address to our own so we can close out the request ourself
+                req.address = Address::Peer(self.id);
+                self.send(Address::Peer(leader_id), Command::ClientRequest(req))?;
+            }
+            None => {
+                self.send(
+                    Address::Client,
+                    Command::ClientResponse(ClientResponse {
+                        id: req.id,
+                        res: Err(ResponseError::new("no known leader")),
+                    }),
+                )?;
+            }
+        }
+        self.apply_self()
+    }
+
+    fn apply_client_response(
+        self,
+        id: ClientRequestId,
+        res: Result<Response, ResponseError>,
+    ) -> Result<RaftHandle> {
+        self.send(
+            Address::Client,
+            Command::ClientResponse(ClientResponse { id, res }),
+        )?;
+        self.apply_self()
+    }
+}
+
+impl From<Raft<Follower>> for Raft<Observer> {
+    fn from(val: Raft<Follower>) -> Raft<Observer> {
+        let mut state = val.state;
+        state.election_timeout = None;
+
+        let raft = Raft {
+            id: val.id,
+            state,
+            role: Observer { leader_id: None },
+            config: val.config,
+            chain: val.chain,
+            rpc_tx: val.rpc_tx,
+            fsm_tx: val.fsm_tx,
+            lease: val.lease,
+            span: val.span,
+        };
+        raft.record_role();
+        raft
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raft::chain::BlockId;
+    use crate::raft::config::RaftConfig;
+    use crate::raft::follower::Follower;
+    use crate::raft::test::new_observer;
+    use crate::raft::{Apply, Command, Node, Raft};
+
+    #[test]
+    fn ignores_timeout() -> anyhow::Result<()> {
+        let ((mut rpc_rx, _), observer) = new_observer();
+        let observer = observer.apply(Command::Timeout)?;
+        assert!(observer.is_observer());
+        let observer = observer.apply(Command::VoteRequest {
+            term: 1,
+            candidate_id: 2,
+            last_term: 0,
+            head: BlockId::new(0),
+        })?;
+        assert!(observer.is_observer());
+        // and never votes
+        assert!(rpc_rx.try_recv().is_err());
+
+        // with a peer to ask for votes, a follower starts an election instead
+        let config = RaftConfig {
+            nodes: vec![Node {
+                id: 2,
+                addr: "127.0.0.1:6670".parse()?,
+            }],
+            ..Default::default()
+        };
+        let (rpc_tx, _rpc_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = tokio::sync::mpsc::unbounded_channel();
+        let follower: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx)?;
+        assert!(follower.apply(Command::Timeout)?.is_candidate());
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
     convert::TryInto,
+    time::Instant,
 };
 
 use crate::raft::chain::BlockId;
@@ -34,11 +35,65 @@ impl ReplicationProgress {
         self.progress.remove(&node_id)
     }
 
+    pub fn node_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.progress.keys().copied()
+    }
+
     pub fn insert(&mut self, node_id: NodeId) {
         self.progress
             .insert(node_id, NodeProgress::Probe(Progress::new(node_id)));
     }
 
+    /// Tracks a node that is replicated to without counting towards commits.
+    pub fn insert_learner(&mut self, node_id: NodeId) {
+        let mut progress = Progress::new(node_id);
+        progress.learner = true;
+        self.progress.insert(node_id, NodeProgress::Probe(progress));
+    }
+
+    pub fn is_learner(&self, node_id: NodeId) -> bool {
+        self.progress.get(&node_id).is_some_and(NodeProgress::is_learner)
+    }
+
+    /// Counts a learner towards commits from now on.
+    pub fn promote(&mut self, node_id: NodeId) {
+        let learner = match self.progress.get_mut(&node_id) {
+            Some(NodeProgress::Probe(prog)) => &mut prog.learner,
+            Some(NodeProgress::Replicate(prog)) => &mut prog.learner,
+            Some(NodeProgress::Snapshot(prog)) => &mut prog.learner,
+            None => return,
+        };
+        *learner = false;
+    }
+
+    /// Records whether the node is caught up as of `now`, returning since when it has been.
+    pub fn caught_up(&mut self, node_id: NodeId, caught_up: bool, now: Instant) -> Option<Instant> {
+        let since = match self.progress.get_mut(&node_id) {
+            Some(NodeProgress::Probe(prog)) => &mut prog.caught_up_since,
+            Some(NodeProgress::Replicate(prog)) => &mut prog.caught_up_since,
+            Some(NodeProgress::Snapshot(prog)) => &mut prog.caught_up_since,
+            None => return None,
+        };
+        match caught_up {
+            true => Some(*since.get_or_insert(now)),
+            false => {
+                *since = None;
+                None
+            }
+        }
+    }
+
+    /// Records that the node just responded to us.
+    pub fn contacted(&mut self, node_id: NodeId) {
+        let last_contact = match self.progress.get_mut(&node_id) {
+            Some(NodeProgress::Probe(prog)) => &mut prog.last_contact,
+            Some(NodeProgress::Replicate(prog)) => &mut prog.last_contact,
+            Some(NodeProgress::Snapshot(prog)) => &mut prog.last_contact,
+            None => return,
+        };
+        *last_contact = Some(Instant::now());
+    }
+
     pub fn advance(&mut self, node_id: NodeId, block_id: BlockId) {
         let node = self.remove(node_id).expect("the node does not exist");
         let node = node.advance(block_id);
@@ -47,7 +102,7 @@ impl ReplicationProgress {
 
     pub fn committed_index(&self) -> BlockId {
         let mut indices = Vec::new();
-        for progress in self.progress.values() {
+        for progress in self.progress.values().filter(|p| !p.is_learner()) {
             match progress {
                 NodeProgress::Probe(pr) => indices.push(pr.head.clone()),
                 NodeProgress::Replicate(pr) => indices.push(pr.head.clone()),
@@ -108,6 +163,24 @@ impl NodeProgress {
             NodeProgress::Snapshot(prog) => prog.head.clone(),
         }
     }
+
+    /// Whether the node is a learner, whose progress doesn't count towards commits.
+    pub fn is_learner(&self) -> bool {
+        match self {
+            NodeProgress::Probe(prog) => prog.learner,
+            NodeProgress::Replicate(prog) => prog.learner,
+            NodeProgress::Snapshot(prog) => prog.learner,
+        }
+    }
+
+    /// When the node last responded to us, if it has at all.
+    pub fn last_contact(&self) -> Option<Instant> {
+        match self {
+            NodeProgress::Probe(prog) => prog.last_contact,
+            NodeProgress::Replicate(prog) => prog.last_contact,
+            NodeProgress::Snapshot(prog) => prog.last_contact,
+        }
+    }
 }
 
 pub trait ProgressState {
@@ -122,6 +195,10 @@ pub struct Progress<T: ProgressState> {
     pub state: T,
     pub active: bool,
     pub head: BlockId,
+    pub last_contact: Option<Instant>,
+    pub learner: bool,
+    /// When the node last caught up with the leader, if it hasn't fallen behind since.
+    pub caught_up_since: Option<Instant>,
 }
 
 impl<T: ProgressState> Progress<T> {
@@ -158,6 +235,9 @@ impl Progress<Probe> {
             state: Probe { paused: false },
             active: false,
             head: BlockId::new(0),
+            last_contact: None,
+            learner: false,
+            caught_up_since: None,
         }
     }
 
@@ -173,6 +253,9 @@ impl From<Progress<Replicate>> for Progress<Probe> {
             state: Probe { paused: false },
             active: progress.active,
             head: progress.head,
+            last_contact: progress.last_contact,
+            learner: progress.learner,
+            caught_up_since: progress.caught_up_since,
         }
     }
 }
@@ -227,6 +310,9 @@ impl From<Progress<Probe>> for Progress<Replicate> {
             },
             active: progress.active,
             head: progress.head,
+            last_contact: progress.last_contact,
+            learner: progress.learner,
+            caught_up_since: progress.caught_up_since,
         }
     }
 }
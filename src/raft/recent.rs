@@ -0,0 +1,56 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// A map that only remembers its most recently inserted entries, forgetting the oldest once it
+/// holds more than its capacity.
+#[derive(Debug)]
+pub struct Recent<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, V> Recent<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.entries.insert(key.clone(), value).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Recent;
+
+    #[test]
+    fn forgets_oldest() {
+        let mut recent = Recent::new(2);
+        recent.insert(1, "a");
+        recent.insert(2, "b");
+        recent.insert(1, "c");
+        assert_eq!(recent.get(&1), Some(&"c"));
+
+        recent.insert(3, "d");
+        assert_eq!(recent.get(&1), None);
+        assert_eq!(recent.get(&2), Some(&"b"));
+        assert_eq!(recent.get(&3), Some(&"d"));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::raft::{Command, NodeId};
 use std::fmt::{Debug, Display, Formatter};
+use uuid::Uuid;
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Address {
@@ -26,28 +27,162 @@ impl Message {
     }
 }
 
+/// The wire encoding used for messages between nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Compact binary encoding, used by default.
+    #[default]
+    Bincode,
+    /// Human readable encoding, useful for debugging.
+    Json,
+}
+
+impl Encoding {
+    pub fn encode(&self, message: &Message) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Bincode => bincode::serialize(message)?,
+            Encoding::Json => serde_json::to_vec(message)?,
+        })
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> anyhow::Result<Message> {
+        Ok(match self {
+            Encoding::Bincode => bincode::deserialize(bytes)?,
+            Encoding::Json => serde_json::from_slice(bytes)?,
+        })
+    }
+}
+
+/// How many recent proposals are remembered to recognise retries.
+pub const RECENT_PROPOSALS: usize = 1024;
+
+/// What the leader is asked to do with a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProposalKind {
+    /// Append the proposal's data to the chain.
+    Write,
+    /// Confirm leadership, so that local state is safe to read.
+    Read,
+    /// Report the health of the cluster as the leader sees it.
+    Describe,
+    /// Start an election on the node the proposal was made on. A leader refuses one.
+    Elect,
+    /// Append an entry that leaves the state machine as it is.
+    Noop,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct Proposal(Vec<u8>);
+pub struct Proposal {
+    /// Chosen by the client and kept across retries, so that the leader only applies the
+    /// proposal once.
+    request_id: Uuid,
+    kind: ProposalKind,
+    data: Vec<u8>,
+}
 
 impl Proposal {
     pub fn new(data: Vec<u8>) -> Self {
-        Self(data)
+        Self::with_request_id(Uuid::new_v4(), data)
+    }
+
+    pub fn with_request_id(request_id: Uuid, data: Vec<u8>) -> Self {
+        Self {
+            request_id,
+            kind: ProposalKind::Write,
+            data,
+        }
+    }
+
+    /// A proposal without data, which is never appended to the chain but answered once the
+    /// leader has confirmed it is still leader, making it safe to read local state.
+    pub fn read() -> Self {
+        Self {
+            kind: ProposalKind::Read,
+            ..Self::new(vec![])
+        }
+    }
+
+    /// A proposal answered by the leader with the health of the cluster, which is never
+    /// appended to the chain.
+    pub fn describe() -> Self {
+        Self {
+            kind: ProposalKind::Describe,
+            ..Self::new(vec![])
+        }
+    }
+
+    /// A proposal that makes the local node stand for election without waiting for its
+    /// election timeout.
+    pub fn elect() -> Self {
+        Self {
+            kind: ProposalKind::Elect,
+            ..Self::new(vec![])
+        }
+    }
+
+    /// A proposal that is appended and committed like a write, but as a no-op entry that
+    /// leaves the state machine unchanged.
+    pub fn noop() -> Self {
+        Self {
+            kind: ProposalKind::Noop,
+            ..Self::new(vec![])
+        }
+    }
+
+    pub fn kind(&self) -> ProposalKind {
+        self.kind
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.kind == ProposalKind::Read
+    }
+
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
     }
 
     pub fn get(self) -> Vec<u8> {
-        self.0
+        self.data
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct Response(Vec<u8>);
+pub struct Response {
+    /// The id of the proposal this is the response to.
+    request_id: Uuid,
+    data: Vec<u8>,
+}
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct ResponseError {}
+pub struct ResponseError {
+    pub message: String,
+}
+
+/// The message of the error a leader refuses proposals with while its backlog is full.
+const BACKLOG_FULL: &str = "too many uncommitted proposals";
+
+impl ResponseError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+
+    /// The leader has too many uncommitted proposals to take another, which it may once more of
+    /// them commit.
+    pub fn backlog_full() -> Self {
+        Self::new(BACKLOG_FULL)
+    }
+
+    pub fn is_backlog_full(&self) -> bool {
+        self.message == BACKLOG_FULL
+    }
+}
 
 impl Display for ResponseError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ResponseError")
+        write!(f, "ResponseError: {}", self.message)
     }
 }
 
@@ -55,10 +190,78 @@ impl std::error::Error for ResponseError {}
 
 impl Response {
     pub fn new(data: Vec<u8>) -> Self {
-        Self(data)
+        Self {
+            request_id: Uuid::nil(),
+            data,
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: Uuid) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
     }
 
     pub fn get(self) -> Vec<u8> {
-        self.0
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, Encoding, Message};
+    use crate::raft::chain::{Block, BlockId};
+    use crate::raft::EntryType;
+    use crate::raft::Command;
+
+    fn round_trip(encoding: Encoding) -> anyhow::Result<()> {
+        let messages = vec![
+            Message::new(Address::Peer(1), Address::Peers, Command::Tick),
+            Message::new(
+                Address::Peer(1),
+                Address::Peer(2),
+                Command::AppendEntries {
+                    term: 3,
+                    leader_id: 1,
+                    blocks: vec![Block {
+                        id: BlockId::new(2),
+                        next: BlockId::new(1),
+                        term: 3,
+                        entry_type: EntryType::Data {
+                            data: vec![1, 2, 3],
+                        },
+                    }],
+                },
+            ),
+            Message::new(
+                Address::Local,
+                Address::Client,
+                Command::VoteRequest {
+                    term: 4,
+                    candidate_id: 2,
+                    last_term: 3,
+                    head: BlockId::new(7),
+                },
+            ),
+        ];
+
+        for message in messages {
+            let bytes = encoding.encode(&message)?;
+            assert_eq!(message, encoding.decode(&bytes)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bincode() -> anyhow::Result<()> {
+        round_trip(Encoding::Bincode)
+    }
+
+    #[test]
+    fn json() -> anyhow::Result<()> {
+        round_trip(Encoding::Json)
     }
 }
@@ -4,11 +4,11 @@ use anyhow::Result;
 use futures::FutureExt;
 use tokio::{
     net::TcpListener,
-    sync::{mpsc::unbounded_channel, oneshot},
+    sync::{mpsc::unbounded_channel, oneshot, watch},
 };
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::time::Duration;
+use tokio::sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
+use tokio::time::{Duration, Interval, MissedTickBehavior};
 use uuid::Uuid;
 
 use crate::raft::{
@@ -17,36 +17,62 @@ use crate::raft::{
     fsm::{self},
 };
 use crate::raft::{ClientRequestId, tcp};
-use crate::raft::{Apply, Command, RaftHandle};
-use crate::raft::rpc::{Address, Message, Proposal, Response, ResponseError};
+use crate::raft::{Apply, Command, Node, RaftHandle, Status};
+use crate::raft::client::ProposalRequest;
+use crate::raft::config::DISCOVERY_PROTOCOL_VERSION;
+use crate::raft::discovery::{self, Membership};
+use crate::raft::lease::Lease;
+use crate::raft::rpc::{Address, Message, ProposalKind, Response, ResponseError};
 use crate::Shutdown;
 
-/// step duration
-const TICK: Duration = Duration::from_millis(100);
-
 #[derive(Debug)]
 pub struct Server {
     config: RaftConfig,
+    lease: Lease,
+    status: watch::Sender<Status>,
+    membership: watch::Sender<Membership>,
+    applied: watch::Sender<u64>,
 }
 
 #[derive(Debug)]
 pub struct ServerRunOpts<T: 'static + fsm::Fsm> {
     pub fsm: T,
-    pub client_rx: UnboundedReceiver<(
-        Proposal,
-        oneshot::Sender<std::result::Result<Response, ResponseError>>,
-    )>,
+    pub client_rx: Receiver<ProposalRequest>,
     pub shutdown: Shutdown,
 }
 
 impl Server {
     pub fn new(config: RaftConfig) -> Self {
-        Server { config }
+        let (status, _) = watch::channel(Status {
+            id: config.id,
+            ..Default::default()
+        });
+        let (membership, _) = watch::channel(Membership::default());
+        let (applied, _) = watch::channel(0);
+        Server {
+            config,
+            lease: Lease::default(),
+            status,
+            membership,
+            applied,
+        }
+    }
+
+    pub fn lease(&self) -> Lease {
+        self.lease.clone()
+    }
+
+    pub fn status(&self) -> watch::Receiver<Status> {
+        self.status.subscribe()
+    }
+
+    pub fn applied(&self) -> watch::Receiver<u64> {
+        self.applied.subscribe()
     }
 
     #[tracing::instrument]
     pub async fn run<T: 'static + fsm::Fsm>(
-        self,
+        mut self,
         run_opts: ServerRunOpts<T>,
     ) -> Result<RaftHandle> {
         tracing::debug!("start raft");
@@ -55,6 +81,7 @@ impl Server {
             client_rx,
             shutdown,
         } = run_opts;
+        self.discover_peers().await;
 
         // tcp receive
         let socket_addr = SocketAddr::new(self.config.ip, self.config.port);
@@ -62,7 +89,14 @@ impl Server {
         let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
         let (tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel::<Message>();
         let (task, tcp_receiver) =
-            tcp::receive_task(shutdown.clone(), listener, tcp_in_tx).remote_handle();
+            tcp::receive_task(
+                shutdown.clone(),
+                listener,
+                tcp_in_tx,
+                self.config.encoding,
+                self.membership.subscribe(),
+            )
+            .remote_handle();
         tokio::spawn(task);
 
         // tcp send
@@ -70,23 +104,37 @@ impl Server {
         let (task, tcp_sender) = tcp::send_task(
             shutdown.clone(),
             self.config.id,
-            self.config.clone().nodes,
+            self.config
+                .nodes
+                .iter()
+                .chain(&self.config.learners)
+                .copied()
+                .collect(),
             tcp_out_rx,
+            self.membership.subscribe(),
+            self.config.encoding,
+            tcp::Backoff::new(&self.config),
         )
         .remote_handle();
         tokio::spawn(task);
 
         // state machine driver
         let (fsm_tx, fsm_rx) = unbounded_channel();
-        let driver = fsm::Driver::new(fsm_rx, rpc_tx.clone(), fsm);
+        let ticks = ticker(&self.config);
+        let raft = RaftHandle::new(self.config, rpc_tx.clone(), fsm_tx.clone(), self.lease);
+        // blocks committed before a restart were applied before it
+        self.applied.send_replace(raft.commit_index());
+        let driver = fsm::Driver::new(fsm_rx, rpc_tx.clone(), fsm).with_applied(self.applied);
         let (task, driver) = driver.run(shutdown.clone()).remote_handle();
         tokio::spawn(task);
 
         // main event loop
-        let raft = RaftHandle::new(self.config, rpc_tx.clone(), fsm_tx.clone());
         let (task, event_loop) = event_loop(
             shutdown.clone(),
             raft,
+            ticks,
+            self.status,
+            self.membership,
             tcp_out_tx,
             rpc_rx,
             tcp_in_rx,
@@ -98,20 +146,52 @@ impl Server {
         let (raft, _, _, _) = tokio::try_join!(event_loop, tcp_receiver, tcp_sender, driver)?;
         Ok(raft)
     }
+
+    /// Replaces the configured peers with the members of the cluster, as learned from the seeds,
+    /// which have the leader add us to them. The configured peers are kept if no seed answers.
+    async fn discover_peers(&mut self) {
+        if self.config.seeds.is_empty() {
+            return;
+        }
+        if !self.config.supports(DISCOVERY_PROTOCOL_VERSION) {
+            tracing::warn!("protocol version is too old to discover peers from seeds");
+            return;
+        }
+        let this = Node {
+            id: self.config.id,
+            addr: SocketAddr::new(self.config.ip, self.config.port),
+        };
+        match discovery::discover(&self.config.seeds, this, self.config.encoding).await {
+            Ok(membership) => {
+                tracing::info!(?membership, "discovered cluster");
+                let id = self.config.id;
+                self.config.nodes = membership.nodes.into_iter().filter(|n| n.id != id).collect();
+            }
+            Err(e) => tracing::warn!(%e, "could not discover cluster, using configured peers"),
+        }
+    }
+}
+
+/// Emits the ticks that drive the state machine, at the configured interval. Ticks missed while
+/// the event loop was busy are delayed rather than fired in a burst.
+fn ticker(config: &RaftConfig) -> Interval {
+    let mut ticks = tokio::time::interval(Duration::from_millis(config.tick_interval_ms));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn event_loop(
     mut shutdown: Shutdown,
     mut raft: RaftHandle,
+    mut ticks: Interval,
+    status: watch::Sender<Status>,
+    membership: watch::Sender<Membership>,
     tcp_tx: UnboundedSender<Message>,
     mut rpc_rx: UnboundedReceiver<Message>,
     mut tcp_rx: UnboundedReceiver<Message>,
-    mut client_rx: UnboundedReceiver<(
-        Proposal,
-        oneshot::Sender<std::result::Result<Response, ResponseError>>,
-    )>,
+    mut client_rx: Receiver<ProposalRequest>,
 ) -> Result<RaftHandle> {
-    let mut step_interval = tokio::time::interval(TICK);
     let mut requests = HashMap::<
         ClientRequestId,
         oneshot::Sender<std::result::Result<Response, ResponseError>>,
@@ -122,7 +202,7 @@ async fn event_loop(
             // shutdown
             _ = shutdown.wait() => break,
             // tick state machine
-            _ = step_interval.tick() => raft = raft.apply(Command::Tick)?,
+            _ = ticks.tick() => raft = raft.apply(Command::Tick)?,
             // intra-cluster communication
             Some(msg) = tcp_rx.recv() => {
                 match msg {
@@ -154,11 +234,27 @@ async fn event_loop(
             },
             // incoming messages from clients
             Some((proposal, res)) = client_rx.recv() => {
-                let id = Uuid::new_v4();
-                requests.insert(id, res);
-                raft = raft.apply(Command::ClientRequest(ClientRequest { id, proposal, address: Address::Client }))?;
+                // forced elections start on this node, rather than being proposed to the leader
+                if proposal.kind() == ProposalKind::Elect {
+                    tracing::info!(status = ?raft.status(), "manually triggered election");
+                    raft = raft.apply(Command::Timeout)?;
+                    let _ = res.send(Ok(Response::new(vec![])));
+                } else {
+                    let id = Uuid::new_v4();
+                    requests.insert(id, res);
+                    raft = raft.apply(Command::ClientRequest(ClientRequest { id, proposal, address: Address::Client }))?;
+                }
             },
         }
+
+        let current = raft.status();
+        status.send_if_modified(|status| std::mem::replace(status, current) != current);
+        let current = raft.membership();
+        membership.send_if_modified(|membership| {
+            let changed = *membership != current;
+            *membership = current;
+            changed
+        });
     }
 
     Ok(raft)
@@ -171,23 +267,36 @@ mod tests {
     use anyhow::Result;
     use tokio::sync::mpsc::{self, unbounded_channel};
 
+    use crate::raft::client::RaftClient;
+    use crate::raft::lease::Lease;
     use crate::raft::RaftConfig;
     use crate::raft::RaftHandle;
+    use crate::raft::RaftRole;
     use crate::Shutdown;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn event_loop() -> Result<()> {
         let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
         let (fsm_tx, _fsm_rx) = unbounded_channel();
-        let raft = RaftHandle::new(RaftConfig::default(), rpc_tx.clone(), fsm_tx.clone());
+        let raft = RaftHandle::new(
+            RaftConfig::default(),
+            rpc_tx.clone(),
+            fsm_tx.clone(),
+            Lease::default(),
+        );
 
         let (_tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel();
         let (tcp_out_tx, _tcp_out_rx) = mpsc::unbounded_channel();
-        let (_client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_client_tx, client_rx) = tokio::sync::mpsc::channel(1);
+        let (status_tx, status_rx) = tokio::sync::watch::channel(Default::default());
+        let (membership_tx, _membership_rx) = tokio::sync::watch::channel(Default::default());
         let shutdown = Shutdown::new();
         let event_loop = super::event_loop(
             shutdown.clone(),
             raft,
+            super::ticker(&RaftConfig::default()),
+            status_tx,
+            membership_tx,
             tcp_out_tx,
             rpc_rx,
             tcp_in_rx,
@@ -202,6 +311,108 @@ mod tests {
         } else {
             panic!("was not elected leader");
         }
+        assert!(status_rx.borrow().is_leader());
         Ok(())
     }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn force_election() -> Result<()> {
+        // long enough that the node won't stand for election on its own
+        let config = RaftConfig {
+            election_timeout: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let raft = RaftHandle::new(config.clone(), rpc_tx, fsm_tx, Lease::default());
+        assert!(raft.is_follower());
+
+        let (_tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel();
+        let (tcp_out_tx, _tcp_out_rx) = mpsc::unbounded_channel();
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(1);
+        let (status_tx, mut status_rx) = tokio::sync::watch::channel(Default::default());
+        let (membership_tx, _membership_rx) = tokio::sync::watch::channel(Default::default());
+        let shutdown = Shutdown::new();
+        tokio::spawn(super::event_loop(
+            shutdown.clone(),
+            raft,
+            super::ticker(&config),
+            status_tx,
+            membership_tx,
+            tcp_out_tx,
+            rpc_rx,
+            tcp_in_rx,
+            client_rx,
+        ));
+
+        let client = RaftClient::new(client_tx, Duration::from_secs(1));
+        client.force_election().await?;
+        // with no other nodes to vote, the candidate wins straight away
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            status_rx.wait_for(|status| status.is_leader()),
+        )
+        .await??;
+        assert!(logs_contain("manually triggered election"));
+        assert!(logs_contain("role=candidate"));
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn status_on_becoming_leader() -> Result<()> {
+        let config = RaftConfig::default();
+        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let raft = RaftHandle::new(config.clone(), rpc_tx, fsm_tx, Lease::default());
+
+        let (_tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel();
+        let (tcp_out_tx, _tcp_out_rx) = mpsc::unbounded_channel();
+        let (_client_tx, client_rx) = tokio::sync::mpsc::channel(1);
+        let (status_tx, mut status_rx) = tokio::sync::watch::channel(Default::default());
+        let (membership_tx, _membership_rx) = tokio::sync::watch::channel(Default::default());
+        let shutdown = Shutdown::new();
+        tokio::spawn(super::event_loop(
+            shutdown.clone(),
+            raft,
+            super::ticker(&config),
+            status_tx,
+            membership_tx,
+            tcp_out_tx,
+            rpc_rx,
+            tcp_in_rx,
+            client_rx,
+        ));
+
+        let status = *tokio::time::timeout(
+            Duration::from_secs(5),
+            status_rx.wait_for(|status| status.role == RaftRole::Leader),
+        )
+        .await??;
+        // the only node wins the first election it stands in
+        assert_eq!(status.term, 1);
+        assert_eq!(status.leader, Some(status.id));
+        // and heartbeats while it leads don't count as changes
+        let changed = tokio::time::timeout(Duration::from_millis(500), status_rx.changed()).await;
+        assert!(changed.is_err());
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticker() {
+        let config = RaftConfig {
+            tick_interval_ms: 50,
+            ..Default::default()
+        };
+        let mut ticks = super::ticker(&config);
+        let start = tokio::time::Instant::now();
+        // the first tick fires straight away
+        ticks.tick().await;
+        for _ in 0..20 {
+            ticks.tick().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
 }
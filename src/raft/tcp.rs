@@ -1,7 +1,10 @@
-use crate::raft::rpc::{Address, Message};
-use crate::raft::{Node, NodeId};
+use crate::raft::config::RaftConfig;
+use crate::raft::discovery::Membership;
+use crate::raft::rpc::{Address, Encoding, Message};
+use crate::raft::{Command, Node, NodeId};
 use anyhow::Result;
 use futures::SinkExt;
+use rand::Rng;
 use std::collections::HashMap;
 
 use crate::Shutdown;
@@ -9,14 +12,17 @@ use tokio::net::{TcpListener, TcpStream};
 
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
 use tokio::time::Duration;
 use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{Framed, FramedWrite, LengthDelimitedCodec};
 
 pub async fn receive_task(
     mut shutdown: Shutdown,
     listener: TcpListener,
     in_tx: UnboundedSender<Message>,
+    encoding: Encoding,
+    membership: watch::Receiver<Membership>,
 ) -> Result<()> {
     loop {
         tokio::select! {
@@ -24,8 +30,9 @@ pub async fn receive_task(
 
             Ok((s, _addr)) = listener.accept() => {
                 let peer_in_tx = in_tx.clone();
+                let membership = membership.clone();
                 tokio::spawn(async move {
-                    match stream_messages(s, peer_in_tx).await {
+                    match stream_messages(s, peer_in_tx, encoding, membership).await {
                         Ok(()) => { }
                         Err(_) => { }
                     }
@@ -37,32 +44,87 @@ pub async fn receive_task(
     Ok(())
 }
 
-async fn stream_messages(stream: TcpStream, in_tx: UnboundedSender<Message>) -> Result<()> {
-    let length_delimited = FramedRead::new(stream, LengthDelimitedCodec::new());
-    let mut stream = tokio_serde::SymmetricallyFramed::new(
-        length_delimited,
-        tokio_serde::formats::SymmetricalJson::default(),
-    );
-
-    while let Some(message) = stream.try_next().await? {
-        in_tx.send(message)?;
+async fn stream_messages(
+    stream: TcpStream,
+    in_tx: UnboundedSender<Message>,
+    encoding: Encoding,
+    membership: watch::Receiver<Membership>,
+) -> Result<()> {
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+
+    while let Some(frame) = stream.try_next().await? {
+        let message = encoding.decode(&frame)?;
+        match message.command {
+            Command::Discover(_) => {
+                let membership = Command::Discovered(membership.borrow().clone());
+                let res = Message::new(Address::Local, message.from.clone(), membership);
+                stream.send(encoding.encode(&res)?.into()).await?;
+                in_tx.send(message)?;
+            }
+            _ => in_tx.send(message)?,
+        }
     }
     Ok(())
 }
 
+/// The waits between attempts to reconnect to a node, doubling after each failure up to an
+/// optional cap. Each wait is shortened by a random part of up to `jitter` of it.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Option<Duration>,
+    jitter: f64,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: &RaftConfig) -> Backoff {
+        Backoff {
+            initial: config.reconnect_backoff,
+            max: config.reconnect_backoff_max,
+            jitter: config.reconnect_jitter,
+            next: config.reconnect_backoff,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = delay.checked_mul(2).unwrap_or(delay);
+        if let Some(max) = self.max {
+            self.next = self.next.min(max);
+        }
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=self.jitter))
+    }
+
+    /// Starts over from the initial wait, once a connection has been made.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Sends messages to `nodes`, and to the nodes that join the cluster later on, as they show up
+/// in `membership`.
 #[tracing::instrument]
 pub async fn send_task(
     mut shutdown: Shutdown,
     id: NodeId,
     nodes: Vec<Node>,
     out_rx: UnboundedReceiver<Message>,
+    mut membership: watch::Receiver<Membership>,
+    encoding: Encoding,
+    backoff: Backoff,
 ) -> Result<()> {
     let mut node_txs: HashMap<NodeId, mpsc::Sender<Message>> = HashMap::new();
+    let senders = shutdown.clone();
+    let connect = move |node: Node| {
+        let (tx, rx) = mpsc::channel::<Message>(1000);
+        tokio::spawn(connect_and_send(node, rx, senders.clone(), encoding, backoff.clone()));
+        tx
+    };
 
     for node in nodes.iter() {
-        let (tx, rx) = mpsc::channel::<Message>(1000);
-        node_txs.insert(node.id, tx);
-        tokio::spawn(connect_and_send(*node, rx, shutdown.clone()));
+        node_txs.insert(node.id, connect(*node));
     }
 
     let mut s = stream::UnboundedReceiverStream(out_rx);
@@ -73,6 +135,15 @@ pub async fn send_task(
                 break
             },
 
+            Ok(()) = membership.changed() => {
+                let nodes = membership.borrow_and_update().nodes.clone();
+                for node in nodes {
+                    if node.id != id && !node_txs.contains_key(&node.id) {
+                        node_txs.insert(node.id, connect(node));
+                    }
+                }
+            },
+
             message = s.next() => {
                 if let Some(mut message) = message {
                     if message.from == Address::Local {
@@ -106,13 +177,16 @@ pub async fn send_task(
 ///
 /// * `node` - The node which messages will be sent to.
 /// * `out_rx` - The channel messages to send are written to.
+/// * `encoding` - The wire encoding for messages.
+/// * `backoff` - The waits between failed connection attempts.
 #[tracing::instrument]
 async fn connect_and_send(
     node: Node,
     mut out_rx: Receiver<Message>,
     mut shutdown: Shutdown,
+    encoding: Encoding,
+    mut backoff: Backoff,
 ) -> Result<()> {
-    let mut backoff = 1;
     loop {
         tokio::select! {
             _ = shutdown.wait() => break,
@@ -121,12 +195,12 @@ async fn connect_and_send(
                 match connect {
                     Ok(socket) => {
                         tracing::debug!(?node, "connected to node");
-                        send_messages(socket, &mut out_rx).await?;
+                        backoff.reset();
+                        send_messages(socket, &mut out_rx, encoding).await?;
                     },
                     Err(e) => {
                         tracing::error!(?node, %e, "error connecting to node");
-                        tokio::time::sleep(Duration::from_secs(backoff)).await;
-                        backoff = backoff.checked_mul(2).unwrap_or(backoff);
+                        tokio::time::sleep(backoff.next_delay()).await;
                     }
                 }
             }
@@ -140,17 +214,18 @@ async fn connect_and_send(
 ///
 /// * `socket` - The TCP socket messages will be written to.
 /// * `out_rx` - The channel from which to receive new messages to write.
-async fn send_messages(socket: TcpStream, out_rx: &mut mpsc::Receiver<Message>) -> Result<()> {
+/// * `encoding` - The wire encoding for messages.
+async fn send_messages(
+    socket: TcpStream,
+    out_rx: &mut mpsc::Receiver<Message>,
+    encoding: Encoding,
+) -> Result<()> {
     // identify frames with a header indicating length
-    let length_delimited = FramedWrite::new(socket, LengthDelimitedCodec::new());
-    let mut stream = tokio_serde::SymmetricallyFramed::new(
-        length_delimited,
-        tokio_serde::formats::SymmetricalJson::default(),
-    );
+    let mut stream = FramedWrite::new(socket, LengthDelimitedCodec::new());
 
     let mut s = stream::ReceiverStream(out_rx);
     while let Some(message) = s.next().await {
-        stream.send(message).await?;
+        stream.send(encoding.encode(&message)?.into()).await?;
     }
     Ok(())
 }
@@ -175,7 +250,8 @@ mod tests {
         let listener = TcpListener::bind(&addr).await?;
         let (tx, mut rx) = mpsc::unbounded_channel();
         let shutdown = Shutdown::new();
-        tokio::spawn(receive_task(shutdown, listener, tx));
+        let (_membership_tx, membership_rx) = watch::channel(Default::default());
+        tokio::spawn(receive_task(shutdown, listener, tx, Encoding::Json, membership_rx));
         let stream = TcpStream::connect(&addr).await?;
         let out_msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
 
@@ -200,6 +276,7 @@ mod tests {
         let listener = TcpListener::bind("127.0.0.1:8080").await?;
         let (tx, rx) = mpsc::unbounded_channel();
         let shutdown = Shutdown::new();
+        let (_membership_tx, membership_rx) = watch::channel(Default::default());
         tokio::spawn(send_task(
             shutdown,
             1,
@@ -208,6 +285,9 @@ mod tests {
                 addr: "127.0.0.1:8080".parse()?,
             }],
             rx,
+            membership_rx,
+            Encoding::Json,
+            Backoff::new(&RaftConfig::default()),
         ));
 
         let out_msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
@@ -227,6 +307,74 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn sends_to_joined_node() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let shutdown = Shutdown::new();
+        let (membership_tx, membership_rx) = watch::channel(Membership::default());
+        tokio::spawn(send_task(
+            shutdown.clone(),
+            1,
+            vec![],
+            rx,
+            membership_rx,
+            Encoding::Json,
+            Backoff::new(&RaftConfig::default()),
+        ));
+
+        let joined = Node {
+            id: 2,
+            addr: listener.local_addr()?,
+        };
+        membership_tx.send(Membership {
+            leader: Some(1),
+            nodes: vec![joined],
+        })?;
+        // the membership change is noticed before the message is sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
+        tx.send(msg.clone())?;
+
+        let (stream, _addr) = listener.accept().await?;
+        let mut frame = FramedRead::new(stream, LengthDelimitedCodec::new());
+        let received = frame.next().await.unwrap()?;
+        assert_eq!(serde_json::from_slice::<Message>(&received)?, msg);
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_jitter_and_cap() {
+        let config = RaftConfig {
+            reconnect_backoff: Duration::from_millis(100),
+            reconnect_backoff_max: Some(Duration::from_millis(1000)),
+            reconnect_jitter: 0.5,
+            ..Default::default()
+        };
+        let mut backoff = Backoff::new(&config);
+        let delays: Vec<Duration> = (0..20).map(|_| backoff.next_delay()).collect();
+        let expected = [100, 200, 400, 800].into_iter().chain(std::iter::repeat(1000));
+        for (delay, base) in delays.iter().zip(expected) {
+            let base = Duration::from_millis(base);
+            assert!(*delay <= base && *delay >= base / 2, "{:?} for {:?}", delay, base);
+        }
+        // the capped waits are spread out rather than all the same
+        assert!(delays[4..].iter().any(|d| *d != delays[4]));
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+
+        // without jitter the waits are exact
+        let mut backoff = Backoff::new(&RaftConfig {
+            reconnect_jitter: 0.0,
+            reconnect_backoff_max: None,
+            ..config
+        });
+        let delays: Vec<u128> = (0..6).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1600, 3200]);
+    }
 }
 
 mod stream {
@@ -1,20 +1,32 @@
 use tokio::sync::mpsc::{self, UnboundedReceiver};
 
 use crate::raft::candidate::Candidate;
+use crate::raft::observer::Observer;
 use crate::raft::fsm::Instruction;
-use crate::raft::Raft;
 use crate::raft::{config::RaftConfig, follower::Follower, fsm::Fsm, rpc::Message};
+use crate::raft::{Entry, EntryType, Raft};
 
-#[derive(Debug)]
-pub(crate) struct TestFsm {
-    state: u8,
+/// A trivial state machine that counts the data entries applied to it.
+#[derive(Debug, Default)]
+pub(crate) struct CounterFsm {
+    count: u64,
 }
 
-impl Fsm for TestFsm {
-    fn transition(&mut self, input: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-        let data = input.first().unwrap();
-        self.state = *data;
-        Ok(input)
+impl Fsm for CounterFsm {
+    fn apply(&mut self, entry: &Entry) -> anyhow::Result<Vec<u8>> {
+        if let EntryType::Data { .. } = entry.entry_type {
+            self.count += 1;
+        }
+        Ok(bincode::serialize(&self.count)?)
+    }
+
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.count)?)
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+        self.count = bincode::deserialize(snapshot)?;
+        Ok(())
     }
 }
 
@@ -39,3 +51,17 @@ pub(crate) fn new_candidate() -> (
     let raft = Raft::from(raft);
     ((rpc_rx, fsm_rx), raft)
 }
+
+pub(crate) fn new_observer() -> (
+    (UnboundedReceiver<Message>, UnboundedReceiver<Instruction>),
+    Raft<Observer>,
+) {
+    let config = RaftConfig {
+        observer: true,
+        ..Default::default()
+    };
+    let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+    let (fsm_tx, fsm_rx) = mpsc::unbounded_channel();
+    let raft: Raft<Follower> = Raft::new(config, rpc_tx, fsm_tx).unwrap();
+    ((rpc_rx, fsm_rx), Raft::from(raft))
+}
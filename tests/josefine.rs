@@ -7,8 +7,16 @@ use josefine::josefine_with_config;
 use tokio::time::Duration;
 use josefine::broker::BrokerId;
 
+use josefine::kafka::KafkaClient;
 use josefine::raft::Node;
 use josefine::util::Shutdown;
+use kafka_protocol::messages::create_topics_request::CreatableTopic;
+use kafka_protocol::messages::{
+    ApiKey, ApiVersionsRequest, CreateTopicsRequest, RequestHeader, RequestKind, ResponseKind,
+    TopicName,
+};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::ResponseError::UnsupportedVersion;
 
 #[derive(Debug)]
 struct NodeManager {
@@ -46,6 +54,9 @@ impl NodeManager {
                 id: x.1.broker.id,
                 ip: x.1.broker.ip,
                 port: x.1.broker.port,
+                rack: x.1.broker.rack.clone(),
+                advertised_host: x.1.broker.advertised_host.clone(),
+                advertised_port: x.1.broker.advertised_port,
             })
             .collect();
 
@@ -98,6 +109,18 @@ impl NodeManager {
     }
 }
 
+/// The broker answers an ApiVersions request at a version newer than it knows with an error,
+/// and the versions it does support.
+fn assert_unsupported_api_versions(res: ResponseKind) {
+    match res {
+        ResponseKind::ApiVersionsResponse(res) => {
+            assert_eq!(res.error_code, UnsupportedVersion.code());
+            assert!(res.api_keys.contains_key(&(ApiKey::ApiVersionsKey as i16)));
+        }
+        res => panic!("wrong response type {:?}", res),
+    }
+}
+
 #[tokio::test]
 #[tracing_test::traced_test]
 async fn single_node() -> anyhow::Result<()> {
@@ -115,9 +138,10 @@ async fn single_node() -> anyhow::Result<()> {
     let mut req = ApiVersionsRequest::default();
     req.client_software_name = StrBytes::from_str("test");
     req.client_software_version = StrBytes::from_str("1.0.0");
-    client
+    let res = client
         .send(header, RequestKind::ApiVersionsRequest(req))
         .await?;
+    assert_unsupported_api_versions(res);
     Ok(())
 }
 
@@ -184,8 +208,9 @@ async fn multi_node() -> anyhow::Result<()> {
     let mut req = ApiVersionsRequest::default();
     req.client_software_name = StrBytes::from_str("test");
     req.client_software_version = StrBytes::from_str("1.0.0");
-    client
+    let res = client
         .send(header, RequestKind::ApiVersionsRequest(req))
         .await?;
+    assert_unsupported_api_versions(res);
     Ok(())
 }